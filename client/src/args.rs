@@ -1,22 +1,39 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
+#[cfg(feature = "tsparser")]
+use crate::langserver::native::NativeCheckServer;
+#[cfg(feature = "tsnative")]
+use crate::langserver::ts::native::NativeTsServer;
 use crate::{
-    cache::Cache,
-    completion::{codex::CodexClientBuilder, ArcCompletionEngine, CompletionClientBuilder},
-    completion::{local::LocalModelClientBuilder, ArcCompletionModel},
+    cache::{manage::NamespaceFilter, Cache, CacheBuilder},
+    completion::{
+        codex::{CodexClientBuilder, RateLimitConfig},
+        ArcCompletionEngine, CompletionClientBuilder,
+    },
+    completion::{
+        local::LocalModelClientBuilder, retry::RetryPolicy, tokenizer::Tokenizer,
+        ArcCompletionModel,
+    },
     get_path_from_rootdir,
-    langserver::{py::PyServer, ts::TsServer, ArcLangServer, LangServer},
+    langserver::{
+        memo::MemoServer, py::PyServer, ts::TsServer, AnnotateType, ArcLangServer, LangServer,
+        ProblemWeights, ProblemWhitelist,
+    },
     main_strategies::{MainStrategy, SimpleStrategy, TreeStrategy},
+    socket::Supervision,
 };
-use tokio::sync::Mutex;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 /// OpenTau, a program that uses Natural Language Models for Code to
 /// type-infer and generate types for gradually typed languages.
 #[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
+#[clap(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 pub struct Args {
+    /// Runs a subcommand instead of completing a file
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
     /// The API token for an online completion engine. Not required if using a local engine.
     #[clap(short, long, value_parser)]
     pub tokens: Option<String>,
@@ -26,13 +43,13 @@ pub struct Args {
     #[clap(short, long, value_parser, default_value = "ts")]
     pub lang: String,
 
-    /// The target file path
-    #[clap(short, long, value_parser)]
-    pub file: String,
+    /// The target file path. Required unless running a subcommand
+    #[clap(short, long, value_parser, required = true)]
+    pub file: Option<String>,
 
-    /// Output file directory path
-    #[clap(short, long, value_parser)]
-    pub output: String,
+    /// Output file directory path. Required unless running a subcommand
+    #[clap(short, long, value_parser, required = true)]
+    pub output: Option<String>,
 
     /// Completion strategy. Either: {"simple": simple completion, "tree": tree completion}
     #[clap(short, long, value_parser, default_value = "tree")]
@@ -68,16 +85,121 @@ pub struct Args {
     #[clap(long, value_parser, default_value_t = 1)]
     pub stop_at: usize,
 
-    /// The Redis URL for the cache
+    /// The URL of the cache. The scheme selects the backend: "redis://host:port" for a
+    /// Redis server, "file://path/to/dir" for a directory of JSON files that persists
+    /// between runs, or "memory://" (optionally "memory://<capacity>") for an in-memory
+    /// LRU cache. The cache can be inspected and managed with `main cache --help`.
     #[clap(short, long, value_parser)]
     pub cache: Option<String>,
 
+    /// The project namespace of the cache. Entries are namespaced by project and engine, so
+    /// that different projects and engines never share completions.
+    #[clap(long, value_parser, default_value = "default")]
+    pub cache_project: String,
+
+    /// The time to live of the cache entries, in seconds. By default, entries never expire.
+    #[clap(long, value_parser)]
+    pub cache_ttl: Option<u64>,
+
+    /// Deletes the cache entries of the project and engine before running
+    #[clap(long, value_parser, default_value_t = false)]
+    pub cache_invalidate: bool,
+
     /// Whether or not to prevent rate limits. You may want to set this to false if You
     /// are using your own model. By default, we try to prevent rate limits, by using
     /// this flag you can disable this behavior.
     #[clap(long, value_parser, default_value_t = false)]
     pub disable_rate_limit: bool,
 
+    /// Path to a JSON file with the requests and tokens per minute quotas of each API key.
+    /// See `RateLimitConfig` for the format. By default, every key may make 20 requests
+    /// per minute.
+    #[clap(long, value_parser)]
+    pub rate_limit_config: Option<String>,
+
+    /// Path to a JSON file with the price per 1000 tokens of each engine, used to estimate
+    /// the cost of the run. See `PriceTable` for the format. By default, codex is priced
+    /// like davinci and the local engines are free.
+    #[clap(long, value_parser)]
+    pub price_table: Option<String>,
+
+    /// The context window of the model, in tokens. Prompts that don't fit get shrunk.
+    /// Defaults to the context window of the engine.
+    #[clap(long, value_parser)]
+    pub context_size: Option<usize>,
+
+    /// Path to the `tokenizer.json` of the local model, to count the tokens of prompts
+    /// exactly. Without it, the tokens are estimated with a safety margin.
+    #[clap(long, value_parser)]
+    pub tokenizer: Option<String>,
+
+    /// The number of holes a local model fills per request. Defaults to all the holes of
+    /// the prompt.
+    #[clap(long, value_parser)]
+    pub hole_batch_size: Option<usize>,
+
+    /// Disables filling multiple holes per request with local models, which makes one
+    /// request per hole
+    #[clap(long, value_parser, default_value_t = false)]
+    pub disable_hole_batching: bool,
+
+    /// The maximum number of attempts for a model request, including the first one.
+    /// Rate limits and transient errors are retried with exponential backoff.
+    #[clap(long, value_parser, default_value_t = 5)]
+    pub retry_max_attempts: usize,
+
+    /// The delay before the first retry of a model request, in milliseconds.
+    /// It is doubled on every retry, with some jitter.
+    #[clap(long, value_parser, default_value_t = 500)]
+    pub retry_base_delay: u64,
+
+    /// The maximum delay between two attempts of a model request, in milliseconds
+    #[clap(long, value_parser, default_value_t = 60000)]
+    pub retry_max_delay: u64,
+
+    /// Which implementation of the completeness and quality heuristic to use. Either:
+    /// {"ls": the language server, "native": a Rust implementation that avoids a round
+    /// trip to the language server, only for "ts"}
+    #[clap(long, value_parser, default_value = "ls")]
+    pub checker: String,
+
+    /// Runs the printing, stubbing, tree building and weaving of the language server
+    /// natively in Rust, instead of in the language server. Only for "ts", and needs the
+    /// `tsnative` feature
+    #[clap(long, value_parser, default_value_t = false)]
+    pub native_ls: bool,
+
+    /// Checks every native weave against the weave of the language server, and uses the
+    /// latter when they differ. Used to validate the native weave on a dataset, only with
+    /// `--native-ls`
+    #[clap(long, value_parser, default_value_t = false)]
+    pub validate_native_weave: bool,
+
+    /// The type checker to run on the completions for "py". Either "mypy" or "pyright",
+    /// which has to be installed
+    #[clap(long, value_parser, default_value = "mypy")]
+    pub py_checker: String,
+
+    /// Disables memoizing the results of the pure language server commands, which the tree
+    /// strategy calls many times with the same arguments
+    #[clap(long, value_parser, default_value_t = false)]
+    pub disable_ls_memo: bool,
+
+    /// The interval between the health checks of the language server, in seconds. A server
+    /// that exited or doesn't answer them is respawned. 0 disables the health checks
+    #[clap(long, value_parser, default_value_t = 30)]
+    pub ls_health_check_interval: u64,
+
+    /// How long a health check may take before the language server is considered hung, in
+    /// seconds. The server may be busy with a slow command before it answers, e.g. type
+    /// checking a large file
+    #[clap(long, value_parser, default_value_t = 120)]
+    pub ls_ping_timeout: u64,
+
+    /// The maximum number of results memoized per language server command
+    #[clap(long, value_parser, default_value_t = MemoServer::DEFAULT_CAPACITY)]
+    pub ls_memo_capacity: usize,
+
     /// The maximum type-quality score for a completion to be valid (lower means better quality)
     #[clap(long, short, value_parser, default_value_t = 1000)]
     pub max_type_quality: u16,
@@ -90,54 +212,278 @@ pub struct Args {
     #[clap(long, value_parser, default_value_t = false)]
     pub enable_defgen: bool,
 
+    /// Writes the completions as JavaScript, where the types are JSDoc comments instead of
+    /// annotations, and type checks them again as JavaScript. Only for "ts", for
+    /// JavaScript files that have to stay JavaScript
+    #[clap(long, value_parser, default_value_t = false)]
+    pub jsdoc: bool,
+
+    /// Writes a declaration file (.d.ts) of the exported declarations of the best
+    /// completion, checked against the original JavaScript, instead of the completions.
+    /// Only for "ts", for JavaScript libraries
+    #[clap(long, value_parser, default_value_t = false)]
+    pub dts: bool,
+
     /// Depth limit for the tree strategy
     #[clap(long, value_parser)]
     pub depth_limit: Option<usize>,
 
+    /// Enables type checking the completions of the simple strategy as they come from the
+    /// model. With streaming, the model requests stop once `stop-at` completions type check,
+    /// so the completions are the first ones that type check rather than the best ones,
+    /// and they are not cached.
+    #[clap(long, value_parser, default_value_t = false)]
+    pub enable_streaming: bool,
+
     /// Disables the usage blocks in the tree strategy prompts
     #[clap(long, value_parser, default_value_t = false)]
     pub disable_usages: bool,
 
-    /// Disables stubbing inner code blocks in the tree strategy prompts
+    /// Disables always stubbing inner code blocks in the tree strategy prompts. They still
+    /// get stubbed when a prompt does not fit in the context window of the model.
     #[clap(long, value_parser, default_value_t = false)]
     pub disable_stubbing: bool,
 
+    /// Disables prepending the declarations imported from sibling modules and .d.ts files
+    /// to the prompts
+    #[clap(long, value_parser, default_value_t = false)]
+    pub disable_import_context: bool,
+
+    /// The problems that are allowed in completions (comma-separated). A problem may be
+    /// allowed only in some statements with `<problem>:<statement>`, for example
+    /// "UsesAny,OverlyBroadType:VarDecl". The problems are: {"NotComplete", "ChangedCode",
+    /// "ChangedComments", "UsesAny", "UsesUnknownIdentifier", "OverlyBroadType",
+    /// "AddedTypeDeclaration"}. By default, "UsesAny", "UsesUnknownIdentifier",
+    /// "OverlyBroadType" and "AddedTypeDeclaration" are allowed everywhere.
+    #[clap(long, value_parser)]
+    pub allow_problems: Option<String>,
+
+    /// The weights that problems add to the type-quality score of a completion
+    /// (comma-separated), for example "UsesAny=100,OverlyBroadType=300". By default,
+    /// problems don't affect the score.
+    #[clap(long, value_parser)]
+    pub problem_weights: Option<String>,
+
     /// List of statements to exclude from being annotated (comma-separated).
     /// You can exclude the following types: {"VarDecl", "FuncDecl", "FuncExpr", "ClassProp", "ClassMethod", "TypeDecl"}
     #[clap(long, value_parser)]
     pub exclude: Option<String>,
 }
 
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Manages the cache of the completions
+    Cache(CacheArgs),
+}
+
+/// Manages the cache of the completions. Run as `main cache <SUBCOMMAND>`.
+#[derive(clap::Args, Debug)]
+pub struct CacheArgs {
+    /// The URL of the cache, like in `--cache` when completing a file
+    #[clap(short, long, value_parser)]
+    pub cache: String,
+
+    #[clap(subcommand)]
+    pub command: CacheCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CacheCommand {
+    /// Shows the number of entries, their size and the hit rate
+    Stats(NamespaceArgs),
+    /// Lists the entries, with their id, namespace, kind, size and time to live
+    Ls(NamespaceArgs),
+    /// Shows an entry, given its id (as listed by `ls`) or its key
+    Show {
+        #[clap(value_parser)]
+        entry: String,
+    },
+    /// Deletes the entries of a namespace, or all of them with `--all`
+    Purge {
+        #[clap(flatten)]
+        namespace: NamespaceArgs,
+        /// Deletes the entries of all the namespaces
+        #[clap(long, value_parser, default_value_t = false)]
+        all: bool,
+    },
+    /// Exports the entries to a JSONL file
+    Export {
+        #[clap(value_parser)]
+        file: String,
+        #[clap(flatten)]
+        namespace: NamespaceArgs,
+    },
+    /// Imports the entries of a JSONL file made by `export`
+    Import {
+        #[clap(value_parser)]
+        file: String,
+    },
+}
+
+/// Selects the namespaces of the cache entries. By default, all of them are selected.
+#[derive(clap::Args, Debug)]
+pub struct NamespaceArgs {
+    /// Only the entries of this project
+    #[clap(long, value_parser)]
+    pub project: Option<String>,
+
+    /// Only the entries of this engine
+    #[clap(long, value_parser)]
+    pub model: Option<String>,
+}
+
+impl NamespaceArgs {
+    pub fn filter(&self) -> NamespaceFilter {
+        NamespaceFilter {
+            project: self.project.clone(),
+            model: self.model.clone(),
+        }
+    }
+}
+
 impl Args {
+    /// The target file. Only call this without a subcommand, clap requires the file then.
+    pub fn file(&self) -> &str {
+        self.file
+            .as_deref()
+            .expect("clap requires --file without a subcommand")
+    }
+
+    /// The output directory. Only call this without a subcommand, clap requires the
+    /// directory then.
+    pub fn output(&self) -> &str {
+        self.output
+            .as_deref()
+            .expect("clap requires --output without a subcommand")
+    }
+
+    /// The problems that are allowed in completions, from `--allow-problems`.
+    pub fn problem_whitelist(&self) -> ProblemWhitelist {
+        match &self.allow_problems {
+            Some(problems) => ProblemWhitelist::parse(problems).unwrap_or_else(|e| {
+                eprintln!("Invalid allowed problems: {e}");
+                std::process::exit(1);
+            }),
+            None => ProblemWhitelist::quality(),
+        }
+    }
+
+    /// The weights of the problems, from `--problem-weights`.
+    pub fn problem_weights(&self) -> ProblemWeights {
+        match &self.problem_weights {
+            Some(weights) => ProblemWeights::parse(weights).unwrap_or_else(|e| {
+                eprintln!("Invalid problem weights: {e}");
+                std::process::exit(1);
+            }),
+            None => ProblemWeights::default(),
+        }
+    }
+
+    /// How the language server is supervised, from `--ls-health-check-interval` and
+    /// `--ls-ping-timeout`.
+    pub fn ls_supervision(&self) -> Supervision {
+        let supervision =
+            Supervision::default().ping_timeout(Duration::from_secs(self.ls_ping_timeout));
+        match self.ls_health_check_interval {
+            0 => supervision,
+            secs => supervision.health_check(Duration::from_secs(secs)),
+        }
+    }
+
     pub async fn lang_client_factory(&self) -> ArcLangServer {
-        match self.lang.as_str() {
+        let ls: ArcLangServer = match self.lang.as_str() {
             "ts" => {
                 let path = get_path_from_rootdir("ts-compiler".to_string());
                 Arc::new(
                     TsServer::make(&path)
                         .await
-                        .expect("failed to make ts server"),
+                        .expect("failed to make ts server")
+                        .supervise(self.ls_supervision()),
                 )
             }
             "py" => {
                 let path = get_path_from_rootdir("py-ast".to_string());
+                let type_checker = self.py_checker.parse().unwrap_or_else(|e| {
+                    eprintln!("{e}");
+                    std::process::exit(1);
+                });
                 Arc::new(
                     PyServer::make(&path)
                         .await
-                        .expect("failed to make py server"),
+                        .expect("failed to make py server")
+                        .supervise(self.ls_supervision())
+                        .type_checker(type_checker),
                 )
             }
             _ => {
                 eprintln!("Unknown language, {}", self.lang);
                 std::process::exit(1);
             }
+        };
+        let ls = match (self.checker.as_str(), self.lang.as_str()) {
+            ("ls", _) => ls,
+            #[cfg(feature = "tsparser")]
+            ("native", "ts") => Arc::new(NativeCheckServer::new(ls)),
+            _ => {
+                eprintln!("Unknown checker for {}, {}", self.lang, self.checker);
+                std::process::exit(1);
+            }
+        };
+        let ls = match (self.native_ls, self.lang.as_str()) {
+            (false, _) => ls,
+            #[cfg(feature = "tsnative")]
+            (true, "ts") => {
+                Arc::new(NativeTsServer::new(ls).validate_weave(self.validate_native_weave))
+            }
+            _ => {
+                eprintln!(
+                    "The native language server is only for ts, and needs the tsnative feature"
+                );
+                std::process::exit(1);
+            }
+        };
+        if self.disable_ls_memo {
+            ls
+        } else {
+            Arc::new(MemoServer::new(ls, self.ls_memo_capacity))
+        }
+    }
+
+    /// Opens the cache, if there is one, namespaced by the project and the engine.
+    pub async fn cache_factory(&self, types: &[AnnotateType]) -> Option<Arc<Cache>> {
+        let url = self.cache.as_ref()?;
+        let mut builder = CacheBuilder::new(url)
+            .stop_at(self.stop_at)
+            .project(&self.cache_project)
+            .model(&self.engine)
+            .temperature(self.temp)
+            .types(types.to_vec())
+            .max_type_score(self.max_type_quality)
+            .problem_weights(self.problem_weights());
+        if let Some(ttl) = self.cache_ttl {
+            builder = builder.ttl(Duration::from_secs(ttl));
+        }
+        let cache = builder.build().await.unwrap_or_else(|e| {
+            eprintln!("Failed to open the cache: {e}");
+            std::process::exit(1);
+        });
+
+        if self.cache_invalidate {
+            match cache.invalidate_own().await {
+                Ok(n) => println!("Invalidated {n} cache entries"),
+                Err(e) => {
+                    eprintln!("Failed to invalidate the cache: {e}");
+                    std::process::exit(1);
+                }
+            }
         }
+        Some(Arc::new(cache))
     }
 
     pub async fn completion_engine_factory(
         &self,
         ls: ArcLangServer,
-        cache: Option<Arc<Mutex<Cache>>>,
+        cache: Option<Arc<Cache>>,
     ) -> ArcCompletionEngine {
         let model: ArcCompletionModel = match self.engine.as_str() {
             "codex" => {
@@ -153,17 +499,40 @@ impl Args {
                     .map(|s| s.to_string())
                     .collect::<Vec<_>>();
 
-                Arc::new(
-                    CodexClientBuilder::new(tokens)
-                        .rate_limit(!self.disable_rate_limit)
-                        .build(),
-                )
+                let mut builder =
+                    CodexClientBuilder::new(tokens).rate_limit(!self.disable_rate_limit);
+                if let Some(path) = &self.rate_limit_config {
+                    let config = RateLimitConfig::from_file(path).unwrap_or_else(|e| {
+                        eprintln!("Failed to read rate limit config: {e}");
+                        std::process::exit(1);
+                    });
+                    builder = builder.rate_limit_config(config);
+                }
+                if let Some(context_size) = self.context_size {
+                    builder = builder.context_size(context_size);
+                }
+
+                Arc::new(builder.build())
             }
             "incoder" | "santacoder" => {
                 let mut builder = LocalModelClientBuilder::new(self.engine.clone());
                 if let Some(endpoint) = &self.endpoint {
                     builder = builder.socket_path(endpoint.clone());
                 }
+                if let Some(context_size) = self.context_size {
+                    builder = builder.context_size(context_size);
+                }
+                if let Some(hole_batch_size) = self.hole_batch_size {
+                    builder = builder.hole_batch_size(hole_batch_size);
+                }
+                if let Some(path) = &self.tokenizer {
+                    let tokenizer = Tokenizer::from_file(path).unwrap_or_else(|e| {
+                        eprintln!("Failed to load the tokenizer: {e}");
+                        std::process::exit(1);
+                    });
+                    builder = builder.tokenizer(tokenizer);
+                }
+                builder = builder.batch_holes(!self.disable_hole_batching);
 
                 Arc::new(
                     builder
@@ -179,7 +548,14 @@ impl Args {
         };
         let mut engine = CompletionClientBuilder::new(ls, model)
             .temperature(self.temp)
-            .max_type_score(self.max_type_quality);
+            .max_type_score(self.max_type_quality)
+            .problem_weights(self.problem_weights())
+            .retry_policy(RetryPolicy {
+                max_attempts: self.retry_max_attempts,
+                base_delay_ms: self.retry_base_delay,
+                max_delay_ms: self.retry_max_delay,
+                ..Default::default()
+            });
         if let Some(cache) = cache {
             engine = engine.cache(cache);
         }
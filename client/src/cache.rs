@@ -1,67 +1,447 @@
-use redis::Commands;
+use std::{collections::BTreeMap, time::Duration};
 
-use crate::completion::CompletionQuery;
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use thiserror::Error;
 
+use crate::{
+    completion::{Completion, CompletionQuery},
+    langserver::{AnnotateType, CheckProblem, ProblemWeights},
+    tree::HyperParams,
+};
+
+use self::{disk::DiskBackend, memory::MemoryBackend, redis::RedisBackend};
+
+pub mod disk;
+pub mod manage;
+pub mod memory;
+pub mod redis;
+
+/// The version of the schema of the cache keys. Bump it whenever the keys or the stored
+/// completions change meaning, so that entries of the old schema are never served.
+pub const KEY_SCHEMA_VERSION: u32 = 3;
+
+/// A key-value store that the cache saves the completions in.
+/// Backends are shared between the tasks of a run, so every operation takes `&self`, and
+/// concurrent operations should not wait on each other more than the store requires.
+#[async_trait]
+pub trait CacheBackend: std::fmt::Debug + Send + Sync {
+    /// Returns the value stored under the given key, if any and it has not expired.
+    async fn get(&self, key: &str) -> Result<Option<String>, CacheError>;
+
+    /// Stores the given value under the given key, replacing the previous one. The entry
+    /// expires after the given time to live, if any.
+    async fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> Result<(), CacheError>;
+
+    /// Deletes the entry under the given key, returning whether there was one.
+    async fn delete(&self, key: &str) -> Result<bool, CacheError>;
+
+    /// Returns the keys of the entries that start with the given prefix and have not
+    /// expired.
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, CacheError>;
+
+    /// Returns the time the entry under the given key has left to live, or None if the
+    /// entry doesn't exist or never expires.
+    async fn ttl(&self, key: &str) -> Result<Option<Duration>, CacheError>;
+
+    /// Increments the counter under the given key, starting at 0, and returns its new value.
+    async fn incr(&self, key: &str, by: u64) -> Result<u64, CacheError>;
+
+    /// Deletes all the entries whose key starts with the given prefix, returning how many
+    /// were deleted.
+    async fn delete_prefix(&self, prefix: &str) -> Result<usize, CacheError> {
+        let keys = self.keys(prefix).await?;
+        for key in keys.iter() {
+            self.delete(key).await?;
+        }
+        Ok(keys.len())
+    }
+}
+
+/// The 64-bit FNV-1a hash of the given string. Unlike the hasher of the standard library,
+/// it is guaranteed to be the same between runs and versions of Rust.
+pub(crate) fn fnv1a(s: &str) -> u64 {
+    s.bytes().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Opens the backend at the given url. The backend is selected by the scheme of the url:
+/// - `redis://host:port` (or `rediss://`, `redis+unix://`): a Redis server.
+/// - `file://path/to/dir`: a directory of JSON files, created if it doesn't exist.
+/// - `memory://` or `memory://capacity`: an in-memory LRU cache, which is lost when
+///   the program exits.
+pub async fn open_backend(url: &str) -> Result<Box<dyn CacheBackend>, CacheError> {
+    let (scheme, rest) = url
+        .split_once("://")
+        .ok_or_else(|| CacheError::InvalidUrl(url.to_string()))?;
+    Ok(match scheme {
+        "redis" | "rediss" | "redis+unix" => Box::new(RedisBackend::new(url).await?),
+        "file" => Box::new(DiskBackend::new(rest)?),
+        "memory" if rest.is_empty() => Box::new(MemoryBackend::default()),
+        "memory" => {
+            let capacity = rest
+                .parse()
+                .map_err(|_| CacheError::InvalidUrl(url.to_string()))?;
+            Box::new(MemoryBackend::new(capacity))
+        }
+        _ => return Err(CacheError::InvalidUrl(url.to_string())),
+    })
+}
+
+#[derive(Debug, Error)]
+pub enum CacheError {
+    #[error("Redis error: {0}")]
+    Redis(#[from] ::redis::RedisError),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Serde error: {0}")]
+    Serde(#[from] serde_json::Error),
+    #[error("Invalid cache url: {0}")]
+    InvalidUrl(String),
+}
+
+/// The cache of the completions. Keys are namespaced by project and model:
+/// `opentau:v<schema version>:<project>:<model>:<rest of the key>`, where the rest of the
+/// key holds every parameter that changes the completions, including the version of
+/// OpenTau.
+/// The cache is meant to be shared behind an `Arc`: every method takes `&self`, and the
+/// lookups and stores of concurrent tasks only contend in the backend.
+#[derive(Debug)]
 pub struct Cache {
     stop_at: usize, // TODO: document why we need this
-    redis: redis::Connection,
+    backend: Box<dyn CacheBackend>,
+    // the namespaces of the keys
+    project: String,
+    model: String,
+    // the parameters of the run that are part of every key
+    temperature: f64,
+    types: Vec<AnnotateType>,
+    max_type_score: u16,
+    problem_weights: ProblemWeights,
+    // the time to live of the stored entries, forever if None
+    ttl: Option<Duration>,
 }
 
-impl std::fmt::Debug for Cache {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Cache")
-            .field("stop_at", &self.stop_at)
-            // redis::Connection doesn't implement Debug, so we have to
-            // do this manually
-            .field("redis", &"redis::Connection")
-            .finish()
+#[derive(Debug, Clone)]
+pub struct CacheBuilder {
+    url: String,
+    /// defaults to 1
+    stop_at: Option<usize>,
+    /// defaults to "default"
+    project: Option<String>,
+    /// defaults to "unknown"
+    model: Option<String>,
+    /// defaults to 1.0
+    temperature: Option<f64>,
+    /// defaults to all the types
+    types: Option<Vec<AnnotateType>>,
+    /// defaults to 1000
+    max_type_score: Option<u16>,
+    /// defaults to no weights
+    problem_weights: Option<ProblemWeights>,
+    /// defaults to no expiration
+    ttl: Option<Duration>,
+}
+
+impl CacheBuilder {
+    /// Makes a builder for the cache at the given url, see `open_backend` for the urls.
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            stop_at: None,
+            project: None,
+            model: None,
+            temperature: None,
+            types: None,
+            max_type_score: None,
+            problem_weights: None,
+            ttl: None,
+        }
+    }
+
+    pub fn stop_at(mut self, stop_at: usize) -> Self {
+        self.stop_at = Some(stop_at);
+        self
+    }
+
+    /// Sets the project namespace of the keys.
+    pub fn project(mut self, project: impl Into<String>) -> Self {
+        self.project = Some(project.into());
+        self
+    }
+
+    /// Sets the model namespace of the keys.
+    pub fn model(mut self, model: impl Into<String>) -> Self {
+        self.model = Some(model.into());
+        self
+    }
+
+    pub fn temperature(mut self, temperature: f64) -> Self {
+        self.temperature = Some(temperature);
+        self
+    }
+
+    pub fn types(mut self, types: Vec<AnnotateType>) -> Self {
+        self.types = Some(types);
+        self
+    }
+
+    pub fn max_type_score(mut self, max_type_score: u16) -> Self {
+        self.max_type_score = Some(max_type_score);
+        self
+    }
+
+    pub fn problem_weights(mut self, problem_weights: ProblemWeights) -> Self {
+        self.problem_weights = Some(problem_weights);
+        self
+    }
+
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
     }
+
+    /// Opens the backend of the cache.
+    pub async fn build(self) -> Result<Cache, CacheError> {
+        let backend = open_backend(&self.url).await?;
+        Ok(self.build_with_backend(backend))
+    }
+
+    /// Makes a cache that stores the completions in the given backend, ignoring the url.
+    pub fn build_with_backend(self, backend: Box<dyn CacheBackend>) -> Cache {
+        Cache {
+            stop_at: self.stop_at.unwrap_or(1),
+            backend,
+            project: self.project.unwrap_or_else(|| "default".to_string()),
+            model: self.model.unwrap_or_else(|| "unknown".to_string()),
+            temperature: self.temperature.unwrap_or(1.0),
+            types: self.types.unwrap_or_else(AnnotateType::all),
+            max_type_score: self.max_type_score.unwrap_or(1000),
+            problem_weights: self.problem_weights.unwrap_or_default(),
+            ttl: self.ttl,
+        }
+    }
+}
+
+/// Makes the given name safe to use as a namespace of the keys.
+fn namespace_segment(name: &str) -> String {
+    name.replace(':', "_")
 }
 
 impl Cache {
-    pub fn new(redis_url: &str, stop_at: usize) -> Result<Self, Box<dyn std::error::Error>> {
-        let client = redis::Client::open(redis_url)?;
-        let conn = client.get_connection()?;
-        Ok(Self {
-            redis: conn,
-            stop_at,
-        })
+    /// The prefix of the keys in the given namespace. Without a model, the namespace is
+    /// the whole project.
+    pub fn namespace_prefix(project: &str, model: Option<&str>) -> String {
+        let mut prefix = format!(
+            "opentau:v{KEY_SCHEMA_VERSION}:{}:",
+            namespace_segment(project)
+        );
+        if let Some(model) = model {
+            prefix.push_str(&namespace_segment(model));
+            prefix.push(':');
+        }
+        prefix
+    }
+
+    /// The prefix of the hit and miss counters of the given namespace, see `namespace_prefix`.
+    pub fn stats_prefix(project: &str, model: Option<&str>) -> String {
+        let mut prefix = format!("opentau:stats:{}:", namespace_segment(project));
+        if let Some(model) = model {
+            prefix.push_str(&namespace_segment(model));
+            prefix.push(':');
+        }
+        prefix
+    }
+
+    /// Deletes all the entries in the given namespace, along with its hit and miss
+    /// counters, returning how many entries were deleted. Without a model, the entries of
+    /// every model of the project are deleted.
+    pub async fn invalidate(
+        &self,
+        project: &str,
+        model: Option<&str>,
+    ) -> Result<usize, CacheError> {
+        self.backend
+            .delete_prefix(&Self::stats_prefix(project, model))
+            .await?;
+        self.backend
+            .delete_prefix(&Self::namespace_prefix(project, model))
+            .await
+    }
+
+    /// Deletes all the entries in the namespace of this cache, returning how many were
+    /// deleted.
+    pub async fn invalidate_own(&self) -> Result<usize, CacheError> {
+        self.invalidate(&self.project, Some(&self.model)).await
     }
 
     /// Stores the given query-result pair in the cache.
-    /// auery is a (input: &str, num_comps: usize, retries: usize) tuple.
-    /// result is a Vec<String> of the type-checked completions
-    pub fn store(
-        &mut self,
+    /// result is the type-checked completions, with their scores and log-probabilities
+    pub async fn store(
+        &self,
         query: &CompletionQuery,
-        result: &Vec<String>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+        result: &[Completion],
+    ) -> Result<(), CacheError> {
         let key = self.to_key(query);
         let value = serde_json::json!(result).to_string();
 
-        self.redis.set(key, value)?;
-        Ok(())
+        self.backend.set(&key, &value, self.ttl).await
     }
 
     /// Returns the cached result for the given query, if it exists.
-    pub fn retrieve(
-        &mut self,
+    pub async fn retrieve(
+        &self,
         query: &CompletionQuery,
-    ) -> Result<Option<Vec<String>>, Box<dyn std::error::Error>> {
+    ) -> Result<Option<Vec<Completion>>, CacheError> {
         let key = self.to_key(query);
+        self.lookup(&key).await
+    }
+
+    /// Stores the rewoven completions of a prompt of a node in the tree strategy.
+    /// The prompt is the code of the node, with its children already completed.
+    pub async fn store_node(
+        &self,
+        prompt: &str,
+        usages: &str,
+        params: &HyperParams,
+        result: &[String],
+    ) -> Result<(), CacheError> {
+        let key = self.to_node_key(prompt, usages, params);
+        let value = serde_json::json!(result).to_string();
 
-        let result: Option<String> = self.redis.get(key)?;
-        Ok(result.map(|s| serde_json::from_str(&s).unwrap()))
+        self.backend.set(&key, &value, self.ttl).await
+    }
+
+    /// Returns the cached rewoven completions for the given prompt of a node in the tree
+    /// strategy, if they exist.
+    pub async fn retrieve_node(
+        &self,
+        prompt: &str,
+        usages: &str,
+        params: &HyperParams,
+    ) -> Result<Option<Vec<String>>, CacheError> {
+        let key = self.to_node_key(prompt, usages, params);
+        self.lookup(&key).await
+    }
+
+    /// Returns the completions stored under the given key, counting the hit or miss in
+    /// the stats of the namespace.
+    async fn lookup<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, CacheError> {
+        let value = self.backend.get(key).await?;
+
+        let counter = if value.is_some() { "hits" } else { "misses" };
+        let stats_key = format!(
+            "{}{counter}",
+            Self::stats_prefix(&self.project, Some(&self.model))
+        );
+        // the stats are best effort, failing to count doesn't fail the lookup
+        if let Err(e) = self.backend.incr(&stats_key, 1).await {
+            eprintln!("Failed to count cache {counter}: {e}");
+        }
+
+        match value {
+            Some(s) => Ok(Some(serde_json::from_str(&s)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Prefixes the given key with the namespace of this cache.
+    fn namespaced(&self, key: serde_json::Value) -> String {
+        format!(
+            "{}{}",
+            Self::namespace_prefix(&self.project, Some(&self.model)),
+            key
+        )
     }
 
-    // NOTE: it ignores fallback
     fn to_key(&self, query: &CompletionQuery) -> String {
-        serde_json::json!({
+        self.namespaced(serde_json::json!({
             "query": query.input,
             "num_comps": query.num_comps,
             "retries": query.retries,
+            "fallback": query.fallback,
+            "instructions": query.instructions,
+            "problem_whitelist": query.problem_whitelist,
+            "enable_type_parser": query.enable_type_parser,
             "stop_at": self.stop_at,
-        })
-        .to_string()
+            "temperature": self.temperature,
+            "types": self.types,
+            "max_type_score": self.max_type_score,
+            "problem_weights": self.sorted_problem_weights(),
+            "version": env!("CARGO_PKG_VERSION"),
+        }))
+    }
+
+    fn to_node_key(&self, prompt: &str, usages: &str, params: &HyperParams) -> String {
+        self.namespaced(serde_json::json!({
+            "node": prompt,
+            "usages": usages,
+            "params": params,
+            "stop_at": self.stop_at,
+            "temperature": self.temperature,
+            "max_type_score": self.max_type_score,
+            "problem_weights": self.sorted_problem_weights(),
+            "version": env!("CARGO_PKG_VERSION"),
+        }))
+    }
+
+    /// The problem weights, sorted by problem so that the keys don't depend on the order
+    /// of the hash map.
+    fn sorted_problem_weights(&self) -> BTreeMap<CheckProblem, u16> {
+        self.problem_weights
+            .weights
+            .iter()
+            .map(|(problem, weight)| (*problem, *weight))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::completion::CompletionQueryBuilder;
+
+    fn cache(builder: CacheBuilder) -> Cache {
+        builder.build_with_backend(Box::new(MemoryBackend::default()))
+    }
+
+    #[test]
+    fn key_has_scoring() {
+        let query = CompletionQueryBuilder::new("let x = 1;".to_string()).build();
+        let key = |builder| cache(builder).to_key(&query);
+        let default = key(CacheBuilder::new("memory://"));
+        assert_eq!(
+            default,
+            key(CacheBuilder::new("memory://").max_type_score(1000))
+        );
+        assert_ne!(
+            default,
+            key(CacheBuilder::new("memory://").max_type_score(500))
+        );
+
+        let weights = ProblemWeights::parse("UsesAny=100,OverlyBroadType=300").unwrap();
+        let weighted = key(CacheBuilder::new("memory://").problem_weights(weights));
+        assert_ne!(default, weighted);
+        // the order the weights are given in doesn't matter
+        let weights = ProblemWeights::parse("OverlyBroadType=300,UsesAny=100").unwrap();
+        assert_eq!(
+            weighted,
+            key(CacheBuilder::new("memory://").problem_weights(weights))
+        );
+    }
+
+    #[tokio::test]
+    async fn stores_scores() {
+        let cache = cache(CacheBuilder::new("memory://"));
+        let query = CompletionQueryBuilder::new("let x: _hole_ = 1;".to_string()).build();
+        let comps = vec![Completion {
+            code: "let x: number = 1;".to_string(),
+            score: 300,
+            fallbacked: false,
+            logprob: Some(-0.5),
+        }];
+        cache.store(&query, &comps).await.unwrap();
+        assert_eq!(cache.retrieve(&query).await.unwrap(), Some(comps));
     }
 }
@@ -0,0 +1,172 @@
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use super::{fnv1a, CacheBackend, CacheError};
+
+/// Stores the cache in a directory, with a JSON file per entry. The cache persists between
+/// runs, and can be cleared by deleting the directory.
+/// Entries are independent files, so concurrent accesses don't wait on each other, except
+/// for increments of counters with the same hash. Increments from other processes may still
+/// be lost.
+#[derive(Debug)]
+pub struct DiskBackend {
+    dir: PathBuf,
+    // makes the names of the temporary files unique within the process
+    next_tmp: AtomicU64,
+    // serializes the increments of the counters, by the hash of their key
+    incr_locks: Vec<Mutex<()>>,
+}
+
+/// The number of locks that the increments of the counters are spread over.
+const NUM_INCR_LOCKS: usize = 16;
+
+/// The contents of an entry file. The key is stored too, so that two keys that hash to the
+/// same file don't get each other's values.
+#[derive(Debug, Serialize, Deserialize)]
+struct DiskEntry {
+    key: String,
+    value: String,
+    /// when the entry expires, in seconds since the unix epoch
+    #[serde(default)]
+    expires_at: Option<u64>,
+}
+
+impl DiskEntry {
+    fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|at| at <= now_secs())
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl DiskBackend {
+    /// Opens the cache in the given directory, creating it if it doesn't exist.
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self, CacheError> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            next_tmp: AtomicU64::new(0),
+            incr_locks: (0..NUM_INCR_LOCKS).map(|_| Mutex::new(())).collect(),
+        })
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{:016x}.json", fnv1a(key)))
+    }
+
+    /// Reads the entry in the given file, if it exists.
+    async fn read_entry(path: &Path) -> Result<Option<DiskEntry>, CacheError> {
+        let contents = match tokio::fs::read_to_string(path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(serde_json::from_str(&contents)?))
+    }
+
+    /// Deletes the given file, if it exists. Another task may have deleted it already.
+    async fn remove_file(path: &Path) -> Result<(), CacheError> {
+        match tokio::fs::remove_file(path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl CacheBackend for DiskBackend {
+    async fn get(&self, key: &str) -> Result<Option<String>, CacheError> {
+        let path = self.entry_path(key);
+        match Self::read_entry(&path).await? {
+            Some(entry) if entry.key != key => Ok(None),
+            Some(entry) if entry.is_expired() => {
+                Self::remove_file(&path).await?;
+                Ok(None)
+            }
+            Some(entry) => Ok(Some(entry.value)),
+            None => Ok(None),
+        }
+    }
+
+    async fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> Result<(), CacheError> {
+        let entry = DiskEntry {
+            key: key.to_string(),
+            value: value.to_string(),
+            expires_at: ttl.map(|ttl| now_secs() + ttl.as_secs()),
+        };
+        // write to a temporary file first, so that readers never see a partial entry
+        let path = self.entry_path(key);
+        let tmp = path.with_extension(format!(
+            "tmp{}-{}",
+            std::process::id(),
+            self.next_tmp.fetch_add(1, Ordering::Relaxed)
+        ));
+        tokio::fs::write(&tmp, serde_json::to_string(&entry)?).await?;
+        tokio::fs::rename(&tmp, &path).await?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool, CacheError> {
+        let path = self.entry_path(key);
+        match Self::read_entry(&path).await? {
+            Some(entry) if entry.key == key => {
+                Self::remove_file(&path).await?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, CacheError> {
+        let mut keys = vec![];
+        let mut files = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(file) = files.next_entry().await? {
+            let path = file.path();
+            if path.extension() != Some(OsStr::new("json")) {
+                continue;
+            }
+            // entries that don't parse are not ours
+            if let Ok(Some(entry)) = Self::read_entry(&path).await {
+                if entry.key.starts_with(prefix) && !entry.is_expired() {
+                    keys.push(entry.key);
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    async fn ttl(&self, key: &str) -> Result<Option<Duration>, CacheError> {
+        Ok(match Self::read_entry(&self.entry_path(key)).await? {
+            Some(entry) if entry.key == key => entry
+                .expires_at
+                .map(|at| Duration::from_secs(at.saturating_sub(now_secs()))),
+            _ => None,
+        })
+    }
+
+    async fn incr(&self, key: &str, by: u64) -> Result<u64, CacheError> {
+        let _guard = self.incr_locks[(fnv1a(key) % NUM_INCR_LOCKS as u64) as usize]
+            .lock()
+            .await;
+        let count = match self.get(key).await? {
+            Some(count) => count.parse().unwrap_or(0) + by,
+            None => by,
+        };
+        self.set(key, &count.to_string(), None).await?;
+        Ok(count)
+    }
+}
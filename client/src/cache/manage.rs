@@ -0,0 +1,309 @@
+use std::{
+    io::{BufRead, Write},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{fnv1a, open_backend, Cache, CacheBackend, CacheError, KEY_SCHEMA_VERSION};
+
+/// Inspects and manages all the entries of a cache, across namespaces. This is what the
+/// `cache` subcommand uses.
+#[derive(Debug)]
+pub struct CacheManager {
+    backend: Box<dyn CacheBackend>,
+}
+
+/// Selects the namespaces of the entries to manage. Both parts are optional, so that all
+/// the projects, or all the models, can be selected.
+#[derive(Debug, Clone, Default)]
+pub struct NamespaceFilter {
+    pub project: Option<String>,
+    pub model: Option<String>,
+}
+
+impl NamespaceFilter {
+    fn matches(&self, project: &str, model: &str) -> bool {
+        self.project.as_deref().is_none_or(|p| p == project)
+            && self.model.as_deref().is_none_or(|m| m == model)
+    }
+
+    /// The prefix of the entry keys to scan, narrowed down to the project if there is one.
+    fn entries_prefix(&self) -> String {
+        match &self.project {
+            Some(project) => Cache::namespace_prefix(project, None),
+            None => format!("opentau:v{KEY_SCHEMA_VERSION}:"),
+        }
+    }
+
+    /// The prefix of the counter keys to scan, narrowed down to the project if there is one.
+    fn stats_prefix(&self) -> String {
+        match &self.project {
+            Some(project) => Cache::stats_prefix(project, None),
+            None => "opentau:stats:".to_string(),
+        }
+    }
+}
+
+/// The namespace and kind of an entry, parsed out of its key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyInfo {
+    pub project: String,
+    pub model: String,
+    /// "query" for the completions of a query, "node" for the completions of a node in the
+    /// tree strategy
+    pub kind: String,
+}
+
+impl KeyInfo {
+    /// Parses the given entry key, returning None if it is not a key of the current schema.
+    pub fn parse(key: &str) -> Option<Self> {
+        let rest = key.strip_prefix(&format!("opentau:v{KEY_SCHEMA_VERSION}:"))?;
+        let mut parts = rest.splitn(3, ':');
+        let project = parts.next()?.to_string();
+        let model = parts.next()?.to_string();
+        let fields: serde_json::Value = serde_json::from_str(parts.next()?).ok()?;
+        let kind = if fields.get("node").is_some() {
+            "node"
+        } else {
+            "query"
+        };
+        Some(Self {
+            project,
+            model,
+            kind: kind.to_string(),
+        })
+    }
+}
+
+/// A short identifier of the given key, which is easier to type than the key.
+pub fn key_id(key: &str) -> String {
+    format!("{:016x}", fnv1a(key))
+}
+
+/// A line of an exported cache.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedEntry {
+    pub key: String,
+    pub value: String,
+    /// when the entry expires, in seconds since the unix epoch
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+}
+
+/// A summary of an entry, as listed by `ls`.
+#[derive(Debug, Clone)]
+pub struct EntrySummary {
+    pub id: String,
+    pub key: String,
+    pub info: KeyInfo,
+    /// the size of the stored value, in bytes
+    pub size: usize,
+    pub ttl: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CacheStats {
+    pub entries: usize,
+    /// the size of the keys and values, in bytes
+    pub size: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheStats {
+    /// The ratio of lookups that were hits, None if there were no lookups.
+    pub fn hit_rate(&self) -> Option<f64> {
+        let lookups = self.hits + self.misses;
+        (lookups > 0).then(|| self.hits as f64 / lookups as f64)
+    }
+}
+
+impl std::fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Entries: {}", self.entries)?;
+        writeln!(f, "Size: {} bytes", self.size)?;
+        write!(f, "Hits: {}, misses: {}", self.hits, self.misses)?;
+        if let Some(rate) = self.hit_rate() {
+            write!(f, ", hit rate: {:.2}%", rate * 100.0)?;
+        }
+        Ok(())
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl CacheManager {
+    /// Opens the cache at the given url, see `open_backend` for the urls.
+    pub async fn open(url: &str) -> Result<Self, CacheError> {
+        Ok(Self::new(open_backend(url).await?))
+    }
+
+    pub fn new(backend: Box<dyn CacheBackend>) -> Self {
+        Self { backend }
+    }
+
+    /// The keys of the entries in the selected namespaces.
+    async fn entry_keys(&self, filter: &NamespaceFilter) -> Result<Vec<String>, CacheError> {
+        let mut keys: Vec<String> = self
+            .backend
+            .keys(&filter.entries_prefix())
+            .await?
+            .into_iter()
+            .filter(|key| {
+                KeyInfo::parse(key).is_some_and(|info| filter.matches(&info.project, &info.model))
+            })
+            .collect();
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// The keys of the hit and miss counters in the selected namespaces.
+    async fn stats_keys(&self, filter: &NamespaceFilter) -> Result<Vec<String>, CacheError> {
+        Ok(self
+            .backend
+            .keys(&filter.stats_prefix())
+            .await?
+            .into_iter()
+            .filter(|key| {
+                let mut parts = key.splitn(5, ':').skip(2);
+                match (parts.next(), parts.next()) {
+                    (Some(project), Some(model)) => filter.matches(project, model),
+                    _ => false,
+                }
+            })
+            .collect())
+    }
+
+    /// Lists the entries in the selected namespaces.
+    pub async fn list(&self, filter: &NamespaceFilter) -> Result<Vec<EntrySummary>, CacheError> {
+        let mut entries = vec![];
+        for key in self.entry_keys(filter).await? {
+            // the entry may have expired since we listed the keys
+            let Some(value) = self.backend.get(&key).await? else {
+                continue;
+            };
+            let info = KeyInfo::parse(&key).unwrap();
+            entries.push(EntrySummary {
+                id: key_id(&key),
+                size: value.len(),
+                ttl: self.backend.ttl(&key).await?,
+                info,
+                key,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Computes the stats of the selected namespaces.
+    pub async fn stats(&self, filter: &NamespaceFilter) -> Result<CacheStats, CacheError> {
+        let mut stats = CacheStats::default();
+        for entry in self.list(filter).await? {
+            stats.entries += 1;
+            stats.size += entry.key.len() + entry.size;
+        }
+        for key in self.stats_keys(filter).await? {
+            let count: u64 = match self.backend.get(&key).await? {
+                Some(count) => count.parse().unwrap_or(0),
+                None => continue,
+            };
+            if key.ends_with(":hits") {
+                stats.hits += count;
+            } else if key.ends_with(":misses") {
+                stats.misses += count;
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Returns the entry with the given id or key, if it exists.
+    pub async fn show(&self, id_or_key: &str) -> Result<Option<ExportedEntry>, CacheError> {
+        let key = if KeyInfo::parse(id_or_key).is_some() {
+            id_or_key.to_string()
+        } else {
+            let keys = self.entry_keys(&NamespaceFilter::default()).await?;
+            match keys.into_iter().find(|key| key_id(key) == id_or_key) {
+                Some(key) => key,
+                None => return Ok(None),
+            }
+        };
+        self.export_entry(key).await
+    }
+
+    /// Deletes the entries in the selected namespaces, along with their hit and miss
+    /// counters, returning how many entries were deleted.
+    pub async fn purge(&self, filter: &NamespaceFilter) -> Result<usize, CacheError> {
+        for key in self.stats_keys(filter).await? {
+            self.backend.delete(&key).await?;
+        }
+        let mut deleted = 0;
+        for key in self.entry_keys(filter).await? {
+            if self.backend.delete(&key).await? {
+                deleted += 1;
+            }
+        }
+        Ok(deleted)
+    }
+
+    async fn export_entry(&self, key: String) -> Result<Option<ExportedEntry>, CacheError> {
+        let Some(value) = self.backend.get(&key).await? else {
+            return Ok(None);
+        };
+        let expires_at = self
+            .backend
+            .ttl(&key)
+            .await?
+            .map(|ttl| now_secs() + ttl.as_secs());
+        Ok(Some(ExportedEntry {
+            key,
+            value,
+            expires_at,
+        }))
+    }
+
+    /// Writes the entries in the selected namespaces to the given writer, as JSON lines.
+    /// Returns how many entries were exported.
+    pub async fn export(
+        &self,
+        writer: &mut impl Write,
+        filter: &NamespaceFilter,
+    ) -> Result<usize, CacheError> {
+        let mut exported = 0;
+        for key in self.entry_keys(filter).await? {
+            if let Some(entry) = self.export_entry(key).await? {
+                serde_json::to_writer(&mut *writer, &entry)?;
+                writer.write_all(b"\n")?;
+                exported += 1;
+            }
+        }
+        writer.flush()?;
+        Ok(exported)
+    }
+
+    /// Reads entries from the given reader, as written by `export`, and stores them.
+    /// Entries that already expired are skipped. Returns how many entries were imported.
+    pub async fn import(&self, reader: impl BufRead) -> Result<usize, CacheError> {
+        let now = now_secs();
+        let mut imported = 0;
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: ExportedEntry = serde_json::from_str(&line)?;
+            let ttl = match entry.expires_at {
+                Some(at) if at <= now => continue,
+                Some(at) => Some(Duration::from_secs(at - now)),
+                None => None,
+            };
+            self.backend.set(&entry.key, &entry.value, ttl).await?;
+            imported += 1;
+        }
+        Ok(imported)
+    }
+}
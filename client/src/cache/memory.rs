@@ -0,0 +1,157 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+
+use crate::lru::Lru;
+
+use super::{fnv1a, CacheBackend, CacheError};
+
+/// The maximum number of shards of the memory cache. Keys are spread over the shards by their
+/// hash, and each shard has its own lock, so that concurrent accesses rarely wait on each
+/// other.
+const MAX_SHARDS: usize = 16;
+
+/// Stores the cache in memory, evicting the least recently used entry of a shard when the
+/// shard is full. Nothing is persisted between runs.
+#[derive(Debug)]
+pub struct MemoryBackend {
+    // the locks are never held across an await, so a std mutex is fine here
+    shards: Vec<Mutex<Shard>>,
+}
+
+/// The entries of a shard, with their expiry.
+#[derive(Debug)]
+struct Shard {
+    entries: Lru<String, MemoryEntry>,
+}
+
+#[derive(Debug)]
+struct MemoryEntry {
+    value: String,
+    expires_at: Option<Instant>,
+}
+
+impl Default for MemoryBackend {
+    fn default() -> Self {
+        Self::new(10000)
+    }
+}
+
+impl MemoryBackend {
+    /// Makes an empty cache that holds at most `capacity` entries.
+    pub fn new(capacity: usize) -> Self {
+        // the capacity is split evenly, the first shards get the remainder. Small caches
+        // have fewer shards, so that every shard can hold an entry.
+        let num_shards = capacity.clamp(1, MAX_SHARDS);
+        let shards = (0..num_shards)
+            .map(|i| {
+                let capacity = capacity / num_shards + usize::from(i < capacity % num_shards);
+                Mutex::new(Shard::new(capacity))
+            })
+            .collect();
+        Self { shards }
+    }
+
+    /// Runs the given function on the shard of the given key.
+    fn with_shard<T>(&self, key: &str, f: impl FnOnce(&mut Shard) -> T) -> T {
+        let shard = &self.shards[(fnv1a(key) % self.shards.len() as u64) as usize];
+        // a panic while holding the lock can't leave the shard in a broken state, as the
+        // entries and the recency are updated together
+        let mut shard = shard.lock().unwrap_or_else(|e| e.into_inner());
+        f(&mut shard)
+    }
+}
+
+impl Shard {
+    fn new(capacity: usize) -> Self {
+        Self {
+            entries: Lru::new(capacity),
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        self.entries.remove(key).is_some()
+    }
+
+    fn get(&mut self, key: &str) -> Option<String> {
+        let expired = self
+            .entries
+            .peek(key)?
+            .expires_at
+            .is_some_and(|at| at <= Instant::now());
+        if expired {
+            self.remove(key);
+            return None;
+        }
+        self.entries.get(key).map(|entry| entry.value.clone())
+    }
+
+    fn set(&mut self, key: &str, value: String, ttl: Option<Duration>) {
+        self.entries.insert(
+            key.to_string(),
+            MemoryEntry {
+                value,
+                expires_at: ttl.map(|ttl| Instant::now() + ttl),
+            },
+        );
+    }
+}
+
+#[async_trait]
+impl CacheBackend for MemoryBackend {
+    async fn get(&self, key: &str) -> Result<Option<String>, CacheError> {
+        Ok(self.with_shard(key, |shard| shard.get(key)))
+    }
+
+    async fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> Result<(), CacheError> {
+        self.with_shard(key, |shard| shard.set(key, value.to_string(), ttl));
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool, CacheError> {
+        Ok(self.with_shard(key, |shard| shard.remove(key)))
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, CacheError> {
+        let now = Instant::now();
+        let mut keys = vec![];
+        for shard in self.shards.iter() {
+            let shard = shard.lock().unwrap_or_else(|e| e.into_inner());
+            keys.extend(
+                shard
+                    .entries
+                    .iter()
+                    .filter(|(key, entry)| {
+                        key.starts_with(prefix) && entry.expires_at.is_none_or(|at| at > now)
+                    })
+                    .map(|(key, _)| key.clone()),
+            );
+        }
+        Ok(keys)
+    }
+
+    async fn ttl(&self, key: &str) -> Result<Option<Duration>, CacheError> {
+        Ok(self.with_shard(key, |shard| {
+            shard
+                .entries
+                .peek(key)
+                .and_then(|entry| entry.expires_at)
+                .map(|at| at.saturating_duration_since(Instant::now()))
+        }))
+    }
+
+    async fn incr(&self, key: &str, by: u64) -> Result<u64, CacheError> {
+        // the read and the write happen under the same lock, so no increment is lost
+        Ok(self.with_shard(key, |shard| {
+            let count = match shard.get(key) {
+                Some(count) => count.parse().unwrap_or(0) + by,
+                None => by,
+            };
+            shard.set(key, count.to_string(), None);
+            count
+        }))
+    }
+}
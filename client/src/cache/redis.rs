@@ -0,0 +1,106 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use redis::{aio::ConnectionManager, AsyncCommands};
+
+use super::{CacheBackend, CacheError};
+
+/// Stores the cache in a Redis server.
+/// Requests are multiplexed over a single connection, which reconnects when it drops, so
+/// concurrent lookups and stores don't wait on each other.
+#[derive(Clone)]
+pub struct RedisBackend {
+    conn: ConnectionManager,
+}
+
+impl std::fmt::Debug for RedisBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisBackend")
+            // redis::aio::ConnectionManager doesn't implement Debug, so we have to
+            // do this manually
+            .field("conn", &"redis::aio::ConnectionManager")
+            .finish()
+    }
+}
+
+impl RedisBackend {
+    pub async fn new(redis_url: &str) -> Result<Self, CacheError> {
+        let client = redis::Client::open(redis_url)?;
+        let conn = ConnectionManager::new(client).await?;
+        Ok(Self { conn })
+    }
+
+    /// A handle to the connection. Handles are cheap to make, and share the connection.
+    fn conn(&self) -> ConnectionManager {
+        self.conn.clone()
+    }
+}
+
+/// Makes a glob pattern that matches the keys that start with the given prefix, escaping
+/// the glob characters of the prefix, so that it is matched literally.
+fn prefix_pattern(prefix: &str) -> String {
+    let mut pattern = String::new();
+    for c in prefix.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('*');
+    pattern
+}
+
+#[async_trait]
+impl CacheBackend for RedisBackend {
+    async fn get(&self, key: &str) -> Result<Option<String>, CacheError> {
+        Ok(self.conn().get(key).await?)
+    }
+
+    async fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> Result<(), CacheError> {
+        let mut conn = self.conn();
+        match ttl {
+            // redis doesn't allow a zero expiration
+            Some(ttl) => {
+                let secs = ttl.as_secs().max(1) as usize;
+                conn.set_ex::<_, _, ()>(key, value, secs).await?
+            }
+            None => conn.set::<_, _, ()>(key, value).await?,
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool, CacheError> {
+        let deleted: usize = self.conn().del(key).await?;
+        Ok(deleted > 0)
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, CacheError> {
+        let mut conn = self.conn();
+        let mut iter = conn.scan_match(prefix_pattern(prefix)).await?;
+        let mut keys = vec![];
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+        Ok(keys)
+    }
+
+    async fn ttl(&self, key: &str) -> Result<Option<Duration>, CacheError> {
+        // negative when the key doesn't exist or never expires
+        let secs: i64 = self.conn().ttl(key).await?;
+        Ok((secs >= 0).then(|| Duration::from_secs(secs as u64)))
+    }
+
+    async fn incr(&self, key: &str, by: u64) -> Result<u64, CacheError> {
+        Ok(self.conn().incr(key, by).await?)
+    }
+
+    async fn delete_prefix(&self, prefix: &str) -> Result<usize, CacheError> {
+        let keys = self.keys(prefix).await?;
+        let mut conn = self.conn();
+        // delete in chunks, so that a huge namespace doesn't make a huge command
+        for chunk in keys.chunks(1000) {
+            conn.del::<_, ()>(chunk).await?;
+        }
+        Ok(keys.len())
+    }
+}
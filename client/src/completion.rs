@@ -1,19 +1,31 @@
-use std::sync::Arc;
+use std::{collections::HashSet, sync::Arc};
 
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tokio::{sync::Mutex, task::JoinHandle};
+use tokio::{
+    sync::{mpsc, Mutex},
+    task::JoinHandle,
+};
 
 use crate::{
     cache::Cache,
     debug,
-    langserver::{ArcLangServer, CheckProblem, LangServer, LangServerError},
+    dedup::canonical_key,
+    langserver::{
+        ArcLangServer, LangServer, LangServerError, Normalizer, ProblemWeights, ProblemWhitelist,
+    },
+    prompt::estimate_prompt_tokens,
     socket::SocketError,
+    usage::UsageTracker,
 };
 
+use self::retry::RetryPolicy;
+
+pub mod builtin;
 pub mod codex;
 pub mod local;
-pub mod builtin;
+pub mod retry;
+pub mod tokenizer;
 
 /// This is the trait that defines operations on the completion engine (Codex, incoder, santacoder,
 /// etc..). The completion engine is coupled with the language server.
@@ -30,6 +42,11 @@ pub trait CompletionEngine: std::fmt::Debug {
         mut query: CompletionQuery,
     ) -> Result<Vec<Completion>, CompletionError>;
 
+    /// Like `complete`, but yields the completions as soon as they pass the filters,
+    /// instead of waiting for all the model requests to finish. The completions are not
+    /// sorted by score. Dropping the stream cancels the outstanding model requests.
+    async fn complete_stream(&self, query: CompletionQuery) -> CompletionStream;
+
     /// Gets the language server object from the completion engine object.
     fn get_ls(&self) -> ArcLangServer;
 
@@ -42,9 +59,21 @@ pub trait CompletionEngine: std::fmt::Debug {
     /// Gets the maximum type score allowed for a completion.
     fn get_max_type_score(&self) -> u16;
 
-    /// Gets a mutex guard to the cache from the codex client.
+    /// Gets the weights that the problems of a completion add to its type score.
+    fn get_problem_weights(&self) -> ProblemWeights;
+
+    /// Gets the retry policy used for querying the model.
+    fn get_retry_policy(&self) -> RetryPolicy;
+
+    /// Gets the maximum number of tokens of a prompt for the model, if there is a limit.
+    fn get_max_prompt_tokens(&self) -> Option<usize>;
+
+    /// Counts the tokens of the given text for the model.
+    fn count_tokens(&self, text: &str) -> usize;
+
+    /// Gets the cache of the completion engine, which can be used concurrently.
     /// If the given completion engine does not use a cache, this will return None.
-    async fn get_cache(&self) -> Option<tokio::sync::MutexGuard<Cache>>;
+    fn get_cache(&self) -> Option<Arc<Cache>>;
 }
 
 pub type ArcCompletionEngine = Arc<dyn CompletionEngine + Send + Sync>;
@@ -57,8 +86,25 @@ pub trait CompletionModel: std::fmt::Debug {
         &self,
         query: &CompletionQuery,
         engine: &dyn CompletionEngine,
-        filtered_completions: Arc<Mutex<Vec<(String, u16)>>>,
+        filtered_completions: CompletionSink,
     ) -> JoinHandle<Result<(), ModelResponseError>>;
+
+    /// The size of the context window of the model in tokens, if it has one.
+    fn context_size(&self) -> Option<usize>;
+
+    /// The maximum number of tokens of a prompt. By default, we leave an eighth of the
+    /// context window for the completion, which is enough for models that only generate
+    /// the types.
+    fn max_prompt_tokens(&self) -> Option<usize> {
+        self.context_size().map(|size| size - size / 8)
+    }
+
+    /// Counts the tokens of the given text, to check that prompts fit. By default, we
+    /// estimate them with a safety margin, models that have their tokenizer count them
+    /// exactly.
+    fn count_tokens(&self, text: &str) -> usize {
+        estimate_prompt_tokens(text)
+    }
 }
 
 pub type ArcCompletionModel = Arc<dyn CompletionModel + Send + Sync>;
@@ -77,9 +123,11 @@ pub struct CompletionQuery {
     /// some kind of instruction based editing.
     pub instructions: Option<String>,
     /// Whitelist of CheckProblems that are allowed to happen in the completion.
-    pub problem_whitelist: Vec<CheckProblem>,
+    pub problem_whitelist: ProblemWhitelist,
     /// Whether to enable the type parser or not.
     pub enable_type_parser: bool,
+    /// Where the models record the tokens used by the requests for this query.
+    pub usage: UsageTracker,
 }
 
 #[derive(Debug, Clone)]
@@ -93,10 +141,12 @@ pub struct CompletionQueryBuilder {
     fallback: Option<bool>,
     /// defaults to ""
     instructions: Option<String>,
-    /// defaults to vec![]
-    problem_whitelist: Option<Vec<CheckProblem>>,
+    /// defaults to allowing the quality problems, see `CheckProblem::quality`
+    problem_whitelist: Option<ProblemWhitelist>,
     /// defaults to true
     enable_type_parser: bool,
+    /// defaults to a tracker that is not shared with anything
+    usage: Option<UsageTracker>,
 }
 
 impl CompletionQueryBuilder {
@@ -109,6 +159,7 @@ impl CompletionQueryBuilder {
             instructions: None,
             problem_whitelist: None,
             enable_type_parser: true,
+            usage: None,
         }
     }
 
@@ -132,8 +183,8 @@ impl CompletionQueryBuilder {
         self
     }
 
-    pub fn problem_whitelist(mut self, problem_whitelist: Vec<CheckProblem>) -> Self {
-        self.problem_whitelist = Some(problem_whitelist);
+    pub fn problem_whitelist<W: Into<ProblemWhitelist>>(mut self, problem_whitelist: W) -> Self {
+        self.problem_whitelist = Some(problem_whitelist.into());
         self
     }
 
@@ -142,6 +193,11 @@ impl CompletionQueryBuilder {
         self
     }
 
+    pub fn usage(mut self, usage: UsageTracker) -> Self {
+        self.usage = Some(usage);
+        self
+    }
+
     pub fn build(self) -> CompletionQuery {
         CompletionQuery {
             input: self.input,
@@ -150,12 +206,18 @@ impl CompletionQueryBuilder {
             instructions: self.instructions,
             fallback: self.fallback.unwrap_or(false),
             enable_type_parser: self.enable_type_parser,
-            problem_whitelist: self.problem_whitelist.unwrap_or(vec![]),
+            problem_whitelist: self
+                .problem_whitelist
+                .unwrap_or_else(ProblemWhitelist::quality),
+            usage: self.usage.unwrap_or_default(),
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+/// A completion of the code. Not `Eq` or `Hash` since it has a log-probability, which is a
+/// float, so it can't be put in a `HashSet` or be the key of a `HashMap`; use its code
+/// instead.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Completion {
     /// the completed code
     pub code: String,
@@ -163,9 +225,15 @@ pub struct Completion {
     pub score: u16,
     /// is this completion from fallback?
     pub fallbacked: bool,
+    /// the log-probability of the annotations given by the model, higher is better.
+    /// None if the model doesn't report it, or if the completion was assembled from many
+    /// queries, like the ones of the tree strategy.
+    #[serde(default)]
+    pub logprob: Option<f64>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+/// A completion along with its number of type errors. Not `Eq` or `Hash`, like `Completion`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TypecheckedCompletion {
     /// the completed code
     pub code: String,
@@ -173,6 +241,11 @@ pub struct TypecheckedCompletion {
     pub score: u16,
     /// is this completion from fallback?
     pub fallbacked: bool,
+    /// the log-probability of the annotations given by the model, higher is better.
+    /// None if the model doesn't report it, or if the completion was assembled from many
+    /// queries, like the ones of the tree strategy.
+    #[serde(default)]
+    pub logprob: Option<f64>,
     /// the number of type errors in the completion. if 0, no type errors.
     pub num_type_errors: usize,
 }
@@ -183,6 +256,7 @@ impl TypecheckedCompletion {
             code: completion.code,
             score: completion.score,
             fallbacked: completion.fallbacked,
+            logprob: completion.logprob,
             num_type_errors,
         }
     }
@@ -194,22 +268,40 @@ impl From<TypecheckedCompletion> for Completion {
             code: tc.code,
             score: tc.score,
             fallbacked: tc.fallbacked,
+            logprob: tc.logprob,
         }
     }
 }
 
 /// sort based on number of type errors (increasing). if the number of type errors is the same,
-/// sort based on score (lower score is better, so increasing)
+/// sort based on score (lower score is better, so increasing). if the score is the same too,
+/// sort based on the log-probability (higher is better, so decreasing), where completions
+/// without one go last. Only the simple strategy has log-probabilities, so this last
+/// tie-break never applies to the completions of the tree strategy.
 pub fn sort_completions(comps: &mut [TypecheckedCompletion]) {
     comps.sort_by(|a, b| {
-        if a.num_type_errors == b.num_type_errors {
-            a.score.cmp(&b.score)
-        } else {
-            a.num_type_errors.cmp(&b.num_type_errors)
-        }
+        a.num_type_errors
+            .cmp(&b.num_type_errors)
+            .then(a.score.cmp(&b.score))
+            .then_with(|| cmp_logprobs(a.logprob, b.logprob))
     });
 }
 
+/// Orders log-probabilities from the most likely to the least likely, with None last.
+fn cmp_logprobs(a: Option<f64>, b: Option<f64>) -> std::cmp::Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => b.partial_cmp(&a).unwrap_or(std::cmp::Ordering::Equal),
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (None, None) => std::cmp::Ordering::Equal,
+    }
+}
+
+/// Adds up the log-probabilities of independent annotations. None if any is unknown.
+pub fn add_logprobs(a: Option<f64>, b: Option<f64>) -> Option<f64> {
+    Some(a? + b?)
+}
+
 #[derive(Debug, Error)]
 pub enum CompletionError {
     // where the Vec<String> is the list of completions we got before the rate limit
@@ -233,28 +325,218 @@ pub enum ModelResponseError {
     InvalidResponse(String),
     #[error("Model could not complete")]
     CouldNotComplete,
+    /// The response, and the time the server asked us to wait before retrying, if any.
     #[error("Model rate limited. Response: {0}")]
-    RateLimited(String),
+    RateLimited(String, Option<std::time::Duration>),
+    #[error("HTTP error {0}. Response: {1}")]
+    Http(u16, String),
+    #[error("API key rejected. Response: {0}")]
+    KeyRejected(String),
     #[error("Socket error: {0}")]
     Socket(#[from] SocketError),
 }
 
+/// Completes the given query. The model requests are already retried by the engine,
+/// according to its retry policy, so the query is not retried again. If we are still rate
+/// limited, the completions we got before the rate limit are returned, if there are any.
+pub async fn complete_keeping_partial(
+    engine: &ArcCompletionEngine,
+    query: CompletionQuery,
+) -> Result<Vec<Completion>, CompletionError> {
+    match engine.complete(query).await {
+        Err(CompletionError::RateLimit(r)) if !r.is_empty() => {
+            eprintln!(
+                "Rate limited, but got {} canditate completions before.",
+                r.len()
+            );
+            Ok(r)
+        }
+        res => res,
+    }
+}
+
+/// Where the models put the completions that passed the filters, along with their scores.
+/// If the sink is streaming, the completions are also sent to the stream as they come.
+/// Completions are deduplicated by their canonical form if the sink has a normalizer.
+/// Safe to clone, the clones share the same completions.
+#[derive(Clone, Default)]
+pub struct CompletionSink {
+    comps: Arc<Mutex<SinkState>>,
+    tx: Option<mpsc::UnboundedSender<Completion>>,
+    normalizer: Option<Arc<Normalizer>>,
+}
+
+#[derive(Default)]
+struct SinkState {
+    comps: Vec<Completion>,
+    /// the canonical forms of the completions
+    keys: HashSet<String>,
+}
+
+impl CompletionSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a sink that also sends the completions to the given channel.
+    pub fn streaming(tx: mpsc::UnboundedSender<Completion>) -> Self {
+        Self {
+            tx: Some(tx),
+            ..Default::default()
+        }
+    }
+
+    /// Sets the normalizer used to deduplicate the completions.
+    pub fn normalizer(mut self, normalizer: Option<Normalizer>) -> Self {
+        self.normalizer = normalizer.map(Arc::new);
+        self
+    }
+
+    fn key(&self, code: &str) -> String {
+        canonical_key(self.normalizer.as_deref(), code)
+    }
+
+    /// Returns true if the given completion, or one with the same canonical form, is
+    /// already in the sink.
+    pub async fn contains(&self, code: &str) -> bool {
+        let key = self.key(code);
+        self.comps.lock().await.keys.contains(&key)
+    }
+
+    /// Adds the given completion to the sink, unless it's a duplicate.
+    pub async fn push(&self, code: String, score: u16, logprob: Option<f64>) {
+        let key = self.key(&code);
+        let mut state = self.comps.lock().await;
+        if !state.keys.insert(key) {
+            return;
+        }
+        let comp = Completion {
+            code,
+            score,
+            fallbacked: false,
+            logprob,
+        };
+        if let Some(tx) = &self.tx {
+            // the receiver may be gone if the stream was dropped, that's fine
+            let _ = tx.send(comp.clone());
+        }
+        state.comps.push(comp);
+    }
+
+    /// Gets the completions in the sink, sorted by score (low..high), and then by
+    /// log-probability (high..low).
+    pub async fn sorted(&self) -> Vec<Completion> {
+        let mut comps = self.comps.lock().await.comps.clone();
+        comps.sort_by(|a, b| {
+            a.score
+                .cmp(&b.score)
+                .then_with(|| cmp_logprobs(a.logprob, b.logprob))
+        });
+        comps
+    }
+}
+
+/// A stream of completions, made by `CompletionEngine::complete_stream`. Ends when all
+/// the model requests are done, with the fallback completion if the query asked for one.
+/// Dropping the stream cancels the outstanding model requests.
+#[derive(Debug)]
+pub struct CompletionStream {
+    rx: mpsc::UnboundedReceiver<Completion>,
+    handles: Vec<JoinHandle<Result<(), ModelResponseError>>>,
+    fallback: Option<Completion>,
+    // the number of completions yielded so far
+    yielded: usize,
+}
+
+impl CompletionStream {
+    /// Gets the next completion. The model requests are retried according to the retry
+    /// policy of the engine, and the errors that remain are handled like in `complete`:
+    /// socket IO errors are returned, as they are usually irrecoverable, and if we are
+    /// still rate limited without having yielded any completion, `RateLimit` is returned.
+    /// Other errors are printed and skipped.
+    pub async fn next(&mut self) -> Option<Result<Completion, CompletionError>> {
+        if let Some(comp) = self.rx.recv().await {
+            self.yielded += 1;
+            return Some(Ok(comp));
+        }
+
+        // all the senders are gone, so all the model requests are done
+        let mut rate_limit = false;
+        for handle in std::mem::take(&mut self.handles) {
+            match handle.await {
+                Ok(Err(e @ ModelResponseError::RateLimited(_, _))) => {
+                    println!("{e}");
+                    rate_limit = true;
+                }
+                Ok(Err(ModelResponseError::Socket(e))) if matches!(e, SocketError::Io(_)) => {
+                    println!("Socket IO error in completion thread: {e:?}");
+                    return Some(Err(CompletionError::Socket(e)));
+                }
+                Ok(Err(e)) => println!("Error in completion thread: {e:?}"),
+                Ok(Ok(())) | Err(_) => {}
+            }
+        }
+
+        let fallback = self.fallback.take();
+        if rate_limit {
+            if self.yielded == 0 && fallback.is_none() {
+                return Some(Err(CompletionError::RateLimit(vec![])));
+            }
+            eprintln!(
+                "Rate limited, but got {} canditate completions before.",
+                self.yielded + fallback.iter().count()
+            );
+        }
+        fallback.map(|comp| {
+            self.yielded += 1;
+            Ok(comp)
+        })
+    }
+
+    /// Cancels the outstanding model requests. The completions that already passed the
+    /// filters are still yielded.
+    pub fn cancel(&mut self) {
+        for handle in self.handles.iter() {
+            handle.abort();
+        }
+    }
+}
+
+impl Drop for CompletionStream {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// The rules that `filter_comps` filters and scores completions by.
+#[derive(Debug, Clone)]
+struct CompFilter {
+    problem_whitelist: ProblemWhitelist,
+    problem_weights: ProblemWeights,
+    max_type_score: u16,
+}
+
+impl CompFilter {
+    fn new(query: &CompletionQuery, engine: &dyn CompletionEngine) -> Self {
+        Self {
+            problem_whitelist: query.problem_whitelist.clone(),
+            problem_weights: engine.get_problem_weights(),
+            max_type_score: engine.get_max_type_score(),
+        }
+    }
+}
+
 /// Filters out completions that don't follow certain rules.
 async fn filter_comps(
-    filtered_completions: Arc<Mutex<Vec<(String, u16)>>>,
+    filtered_completions: CompletionSink,
     lang_client: ArcLangServer,
     input_text: &str,
     comp_text: String,
-    problem_whitelist: Vec<CheckProblem>,
-    max_type_score: u16,
+    logprob: Option<f64>,
+    filter: &CompFilter,
 ) -> Result<(), ModelResponseError> {
     // check first if it's duplicate in our filtered completions
-    if !filtered_completions
-        .lock()
-        .await
-        .iter()
-        .any(|(c, _)| c == &comp_text)
-    {
+    if !filtered_completions.contains(&comp_text).await {
         let (problems, score) = lang_client
             .check_complete(input_text, &comp_text)
             .await
@@ -262,10 +544,13 @@ async fn filter_comps(
                 println!("Error checking completion: {e}");
                 ModelResponseError::CouldNotComplete
             })?;
+        let score = filter.problem_weights.score(&problems, score);
 
         // we don't want completions with higher type score than the max
-        if problems.iter().all(|p| problem_whitelist.contains(p)) && score <= max_type_score {
-            filtered_completions.lock().await.push((comp_text, score));
+        if problems.iter().all(|p| filter.problem_whitelist.allows(p))
+            && score <= filter.max_type_score
+        {
+            filtered_completions.push(comp_text, score, logprob).await;
         } else {
             debug!("Filtered out completion (Problems: {problems:?}):\n{comp_text}");
         }
@@ -284,12 +569,63 @@ pub struct CompletionClient {
     pub temperature: f64,
     // the maxmimum type score
     pub max_type_score: u16,
+    // the weights that the problems add to the type score
+    pub problem_weights: ProblemWeights,
+    // the retry policy for the model requests
+    pub retry_policy: RetryPolicy,
     // The cache to use for the completions
-    cache: Option<Arc<Mutex<Cache>>>,
+    cache: Option<Arc<Cache>>,
     // The model that we are using
     pub model: ArcCompletionModel,
 }
 
+impl CompletionClient {
+    /// Puts the cached completions of the query in the sink if there are any, otherwise
+    /// spawns the model requests for the query, which put their completions in the sink.
+    async fn spawn_comps(
+        &self,
+        query: &mut CompletionQuery,
+        sink: &CompletionSink,
+    ) -> Vec<JoinHandle<Result<(), ModelResponseError>>> {
+        let mut handles: Vec<JoinHandle<Result<(), ModelResponseError>>> = Vec::new();
+
+        // check cache first, if the cache is set
+        // NOTE: we need to query a list of comps with the same (input, num_comps, retries) tuple
+
+        if let Some(cache) = &self.cache {
+            let cached_completions = cache.retrieve(query).await.unwrap_or_else(|e| {
+                eprintln!("Failed to retrieve completions from cache: {e}");
+                None
+            });
+            if let Some(cached_completions) = cached_completions {
+                for c in cached_completions {
+                    sink.push(c.code, c.score, c.logprob).await;
+                }
+                query.retries = 0; // so we don't make any requests to codex
+            }
+        }
+
+        while query.retries > 0 {
+            handles.push(self.model.spawn_comp(query, self, sink.clone()));
+            query.retries -= 1;
+        }
+
+        handles
+    }
+
+    /// The completion that has the `any` type in all holes.
+    fn fallback_completion(&self, query: &CompletionQuery) -> Completion {
+        Completion {
+            code: query
+                .input
+                .replace(HOLE_IDENTIFIER, &self.lang_server.any_type()),
+            score: 1000,
+            fallbacked: true,
+            logprob: None,
+        }
+    }
+}
+
 const HOLE_IDENTIFIER: &str = "_hole_";
 const INSTRUCTIONS: &str = "Substitute the identifier _hole_ with the correct type.";
 
@@ -308,31 +644,8 @@ impl CompletionEngine for CompletionClient {
         // we filter incomplete completions
         // scored vec: implemented scoring, sort resulting vec by score,
         //             and fall back to all "any" in worst case (if enabled)
-        let filtered_completions: Arc<Mutex<Vec<(String, u16)>>> = Arc::new(Mutex::new(Vec::new()));
-        let mut handles: Vec<JoinHandle<Result<(), ModelResponseError>>> = Vec::new();
-
-        // check cache first, if the cache is set
-        // NOTE: we need to query a list of comps with the same (input, num_comps, retries) tuple
-
-        if let Some(cache) = &self.cache {
-            let mut cache = cache.lock().await;
-            let cached_completions = cache.retrieve(&query).unwrap();
-            if let Some(cached_completions) = cached_completions {
-                filtered_completions
-                    .lock()
-                    .await
-                    .extend(cached_completions.into_iter().map(|c| (c, 0)));
-                query.retries = 0; // so we don't make any requests to codex
-            }
-        }
-
-        while query.retries > 0 {
-            handles.push(
-                self.model
-                    .spawn_comp(&query, self, filtered_completions.clone()),
-            );
-            query.retries -= 1;
-        }
+        let filtered_completions = CompletionSink::new().normalizer(self.get_ls().get_normalizer());
+        let handles = self.spawn_comps(&mut query, &filtered_completions).await;
 
         let mut rate_limit = false;
 
@@ -340,7 +653,7 @@ impl CompletionEngine for CompletionClient {
             let res = handle.await.unwrap();
             if let Err(e) = res {
                 match e {
-                    ModelResponseError::RateLimited(_) => {
+                    ModelResponseError::RateLimited(_, _) => {
                         println!("{e}");
                         rate_limit = true;
                     }
@@ -357,31 +670,13 @@ impl CompletionEngine for CompletionClient {
         }
 
         // sort the vec by score, low..high
-        filtered_completions
-            .lock()
-            .await
-            .sort_by(|(_, s1), (_, s2)| s1.cmp(s2));
+        let sorted_completions = filtered_completions.sorted().await;
 
-        let mut final_completions = filtered_completions
-            .lock()
-            .await
-            .iter()
-            .map(|(c, i)| Completion {
-                code: c.to_string(),
-                score: *i,
-                fallbacked: false,
-            })
-            .collect::<Vec<Completion>>();
+        let mut final_completions = sorted_completions.clone();
 
         if query.fallback {
             // NOTE: we add the fallback despite the type score limit
-            final_completions.push(Completion {
-                code: query
-                    .input
-                    .replace(HOLE_IDENTIFIER, &self.lang_server.any_type()),
-                score: 1000,
-                fallbacked: true,
-            });
+            final_completions.push(self.fallback_completion(&query));
         }
 
         if rate_limit {
@@ -396,10 +691,9 @@ impl CompletionEngine for CompletionClient {
 
         // print out scores
         print!("Score(s): ");
-        let lock = filtered_completions.lock().await;
-        for (i, (_, score)) in lock.iter().enumerate() {
-            print!("{score}");
-            if i != lock.len() - 1 {
+        for (i, comp) in sorted_completions.iter().enumerate() {
+            print!("{}", comp.score);
+            if i != sorted_completions.len() - 1 {
                 print!(", ");
             }
         }
@@ -408,6 +702,23 @@ impl CompletionEngine for CompletionClient {
         Ok(final_completions)
     }
 
+    /// Streams the completions of the given input code as they pass the filters.
+    /// See `complete` for the format of the query.
+    async fn complete_stream(&self, mut query: CompletionQuery) -> CompletionStream {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let sink = CompletionSink::streaming(tx).normalizer(self.get_ls().get_normalizer());
+        let handles = self.spawn_comps(&mut query, &sink).await;
+        // we drop our sink, so the stream ends once the model requests are done
+        drop(sink);
+
+        CompletionStream {
+            rx,
+            handles,
+            fallback: query.fallback.then(|| self.fallback_completion(&query)),
+            yielded: 0,
+        }
+    }
+
     /// Gets the language server object from the codex client
     fn get_ls(&self) -> ArcLangServer {
         self.lang_server.clone()
@@ -428,13 +739,29 @@ impl CompletionEngine for CompletionClient {
         self.max_type_score
     }
 
-    /// Gets a mutex guard to the cache from the codex client, if a cache is being used
-    async fn get_cache(&self) -> Option<tokio::sync::MutexGuard<Cache>> {
-        if let Some(cache) = &self.cache {
-            Some(cache.lock().await)
-        } else {
-            None
-        }
+    /// Gets the weights that the problems of a completion add to its type score.
+    fn get_problem_weights(&self) -> ProblemWeights {
+        self.problem_weights.clone()
+    }
+
+    /// Gets the retry policy used for querying the model.
+    fn get_retry_policy(&self) -> RetryPolicy {
+        self.retry_policy.clone()
+    }
+
+    /// Gets the maximum number of tokens of a prompt for the model, if there is a limit.
+    fn get_max_prompt_tokens(&self) -> Option<usize> {
+        self.model.max_prompt_tokens()
+    }
+
+    /// Counts the tokens of the given text with the tokenizer of the model.
+    fn count_tokens(&self, text: &str) -> usize {
+        self.model.count_tokens(text)
+    }
+
+    /// Gets the cache of the codex client, if a cache is being used
+    fn get_cache(&self) -> Option<Arc<Cache>> {
+        self.cache.clone()
     }
 }
 
@@ -443,7 +770,9 @@ pub struct CompletionClientBuilder {
     endpoint: Option<String>,
     temperature: Option<f64>,
     max_type_score: Option<u16>,
-    cache: Option<Arc<Mutex<Cache>>>,
+    problem_weights: Option<ProblemWeights>,
+    retry_policy: Option<RetryPolicy>,
+    cache: Option<Arc<Cache>>,
     model: ArcCompletionModel,
 }
 
@@ -454,6 +783,8 @@ impl CompletionClientBuilder {
             endpoint: None,
             temperature: None,
             max_type_score: None,
+            problem_weights: None,
+            retry_policy: None,
             cache: None,
             model,
         }
@@ -469,7 +800,7 @@ impl CompletionClientBuilder {
         self
     }
 
-    pub fn cache(mut self, cache: Arc<Mutex<Cache>>) -> Self {
+    pub fn cache(mut self, cache: Arc<Cache>) -> Self {
         self.cache = Some(cache);
         self
     }
@@ -479,12 +810,26 @@ impl CompletionClientBuilder {
         self
     }
 
+    /// Sets the weights that the problems of a completion add to its type score.
+    /// By default, problems don't affect the score.
+    pub fn problem_weights(mut self, problem_weights: ProblemWeights) -> Self {
+        self.problem_weights = Some(problem_weights);
+        self
+    }
+
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = Some(retry_policy);
+        self
+    }
+
     pub fn build(self) -> CompletionClient {
         CompletionClient {
             lang_server: self.lang_server,
             endpoint: self.endpoint,
             temperature: self.temperature.unwrap_or(1.0),
             max_type_score: self.max_type_score.unwrap_or(1000),
+            problem_weights: self.problem_weights.unwrap_or_default(),
+            retry_policy: self.retry_policy.unwrap_or_default(),
             cache: self.cache,
             model: self.model,
         }
@@ -1,7 +1,5 @@
-use std::sync::Arc;
-
 use serde::{Deserialize, Serialize};
-use tokio::{sync::Mutex, task::JoinHandle};
+use tokio::task::JoinHandle;
 
 use crate::{
     debug, get_path_from_rootdir,
@@ -9,7 +7,10 @@ use crate::{
     socket::{SendToSocket, SingleThreadedSocket, SocketAbstraction, SocketPool},
 };
 
-use super::{filter_comps, CompletionEngine, CompletionModel, CompletionQuery, ModelResponseError};
+use super::{
+    filter_comps, CompFilter, CompletionEngine, CompletionModel, CompletionQuery, CompletionSink,
+    ModelResponseError,
+};
 
 #[derive(Debug, Clone)]
 pub struct BuiltinClient {}
@@ -31,14 +32,13 @@ impl CompletionModel for BuiltinClient {
         &self,
         query: &CompletionQuery,
         engine: &dyn CompletionEngine,
-        filtered_completions: Arc<Mutex<Vec<(String, u16)>>>,
+        filtered_completions: CompletionSink,
     ) -> JoinHandle<Result<(), ModelResponseError>> {
         let lang_client = engine.get_ls();
-        let max_type_score = engine.get_max_type_score();
         let mut code = query.input.clone();
         // replace all `: _hole_` with nothing
         code = code.replace(": _hole_", "");
-        let problem_whitelist = query.problem_whitelist.clone();
+        let filter = CompFilter::new(query, engine);
         tokio::task::spawn(async move {
             // by running weaving on the same code, we are essentially triggering the type inference
             // process in the typescript compiler.
@@ -48,11 +48,16 @@ impl CompletionModel for BuiltinClient {
                 lang_client.clone(),
                 &code,
                 completion,
-                problem_whitelist.clone(),
-                max_type_score,
+                None,
+                &filter,
             )
             .await?;
             Ok(())
         })
     }
+
+    /// There is no model, the language server infers the types.
+    fn context_size(&self) -> Option<usize> {
+        None
+    }
 }
@@ -1,79 +1,245 @@
 use std::sync::Arc;
 
 use serde::{Deserialize, Serialize};
-use tokio::{sync::Mutex, task::JoinHandle};
+use tokio::task::JoinHandle;
 
-use crate::completion::filter_comps;
+use crate::{
+    completion::{filter_comps, retry::parse_retry_after, CompFilter},
+    usage::{estimate_tokens, TokenUsage},
+};
 
-use super::{CompletionEngine, CompletionModel, CompletionQuery, ModelResponseError, INSTRUCTIONS};
+use self::rl::KeyOutcome;
+
+use super::{
+    tokenizer::Tokenizer, CompletionEngine, CompletionModel, CompletionQuery, CompletionSink,
+    ModelResponseError, INSTRUCTIONS,
+};
+
+/// The quota of a single API key. `None` means unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyQuota {
+    pub requests_per_minute: Option<u32>,
+    pub tokens_per_minute: Option<u32>,
+}
+
+impl Default for KeyQuota {
+    /// 20 requests per minute, in bursts of at most 4 requests. This is the
+    /// quota of the original edit endpoint.
+    fn default() -> Self {
+        Self {
+            requests_per_minute: Some(20),
+            tokens_per_minute: None,
+        }
+    }
+}
+
+/// Rate limiting configuration for a pool of API keys. Read from a JSON file in the
+/// following format:
+/// ```json
+/// {
+///   "default": { "requests_per_minute": 20, "tokens_per_minute": 40000 },
+///   "keys": { "sk-...": { "requests_per_minute": 60, "tokens_per_minute": null } }
+/// }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// The quota of keys that are not listed in `keys`.
+    pub default: KeyQuota,
+    /// Per-key quotas.
+    pub keys: std::collections::HashMap<String, KeyQuota>,
+}
+
+impl RateLimitConfig {
+    /// Reads the configuration from the given JSON file.
+    pub fn from_file(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let contents = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    fn quota_for(&self, key: &str) -> KeyQuota {
+        self.keys.get(key).copied().unwrap_or(self.default)
+    }
+}
 
 mod rl {
-    use dashmap::DashMap;
     use governor::{
         clock::{QuantaClock, QuantaInstant},
         middleware::NoOpMiddleware,
-        state::InMemoryState,
+        state::{InMemoryState, NotKeyed},
         Quota, RateLimiter,
     };
-    use std::sync::Arc;
+    use std::{
+        num::NonZeroU32,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
     use tokio::sync::Mutex;
 
-    type RL = Arc<
-        RateLimiter<
-            String,
-            DashMap<String, InMemoryState>,
-            QuantaClock,
-            NoOpMiddleware<QuantaInstant>,
-        >,
-    >;
+    use super::RateLimitConfig;
+
+    type RL = RateLimiter<NotKeyed, InMemoryState, QuantaClock, NoOpMiddleware<QuantaInstant>>;
+
+    /// The first cooldown after a rate limit response that did not say how long to wait.
+    /// Doubled on every consecutive rate limit of the same key.
+    const INITIAL_COOLDOWN: Duration = Duration::from_secs(2);
+    const MAX_COOLDOWN: Duration = Duration::from_secs(120);
+
+    /// What happened when a key was used, reported back to the pool.
+    #[derive(Debug, Clone, Copy)]
+    pub(super) enum KeyOutcome {
+        Ok,
+        /// The key got rate limited, with the time the server asked us to wait, if any.
+        RateLimited(Option<Duration>),
+        /// The key was rejected (401/403), it won't be used anymore.
+        Rejected,
+    }
+
+    #[derive(Debug, Default)]
+    struct KeyStatus {
+        disabled: bool,
+        cooldown_until: Option<Instant>,
+        cooldown: Option<Duration>,
+    }
+
+    #[derive(Debug)]
+    struct Key {
+        token: String,
+        // we may not have a rate limiting policy for any of these
+        requests: Option<RL>,
+        tokens: Option<RL>,
+        status: std::sync::Mutex<KeyStatus>,
+    }
 
     #[derive(Clone, Debug)]
     /// Rate limited pool of tokens that can be used to query codex
     pub(super) struct RateLimitedTokenPool {
-        tokens: Arc<Vec<String>>,
+        keys: Arc<Vec<Key>>,
         token_idx: Arc<Mutex<usize>>,
-        rl: Option<RL>, // we may not have a rate limiting policy
+    }
+
+    fn limiter(per_minute: Option<u32>, burst_divisor: u32) -> Option<RL> {
+        let per_minute = NonZeroU32::new(per_minute?)?;
+        // we don't want to use the whole minute's quota in a single burst
+        let burst = NonZeroU32::new(std::cmp::max(1, per_minute.get() / burst_divisor)).unwrap();
+        Some(RateLimiter::direct(
+            Quota::per_minute(per_minute).allow_burst(burst),
+        ))
     }
 
     impl RateLimitedTokenPool {
-        async fn next_token(&self) -> String {
+        /// Picks the next key that is not disabled, preferring keys that are not cooling
+        /// down from a rate limit. Returns the index of the key and how long to wait
+        /// for its cooldown. Returns None if all the keys were rejected.
+        async fn next_key(&self) -> Option<(usize, Duration)> {
             let mut token_idx = self.token_idx.lock().await;
-            let token = self.tokens[*token_idx].clone();
-            *token_idx = (*token_idx + 1) % self.tokens.len();
-            token
+            let now = Instant::now();
+            let mut best: Option<(usize, Duration)> = None;
+            for i in 0..self.keys.len() {
+                let idx = (*token_idx + i) % self.keys.len();
+                let status = self.keys[idx].status.lock().unwrap();
+                if status.disabled {
+                    continue;
+                }
+                let wait = status
+                    .cooldown_until
+                    .map(|until| until.saturating_duration_since(now))
+                    .unwrap_or_default();
+                if best.is_none_or(|(_, w)| wait < w) {
+                    best = Some((idx, wait));
+                }
+                if wait.is_zero() {
+                    break;
+                }
+            }
+            if let Some((idx, _)) = best {
+                *token_idx = (idx + 1) % self.keys.len();
+            }
+            best
         }
 
-        /// Waits for a token to become available, then returns it.
-        pub async fn wait_token(&self) -> String {
-            let token = self.next_token().await;
-            match &self.rl {
-                Some(rl) => {
-                    rl.until_key_ready(&token).await;
+        /// Waits for a token to become available, then returns it. `est_tokens` is the
+        /// estimated number of tokens that the request will use, for the tokens per minute
+        /// quota. Returns None if all the keys were rejected.
+        pub async fn wait_token(&self, est_tokens: u32) -> Option<String> {
+            let (idx, cooldown) = self.next_key().await?;
+            if !cooldown.is_zero() {
+                tokio::time::sleep(cooldown).await;
+            }
+            let key = &self.keys[idx];
+            if let Some(rl) = &key.requests {
+                rl.until_ready().await;
+            }
+            if let Some(rl) = &key.tokens {
+                // requests larger than the burst size can never be allowed, so we cap them
+                let n = NonZeroU32::new(est_tokens.max(1)).unwrap();
+                if let Err(cap) = rl.until_n_ready(n).await {
+                    rl.until_n_ready(NonZeroU32::new(cap.0).unwrap())
+                        .await
+                        .unwrap();
                 }
-                None => (),
             }
-            token
+            Some(key.token.clone())
         }
 
-        /// Creates a new limiter pool given the list of tokens.
-        /// If rl is false, then no rate limiting is applied.
+        /// Reports the outcome of a request made with the given token, such that the pool
+        /// can adapt. Rate limited keys cool down (for as long as the server asked, or
+        /// for an exponentially increasing time), rejected keys are never used again.
+        pub fn report(&self, token: &str, outcome: KeyOutcome) {
+            let Some(key) = self.keys.iter().find(|k| k.token == token) else {
+                return;
+            };
+            let mut status = key.status.lock().unwrap();
+            match outcome {
+                KeyOutcome::Ok => {
+                    status.cooldown = None;
+                    status.cooldown_until = None;
+                }
+                KeyOutcome::RateLimited(retry_after) => {
+                    let cooldown = match (retry_after, status.cooldown) {
+                        (Some(after), _) => after,
+                        (None, Some(prev)) => std::cmp::min(prev * 2, MAX_COOLDOWN),
+                        (None, None) => INITIAL_COOLDOWN,
+                    };
+                    eprintln!(
+                        "API key rate limited, cooling down for {}ms",
+                        cooldown.as_millis()
+                    );
+                    status.cooldown = Some(cooldown);
+                    status.cooldown_until = Some(Instant::now() + cooldown);
+                }
+                KeyOutcome::Rejected => {
+                    eprintln!("API key rejected, it won't be used anymore");
+                    status.disabled = true;
+                }
+            }
+        }
+
+        /// Creates a new limiter pool given the list of tokens and the quotas for them.
+        /// If rl is false, then no quotas are applied, but rate limited and rejected
+        /// keys are still handled.
         ///
         /// # Panics
         /// Panics if the list of tokens is empty.
-        pub fn new(tokens: Vec<String>, rl: bool) -> Self {
+        pub fn new(tokens: Vec<String>, config: &RateLimitConfig, rl: bool) -> Self {
             assert!(!tokens.is_empty());
-            let reset = 120 / 10; // 2 minutes / 10 cells = 12 seconds
-            let rate_limiter = Arc::new(RateLimiter::keyed(
-                Quota::with_period(std::time::Duration::from_secs(reset)) // we want to reset every 2 minutes
-                    .unwrap()
-                    .allow_burst(std::num::NonZeroU32::new(4).unwrap()), // max 4 requests per 12 seconds
-            ));
-
-            rate_limiter.check_key(&tokens[0]).unwrap();
+            let keys = tokens
+                .into_iter()
+                .map(|token| {
+                    let quota = config.quota_for(&token);
+                    Key {
+                        // bursts of 1/5 of the requests per minute, e.g. the default of 20
+                        // requests per minute allows 4 requests every 12 seconds
+                        requests: limiter(quota.requests_per_minute, 5).filter(|_| rl),
+                        tokens: limiter(quota.tokens_per_minute, 1).filter(|_| rl),
+                        status: Default::default(),
+                        token,
+                    }
+                })
+                .collect();
 
             Self {
-                rl: if rl { Some(rate_limiter) } else { None },
-                tokens: Arc::new(tokens),
+                keys: Arc::new(keys),
                 token_idx: Arc::new(Mutex::new(0)),
             }
         }
@@ -88,6 +254,10 @@ pub struct CodexClient {
     client: reqwest::Client,
     // The rate limited token pool, that produces the token used for this client
     rate_limiter: rl::RateLimitedTokenPool,
+    // the context window of the model, in tokens
+    context_size: usize,
+    // the tokenizer of the model, to count the tokens of prompts exactly
+    tokenizer: Arc<Tokenizer>,
 }
 
 #[derive(Clone)]
@@ -95,6 +265,8 @@ pub struct CodexClientBuilder {
     client: Option<reqwest::Client>,
     tokens: Vec<String>,
     rate_limit: bool,
+    rate_limit_config: RateLimitConfig,
+    context_size: Option<usize>,
 }
 
 impl CodexClientBuilder {
@@ -103,6 +275,8 @@ impl CodexClientBuilder {
             client: None,
             tokens,
             rate_limit: true,
+            rate_limit_config: RateLimitConfig::default(),
+            context_size: None,
         }
     }
 
@@ -116,13 +290,29 @@ impl CodexClientBuilder {
         self
     }
 
+    /// Sets the per-key quotas. Without this, every key gets the default quota.
+    pub fn rate_limit_config(mut self, config: RateLimitConfig) -> Self {
+        self.rate_limit_config = config;
+        self
+    }
+
+    /// Sets the context window of the model, in tokens. Defaults to the context window of
+    /// davinci-edit.
+    pub fn context_size(mut self, context_size: usize) -> Self {
+        self.context_size = Some(context_size);
+        self
+    }
+
     /// Builds the client and consumes the builder
     pub fn build(self) -> CodexClient {
-        let client = self.client.unwrap_or_else(reqwest::Client::new);
-        let rate_limiter = rl::RateLimitedTokenPool::new(self.tokens, self.rate_limit);
+        let client = self.client.unwrap_or_default();
+        let rate_limiter =
+            rl::RateLimitedTokenPool::new(self.tokens, &self.rate_limit_config, self.rate_limit);
         CodexClient {
             client,
             rate_limiter,
+            context_size: self.context_size.unwrap_or(CONTEXT_SIZE),
+            tokenizer: Arc::new(Tokenizer::p50k()),
         }
     }
 }
@@ -133,7 +323,7 @@ impl CompletionModel for CodexClient {
         &self,
         query: &CompletionQuery,
         engine: &dyn CompletionEngine,
-        filtered_completions: Arc<Mutex<Vec<(String, u16)>>>,
+        filtered_completions: CompletionSink,
     ) -> JoinHandle<Result<(), ModelResponseError>> {
         // clones for the closure
 
@@ -145,12 +335,14 @@ impl CompletionModel for CodexClient {
             .unwrap_or_else(|| "https://api.openai.com/v1/edits".to_string());
         let temp = engine.get_temperature();
         let rl = self.rate_limiter.clone();
-        let max_type_score = engine.get_max_type_score();
+        let policy = engine.get_retry_policy();
+        let usage = query.usage.clone();
+        let tokenizer = self.tokenizer.clone();
 
         // from query:
         let num_comps = query.num_comps;
         let input = query.input.to_string();
-        let problem_whitelist = query.problem_whitelist.clone();
+        let filter = CompFilter::new(query, engine);
         let instructions = query
             .instructions
             .as_ref()
@@ -158,36 +350,102 @@ impl CompletionModel for CodexClient {
             .unwrap_or_else(|| INSTRUCTIONS.to_string());
 
         tokio::spawn(async move {
-            let token = rl.wait_token().await;
-            let req = client
-                .post(&endpoint)
-                .bearer_auth(token)
-                .header("Content-Type", "application/json")
-                .body(serde_json::to_string(&EditReq {
-                    model: "text-davinci-edit-001".to_string(),
-                    input: input.to_string(),
-                    n: num_comps,
-                    temperature: temp,
-                    instruction: instructions,
-                })?)
-                .timeout(std::time::Duration::from_secs(std::cmp::max(
-                    30, // make timeout scale up with number of completions
-                    (num_comps * 10) as u64,
-                )));
-            let res = req.send().await?;
-            let body = res.text().await?;
-            let choices: Vec<EditRespChoice> = match serde_json::from_str::<EditResp>(&body) {
-                Ok(EditResp::Choices { choices }) => choices,
-                Ok(EditResp::Error { error }) => return Err(error.into()),
-                Err(e) => {
-                    eprintln!("Error parsing response from codex: {e}");
-                    eprintln!("Response: {body}");
-                    return Err(ModelResponseError::CouldNotComplete);
-                }
-            };
+            let (choices, api_usage) = policy
+                .run(|| {
+                    let client = client.clone();
+                    let endpoint = endpoint.clone();
+                    let rl = rl.clone();
+                    let edit_req = EditReq {
+                        model: "text-davinci-edit-001".to_string(),
+                        input: input.to_string(),
+                        n: num_comps,
+                        temperature: temp,
+                        instruction: instructions.clone(),
+                    };
+                    // the edit endpoint returns the whole input for each completion
+                    let est_tokens = estimate_tokens(&edit_req.input)
+                        .saturating_mul(num_comps as u64 + 1)
+                        .try_into()
+                        .unwrap_or(u32::MAX);
+                    async move {
+                        let token = rl.wait_token(est_tokens).await.ok_or_else(|| {
+                            ModelResponseError::InvalidResponse(
+                                "all the API keys were rejected".to_string(),
+                            )
+                        })?;
+                        let req = client
+                            .post(&endpoint)
+                            .bearer_auth(&token)
+                            .header("Content-Type", "application/json")
+                            .body(serde_json::to_string(&edit_req)?)
+                            .timeout(std::time::Duration::from_secs(std::cmp::max(
+                                30, // make timeout scale up with number of completions
+                                (num_comps * 10) as u64,
+                            )));
+                        let res = req.send().await?;
+                        let status = res.status();
+                        let retry_after = res
+                            .headers()
+                            .get(reqwest::header::RETRY_AFTER)
+                            .and_then(|v| v.to_str().ok())
+                            .and_then(parse_retry_after);
+                        let body = res.text().await?;
+                        match status {
+                            reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                                rl.report(&token, KeyOutcome::RateLimited(retry_after));
+                                return Err(ModelResponseError::RateLimited(body, retry_after));
+                            }
+                            reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN => {
+                                rl.report(&token, KeyOutcome::Rejected);
+                                return Err(ModelResponseError::KeyRejected(body));
+                            }
+                            _ => {}
+                        }
+
+                        match serde_json::from_str::<EditResp>(&body) {
+                            Ok(EditResp::Choices { choices, usage }) => {
+                                rl.report(&token, KeyOutcome::Ok);
+                                Ok((choices, usage))
+                            }
+                            Ok(EditResp::Error { error }) => match error {
+                                // keep the Retry-After of the response, the error body
+                                // doesn't have it
+                                EditRespError::RateLimited { message } => {
+                                    rl.report(&token, KeyOutcome::RateLimited(retry_after));
+                                    Err(ModelResponseError::RateLimited(message, retry_after))
+                                }
+                                error => Err(error.into()),
+                            },
+                            Err(_) if !status.is_success() => {
+                                Err(ModelResponseError::Http(status.as_u16(), body))
+                            }
+                            Err(e) => {
+                                eprintln!("Error parsing response from codex: {e}");
+                                eprintln!("Response: {body}");
+                                Err(ModelResponseError::CouldNotComplete)
+                            }
+                        }
+                    }
+                })
+                .await?;
 
             println!("Got {} responses from codex", choices.len());
 
+            usage.record(match api_usage {
+                Some(u) => TokenUsage::request(u.prompt_tokens, u.completion_tokens),
+                // count it with the tokenizer if the API didn't tell us
+                None => TokenUsage::request(
+                    (tokenizer.count(&input) + tokenizer.count(&instructions)) as u64,
+                    choices
+                        .iter()
+                        .map(|c| match c {
+                            EditRespChoice::Text { text } => tokenizer.count(text) as u64,
+                            EditRespChoice::Error { .. } => 0,
+                        })
+                        .sum(),
+                ),
+            });
+
             for comp in choices.into_iter() {
                 let text = match comp {
                     EditRespChoice::Text { text } => text,
@@ -202,8 +460,9 @@ impl CompletionModel for CodexClient {
                     lang_client.clone(),
                     &input,
                     text,
-                    problem_whitelist.clone(),
-                    max_type_score,
+                    // the edits API doesn't give us log-probabilities
+                    None,
+                    &filter,
                 )
                 .await?;
             }
@@ -211,8 +470,25 @@ impl CompletionModel for CodexClient {
             Ok(())
         })
     }
+
+    fn context_size(&self) -> Option<usize> {
+        Some(self.context_size)
+    }
+
+    /// The edit endpoint returns the whole input, so the prompt can use at most half
+    /// of the context window.
+    fn max_prompt_tokens(&self) -> Option<usize> {
+        Some(self.context_size / 2)
+    }
+
+    fn count_tokens(&self, text: &str) -> usize {
+        self.tokenizer.count(text)
+    }
 }
 
+/// The context window of davinci-edit, in tokens.
+const CONTEXT_SIZE: usize = 2048;
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct EditReq {
     pub model: String,
@@ -225,10 +501,20 @@ pub struct EditReq {
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum EditResp {
-    Choices { choices: Vec<EditRespChoice> },
+    Choices {
+        choices: Vec<EditRespChoice>,
+        #[serde(default)]
+        usage: Option<EditRespUsage>,
+    },
     Error { error: EditRespError },
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EditRespUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum EditRespChoice {
@@ -249,7 +535,9 @@ impl From<EditRespError> for ModelResponseError {
     fn from(e: EditRespError) -> Self {
         match e {
             EditRespError::InvalidEdit { message } => ModelResponseError::InvalidResponse(message),
-            EditRespError::RateLimited { message } => ModelResponseError::RateLimited(message),
+            EditRespError::RateLimited { message } => {
+                ModelResponseError::RateLimited(message, None)
+            }
         }
     }
 }
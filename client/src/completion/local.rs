@@ -1,24 +1,46 @@
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
 
 use serde::{Deserialize, Serialize};
-use tokio::{sync::Mutex, task::JoinHandle};
+use tokio::task::JoinHandle;
 
 use crate::{
     debug, get_path_from_rootdir,
+    langserver::ArcLangServer,
+    prompt::estimate_prompt_tokens,
     socket::{SendToSocket, SingleThreadedSocket, SocketAbstraction, SocketPool},
+    usage::{TokenUsage, UsageTracker},
 };
 
-use super::{filter_comps, CompletionEngine, CompletionModel, CompletionQuery, ModelResponseError};
+use super::{
+    add_logprobs, filter_comps, retry::RetryPolicy, tokenizer::Tokenizer, CompFilter,
+    CompletionEngine, CompletionModel, CompletionQuery, CompletionSink, ModelResponseError,
+};
 
 #[derive(Debug, Clone)]
 pub struct LocalModelClient {
     /// Unix socket to communicate with the model server
     socket: Arc<dyn SendToSocket>,
+    /// The context window of the model, in tokens
+    context_size: usize,
+    /// The number of holes to fill per request, None for all of them
+    hole_batch_size: Option<usize>,
+    /// Whether to fill multiple holes per request. Turned off if the server doesn't
+    /// support it.
+    batch_holes: Arc<AtomicBool>,
+    /// The tokenizer of the model, if we have it. Otherwise, the tokens are estimated.
+    tokenizer: Option<Arc<Tokenizer>>,
 }
 
 pub struct LocalModelClientBuilder {
     kind: String, // e.g. incoder, or santacoder
     socket_path: Option<String>,
+    context_size: Option<usize>,
+    hole_batch_size: Option<usize>,
+    batch_holes: bool,
+    tokenizer: Option<Tokenizer>,
 }
 
 impl LocalModelClientBuilder {
@@ -26,6 +48,10 @@ impl LocalModelClientBuilder {
         Self {
             kind,
             socket_path: None,
+            context_size: None,
+            hole_batch_size: None,
+            batch_holes: true,
+            tokenizer: None,
         }
     }
 
@@ -34,7 +60,39 @@ impl LocalModelClientBuilder {
         self
     }
 
+    /// Sets the context window of the model, in tokens. Defaults to 2048, the context
+    /// window of both incoder and santacoder.
+    pub fn context_size(mut self, context_size: usize) -> Self {
+        self.context_size = Some(context_size);
+        self
+    }
+
+    /// Sets whether to fill multiple holes per request. Defaults to true. If the server
+    /// doesn't support it, we fall back to one hole per request anyway.
+    pub fn batch_holes(mut self, batch_holes: bool) -> Self {
+        self.batch_holes = batch_holes;
+        self
+    }
+
+    /// Sets the number of holes to fill per request. Defaults to all the holes of the
+    /// prompt.
+    pub fn hole_batch_size(mut self, hole_batch_size: usize) -> Self {
+        self.hole_batch_size = Some(hole_batch_size);
+        self
+    }
+
+    /// Sets the tokenizer of the model, to count the tokens of prompts exactly. Defaults to
+    /// estimating them.
+    pub fn tokenizer(mut self, tokenizer: Tokenizer) -> Self {
+        self.tokenizer = Some(tokenizer);
+        self
+    }
+
     pub async fn build(self) -> Result<LocalModelClient, ModelResponseError> {
+        let context_size = self.context_size.unwrap_or(2048);
+        let hole_batch_size = self.hole_batch_size;
+        let batch_holes = Arc::new(AtomicBool::new(self.batch_holes));
+        let tokenizer = self.tokenizer.map(Arc::new);
         // if we have a socket path, use that. open a pool. split on
         // comma.
         if let Some(socket_path) = self.socket_path {
@@ -42,6 +100,10 @@ impl LocalModelClientBuilder {
             let pool = SocketPool::make(socket_paths).await;
             return Ok(LocalModelClient {
                 socket: Arc::new(pool),
+                context_size,
+                hole_batch_size,
+                batch_holes,
+                tokenizer,
             });
         };
 
@@ -56,7 +118,13 @@ impl LocalModelClientBuilder {
                 .await
                 .map_err(|e| ModelResponseError::InvalidResponse(e.to_string()))?,
         ));
-        Ok(LocalModelClient { socket })
+        Ok(LocalModelClient {
+            socket,
+            context_size,
+            hole_batch_size,
+            batch_holes,
+            tokenizer,
+        })
     }
 }
 
@@ -66,19 +134,259 @@ impl LocalModelClientBuilder {
 ///     code: <code>,
 ///     num_samples: <num_samples>,
 ///     temperature: <temperature>,
+///     num_holes: <num_holes>, (optional)
 /// }
+/// If `num_holes` is given, servers that support batching fill the first `num_holes`
+/// holes of the code in one go, and return the alternatives for each hole in
+/// `hole_annotations`. Older servers ignore it, and only fill the first hole.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LocalModelSocketReq {
     pub code: String,
     pub num_samples: usize,
     pub temperature: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num_holes: Option<usize>,
 }
 
+/// Response of the local server, in the format of
+/// {
+///     type: "single" | "batch",
+///     type_annotations: [<annotation for the first hole>, ...],
+///     hole_annotations: [[<annotation for the i-th hole>, ...], ...], (optional)
+///     type_annotation_logprobs: [<log-probability of the annotation>, ...], (optional)
+///     hole_logprobs: [[<log-probability of the annotation>, ...], ...], (optional)
+/// }
+/// The log-probabilities are parallel to the annotations, and are used to rank the
+/// completions that are otherwise equal.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LocalModelSocketResp {
     #[serde(rename = "type")]
     pub type_: String,
     pub type_annotations: Vec<String>,
+    /// The alternatives for each of the filled holes, in order. Only sent by servers
+    /// that support batching.
+    #[serde(default)]
+    pub hole_annotations: Option<Vec<Vec<String>>>,
+    /// The log-probabilities of `type_annotations`, if the server reports them.
+    #[serde(default)]
+    pub type_annotation_logprobs: Option<Vec<f64>>,
+    /// The log-probabilities of `hole_annotations`, if the server reports them.
+    #[serde(default)]
+    pub hole_logprobs: Option<Vec<Vec<f64>>>,
+    /// The number of tokens in the prompt, counted by the tokenizer of the model.
+    #[serde(default)]
+    pub prompt_tokens: Option<u64>,
+    /// The number of tokens the model generated, over all the samples.
+    #[serde(default)]
+    pub completion_tokens: Option<u64>,
+}
+
+impl LocalModelSocketResp {
+    /// The token usage of the request that got this response. Servers that don't report
+    /// their token counts get them estimated from the code and the annotations.
+    fn usage(&self, code: &str) -> TokenUsage {
+        match (self.prompt_tokens, self.completion_tokens) {
+            (Some(prompt), Some(completion)) => TokenUsage::request(prompt, completion),
+            _ => {
+                let annots: Vec<&str> = self
+                    .type_annotations
+                    .iter()
+                    .chain(self.hole_annotations.iter().flatten().flatten())
+                    .map(String::as_str)
+                    .collect();
+                TokenUsage::estimated(code, &annots)
+            }
+        }
+    }
+}
+
+type TypeParser = Box<dyn Fn(&str) -> Option<String> + Sync + Send>;
+
+/// Fills the holes of a prompt with the annotations of the local server.
+struct HoleFiller {
+    socket: Arc<dyn SendToSocket>,
+    lang_client: ArcLangServer,
+    type_parser: Option<TypeParser>,
+    policy: RetryPolicy,
+    temperature: f64,
+    usage: UsageTracker,
+}
+
+impl HoleFiller {
+    async fn request(
+        &self,
+        code: &str,
+        num_samples: usize,
+        num_holes: Option<usize>,
+    ) -> Result<LocalModelSocketResp, ModelResponseError> {
+        let req = LocalModelSocketReq {
+            code: code.to_string(),
+            num_samples,
+            temperature: self.temperature,
+            num_holes,
+        };
+        let req = serde_json::to_value(&req)?;
+        let resp: LocalModelSocketResp = serde_json::from_value(
+            self.policy
+                .run(|| self.socket.send_req(req.clone()))
+                .await?,
+        )?;
+        self.usage.record(resp.usage(code));
+        Ok(resp)
+    }
+
+    /// Parses the given annotation into a type, if we have a parser.
+    fn parse(&self, annot: &str) -> Option<String> {
+        match &self.type_parser {
+            Some(parser) => {
+                let parsed = parser(annot);
+                match &parsed {
+                    Some(parsed) => {
+                        debug!("succesfully parsed into {parsed}");
+                    }
+                    None => {
+                        debug!("failed to parse {annot}");
+                    }
+                }
+                parsed
+            }
+            // if we don't have a parser, just pray that it's valid
+            None => Some(annot.to_string()),
+        }
+    }
+
+    /// Picks the first alternative that parses, starting at the `start`-th one and
+    /// wrapping around, along with its log-probability. Falls back to the any type,
+    /// which has no log-probability.
+    fn pick(
+        &self,
+        alternatives: &[String],
+        logprobs: Option<&Vec<f64>>,
+        start: usize,
+    ) -> (String, Option<f64>) {
+        let len = alternatives.len();
+        (0..len)
+            .find_map(|i| {
+                let idx = (start + i) % len;
+                let parsed = self.parse(&alternatives[idx])?;
+                Some((parsed, logprobs.and_then(|l| l.get(idx).copied())))
+            })
+            .unwrap_or_else(|| {
+                debug!("falling back to any type :(");
+                (self.lang_client.any_type(), None)
+            })
+    }
+
+    /// Fills the holes one at a time. The first hole gets `num_comps` samples, one per
+    /// candidate, then the rest of the holes of each candidate get filled with the
+    /// first sample that parses.
+    async fn sequential(
+        &self,
+        code: &str,
+        num_comps: usize,
+        num_holes: usize,
+    ) -> Result<Vec<(String, Option<f64>)>, ModelResponseError> {
+        let mut completions = Vec::with_capacity(num_comps);
+
+        // first run, consider all that work
+        let resp = self.request(code, num_comps, None).await?;
+        debug!("got annotations {:?}", resp.type_annotations);
+        for (i, annot) in resp.type_annotations.iter().enumerate() {
+            let (parsed, logprob) = match self.parse(annot) {
+                Some(parsed) => (
+                    parsed,
+                    resp.type_annotation_logprobs
+                        .as_ref()
+                        .and_then(|l| l.get(i).copied()),
+                ),
+                None => {
+                    debug!("falling back to any type :(");
+                    (self.lang_client.any_type(), None)
+                }
+            };
+            let comp = code.replacen("_hole_", &parsed, 1);
+            debug!("current completion: {comp}");
+            completions.push((comp, logprob));
+        }
+
+        for (completion, logprob) in completions.iter_mut() {
+            for _ in 1..num_holes {
+                // we don't use num_comps because here we only pick the first
+                // one that parses
+                let resp = self.request(completion, 3, None).await?;
+                debug!("got annotations {:?}", resp.type_annotations);
+                let (solved, solved_logprob) = self.pick(
+                    &resp.type_annotations,
+                    resp.type_annotation_logprobs.as_ref(),
+                    0,
+                );
+                *completion = completion.replacen("_hole_", &solved, 1);
+                *logprob = add_logprobs(*logprob, solved_logprob);
+            }
+        }
+
+        Ok(completions)
+    }
+
+    /// Fills the holes `batch_size` at a time. The first batch gets `num_comps` samples,
+    /// and the i-th candidate is made of the i-th alternative of each hole. The rest of
+    /// the batches of each candidate get filled with the first alternatives that parse.
+    /// Returns None if the server doesn't support batching.
+    async fn batched(
+        &self,
+        code: &str,
+        num_comps: usize,
+        num_holes: usize,
+        batch_size: usize,
+    ) -> Result<Option<Vec<(String, Option<f64>)>>, ModelResponseError> {
+        let batch_size = batch_size.clamp(1, num_holes);
+        let resp = self.request(code, num_comps, Some(batch_size)).await?;
+        let Some(alternatives) = resp.hole_annotations else {
+            return Ok(None);
+        };
+        let hole_logprobs = resp.hole_logprobs.unwrap_or_default();
+        debug!("got hole annotations {:?}", alternatives);
+
+        let mut completions = Vec::with_capacity(num_comps);
+        for i in 0..num_comps {
+            let mut completion = code.to_string();
+            let mut logprob = Some(0.0);
+            for (h, hole) in alternatives.iter().enumerate() {
+                let (solved, solved_logprob) = self.pick(hole, hole_logprobs.get(h), i);
+                completion = completion.replacen("_hole_", &solved, 1);
+                logprob = add_logprobs(logprob, solved_logprob);
+            }
+            completions.push((completion, logprob));
+        }
+
+        let filled = alternatives.len();
+        for (completion, logprob) in completions.iter_mut() {
+            let mut left = num_holes.saturating_sub(filled);
+            while left > 0 {
+                let resp = self
+                    .request(completion, 3, Some(std::cmp::min(batch_size, left)))
+                    .await?;
+                let alternatives = resp.hole_annotations.unwrap_or_default();
+                let hole_logprobs = resp.hole_logprobs.unwrap_or_default();
+                debug!("got hole annotations {:?}", alternatives);
+                for (h, hole) in alternatives.iter().take(left).enumerate() {
+                    let (solved, solved_logprob) = self.pick(hole, hole_logprobs.get(h), 0);
+                    *completion = completion.replacen("_hole_", &solved, 1);
+                    *logprob = add_logprobs(*logprob, solved_logprob);
+                }
+                if alternatives.is_empty() {
+                    // make sure we make progress
+                    *completion = completion.replacen("_hole_", &self.lang_client.any_type(), 1);
+                    *logprob = None;
+                    left -= 1;
+                } else {
+                    left -= std::cmp::min(alternatives.len(), left);
+                }
+            }
+        }
+
+        Ok(Some(completions))
+    }
 }
 
 impl CompletionModel for LocalModelClient {
@@ -86,16 +394,22 @@ impl CompletionModel for LocalModelClient {
         &self,
         query: &CompletionQuery,
         engine: &dyn CompletionEngine,
-        filtered_completions: Arc<Mutex<Vec<(String, u16)>>>,
+        filtered_completions: CompletionSink,
     ) -> JoinHandle<Result<(), ModelResponseError>> {
         let lang_client = engine.get_ls();
-        let max_type_score = engine.get_max_type_score();
         let num_comps = query.num_comps;
         let code = query.input.clone();
-        let problem_whitelist = query.problem_whitelist.clone();
-        let socket = self.socket.clone();
-        let temperature = engine.get_temperature();
-        let type_parser = lang_client.get_type_parser();
+        let filter = CompFilter::new(query, engine);
+        let hole_batch_size = self.hole_batch_size;
+        let batch_holes = self.batch_holes.clone();
+        let filler = HoleFiller {
+            socket: self.socket.clone(),
+            lang_client: lang_client.clone(),
+            type_parser: lang_client.get_type_parser(),
+            policy: engine.get_retry_policy(),
+            temperature: engine.get_temperature(),
+            usage: query.usage.clone(),
+        };
 
         // count the number of _hole_'s in the code
         let num_holes = code.matches("_hole_").count();
@@ -108,87 +422,42 @@ impl CompletionModel for LocalModelClient {
                     lang_client.clone(),
                     &code,
                     code.clone(),
-                    problem_whitelist.clone(),
-                    max_type_score,
+                    None,
+                    &filter,
                 )
                 .await;
             }
 
-            let mut completions = Vec::with_capacity(num_comps);
-
-            // first run, consider all that work
-            let req = LocalModelSocketReq {
-                code: code.clone(),
-                num_samples: num_comps,
-                temperature,
+            let batched = if num_holes > 1 && batch_holes.load(Ordering::Relaxed) {
+                let batch_size = hole_batch_size.unwrap_or(num_holes);
+                filler
+                    .batched(&code, num_comps, num_holes, batch_size)
+                    .await?
+            } else {
+                None
             };
 
-            let resp: LocalModelSocketResp =
-                serde_json::from_value(socket.send_req(serde_json::to_value(&req)?).await?)?;
-
-            debug!("got annotations {:?}", resp.type_annotations);
-            for annot in resp.type_annotations {
-                if let Some(parser) = &type_parser {
-                    let parsed = parser(&annot).unwrap_or_else(|| {
-                        debug!("failed to parse {annot}. falling back to any type :(");
-                        lang_client.any_type()
-                    });
-                    debug!("succesfully parsed into {parsed}");
-                    let comp = code.replacen("_hole_", &parsed, 1);
-                    debug!("current completion: {comp}");
-                    completions.push(comp);
-                } else {
-                    // if we don't have a parser, just pray that it's valid
-                    let comp = code.replacen("_hole_", &annot, 1);
-                    completions.push(comp);
-                }
-            }
-
-            for mut completion in completions.into_iter() {
-                for _ in 1..num_holes {
-                    let req = LocalModelSocketReq {
-                        code: completion.clone(),
-                        // we don't use num_comps because here we only pick the first
-                        // one that parses
-                        num_samples: 3,
-                        temperature,
-                    };
-
-                    let resp: LocalModelSocketResp = serde_json::from_value(
-                        socket.send_req(serde_json::to_value(&req)?).await?,
-                    )?;
-
-                    // get the first annot that parses, or fallback to any
-                    let mut solved = None;
-                    debug!("got annotations {:?}", resp.type_annotations);
-                    for annot in resp.type_annotations {
-                        if let Some(parser) = &type_parser {
-                            if let Some(parsed) = parser(&annot) {
-                                debug!("succesfully parsed into {parsed}");
-                                solved = Some(parsed);
-                                break;
-                            }
-                        } else {
-                            // if we don't have a parser, just pray that it's valid
-                            solved = Some(annot);
-                            break;
-                        }
+            let completions = match batched {
+                Some(completions) => completions,
+                None => {
+                    if num_holes > 1 && batch_holes.swap(false, Ordering::Relaxed) {
+                        eprintln!(
+                            "The local model server does not support batched holes, \
+                             falling back to one hole per request"
+                        );
                     }
-
-                    let solved = solved.unwrap_or_else(|| {
-                        debug!("falling back to any type :(");
-                        lang_client.any_type()
-                    });
-                    completion = completion.replacen("_hole_", &solved, 1);
+                    filler.sequential(&code, num_comps, num_holes).await?
                 }
+            };
 
+            for (completion, logprob) in completions.into_iter() {
                 filter_comps(
                     filtered_completions.clone(),
                     lang_client.clone(),
                     &code,
                     completion,
-                    problem_whitelist.clone(),
-                    max_type_score,
+                    logprob,
+                    &filter,
                 )
                 .await?;
             }
@@ -196,4 +465,44 @@ impl CompletionModel for LocalModelClient {
             Ok(())
         })
     }
+
+    fn context_size(&self) -> Option<usize> {
+        Some(self.context_size)
+    }
+
+    fn count_tokens(&self, text: &str) -> usize {
+        match &self.tokenizer {
+            Some(tokenizer) => tokenizer.count(text),
+            None => estimate_prompt_tokens(text),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reported_usage() {
+        let resp: LocalModelSocketResp = serde_json::from_str(
+            r#"{"type": "single", "type_annotations": ["number"],
+                "prompt_tokens": 12, "completion_tokens": 3}"#,
+        )
+        .unwrap();
+        assert_eq!(resp.usage("let x: _hole_ = 1;"), TokenUsage::request(12, 3));
+    }
+
+    #[test]
+    fn estimated_usage() {
+        // older servers don't report the counts
+        let resp: LocalModelSocketResp =
+            serde_json::from_str(r#"{"type": "single", "type_annotations": ["number"]}"#).unwrap();
+        let usage = resp.usage("let x: _hole_ = 1;");
+        assert_eq!(
+            usage,
+            TokenUsage::estimated("let x: _hole_ = 1;", &["number"])
+        );
+        assert_eq!(usage.estimated_requests, 1);
+        assert!(usage.to_string().contains("estimated"));
+    }
 }
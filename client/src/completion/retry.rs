@@ -0,0 +1,301 @@
+use std::{future::Future, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::socket::SocketError;
+
+use super::ModelResponseError;
+
+/// How an error should be treated by a `RetryPolicy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorClass {
+    /// The model (or the API key) is rate limited. Retrying after some time may succeed.
+    RateLimit,
+    /// A temporary failure, such as a timeout, a server error or an unusable response.
+    Transient,
+    /// An error that will not go away by retrying, e.g. a dead socket or a bad request.
+    Fatal,
+}
+
+/// An error that can be classified for retrying.
+pub trait Retryable {
+    /// The class of the error.
+    fn class(&self) -> ErrorClass;
+
+    /// The time the server asked us to wait before retrying, if any (e.g. the HTTP
+    /// `Retry-After` header).
+    fn retry_after(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// A retry policy with exponential backoff and jitter, applied by the model backends to
+/// every model request. The strategies don't retry the queries again on top of it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// The maximum number of attempts, including the first one. 1 disables retrying.
+    pub max_attempts: usize,
+    /// The delay before the first retry, in milliseconds. Doubled on every retry.
+    pub base_delay_ms: u64,
+    /// The maximum delay between two attempts, in milliseconds.
+    pub max_delay_ms: u64,
+    /// The fraction of the delay that is randomized, in [0, 1]. With 0.5, a delay of
+    /// 1s becomes a random delay between 0.5s and 1s.
+    pub jitter: f64,
+    /// Whether to retry rate limit errors.
+    pub retry_rate_limits: bool,
+    /// Whether to retry transient errors.
+    pub retry_transient: bool,
+    /// Whether to wait for the time given by the server (e.g. `Retry-After`), instead of
+    /// our own backoff, when it is longer.
+    pub respect_retry_after: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay_ms: 500,
+            max_delay_ms: 60_000,
+            jitter: 0.5,
+            retry_rate_limits: true,
+            retry_transient: true,
+            respect_retry_after: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that never retries.
+    pub fn never() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Returns true if an error of the given class should be retried, given that
+    /// `attempt` attempts (starting at 1) were already made.
+    pub fn should_retry(&self, class: ErrorClass, attempt: usize) -> bool {
+        if attempt >= self.max_attempts {
+            return false;
+        }
+        match class {
+            ErrorClass::RateLimit => self.retry_rate_limits,
+            ErrorClass::Transient => self.retry_transient,
+            ErrorClass::Fatal => false,
+        }
+    }
+
+    /// The delay to wait before the next attempt, given that `attempt` attempts (starting
+    /// at 1) were already made.
+    pub fn delay(&self, attempt: usize, retry_after: Option<Duration>) -> Duration {
+        let exp = attempt.saturating_sub(1).min(32) as u32;
+        let backoff = self
+            .base_delay_ms
+            .saturating_mul(2u64.saturating_pow(exp))
+            .min(self.max_delay_ms);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let backoff = {
+            // need to make sure we drop this before an await
+            let mut rng = rand::thread_rng();
+            let factor = 1.0 - jitter * rand::Rng::gen::<f64>(&mut rng);
+            Duration::from_millis((backoff as f64 * factor) as u64)
+        };
+
+        match retry_after {
+            Some(after) if self.respect_retry_after => std::cmp::max(after, backoff),
+            _ => backoff,
+        }
+    }
+
+    /// Runs the given operation until it succeeds, or until the policy says to stop.
+    /// The last error is returned if all attempts fail.
+    pub async fn run<T, E, F, Fut>(&self, mut op: F) -> Result<T, E>
+    where
+        E: Retryable + std::fmt::Display,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match op().await {
+                Ok(res) => return Ok(res),
+                Err(e) if self.should_retry(e.class(), attempt) => {
+                    let delay = self.delay(attempt, e.retry_after());
+                    eprintln!(
+                        "Attempt {attempt}/{} failed: {e}. Retrying in {}ms.",
+                        self.max_attempts,
+                        delay.as_millis()
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Parses the value of an HTTP `Retry-After` header. Only the delay-seconds form is
+/// supported, HTTP dates are ignored.
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    value.trim().parse::<u64>().ok().map(Duration::from_secs)
+}
+
+impl Retryable for SocketError {
+    fn class(&self) -> ErrorClass {
+        match self {
+            // socket IO errors are usually irrecoverable
+            SocketError::Io(_) => ErrorClass::Fatal,
+            SocketError::Serde(_) | SocketError::Service(_) => ErrorClass::Transient,
+        }
+    }
+}
+
+impl Retryable for ModelResponseError {
+    fn class(&self) -> ErrorClass {
+        match self {
+            ModelResponseError::RateLimited(_, _) => ErrorClass::RateLimit,
+            ModelResponseError::Http(status, _) => match status {
+                429 => ErrorClass::RateLimit,
+                408 | 500..=599 => ErrorClass::Transient,
+                _ => ErrorClass::Fatal,
+            },
+            ModelResponseError::Reqwest(e) if e.is_timeout() || e.is_connect() => {
+                ErrorClass::Transient
+            }
+            ModelResponseError::Reqwest(_) => ErrorClass::Fatal,
+            ModelResponseError::Serde(_) => ErrorClass::Transient,
+            ModelResponseError::CouldNotComplete | ModelResponseError::InvalidResponse(_) => {
+                ErrorClass::Fatal
+            }
+            // the pool will pick another key on the next attempt, if there is one
+            ModelResponseError::KeyRejected(_) => ErrorClass::Transient,
+            ModelResponseError::Socket(e) => e.class(),
+        }
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            ModelResponseError::RateLimited(_, after) => *after,
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay_ms: 100,
+            max_delay_ms: 1000,
+            jitter: 0.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn should_retry() {
+        let policy = policy();
+        assert!(policy.should_retry(ErrorClass::Transient, 1));
+        assert!(policy.should_retry(ErrorClass::RateLimit, 2));
+        // the third attempt is the last one
+        assert!(!policy.should_retry(ErrorClass::Transient, 3));
+        assert!(!policy.should_retry(ErrorClass::Fatal, 1));
+
+        let no_rate_limits = RetryPolicy {
+            retry_rate_limits: false,
+            ..policy.clone()
+        };
+        assert!(!no_rate_limits.should_retry(ErrorClass::RateLimit, 1));
+        assert!(no_rate_limits.should_retry(ErrorClass::Transient, 1));
+        assert!(!RetryPolicy::never().should_retry(ErrorClass::Transient, 1));
+    }
+
+    #[test]
+    fn delay() {
+        let policy = policy();
+        let ms = Duration::from_millis;
+        assert_eq!(policy.delay(1, None), ms(100));
+        assert_eq!(policy.delay(2, None), ms(200));
+        assert_eq!(policy.delay(4, None), ms(800));
+        // capped by the maximum delay, even after many attempts
+        assert_eq!(policy.delay(5, None), ms(1000));
+        assert_eq!(policy.delay(100, None), ms(1000));
+
+        // the server may ask for longer, but not for shorter
+        assert_eq!(policy.delay(1, Some(ms(5000))), ms(5000));
+        assert_eq!(policy.delay(2, Some(ms(10))), ms(200));
+        let ignore_server = RetryPolicy {
+            respect_retry_after: false,
+            ..policy
+        };
+        assert_eq!(ignore_server.delay(1, Some(ms(5000))), ms(100));
+    }
+
+    #[test]
+    fn jitter() {
+        let policy = RetryPolicy {
+            jitter: 0.5,
+            ..policy()
+        };
+        for _ in 0..100 {
+            let delay = policy.delay(2, None);
+            assert!(delay >= Duration::from_millis(100) && delay <= Duration::from_millis(200));
+        }
+    }
+
+    #[test]
+    fn retry_after_header() {
+        assert_eq!(parse_retry_after(" 30 "), Some(Duration::from_secs(30)));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+    }
+
+    #[tokio::test]
+    async fn run() {
+        let policy = RetryPolicy {
+            base_delay_ms: 0,
+            ..policy()
+        };
+        let attempts = AtomicUsize::new(0);
+        // fails twice with a server error, then succeeds on the last attempt
+        let res = policy
+            .run(|| async {
+                match attempts.fetch_add(1, Ordering::SeqCst) {
+                    0 | 1 => Err(ModelResponseError::Http(503, String::new())),
+                    n => Ok(n),
+                }
+            })
+            .await;
+        assert!(matches!(res, Ok(2)));
+
+        // fatal errors are returned right away
+        attempts.store(0, Ordering::SeqCst);
+        let res: Result<(), _> = policy
+            .run(|| async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(ModelResponseError::Http(400, String::new()))
+            })
+            .await;
+        assert!(matches!(res, Err(ModelResponseError::Http(400, _))));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+        // and the last error once the attempts run out
+        attempts.store(0, Ordering::SeqCst);
+        let res: Result<(), _> = policy
+            .run(|| async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(ModelResponseError::Http(500, String::new()))
+            })
+            .await;
+        assert!(matches!(res, Err(ModelResponseError::Http(500, _))));
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use tiktoken_rs::CoreBPE;
+
+/// The pre-tokenizer regex of GPT-2, which every byte-level BPE tokenizer we support uses.
+/// `{digits}` is replaced by how the tokenizer splits numbers.
+const GPT2_PATTERN: &str =
+    r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+|{digits}| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+";
+
+/// Counts the tokens of a text exactly, with the byte-level BPE of a model.
+#[derive(Clone)]
+pub struct Tokenizer {
+    bpe: CoreBPE,
+    /// Whether the tokenizer adds a space before the text, like it was in the middle of
+    /// a sentence.
+    prefix_space: bool,
+}
+
+impl Tokenizer {
+    /// The tokenizer of the codex models.
+    pub fn p50k() -> Self {
+        Self {
+            bpe: tiktoken_rs::p50k_base().expect("the p50k vocabulary is bundled"),
+            prefix_space: false,
+        }
+    }
+
+    /// Loads the `tokenizer.json` of a Hugging Face model, like the ones of InCoder and
+    /// SantaCoder. Only byte-level BPE tokenizers are supported, whose pre-tokenizer is the
+    /// one of GPT-2, with or without splitting the digits first.
+    pub fn from_file(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let contents = std::fs::read_to_string(path)?;
+        Self::from_json(&contents)
+    }
+
+    fn from_json(json: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let file: HfTokenizer = serde_json::from_str(json)?;
+        if file.model.type_ != "BPE" {
+            return Err(format!("{} models are not supported", file.model.type_).into());
+        }
+
+        let pre_tokenizers = match file.pre_tokenizer {
+            Some(HfPreTokenizer::Sequence { pretokenizers }) => pretokenizers,
+            Some(pre_tokenizer) => vec![pre_tokenizer],
+            None => vec![],
+        };
+        let mut digits = r" ?\p{N}+";
+        let mut byte_level = None;
+        for pre_tokenizer in pre_tokenizers {
+            match pre_tokenizer {
+                HfPreTokenizer::Digits { individual_digits } if byte_level.is_none() => {
+                    digits = if individual_digits {
+                        r"\p{N}"
+                    } else {
+                        r"\p{N}+"
+                    };
+                }
+                HfPreTokenizer::ByteLevel {
+                    add_prefix_space,
+                    use_regex,
+                } if byte_level.is_none() => byte_level = Some((add_prefix_space, use_regex)),
+                pre_tokenizer => {
+                    return Err(format!("unsupported pre-tokenizer {pre_tokenizer:?}").into())
+                }
+            }
+        }
+        let Some((prefix_space, use_regex)) = byte_level else {
+            return Err("only byte-level tokenizers are supported".into());
+        };
+        let pattern = if use_regex {
+            GPT2_PATTERN.replace("{digits}", digits)
+        } else {
+            r"[\s\S]+".to_string()
+        };
+
+        // the rank of a token is the order of the merge that makes it, which is what BPE
+        // merges by
+        let chars = byte_chars();
+        let decode = |token: &str| -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+            token
+                .chars()
+                .map(|c| chars.get(&c).copied())
+                .collect::<Option<_>>()
+                .ok_or_else(|| format!("token {token:?} is not byte-level").into())
+        };
+        let mut encoder: HashMap<Vec<u8>, usize> =
+            (0..=255u8).map(|b| (vec![b], b.into())).collect();
+        for (i, merge) in file.model.merges.iter().enumerate() {
+            let (left, right) = match merge {
+                HfMerge::Joined(merge) => merge
+                    .split_once(' ')
+                    .ok_or_else(|| format!("invalid merge {merge:?}"))?,
+                HfMerge::Pair(left, right) => (left.as_str(), right.as_str()),
+            };
+            let mut token = decode(left)?;
+            token.extend(decode(right)?);
+            encoder.entry(token).or_insert(256 + i);
+        }
+
+        let bpe = CoreBPE::new(encoder.into_iter().collect(), Default::default(), &pattern)
+            .map_err(|e| e.to_string())?;
+        Ok(Self { bpe, prefix_space })
+    }
+
+    /// Counts the tokens of the given text.
+    pub fn count(&self, text: &str) -> usize {
+        if self.prefix_space && !text.starts_with(' ') {
+            self.bpe.encode_ordinary(&format!(" {text}")).len()
+        } else {
+            self.bpe.encode_ordinary(text).len()
+        }
+    }
+}
+
+impl std::fmt::Debug for Tokenizer {
+    // the vocabulary is too large to print
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tokenizer")
+            .field("prefix_space", &self.prefix_space)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Maps the characters that byte-level tokenizers write the bytes of a token with back to
+/// the bytes. Printable bytes are written as themselves, the others as the characters from
+/// U+0100 on, in order.
+fn byte_chars() -> HashMap<char, u8> {
+    let mut shifted = 0;
+    (0..=255u8)
+        .map(|b| {
+            let printable = matches!(b, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF);
+            let c = if printable {
+                char::from(b)
+            } else {
+                shifted += 1;
+                char::from_u32(255 + shifted).unwrap()
+            };
+            (c, b)
+        })
+        .collect()
+}
+
+/// The parts of a `tokenizer.json` that we need.
+#[derive(Deserialize)]
+struct HfTokenizer {
+    model: HfModel,
+    pre_tokenizer: Option<HfPreTokenizer>,
+}
+
+#[derive(Deserialize)]
+struct HfModel {
+    #[serde(rename = "type")]
+    type_: String,
+    #[serde(default)]
+    merges: Vec<HfMerge>,
+}
+
+/// Older versions of the tokenizers library write merges as "left right", newer ones as
+/// pairs.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HfMerge {
+    Joined(String),
+    Pair(String, String),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+enum HfPreTokenizer {
+    Sequence {
+        pretokenizers: Vec<HfPreTokenizer>,
+    },
+    Digits {
+        #[serde(default)]
+        individual_digits: bool,
+    },
+    ByteLevel {
+        #[serde(default = "default_true")]
+        add_prefix_space: bool,
+        #[serde(default = "default_true")]
+        use_regex: bool,
+    },
+    #[serde(other)]
+    Other,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A tokenizer that knows "ab", "abc", " a", "12", " 12" and "ab a", in that order.
+    fn tokenizer(pre_tokenizer: &str) -> Tokenizer {
+        Tokenizer::from_json(&format!(
+            r#"{{
+                "model": {{
+                    "type": "BPE",
+                    "vocab": {{}},
+                    "merges": ["a b", "ab c", "Ġ a", "1 2", "Ġ 12", "ab Ġa"]
+                }},
+                "pre_tokenizer": {pre_tokenizer}
+            }}"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn p50k() {
+        assert_eq!(Tokenizer::p50k().count("hello world"), 2);
+    }
+
+    #[test]
+    fn merges() {
+        let tok =
+            tokenizer(r#"{"type": "ByteLevel", "add_prefix_space": false, "use_regex": true}"#);
+        assert_eq!(tok.count("abc"), 1);
+        assert_eq!(tok.count("abcab"), 2);
+        assert_eq!(tok.count("cba"), 3);
+        // the regex splits words, with the space before them
+        assert_eq!(tok.count("ab a"), 2);
+        assert_eq!(tok.count("ab ab"), 3);
+    }
+
+    #[test]
+    fn prefix_space() {
+        let tok = tokenizer(r#"{"type": "ByteLevel"}"#);
+        assert_eq!(tok.count("a"), 1);
+        assert_eq!(tok.count("ab"), 2);
+    }
+
+    #[test]
+    fn digits() {
+        let pre_tokenizer = |individual: bool| {
+            format!(
+                r#"{{"type": "Sequence", "pretokenizers": [
+                    {{"type": "Digits", "individual_digits": {individual}}},
+                    {{"type": "ByteLevel", "add_prefix_space": false}}
+                ]}}"#
+            )
+        };
+        assert_eq!(tokenizer(&pre_tokenizer(true)).count("a 12"), 4);
+        // splitting the digits first leaves the space on its own
+        assert_eq!(tokenizer(&pre_tokenizer(false)).count("a 12"), 3);
+        let whole = tokenizer(r#"{"type": "ByteLevel", "add_prefix_space": false}"#);
+        assert_eq!(whole.count("a 12"), 2);
+    }
+
+    #[test]
+    fn no_regex() {
+        let tok =
+            tokenizer(r#"{"type": "ByteLevel", "add_prefix_space": false, "use_regex": false}"#);
+        // without the regex, tokens can span words
+        assert_eq!(tok.count("ab a"), 1);
+    }
+
+    #[test]
+    fn unsupported() {
+        let json = r#"{"model": {"type": "WordPiece", "vocab": {}}, "pre_tokenizer": null}"#;
+        assert!(Tokenizer::from_json(json).is_err());
+        let json =
+            r#"{"model": {"type": "BPE", "merges": []}, "pre_tokenizer": {"type": "Whitespace"}}"#;
+        assert!(Tokenizer::from_json(json).is_err());
+        let json = r#"{"model": {"type": "BPE", "merges": []}, "pre_tokenizer": null}"#;
+        assert!(Tokenizer::from_json(json).is_err());
+    }
+}
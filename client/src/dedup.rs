@@ -0,0 +1,121 @@
+use std::collections::HashSet;
+
+use crate::langserver::{ArcLangServer, Normalizer};
+
+/// The key that code is deduplicated by: its canonical form if we have a normalizer and
+/// the code can be normalized, the code itself otherwise.
+pub fn canonical_key(normalizer: Option<&Normalizer>, code: &str) -> String {
+    normalizer
+        .and_then(|normalize| normalize(code))
+        .unwrap_or_else(|| code.to_string())
+}
+
+/// A set of code that is deduplicated by canonical form, such that code that only differs
+/// in the way its types are written is only kept once. Keeps the first code that was
+/// inserted for each canonical form, in insertion order.
+///
+/// Without a normalizer, this behaves like a `HashSet<String>` that keeps its order.
+pub struct CanonicalSet {
+    normalizer: Option<Normalizer>,
+    keys: HashSet<String>,
+    codes: Vec<String>,
+}
+
+impl CanonicalSet {
+    pub fn new(normalizer: Option<Normalizer>) -> Self {
+        Self {
+            normalizer,
+            keys: HashSet::new(),
+            codes: vec![],
+        }
+    }
+
+    /// Creates an empty set that uses the normalizer of the given language server.
+    pub fn for_ls(ls: &ArcLangServer) -> Self {
+        Self::new(ls.get_normalizer())
+    }
+
+    /// Inserts the given code, unless code with the same canonical form is already in the
+    /// set. Returns true if it was inserted.
+    pub fn insert(&mut self, code: String) -> bool {
+        let key = canonical_key(self.normalizer.as_ref(), &code);
+        if !self.keys.insert(key) {
+            return false;
+        }
+        self.codes.push(code);
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.codes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.codes.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, String> {
+        self.codes.iter()
+    }
+
+    pub fn as_slice(&self) -> &[String] {
+        &self.codes
+    }
+}
+
+impl IntoIterator for CanonicalSet {
+    type Item = String;
+    type IntoIter = std::vec::IntoIter<String>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.codes.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Normalizes by collapsing the whitespace, and can't normalize code with a `?`.
+    fn collapse() -> Normalizer {
+        Box::new(|code: &str| {
+            if code.contains('?') {
+                None
+            } else {
+                Some(code.split_whitespace().collect::<Vec<_>>().join(" "))
+            }
+        })
+    }
+
+    #[test]
+    fn keeps_first_of_each_form() {
+        let mut set = CanonicalSet::new(Some(collapse()));
+        assert!(set.insert("let x: number;".to_string()));
+        assert!(!set.insert("let  x:   number;".to_string()));
+        assert!(set.insert("let x: string;".to_string()));
+        assert!(!set.insert("let x:\nstring;".to_string()));
+        assert_eq!(set.len(), 2);
+        assert_eq!(set.as_slice(), ["let x: number;", "let x: string;"]);
+    }
+
+    #[test]
+    fn falls_back_to_code() {
+        let mut set = CanonicalSet::new(Some(collapse()));
+        assert!(set.insert("let x = a ? b : c;".to_string()));
+        // can't be normalized, so it's only a duplicate if it's the same code
+        assert!(set.insert("let x = a  ?  b : c;".to_string()));
+        assert!(!set.insert("let x = a ? b : c;".to_string()));
+        assert_eq!(set.len(), 2);
+    }
+
+    #[test]
+    fn without_normalizer() {
+        let mut set = CanonicalSet::new(None);
+        assert!(set.is_empty());
+        assert!(set.insert("b".to_string()));
+        assert!(set.insert("a".to_string()));
+        assert!(set.insert(" a".to_string()));
+        assert!(!set.insert("b".to_string()));
+        assert_eq!(set.into_iter().collect::<Vec<_>>(), ["b", "a", " a"]);
+    }
+}
@@ -0,0 +1,788 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::debug;
+
+/// The comment that opens the declaration summary that gets prepended to prompts.
+pub const CONTEXT_HEADER: &str = "// --- declarations from imports ---";
+/// The comment that closes the declaration summary that gets prepended to prompts.
+pub const CONTEXT_FOOTER: &str = "// --- end of declarations from imports ---";
+
+/// How many levels of `export * from "..."` and `export { .. } from "..."` we follow
+/// when collecting the exports of a module.
+const MAX_REEXPORT_DEPTH: usize = 3;
+
+/// A declaration that is imported by the file we are completing.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ImportedDecl {
+    /// The local name of the import in the file. For namespace imports (e.g.
+    /// `import * as fs from "fs"`), all the declarations share the namespace name.
+    pub local: String,
+    /// The declaration signature, without any function bodies.
+    pub decl: String,
+}
+
+/// All the declarations imported from a single module specifier.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ImportSection {
+    /// The module specifier, as it is written in the import statement.
+    pub specifier: String,
+    pub decls: Vec<ImportedDecl>,
+}
+
+/// Import-aware context for prompts. Holds the signatures of everything that the
+/// file imports from sibling modules, `node_modules/@types` or bundled `.d.ts` files,
+/// such that the model can see the types that the type checker sees.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ImportContext {
+    pub sections: Vec<ImportSection>,
+}
+
+impl ImportContext {
+    /// Builds the import context for the given file. `file_path` is used to resolve
+    /// relative imports and to find the `node_modules` directory, `code` is the contents
+    /// of the file. Imports that cannot be resolved or parsed are skipped.
+    ///
+    /// Requires the `tsparser` feature; without it, the context is always empty.
+    pub fn build(file_path: &Path, code: &str) -> Self {
+        #[cfg(feature = "tsparser")]
+        {
+            let dir = file_path.parent().unwrap_or_else(|| Path::new("."));
+            swc_impl::build_context(dir, code)
+        }
+        #[cfg(not(feature = "tsparser"))]
+        {
+            let _ = (file_path, code);
+            Self::default()
+        }
+    }
+
+    /// Returns true if there are no imported declarations.
+    pub fn is_empty(&self) -> bool {
+        self.sections.iter().all(|s| s.decls.is_empty())
+    }
+
+    /// Produces the declaration summary of all the imports that are referenced in the
+    /// given code. Returns an empty string if nothing relevant is imported.
+    pub fn summary_for(&self, code: &str) -> String {
+        let mut body = String::new();
+        for section in &self.sections {
+            let mut decls: Vec<&str> = vec![];
+            for d in section.decls.iter().filter(|d| mentions(code, &d.local)) {
+                // the same declaration may be imported under multiple names
+                if !decls.contains(&d.decl.as_str()) {
+                    decls.push(&d.decl);
+                }
+            }
+            if decls.is_empty() {
+                continue;
+            }
+            body.push_str(&format!("// from \"{}\":\n", section.specifier));
+            for d in decls {
+                body.push_str(d);
+                body.push('\n');
+            }
+        }
+
+        if body.is_empty() {
+            return body;
+        }
+        format!("{CONTEXT_HEADER}\n{body}{CONTEXT_FOOTER}")
+    }
+
+    /// Prepends the declaration summary that is relevant for the given prompt to it.
+    pub fn prepend_to(&self, prompt: &str) -> String {
+        let summary = self.summary_for(prompt);
+        if summary.is_empty() {
+            prompt.to_string()
+        } else {
+            format!("{summary}\n{prompt}")
+        }
+    }
+
+    /// Removes the declaration summary that `prepend_to` added to the given prompt from the
+    /// code that the prompt was completed with. The completion has to start with the same
+    /// summary as the prompt, otherwise the model changed it, and None is returned. The
+    /// completions of prompts without a summary are returned as they are.
+    pub fn strip(prompt: &str, code: &str) -> Option<String> {
+        match summary_of(prompt) {
+            Some(summary) => code.strip_prefix(summary).map(str::to_string),
+            None => Some(code.to_string()),
+        }
+    }
+}
+
+/// The declaration summary that starts the given prompt, with the newline after it.
+fn summary_of(prompt: &str) -> Option<&str> {
+    if !prompt.starts_with(CONTEXT_HEADER) {
+        return None;
+    }
+    let footer = format!("\n{CONTEXT_FOOTER}\n");
+    let end = prompt.find(&footer)? + footer.len();
+    Some(&prompt[..end])
+}
+
+/// Checks if the given identifier occurs in the code as a whole word.
+fn mentions(code: &str, ident: &str) -> bool {
+    let is_ident_char = |c: char| c.is_alphanumeric() || c == '_' || c == '$';
+    code.match_indices(ident).any(|(i, _)| {
+        let before = code[..i].chars().next_back();
+        let after = code[i + ident.len()..].chars().next();
+        !before.is_some_and(is_ident_char) && !after.is_some_and(is_ident_char)
+    })
+}
+
+/// Resolves the given module specifier, imported from a file in `from_dir`, to a
+/// file on disk. Relative specifiers are resolved against sibling files, bare
+/// specifiers against `node_modules` (bundled types first, then `@types`).
+pub fn resolve_module(from_dir: &Path, specifier: &str) -> Option<PathBuf> {
+    if specifier.starts_with('.') || specifier.starts_with('/') {
+        return resolve_file(&from_dir.join(specifier));
+    }
+
+    // split into package name and subpath, handling scoped packages
+    let mut parts = specifier.splitn(if specifier.starts_with('@') { 3 } else { 2 }, '/');
+    let pkg = if specifier.starts_with('@') {
+        format!("{}/{}", parts.next()?, parts.next()?)
+    } else {
+        parts.next()?.to_string()
+    };
+    let subpath = parts.next();
+
+    for dir in from_dir.ancestors() {
+        let node_modules = dir.join("node_modules");
+        if !node_modules.is_dir() {
+            continue;
+        }
+
+        let pkg_dir = node_modules.join(&pkg);
+        if let Some(sub) = subpath {
+            if let Some(p) = resolve_file(&pkg_dir.join(sub)) {
+                return Some(p);
+            }
+        } else if let Some(p) = package_types(&pkg_dir) {
+            return Some(p);
+        }
+
+        // @types/@scope/pkg is mangled into @types/scope__pkg
+        let types_name = pkg.trim_start_matches('@').replace('/', "__");
+        let types_dir = node_modules.join("@types").join(types_name);
+        let types_path = match subpath {
+            Some(sub) => resolve_file(&types_dir.join(sub)),
+            None => package_types(&types_dir),
+        };
+        if types_path.is_some() {
+            return types_path;
+        }
+    }
+    None
+}
+
+/// Resolves the `types` (or `typings`) entry of the package in `pkg_dir`, falling back
+/// to `index.d.ts`.
+fn package_types(pkg_dir: &Path) -> Option<PathBuf> {
+    if !pkg_dir.is_dir() {
+        return None;
+    }
+    if let Ok(manifest) = std::fs::read_to_string(pkg_dir.join("package.json")) {
+        if let Ok(manifest) = serde_json::from_str::<serde_json::Value>(&manifest) {
+            for field in ["types", "typings"] {
+                if let Some(types) = manifest[field].as_str() {
+                    if let Some(p) = resolve_file(&pkg_dir.join(types)) {
+                        return Some(p);
+                    }
+                }
+            }
+        }
+    }
+    resolve_file(&pkg_dir.join("index"))
+}
+
+/// Resolves a path without an extension (or with a `.js` one) to a typed file, preferring
+/// TypeScript sources and declaration files over JavaScript.
+fn resolve_file(base: &Path) -> Option<PathBuf> {
+    let base_str = base.to_str()?;
+    let stem = base_str
+        .strip_suffix(".js")
+        .or_else(|| base_str.strip_suffix(".mjs"))
+        .or_else(|| base_str.strip_suffix(".cjs"))
+        .unwrap_or(base_str);
+
+    let candidates = [
+        format!("{stem}.ts"),
+        format!("{stem}.tsx"),
+        format!("{stem}.d.ts"),
+        base_str.to_string(),
+        format!("{stem}.js"),
+        format!("{stem}/index.ts"),
+        format!("{stem}/index.d.ts"),
+        format!("{stem}/index.js"),
+    ];
+    candidates
+        .into_iter()
+        .map(PathBuf::from)
+        .find(|p| p.is_file())
+}
+
+/// The exports of a module, mapping each exported name (`default` for the default
+/// export) to its declaration signatures. Overloads and merged declarations produce
+/// more than one signature.
+type ModuleExports = BTreeMap<String, Vec<String>>;
+
+#[cfg(feature = "tsparser")]
+mod swc_impl {
+    use std::{collections::BTreeMap, path::Path};
+
+    use swc_common::{sync::Lrc, BytePos, FileName, SourceMap, Spanned};
+    use swc_ecma_ast::{
+        Class, ClassMember, Decl, DefaultDecl, Expr, ImportSpecifier, Module, ModuleDecl,
+        ModuleExportName, ModuleItem, Pat, Stmt, TsModuleName, TsModuleRef, TsNamespaceBody,
+        VarDeclKind,
+    };
+    use swc_ecma_parser::{lexer::Lexer, Parser, StringInput, Syntax, TsConfig};
+
+    use super::{
+        debug, resolve_module, ImportContext, ImportSection, ImportedDecl, ModuleExports,
+        MAX_REEXPORT_DEPTH,
+    };
+
+    /// What a single import statement pulls out of a module.
+    enum Imported {
+        /// `import { orig as local } from "..."` or `import local from "..."` (orig is `default`)
+        Named { orig: String, local: String },
+        /// `import * as local from "..."` or `import local = require("...")`
+        Namespace { local: String },
+    }
+
+    /// Source text of a parsed file, used to slice declarations out by their spans.
+    struct Src {
+        text: String,
+        start: BytePos,
+    }
+
+    impl Src {
+        fn slice(&self, lo: BytePos, hi: BytePos) -> &str {
+            let lo = (lo.0 - self.start.0) as usize;
+            let hi = (hi.0 - self.start.0) as usize;
+            &self.text[lo..hi]
+        }
+
+        /// Finds the byte position of the first `c` at or after `from`.
+        fn find_from(&self, from: BytePos, c: char) -> Option<BytePos> {
+            let off = (from.0 - self.start.0) as usize;
+            self.text[off..].find(c).map(|i| BytePos(from.0 + i as u32))
+        }
+    }
+
+    fn parse(code: &str, dts: bool) -> Option<(Module, Src)> {
+        let cm: Lrc<SourceMap> = Default::default();
+        let fm = cm.new_source_file(FileName::Anon, code.to_string());
+        let lexer = Lexer::new(
+            Syntax::Typescript(TsConfig {
+                dts,
+                ..Default::default()
+            }),
+            Default::default(),
+            StringInput::from(&*fm),
+            None,
+        );
+        let mut parser = Parser::new_from(lexer);
+        let module = parser.parse_module().ok()?;
+        Some((
+            module,
+            Src {
+                text: fm.src.to_string(),
+                start: fm.start_pos,
+            },
+        ))
+    }
+
+    pub(super) fn build_context(dir: &Path, code: &str) -> ImportContext {
+        let Some((module, _)) = parse(code, false) else {
+            debug!("import context: could not parse the input file");
+            return ImportContext::default();
+        };
+
+        // group the imports by specifier, keeping the order of the file
+        let mut imports: Vec<(String, Vec<Imported>)> = vec![];
+        let mut add =
+            |spec: &str, imported: Imported| match imports.iter_mut().find(|(s, _)| s == spec) {
+                Some((_, v)) => v.push(imported),
+                None => imports.push((spec.to_string(), vec![imported])),
+            };
+
+        for item in &module.body {
+            match item {
+                ModuleItem::ModuleDecl(ModuleDecl::Import(import)) => {
+                    for spec in &import.specifiers {
+                        let imported = match spec {
+                            ImportSpecifier::Named(n) => Imported::Named {
+                                orig: n
+                                    .imported
+                                    .as_ref()
+                                    .map(export_name)
+                                    .unwrap_or_else(|| n.local.sym.to_string()),
+                                local: n.local.sym.to_string(),
+                            },
+                            ImportSpecifier::Default(d) => Imported::Named {
+                                orig: "default".to_string(),
+                                local: d.local.sym.to_string(),
+                            },
+                            ImportSpecifier::Namespace(ns) => Imported::Namespace {
+                                local: ns.local.sym.to_string(),
+                            },
+                        };
+                        add(&import.src.value, imported);
+                    }
+                }
+                ModuleItem::ModuleDecl(ModuleDecl::TsImportEquals(import)) => {
+                    if let TsModuleRef::TsExternalModuleRef(r) = &import.module_ref {
+                        add(
+                            &r.expr.value,
+                            Imported::Namespace {
+                                local: import.id.sym.to_string(),
+                            },
+                        );
+                    }
+                }
+                // const x = require("..."), const { a, b } = require("...")
+                ModuleItem::Stmt(Stmt::Decl(Decl::Var(var))) => {
+                    for d in &var.decls {
+                        let Some(spec) = d.init.as_deref().and_then(require_specifier) else {
+                            continue;
+                        };
+                        match &d.name {
+                            Pat::Ident(id) => add(
+                                &spec,
+                                Imported::Namespace {
+                                    local: id.id.sym.to_string(),
+                                },
+                            ),
+                            Pat::Object(obj) => {
+                                for prop in &obj.props {
+                                    use swc_ecma_ast::{ObjectPatProp, PropName};
+                                    match prop {
+                                        ObjectPatProp::Assign(a) => add(
+                                            &spec,
+                                            Imported::Named {
+                                                orig: a.key.sym.to_string(),
+                                                local: a.key.sym.to_string(),
+                                            },
+                                        ),
+                                        ObjectPatProp::KeyValue(kv) => {
+                                            if let (PropName::Ident(k), Pat::Ident(v)) =
+                                                (&kv.key, &*kv.value)
+                                            {
+                                                add(
+                                                    &spec,
+                                                    Imported::Named {
+                                                        orig: k.sym.to_string(),
+                                                        local: v.id.sym.to_string(),
+                                                    },
+                                                )
+                                            }
+                                        }
+                                        ObjectPatProp::Rest(_) => {}
+                                    }
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let mut sections = vec![];
+        for (specifier, imported) in imports {
+            let Some(exports) = module_exports(dir, &specifier, 0) else {
+                debug!("import context: could not resolve {specifier}");
+                continue;
+            };
+
+            let mut decls = vec![];
+            for imp in imported {
+                match imp {
+                    Imported::Named { orig, local } => {
+                        for decl in exports.get(&orig).into_iter().flatten() {
+                            decls.push(ImportedDecl {
+                                local: local.clone(),
+                                decl: decl.clone(),
+                            });
+                        }
+                    }
+                    Imported::Namespace { local } => {
+                        for decl in exports.values().flatten() {
+                            decls.push(ImportedDecl {
+                                local: local.clone(),
+                                decl: decl.clone(),
+                            });
+                        }
+                    }
+                }
+            }
+            if !decls.is_empty() {
+                sections.push(ImportSection { specifier, decls });
+            }
+        }
+
+        ImportContext { sections }
+    }
+
+    /// If the given expression is a `require("...")` call, returns the specifier.
+    fn require_specifier(expr: &Expr) -> Option<String> {
+        use swc_ecma_ast::{Callee, Lit};
+        let Expr::Call(call) = expr else { return None };
+        let Callee::Expr(callee) = &call.callee else {
+            return None;
+        };
+        let Expr::Ident(id) = &**callee else {
+            return None;
+        };
+        if &*id.sym != "require" || call.args.len() != 1 {
+            return None;
+        }
+        match &*call.args[0].expr {
+            Expr::Lit(Lit::Str(s)) => Some(s.value.to_string()),
+            _ => None,
+        }
+    }
+
+    fn export_name(name: &ModuleExportName) -> String {
+        match name {
+            ModuleExportName::Ident(i) => i.sym.to_string(),
+            ModuleExportName::Str(s) => s.value.to_string(),
+        }
+    }
+
+    /// Resolves and parses the given module, collecting its exports.
+    fn module_exports(dir: &Path, specifier: &str, depth: usize) -> Option<ModuleExports> {
+        let path = resolve_module(dir, specifier)?;
+        let code = std::fs::read_to_string(&path).ok()?;
+        let dts = path.to_str()?.ends_with(".d.ts");
+        let (module, src) = parse(&code, dts)?;
+        let module_dir = path.parent()?;
+        Some(collect_exports(
+            &module.body,
+            &src,
+            module_dir,
+            Some(specifier),
+            depth,
+        ))
+    }
+
+    /// Collects the exported declarations out of the given module items. `ambient` is
+    /// the specifier that we are looking for in `declare module "..." { .. }` blocks,
+    /// which is how many bundled `.d.ts` files declare their exports.
+    fn collect_exports(
+        items: &[ModuleItem],
+        src: &Src,
+        dir: &Path,
+        ambient: Option<&str>,
+        depth: usize,
+    ) -> ModuleExports {
+        let mut exports = ModuleExports::new();
+        // declarations that are not exported directly, but may be through an export list
+        let mut locals: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        // (exported name, local name) pairs from export lists and default exports
+        let mut aliases: Vec<(String, String)> = vec![];
+
+        for item in items {
+            match item {
+                ModuleItem::ModuleDecl(ModuleDecl::ExportDecl(export)) => {
+                    for (name, sig) in decl_signatures(&export.decl, export.span.lo, src) {
+                        exports.entry(name).or_default().push(sig);
+                    }
+                }
+                ModuleItem::ModuleDecl(ModuleDecl::ExportDefaultDecl(export)) => {
+                    let sig = match &export.decl {
+                        DefaultDecl::Fn(f) => match &f.function.body {
+                            Some(body) => fn_header(src, export.span.lo, body.span.lo),
+                            None => src.slice(export.span.lo, export.span.hi).to_string(),
+                        },
+                        DefaultDecl::Class(c) => class_signature(src, export.span.lo, &c.class),
+                        DefaultDecl::TsInterfaceDecl(_) => {
+                            src.slice(export.span.lo, export.span.hi).to_string()
+                        }
+                    };
+                    exports.entry("default".to_string()).or_default().push(sig);
+                }
+                ModuleItem::ModuleDecl(ModuleDecl::ExportDefaultExpr(export)) => {
+                    if let Expr::Ident(id) = &*export.expr {
+                        aliases.push(("default".to_string(), id.sym.to_string()));
+                    }
+                }
+                // `export = Foo` makes Foo both the default export and the namespace
+                ModuleItem::ModuleDecl(ModuleDecl::TsExportAssignment(export)) => {
+                    if let Expr::Ident(id) = &*export.expr {
+                        aliases.push(("default".to_string(), id.sym.to_string()));
+                        aliases.push((id.sym.to_string(), id.sym.to_string()));
+                    }
+                }
+                ModuleItem::ModuleDecl(ModuleDecl::ExportNamed(named)) => {
+                    let reexported = match &named.src {
+                        Some(s) if depth < MAX_REEXPORT_DEPTH => {
+                            module_exports(dir, &s.value, depth + 1)
+                        }
+                        Some(_) => continue,
+                        None => None,
+                    };
+                    for spec in &named.specifiers {
+                        use swc_ecma_ast::ExportSpecifier;
+                        let ExportSpecifier::Named(n) = spec else {
+                            continue;
+                        };
+                        let orig = export_name(&n.orig);
+                        let exported = n
+                            .exported
+                            .as_ref()
+                            .map(export_name)
+                            .unwrap_or_else(|| orig.clone());
+                        match &reexported {
+                            Some(re) => {
+                                if let Some(sigs) = re.get(&orig) {
+                                    exports.entry(exported).or_default().extend(sigs.clone());
+                                }
+                            }
+                            None => aliases.push((exported, orig)),
+                        }
+                    }
+                }
+                ModuleItem::ModuleDecl(ModuleDecl::ExportAll(all))
+                    if depth < MAX_REEXPORT_DEPTH =>
+                {
+                    if let Some(re) = module_exports(dir, &all.src.value, depth + 1) {
+                        for (name, sigs) in re {
+                            if name != "default" {
+                                exports.entry(name).or_default().extend(sigs);
+                            }
+                        }
+                    }
+                }
+                ModuleItem::Stmt(Stmt::Decl(Decl::TsModule(m))) => {
+                    // declare module "specifier" { ... }
+                    if let (TsModuleName::Str(name), Some(wanted)) = (&m.id, ambient) {
+                        if &*name.value == wanted {
+                            if let Some(TsNamespaceBody::TsModuleBlock(block)) = &m.body {
+                                let inner = collect_exports(&block.body, src, dir, None, depth);
+                                for (name, sigs) in inner {
+                                    exports.entry(name).or_default().extend(sigs);
+                                }
+                            }
+                            continue;
+                        }
+                    }
+                    let decl = Decl::TsModule(m.clone());
+                    for (name, sig) in decl_signatures(&decl, m.span.lo, src) {
+                        locals.entry(name).or_default().push(sig);
+                    }
+                }
+                ModuleItem::Stmt(Stmt::Decl(decl)) => {
+                    for (name, sig) in decl_signatures(decl, decl.span().lo, src) {
+                        locals.entry(name).or_default().push(sig);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        for (exported, local) in aliases {
+            if let Some(sigs) = locals.get(&local) {
+                exports.entry(exported).or_default().extend(sigs.clone());
+            }
+        }
+
+        exports
+    }
+
+    /// Produces `(name, signature)` pairs for the given declaration, where `start` is the
+    /// position where the signature should start (e.g. including the `export` keyword).
+    fn decl_signatures(decl: &Decl, start: BytePos, src: &Src) -> Vec<(String, String)> {
+        match decl {
+            Decl::Fn(f) => {
+                let sig = match &f.function.body {
+                    Some(body) => fn_header(src, start, body.span.lo),
+                    None => src.slice(start, f.function.span.hi).to_string(),
+                };
+                vec![(f.ident.sym.to_string(), sig)]
+            }
+            Decl::Class(c) => vec![(
+                c.ident.sym.to_string(),
+                class_signature(src, start, &c.class),
+            )],
+            Decl::Var(var) => {
+                let kind = match var.kind {
+                    VarDeclKind::Var => "var",
+                    VarDeclKind::Let => "let",
+                    VarDeclKind::Const => "const",
+                };
+                // keep the `export` / `declare` modifiers that come before the kind
+                let decl_start = var.decls.first().map(|d| d.span.lo).unwrap_or(var.span.lo);
+                let prefix = src.slice(start, decl_start).trim_end();
+                let prefix = prefix.strip_suffix(kind).unwrap_or(prefix);
+
+                var.decls
+                    .iter()
+                    .filter_map(|d| {
+                        let Pat::Ident(id) = &d.name else { return None };
+                        let name = id.id.sym.to_string();
+                        let sig = match d.init.as_deref() {
+                            Some(Expr::Arrow(arrow)) => format!(
+                                "{} ...",
+                                src.slice(d.span.lo, arrow.body.span().lo).trim_end()
+                            ),
+                            Some(Expr::Fn(f)) => match &f.function.body {
+                                Some(body) => format!(
+                                    "{} {{ ... }}",
+                                    src.slice(d.span.lo, body.span.lo).trim_end()
+                                ),
+                                None => name.clone(),
+                            },
+                            _ => match &id.type_ann {
+                                Some(ann) => src.slice(d.span.lo, ann.span.hi).to_string(),
+                                None => name.clone(),
+                            },
+                        };
+                        Some((name, format!("{prefix}{kind} {sig};")))
+                    })
+                    .collect()
+            }
+            Decl::TsInterface(i) => {
+                vec![(
+                    i.id.sym.to_string(),
+                    src.slice(start, i.span.hi).to_string(),
+                )]
+            }
+            Decl::TsTypeAlias(t) => {
+                vec![(
+                    t.id.sym.to_string(),
+                    src.slice(start, t.span.hi).to_string(),
+                )]
+            }
+            Decl::TsEnum(e) => vec![(
+                e.id.sym.to_string(),
+                src.slice(start, e.span.hi).to_string(),
+            )],
+            Decl::TsModule(m) => match &m.id {
+                TsModuleName::Ident(id) => {
+                    vec![(id.sym.to_string(), src.slice(start, m.span.hi).to_string())]
+                }
+                TsModuleName::Str(_) => vec![],
+            },
+        }
+    }
+
+    /// The header of a function with a body, e.g. `function f(a: number): string;`
+    fn fn_header(src: &Src, start: BytePos, body_start: BytePos) -> String {
+        format!("{};", src.slice(start, body_start).trim_end())
+    }
+
+    /// Produces the signature of a class, keeping the signatures of the public members.
+    fn class_signature(src: &Src, start: BytePos, class: &Class) -> String {
+        // the body starts at the first `{` after the class header
+        let mut header_end = start;
+        for span in class
+            .super_class
+            .iter()
+            .map(|s| s.span())
+            .chain(class.super_type_params.iter().map(|s| s.span))
+            .chain(class.type_params.iter().map(|s| s.span))
+            .chain(class.implements.iter().map(|s| s.span))
+        {
+            header_end = std::cmp::max(header_end, span.hi);
+        }
+        let Some(body_start) = src.find_from(header_end, '{') else {
+            return src.slice(start, class.span.hi).to_string();
+        };
+
+        let mut sig = format!("{} {{\n", src.slice(start, body_start).trim_end());
+        for member in &class.body {
+            use swc_ecma_ast::Accessibility;
+            let member_sig = match member {
+                ClassMember::Constructor(c) if c.accessibility != Some(Accessibility::Private) => {
+                    match &c.body {
+                        Some(body) => fn_header(src, c.span.lo, body.span.lo),
+                        None => src.slice(c.span.lo, c.span.hi).to_string(),
+                    }
+                }
+                ClassMember::Method(m) if m.accessibility != Some(Accessibility::Private) => {
+                    match &m.function.body {
+                        Some(body) => fn_header(src, m.span.lo, body.span.lo),
+                        None => src.slice(m.span.lo, m.span.hi).to_string(),
+                    }
+                }
+                ClassMember::ClassProp(p) if p.accessibility != Some(Accessibility::Private) => {
+                    let end = match (&p.type_ann, &p.value) {
+                        (Some(ann), _) => ann.span.hi,
+                        (None, _) => p.key.span().hi,
+                    };
+                    format!("{};", src.slice(p.span.lo, end).trim_end())
+                }
+                ClassMember::TsIndexSignature(s) => src.slice(s.span.lo, s.span.hi).to_string(),
+                _ => continue,
+            };
+            sig.push_str("    ");
+            sig.push_str(member_sig.trim_end_matches(';'));
+            sig.push_str(";\n");
+        }
+        sig.push('}');
+        sig
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> ImportContext {
+        ImportContext {
+            sections: vec![ImportSection {
+                specifier: "./util".to_string(),
+                decls: vec![ImportedDecl {
+                    local: "add".to_string(),
+                    decl: "declare function add(a: number, b: number): number;".to_string(),
+                }],
+            }],
+        }
+    }
+
+    #[test]
+    fn summary_of_prompt() {
+        let prompt = context().prepend_to("let x: _hole_ = add(1, 2);");
+        let summary = summary_of(&prompt).unwrap();
+        assert!(summary.starts_with(CONTEXT_HEADER));
+        assert!(summary.ends_with(&format!("{CONTEXT_FOOTER}\n")));
+        assert_eq!(&prompt[summary.len()..], "let x: _hole_ = add(1, 2);");
+
+        // nothing is imported that the code uses
+        assert_eq!(summary_of(&context().prepend_to("let x = 1;")), None);
+        // the summary has to be closed
+        assert_eq!(summary_of(&format!("{CONTEXT_HEADER}\nlet x = 1;")), None);
+    }
+
+    #[test]
+    fn strip() {
+        let prompt = context().prepend_to("let x: _hole_ = add(1, 2);");
+        let summary = summary_of(&prompt).unwrap();
+        let completed = format!("{summary}let x: number = add(1, 2);");
+        assert_eq!(
+            ImportContext::strip(&prompt, &completed).as_deref(),
+            Some("let x: number = add(1, 2);")
+        );
+
+        // the model changed the summary
+        let changed = completed.replace("b: number", "b: any");
+        assert_eq!(ImportContext::strip(&prompt, &changed), None);
+
+        // without a summary, the completion is left as it is
+        assert_eq!(
+            ImportContext::strip("let x: _hole_ = 1;", "let x: number = 1;").as_deref(),
+            Some("let x: number = 1;")
+        );
+    }
+}
@@ -1,4 +1,8 @@
-use std::{str::FromStr, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+    sync::Arc,
+};
 
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
@@ -6,11 +10,15 @@ use thiserror::Error;
 
 use crate::{socket::SocketError, tree::CodeBlockTree, typedef_gen::ObjectInfoMap};
 
+pub mod memo; // memoizes the pure commands of a language server
+#[cfg(feature = "tsparser")]
+pub mod native; // the native heuristic checker, wrapping the typescript server
+pub mod protocol; // the requests and responses of the language servers
 pub mod py; // the python server
 pub mod ts; // the typescript server
 
 /// The kinds of problems that can occur when running the heuristics on a completion.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum CheckProblem {
     /// The completion still has holes or undefined types.
     NotComplete,
@@ -18,6 +26,15 @@ pub enum CheckProblem {
     ChangedCode,
     /// The completion added/removed comments.
     ChangedComments,
+    /// The completion annotated something with `any`.
+    UsesAny,
+    /// The completion used a type that doesn't exist.
+    UsesUnknownIdentifier,
+    /// The completion used a type that almost anything is assignable to, such as `object`,
+    /// `{}`, `Object`, `Function` or `unknown`.
+    OverlyBroadType,
+    /// The completion declared new types (type aliases, interfaces or enums).
+    AddedTypeDeclaration,
 }
 
 impl CheckProblem {
@@ -27,8 +44,47 @@ impl CheckProblem {
             CheckProblem::NotComplete,
             CheckProblem::ChangedCode,
             CheckProblem::ChangedComments,
+            CheckProblem::UsesAny,
+            CheckProblem::UsesUnknownIdentifier,
+            CheckProblem::OverlyBroadType,
+            CheckProblem::AddedTypeDeclaration,
         ]
     }
+
+    /// Returns the problems that only say something about the quality of the types, and
+    /// not about whether the completion is valid. These are allowed by default, and only
+    /// affect the score through their weights.
+    pub fn quality() -> Vec<CheckProblem> {
+        vec![
+            CheckProblem::UsesAny,
+            CheckProblem::UsesUnknownIdentifier,
+            CheckProblem::OverlyBroadType,
+            CheckProblem::AddedTypeDeclaration,
+        ]
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CheckProblem::NotComplete => "NotComplete",
+            CheckProblem::ChangedCode => "ChangedCode",
+            CheckProblem::ChangedComments => "ChangedComments",
+            CheckProblem::UsesAny => "UsesAny",
+            CheckProblem::UsesUnknownIdentifier => "UsesUnknownIdentifier",
+            CheckProblem::OverlyBroadType => "OverlyBroadType",
+            CheckProblem::AddedTypeDeclaration => "AddedTypeDeclaration",
+        }
+    }
+}
+
+impl FromStr for CheckProblem {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        CheckProblem::all()
+            .into_iter()
+            .find(|p| p.as_str() == s)
+            .ok_or(())
+    }
 }
 
 impl<'a> Deserialize<'a> for CheckProblem {
@@ -37,19 +93,148 @@ impl<'a> Deserialize<'a> for CheckProblem {
         D: serde::Deserializer<'a>,
     {
         let s = String::deserialize(deserializer)?;
-        match s.as_str() {
-            "NotComplete" => Ok(CheckProblem::NotComplete),
-            "ChangedCode" => Ok(CheckProblem::ChangedCode),
-            "ChangedComments" => Ok(CheckProblem::ChangedComments),
-            _ => Err(serde::de::Error::custom(format!(
-                "invalid CheckProblem: {s}"
-            ))),
+        CheckProblem::from_str(&s)
+            .map_err(|_| serde::de::Error::custom(format!("invalid CheckProblem: {s}")))
+    }
+}
+
+impl Serialize for CheckProblem {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+/// A problem found by the heuristics in a completion. Problems found in a type annotation
+/// have the kind of statement that the annotation belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct FoundProblem {
+    pub problem: CheckProblem,
+    #[serde(default)]
+    pub annot: Option<AnnotateType>,
+}
+
+/// The problems that are allowed in completions. A problem is allowed if it's allowed
+/// everywhere, or if it was found in a kind of statement that allows it.
+///
+/// The format in JSON is:
+/// ```json
+/// {
+///     "all": ["UsesAny"],
+///     "per_type": { "VarDecl": ["OverlyBroadType"] }
+/// }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ProblemWhitelist {
+    #[serde(default)]
+    pub all: Vec<CheckProblem>,
+    #[serde(default)]
+    pub per_type: BTreeMap<AnnotateType, Vec<CheckProblem>>,
+}
+
+impl ProblemWhitelist {
+    /// Allows the quality problems everywhere, see `CheckProblem::quality`.
+    pub fn quality() -> Self {
+        CheckProblem::quality().into()
+    }
+
+    /// Returns true if the given problem is allowed.
+    pub fn allows(&self, found: &FoundProblem) -> bool {
+        self.all.contains(&found.problem)
+            || found
+                .annot
+                .and_then(|annot| self.per_type.get(&annot))
+                .is_some_and(|allowed| allowed.contains(&found.problem))
+    }
+
+    /// Additionally allows the given problems everywhere.
+    pub fn with(mut self, problems: &[CheckProblem]) -> Self {
+        for problem in problems {
+            if !self.all.contains(problem) {
+                self.all.push(*problem);
+            }
+        }
+        self
+    }
+
+    /// Parses a comma-separated list of problems, where each problem is allowed everywhere,
+    /// or only in the given kind of statement if written as `<problem>:<statement>`.
+    /// For example: "UsesAny,OverlyBroadType:VarDecl".
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut whitelist = Self::default();
+        for item in s.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+            let (problem, annot) = match item.split_once(':') {
+                Some((problem, annot)) => (problem, Some(annot)),
+                None => (item, None),
+            };
+            let problem = CheckProblem::from_str(problem)
+                .map_err(|_| format!("unknown check problem: {problem}"))?;
+            match annot {
+                Some(annot) => {
+                    let annot = AnnotateType::from_str(annot)
+                        .map_err(|_| format!("unknown statement type: {annot}"))?;
+                    whitelist.per_type.entry(annot).or_default().push(problem);
+                }
+                None => whitelist.all.push(problem),
+            }
+        }
+        Ok(whitelist)
+    }
+}
+
+impl From<Vec<CheckProblem>> for ProblemWhitelist {
+    fn from(all: Vec<CheckProblem>) -> Self {
+        Self {
+            all,
+            per_type: BTreeMap::new(),
         }
     }
 }
 
+/// The weight of each problem, which gets added to the heuristic score of a completion
+/// once per kind of statement the problem was found in. Problems without a weight don't
+/// affect the score.
+///
+/// The format in JSON is a map from the problem to its weight, e.g. `{"OverlyBroadType": 300}`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ProblemWeights {
+    pub weights: HashMap<CheckProblem, u16>,
+}
+
+impl ProblemWeights {
+    /// Adds the weights of the given problems to the given score, capped at 1000.
+    pub fn score(&self, problems: &[FoundProblem], score: u16) -> u16 {
+        let added: u32 = problems
+            .iter()
+            .filter_map(|p| self.weights.get(&p.problem))
+            .map(|w| *w as u32)
+            .sum();
+        std::cmp::min(score as u32 + added, 1000) as u16
+    }
+
+    /// Parses a comma-separated list of `<problem>=<weight>`, e.g. "UsesAny=100,OverlyBroadType=300".
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut weights = HashMap::new();
+        for item in s.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+            let (problem, weight) = item
+                .split_once('=')
+                .ok_or_else(|| format!("expected <problem>=<weight>, got: {item}"))?;
+            let problem = CheckProblem::from_str(problem)
+                .map_err(|_| format!("unknown check problem: {problem}"))?;
+            let weight = weight
+                .parse()
+                .map_err(|_| format!("invalid weight for {problem:?}: {weight}"))?;
+            weights.insert(problem, weight);
+        }
+        Ok(Self { weights })
+    }
+}
+
 /// The kinds of statements that can be annotated by the language server.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum AnnotateType {
     /// Variable declaration
     VarDecl,
@@ -153,11 +338,12 @@ pub trait LangServerCommands {
     async fn stub(&self, code: &str) -> Result<String, LangServerError>;
 
     /// checks if the given code is complete, comparing it to the original input.
+    /// returns the problems found, and the heuristic score of the types.
     async fn check_complete(
         &self,
         original: &str,
         completed: &str,
-    ) -> Result<(Vec<CheckProblem>, u16), LangServerError>;
+    ) -> Result<(Vec<FoundProblem>, u16), LangServerError>;
 
     /// performs a type weaving operation on the given `original` code, such that the types of the
     /// `nettle` code are transplanted into the `original` code. The `level` parameter specifies the
@@ -241,6 +427,25 @@ pub trait LangServer: LangServerCommands + std::fmt::Debug {
     /// number of errors otherwise.
     async fn type_check(&self, code: &str) -> Result<usize, LangServerError>;
 
+    /// type checks the given JavaScript code, which is typed by JSDoc comments. returns
+    /// the number of errors like `type_check`. Only the languages that have JavaScript as
+    /// a dialect support it.
+    async fn type_check_js(&self, _code: &str) -> Result<usize, LangServerError> {
+        Err(LangServerError::Unsupported("typecheckJs"))
+    }
+
+    /// checks the given declaration file against the JavaScript code it declares: the
+    /// declarations have to be valid, and the exports of the code have to match them.
+    /// returns the number of errors like `type_check`. Like `type_check_js`, only the
+    /// languages that have JavaScript as a dialect support it.
+    async fn check_declarations(
+        &self,
+        _declarations: &str,
+        _original: &str,
+    ) -> Result<usize, LangServerError> {
+        Err(LangServerError::Unsupported("checkDts"))
+    }
+
     /// produces the Any type for the given language.
     /// for example, in TypeScript, this would be `any`.
     fn any_type(&self) -> String;
@@ -249,59 +454,37 @@ pub trait LangServer: LangServerCommands + std::fmt::Debug {
     /// The target function may require to enable features of the crate. If
     /// the feature is disabled or the language does not support it, None is returned.
     fn get_type_parser(&self) -> Option<Box<dyn Fn(&str) -> Option<String> + Sync + Send>>;
-}
 
-pub type ArcLangServer = Arc<dyn LangServer + Send + Sync>;
-
-/// Request to the language server with a given command and text
-/// in the format of {cmd: "the-cmd", text: "the-text"}
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct LSReq {
-    pub cmd: String,
-    pub text: String,
-}
+    /// Produces a function that maps code to a canonical form, used to deduplicate
+    /// completions that only differ in the way their types are written. Like the type
+    /// parser, None is returned if the feature is disabled or the language does not
+    /// support it.
+    fn get_normalizer(&self) -> Option<Normalizer>;
+
+    /// Produces a function that splits code into parts that fit in the given number of
+    /// tokens, as counted by the given function, at the boundaries of its statements and blocks, such that every part is
+    /// valid code that can be woven back into the whole. None is returned if the feature
+    /// is disabled or the language does not support it.
+    fn get_splitter(&self) -> Option<Splitter> {
+        None
+    }
 
-/// Request to the language server for the printer command.
-/// in the format of {cmd: "the-cmd", text: "the-text", typeName: "the-type-name"}
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct LSPrintReq {
-    pub cmd: String,
-    pub text: String,
-    #[serde(rename = "typeName")]
-    pub type_name: String,
-    pub types: Vec<AnnotateType>,
+    /// The commands that the language server does not support, by their name in the
+    /// protocol (e.g. "tree"). Calling them returns `LangServerError::Unsupported`.
+    fn unsupported_commands(&self) -> Vec<&'static str> {
+        vec![]
+    }
 }
 
-/// Request to the language server for the check command.
-/// in the format of {cmd: "the-cmd", text: "the-completed-text", original: "the-original-text"}
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct LSCheckReq {
-    pub cmd: String,
-    pub text: String,
-    pub original: String,
-}
+/// Maps code to its canonical form, or None if the code could not be normalized.
+pub type Normalizer = Box<dyn Fn(&str) -> Option<String> + Sync + Send>;
 
-/// Request to the language server for the weave command.
-/// in the format of {cmd: "the-cmd", text: "the-original-text",
-///                   nettle: "the-nettle-text", level: 0}
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct LSWeaveReq {
-    pub cmd: String,
-    pub text: String,
-    pub nettle: String,
-    pub level: usize,
-}
+/// Splits code into parts of at most the given number of tokens, as counted by the given
+/// function, or None if the code could not be split.
+pub type Splitter =
+    Box<dyn Fn(&str, usize, &dyn Fn(&str) -> usize) -> Option<Vec<String>> + Sync + Send>;
 
-/// Request to the language server, for the usages command.
-/// in the format of {cmd: "the-cmd", text: "the-outer-block",
-///                   innerBlock: "the-inner-block"}
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct LSUsagesReq {
-    pub cmd: String,
-    pub text: String, // NOTE: this is outer_block
-    #[serde(rename = "innerBlock")]
-    pub inner_block: String,
-}
+pub type ArcLangServer = Arc<dyn LangServer + Send + Sync>;
 
 #[derive(Debug, Clone, Error)]
 pub enum LangServerError {
@@ -311,6 +494,12 @@ pub enum LangServerError {
     ProcessSpawn,
     #[error("Socket IO error")]
     SocketIO,
+    #[error("The language server does not support the {0} command")]
+    Unsupported(&'static str),
+    #[error("Malformed response to the {cmd} command: {reason}")]
+    MalformedResponse { cmd: &'static str, reason: String },
+    #[error("The language server speaks protocol version {found}, expected {expected}")]
+    ProtocolVersion { expected: u32, found: u32 },
 }
 
 impl From<SocketError> for LangServerError {
@@ -325,7 +514,7 @@ impl From<SocketError> for LangServerError {
 /// Implements the LangServerCommands trait for a given language server.
 ///
 /// # IMPORTANT
-/// The language server must have a `socket` field of type `SocketAbstraction`.
+/// The language server must have a `socket` field of type `LSSocket`.
 #[macro_export]
 macro_rules! impl_langserver_commands {
     ($name:ident) => {
@@ -337,63 +526,32 @@ macro_rules! impl_langserver_commands {
                 type_name: &str,
                 types: &[$crate::langserver::AnnotateType],
             ) -> Result<String, $crate::langserver::LangServerError> {
-                let req = $crate::langserver::LSPrintReq {
-                    cmd: "print".to_string(),
-                    text: base64::encode(code),
+                let req = $crate::langserver::protocol::LSPrintReq {
+                    text: code.to_string(),
                     type_name: type_name.to_string(),
                     types: types.to_vec(),
                 };
-
-                use $crate::socket::SendToSocket;
-                let resp = self
-                    .socket
-                    .send_req(serde_json::to_value(&req).unwrap())
-                    .await?;
-                // decode the response
-                let resp = base64::decode(resp["text"].as_str().unwrap()).unwrap();
-
-                Ok(String::from_utf8(resp).unwrap())
+                Ok(self.socket.send(&req).await?.text)
             }
 
             async fn to_tree(
                 &self,
                 code: &str,
             ) -> Result<$crate::tree::CodeBlockTree, $crate::langserver::LangServerError> {
-                let req = $crate::langserver::LSReq {
-                    cmd: "tree".to_string(),
-                    text: base64::encode(code),
+                let req = $crate::langserver::protocol::LSTreeReq {
+                    text: code.to_string(),
                 };
-
-                use $crate::socket::SendToSocket;
-                let resp = self
-                    .socket
-                    .send_req(serde_json::to_value(&req).unwrap())
-                    .await?;
-
-                // decode the response
-                let tree = base64::decode(resp["text"].as_str().unwrap()).unwrap();
-
-                Ok(serde_json::from_slice(&tree).unwrap())
+                Ok(self.socket.send(&req).await?.text)
             }
 
             async fn stub(
                 &self,
                 code: &str,
             ) -> Result<String, $crate::langserver::LangServerError> {
-                let req = $crate::langserver::LSReq {
-                    cmd: "stub".to_string(),
-                    text: base64::encode(code),
+                let req = $crate::langserver::protocol::LSStubReq {
+                    text: code.to_string(),
                 };
-
-                use $crate::socket::SendToSocket;
-                let resp = self
-                    .socket
-                    .send_req(serde_json::to_value(&req).unwrap())
-                    .await?;
-                // decode the response
-                let resp = base64::decode(resp["text"].as_str().unwrap()).unwrap();
-
-                Ok(String::from_utf8(resp).unwrap())
+                Ok(self.socket.send(&req).await?.text)
             }
 
             async fn check_complete(
@@ -401,31 +559,28 @@ macro_rules! impl_langserver_commands {
                 original: &str,
                 completed: &str,
             ) -> Result<
-                (Vec<$crate::langserver::CheckProblem>, u16),
+                (Vec<$crate::langserver::FoundProblem>, u16),
                 $crate::langserver::LangServerError,
             > {
-                // encode original and completed into json: {original: "", completed: ""}
-                let req = $crate::langserver::LSCheckReq {
-                    cmd: "check".to_string(),
-                    text: base64::encode(completed),
-                    original: base64::encode(original),
+                let req = $crate::langserver::protocol::LSCheckReq {
+                    text: completed.to_string(),
+                    original: original.to_string(),
                 };
-                use $crate::socket::SendToSocket;
-                let resp = self
-                    .socket
-                    .send_req(serde_json::to_value(&req).unwrap())
-                    .await?;
-
-                let problems_json = resp["problems"].as_array().unwrap();
-                let mut problems = Vec::new();
-                for p in problems_json {
-                    problems.push(serde_json::from_value(p.clone()).unwrap());
+                let resp = self.socket.send(&req).await?;
+
+                // the problems found in annotations, along with the kind of statement,
+                // and the rest of the problems, which are about the whole completion
+                let mut problems = resp.annot_problems;
+                for problem in resp.problems {
+                    if !problems.iter().any(|f| f.problem == problem) {
+                        problems.push($crate::langserver::FoundProblem {
+                            problem,
+                            annot: None,
+                        });
+                    }
                 }
 
-                Ok((
-                    problems,
-                    resp["score"].as_u64().unwrap().try_into().unwrap(),
-                ))
+                Ok((problems, resp.score))
             }
 
             async fn weave(
@@ -434,22 +589,12 @@ macro_rules! impl_langserver_commands {
                 nettle: &str,
                 level: usize,
             ) -> Result<String, $crate::langserver::LangServerError> {
-                let req = $crate::langserver::LSWeaveReq {
-                    cmd: "weave".to_string(),
-                    text: base64::encode(original),
-                    nettle: base64::encode(nettle),
+                let req = $crate::langserver::protocol::LSWeaveReq {
+                    text: original.to_string(),
+                    nettle: nettle.to_string(),
                     level,
                 };
-
-                use $crate::socket::SendToSocket;
-                let resp = self
-                    .socket
-                    .send_req(serde_json::to_value(&req).unwrap())
-                    .await?;
-                // decode the response
-                let resp = base64::decode(resp["text"].as_str().unwrap()).unwrap();
-
-                Ok(String::from_utf8(resp).unwrap())
+                Ok(self.socket.send(&req).await?.text)
             }
 
             async fn usages(
@@ -457,25 +602,12 @@ macro_rules! impl_langserver_commands {
                 outer_block: &str,
                 inner_block: &str,
             ) -> Result<(String, usize), $crate::langserver::LangServerError> {
-                let req = $crate::langserver::LSUsagesReq {
-                    cmd: "usages".to_string(),
-                    text: base64::encode(outer_block),
-                    inner_block: base64::encode(inner_block),
+                let req = $crate::langserver::protocol::LSUsagesReq {
+                    text: outer_block.to_string(),
+                    inner_block: inner_block.to_string(),
                 };
-
-                use $crate::socket::SendToSocket;
-                let resp = self
-                    .socket
-                    .send_req(serde_json::to_value(&req).unwrap())
-                    .await?;
-                // decode the response
-                let num_usages = resp["numUsages"].as_u64().unwrap();
-                let resp = base64::decode(resp["text"].as_str().unwrap()).unwrap();
-
-                Ok((
-                    String::from_utf8(resp).unwrap(),
-                    num_usages.try_into().unwrap(),
-                ))
+                let resp = self.socket.send(&req).await?;
+                Ok((resp.text, resp.num_usages))
             }
 
             async fn object_info(
@@ -483,40 +615,20 @@ macro_rules! impl_langserver_commands {
                 code: &str,
             ) -> Result<$crate::typedef_gen::ObjectInfoMap, $crate::langserver::LangServerError>
             {
-                let req = $crate::langserver::LSReq {
-                    cmd: "objectInfo".to_string(),
-                    text: base64::encode(code),
+                let req = $crate::langserver::protocol::LSObjectInfoReq {
+                    text: code.to_string(),
                 };
-
-                use $crate::socket::SendToSocket;
-                let resp = self
-                    .socket
-                    .send_req(serde_json::to_value(&req).unwrap())
-                    .await?;
-                // decode the response
-                let resp = base64::decode(resp["text"].as_str().unwrap()).unwrap();
-
-                Ok(serde_json::from_slice(&resp).unwrap())
+                Ok(self.socket.send(&req).await?.text)
             }
 
             async fn typedef_gen(
                 &self,
                 code: &str,
             ) -> Result<String, $crate::langserver::LangServerError> {
-                let req = $crate::langserver::LSReq {
-                    cmd: "typedefGen".to_string(),
-                    text: base64::encode(code),
+                let req = $crate::langserver::protocol::LSTypedefGenReq {
+                    text: code.to_string(),
                 };
-
-                use $crate::socket::SendToSocket;
-                let resp = self
-                    .socket
-                    .send_req(serde_json::to_value(&req).unwrap())
-                    .await?;
-                // decode the response
-                let resp = base64::decode(resp["text"].as_str().unwrap()).unwrap();
-
-                Ok(String::from_utf8(resp).unwrap())
+                Ok(self.socket.send(&req).await?.text)
             }
         }
     };
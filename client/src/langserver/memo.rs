@@ -0,0 +1,270 @@
+use std::{
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use async_trait::async_trait;
+
+use crate::{lru::Lru, tree::CodeBlockTree, typedef_gen::ObjectInfoMap};
+
+use super::{
+    ts::TsServer, AnnotateType, ArcLangServer, FoundProblem, LangServer, LangServerCommands,
+    LangServerError, Normalizer, Splitter,
+};
+
+/// A language server that memoizes the results of the pure commands of the wrapped
+/// language server: `pretty_print`, `stub`, `check_complete`, `weave` and `usages`. The
+/// tree strategy calls these many times with the same arguments, for example when it
+/// re-weaves the same child into the same parent.
+///
+/// Each command has its own table, keyed by a 128-bit hash of the arguments, which holds at
+/// most `capacity` results and evicts the least recently used one when it is full. Only
+/// successful results are memoized, so errors are retried.
+#[derive(Debug)]
+pub struct MemoServer {
+    inner: ArcLangServer,
+    pretty_print: Memo<String>,
+    stub: Memo<String>,
+    check_complete: Memo<(Vec<FoundProblem>, u16)>,
+    weave: Memo<String>,
+    usages: Memo<(String, usize)>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl MemoServer {
+    /// The default number of results memoized per command.
+    pub const DEFAULT_CAPACITY: usize = 1000;
+
+    /// Wraps the given language server, memoizing at most `capacity` results per command.
+    pub fn new(inner: ArcLangServer, capacity: usize) -> Self {
+        Self {
+            inner,
+            pretty_print: Memo::new(capacity),
+            stub: Memo::new(capacity),
+            check_complete: Memo::new(capacity),
+            weave: Memo::new(capacity),
+            usages: Memo::new(capacity),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// The number of calls that were served from the tables, and the number of calls that
+    /// went to the wrapped language server.
+    pub fn stats(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Gets the result for the given arguments from the given table, otherwise calls the
+    /// wrapped language server with `call` and memoizes its result.
+    async fn memoized<V, F>(
+        &self,
+        memo: &Memo<V>,
+        args: impl Hash,
+        call: F,
+    ) -> Result<V, LangServerError>
+    where
+        V: Clone,
+        F: std::future::Future<Output = Result<V, LangServerError>>,
+    {
+        let key = memo_key(args);
+        if let Some(value) = memo.get(key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(value);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let value = call.await?;
+        memo.insert(key, value.clone());
+        Ok(value)
+    }
+}
+
+#[async_trait]
+impl LangServer for MemoServer {
+    /// Makes a memoized TypeScript language server, use `new` to wrap other servers.
+    async fn make(server_path: &str) -> Result<Self, LangServerError> {
+        let inner = TsServer::make(server_path).await?;
+        Ok(Self::new(Arc::new(inner), Self::DEFAULT_CAPACITY))
+    }
+
+    async fn type_check(&self, code: &str) -> Result<usize, LangServerError> {
+        self.inner.type_check(code).await
+    }
+
+    async fn type_check_js(&self, code: &str) -> Result<usize, LangServerError> {
+        self.inner.type_check_js(code).await
+    }
+
+    async fn check_declarations(
+        &self,
+        declarations: &str,
+        original: &str,
+    ) -> Result<usize, LangServerError> {
+        self.inner.check_declarations(declarations, original).await
+    }
+
+    fn any_type(&self) -> String {
+        self.inner.any_type()
+    }
+
+    fn get_type_parser(&self) -> Option<Box<dyn Fn(&str) -> Option<String> + Sync + Send>> {
+        self.inner.get_type_parser()
+    }
+
+    fn get_normalizer(&self) -> Option<Normalizer> {
+        self.inner.get_normalizer()
+    }
+
+    fn get_splitter(&self) -> Option<Splitter> {
+        self.inner.get_splitter()
+    }
+
+    fn unsupported_commands(&self) -> Vec<&'static str> {
+        self.inner.unsupported_commands()
+    }
+}
+
+#[async_trait]
+impl LangServerCommands for MemoServer {
+    async fn pretty_print(
+        &self,
+        code: &str,
+        type_name: &str,
+        types: &[AnnotateType],
+    ) -> Result<String, LangServerError> {
+        self.memoized(
+            &self.pretty_print,
+            (code, type_name, types),
+            self.inner.pretty_print(code, type_name, types),
+        )
+        .await
+    }
+
+    async fn to_tree(&self, code: &str) -> Result<CodeBlockTree, LangServerError> {
+        self.inner.to_tree(code).await
+    }
+
+    async fn stub(&self, code: &str) -> Result<String, LangServerError> {
+        self.memoized(&self.stub, code, self.inner.stub(code)).await
+    }
+
+    async fn check_complete(
+        &self,
+        original: &str,
+        completed: &str,
+    ) -> Result<(Vec<FoundProblem>, u16), LangServerError> {
+        self.memoized(
+            &self.check_complete,
+            (original, completed),
+            self.inner.check_complete(original, completed),
+        )
+        .await
+    }
+
+    async fn weave(
+        &self,
+        original: &str,
+        nettle: &str,
+        level: usize,
+    ) -> Result<String, LangServerError> {
+        self.memoized(
+            &self.weave,
+            (original, nettle, level),
+            self.inner.weave(original, nettle, level),
+        )
+        .await
+    }
+
+    async fn usages(
+        &self,
+        outer_block: &str,
+        inner_block: &str,
+    ) -> Result<(String, usize), LangServerError> {
+        self.memoized(
+            &self.usages,
+            (outer_block, inner_block),
+            self.inner.usages(outer_block, inner_block),
+        )
+        .await
+    }
+
+    async fn object_info(&self, code: &str) -> Result<ObjectInfoMap, LangServerError> {
+        self.inner.object_info(code).await
+    }
+
+    async fn typedef_gen(&self, code: &str) -> Result<String, LangServerError> {
+        self.inner.typedef_gen(code).await
+    }
+}
+
+/// A table of memoized results, evicting the least recently used one when it is full.
+/// The lock is only held to look up or insert a result, never while calling the language
+/// server, so concurrent calls don't wait on each other.
+#[derive(Debug)]
+struct Memo<V> {
+    table: Mutex<Lru<u128, V>>,
+}
+
+impl<V: Clone> Memo<V> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            table: Mutex::new(Lru::new(capacity)),
+        }
+    }
+
+    fn get(&self, key: u128) -> Option<V> {
+        let mut table = self.table.lock().unwrap_or_else(|e| e.into_inner());
+        table.get(&key).cloned()
+    }
+
+    fn insert(&self, key: u128, value: V) {
+        let mut table = self.table.lock().unwrap_or_else(|e| e.into_inner());
+        table.insert(key, value);
+    }
+}
+
+/// The key of the given arguments in a table. The arguments can be large files, so the
+/// tables hold their 128-bit hash instead of a copy of them; with 128 bits, two different
+/// arguments practically never share a key.
+fn memo_key(args: impl Hash) -> u128 {
+    let mut hasher = Fnv128(0x6c62272e07bb014262b821756295c58d);
+    args.hash(&mut hasher);
+    hasher.0
+}
+
+/// The 128-bit FNV-1a hash. `Hash` separates the fields of tuples and slices, for example
+/// strings end with a 0xff byte, so ("ab", "c") and ("a", "bc") hash differently.
+struct Fnv128(u128);
+
+impl Hasher for Fnv128 {
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = (self.0 ^ b as u128).wrapping_mul(0x0000000001000000000000000000013b);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0 as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys() {
+        // the empty input hashes to the offset basis
+        assert_eq!(memo_key(()), 0x6c62272e07bb014262b821756295c58d);
+        assert_eq!(memo_key(("ab", "c")), memo_key(("ab", "c")));
+        assert_ne!(memo_key(("ab", "c")), memo_key(("a", "bc")));
+        assert_ne!(memo_key(("a", "b", 1usize)), memo_key(("a", "b", 2usize)));
+    }
+}
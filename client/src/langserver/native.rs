@@ -0,0 +1,580 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use swc_common::{comments::SingleThreadedComments, sync::Lrc, BytePos, FileName, SourceMap, Span};
+use swc_ecma_ast::{
+    ArrowExpr, ClassMethod, ClassProp, Constructor, Decl, Expr, FnDecl, FnExpr, Function, Module,
+    ParamOrTsParamProp, Pat, PrivateMethod, PrivateProp, TsEntityName, TsEnumDecl,
+    TsFnOrConstructorType, TsFnParam, TsFnType, TsInterfaceDecl, TsKeywordTypeKind,
+    TsParamPropParam, TsPropertySignature, TsType, TsTypeAliasDecl, TsTypeAnn, TsTypeElement,
+    TsUnionOrIntersectionType,
+};
+use swc_ecma_parser::{lexer::Lexer, Parser, StringInput, Syntax};
+use swc_ecma_visit::{Visit, VisitWith};
+
+use crate::{tree::CodeBlockTree, typedef_gen::ObjectInfoMap};
+
+use super::{
+    ts::TsServer, AnnotateType, ArcLangServer, CheckProblem, FoundProblem, LangServer,
+    LangServerCommands, LangServerError, Normalizer, Splitter,
+};
+
+/// A language server that runs the completeness and quality heuristic (`check_complete`)
+/// natively on the swc AST of the code, instead of sending it to the TypeScript
+/// compiler. Every other command goes to the wrapped language server, and so does
+/// `check_complete` when swc can't parse the code.
+///
+/// The heuristic mirrors the one of the language server, but only looks at the syntax
+/// of the types: type aliases are not resolved, and `UsesUnknownIdentifier` is never
+/// reported, as that needs a type checker.
+#[derive(Debug)]
+pub struct NativeCheckServer {
+    inner: ArcLangServer,
+}
+
+impl NativeCheckServer {
+    /// Wraps the given TypeScript language server.
+    pub fn new(inner: ArcLangServer) -> Self {
+        Self { inner }
+    }
+}
+
+#[async_trait]
+impl LangServer for NativeCheckServer {
+    async fn make(server_path: &str) -> Result<Self, LangServerError> {
+        let inner = TsServer::make(server_path).await?;
+        Ok(Self::new(Arc::new(inner)))
+    }
+
+    async fn type_check(&self, code: &str) -> Result<usize, LangServerError> {
+        self.inner.type_check(code).await
+    }
+
+    async fn type_check_js(&self, code: &str) -> Result<usize, LangServerError> {
+        self.inner.type_check_js(code).await
+    }
+
+    async fn check_declarations(
+        &self,
+        declarations: &str,
+        original: &str,
+    ) -> Result<usize, LangServerError> {
+        self.inner.check_declarations(declarations, original).await
+    }
+
+    fn any_type(&self) -> String {
+        self.inner.any_type()
+    }
+
+    fn get_type_parser(&self) -> Option<Box<dyn Fn(&str) -> Option<String> + Sync + Send>> {
+        self.inner.get_type_parser()
+    }
+
+    fn get_normalizer(&self) -> Option<Normalizer> {
+        self.inner.get_normalizer()
+    }
+
+    fn get_splitter(&self) -> Option<Splitter> {
+        self.inner.get_splitter()
+    }
+
+    fn unsupported_commands(&self) -> Vec<&'static str> {
+        self.inner.unsupported_commands()
+    }
+}
+
+#[async_trait]
+impl LangServerCommands for NativeCheckServer {
+    async fn pretty_print(
+        &self,
+        code: &str,
+        type_name: &str,
+        types: &[AnnotateType],
+    ) -> Result<String, LangServerError> {
+        self.inner.pretty_print(code, type_name, types).await
+    }
+
+    async fn to_tree(&self, code: &str) -> Result<CodeBlockTree, LangServerError> {
+        self.inner.to_tree(code).await
+    }
+
+    async fn stub(&self, code: &str) -> Result<String, LangServerError> {
+        self.inner.stub(code).await
+    }
+
+    async fn check_complete(
+        &self,
+        original: &str,
+        completed: &str,
+    ) -> Result<(Vec<FoundProblem>, u16), LangServerError> {
+        match native_check(original, completed) {
+            Some(res) => Ok(res),
+            None => self.inner.check_complete(original, completed).await,
+        }
+    }
+
+    async fn weave(
+        &self,
+        original: &str,
+        nettle: &str,
+        level: usize,
+    ) -> Result<String, LangServerError> {
+        self.inner.weave(original, nettle, level).await
+    }
+
+    async fn usages(
+        &self,
+        outer_block: &str,
+        inner_block: &str,
+    ) -> Result<(String, usize), LangServerError> {
+        self.inner.usages(outer_block, inner_block).await
+    }
+
+    async fn object_info(&self, code: &str) -> Result<ObjectInfoMap, LangServerError> {
+        self.inner.object_info(code).await
+    }
+
+    async fn typedef_gen(&self, code: &str) -> Result<String, LangServerError> {
+        self.inner.typedef_gen(code).await
+    }
+}
+
+/// Runs the heuristic on the completed code, against the original code. Returns None if
+/// either of them doesn't parse.
+pub fn native_check(original: &str, completed: &str) -> Option<(Vec<FoundProblem>, u16)> {
+    let original = Parsed::parse(original)?;
+    let completed = Parsed::parse(completed)?;
+
+    let mut scorer = Scorer {
+        src: &completed.src,
+        is_completed: true,
+        raw_score: 0.0,
+        num_type_nodes: 0,
+        annot_problems: vec![],
+    };
+    completed.module.visit_with(&mut scorer);
+
+    let mut problems = vec![];
+    if !scorer.is_completed {
+        problems.push(CheckProblem::NotComplete);
+    }
+    if original.num_comments != completed.num_comments {
+        problems.push(CheckProblem::ChangedComments);
+    }
+    if type_decl_count(&completed.module) > type_decl_count(&original.module) {
+        problems.push(CheckProblem::AddedTypeDeclaration);
+    }
+    if original.untyped_tokens() != completed.untyped_tokens() {
+        problems.push(CheckProblem::ChangedCode);
+    }
+
+    let score = scorer.score();
+    // the problems found in annotations are problems of the whole completion too
+    let mut found = scorer.annot_problems;
+    for problem in problems {
+        if !found.iter().any(|f| f.problem == problem) {
+            found.push(FoundProblem {
+                problem,
+                annot: None,
+            });
+        }
+    }
+
+    Some((found, score))
+}
+
+/// Code parsed by swc, along with its tokens and the number of comments in it.
+struct Parsed {
+    module: Module,
+    src: Src,
+    tokens: Vec<Span>,
+    num_comments: usize,
+}
+
+impl Parsed {
+    fn parse(code: &str) -> Option<Self> {
+        let cm: Lrc<SourceMap> = Default::default();
+        let fm = cm.new_source_file(FileName::Anon, code.to_string());
+
+        let comments = SingleThreadedComments::default();
+        let mut parser = Parser::new(
+            Syntax::Typescript(Default::default()),
+            StringInput::from(&*fm),
+            Some(&comments),
+        );
+        let module = parser.parse_module().ok()?;
+        if !parser.take_errors().is_empty() {
+            return None;
+        }
+        let (leading, trailing) = comments.take_all();
+        let num_comments = leading.borrow().values().map(Vec::len).sum::<usize>()
+            + trailing.borrow().values().map(Vec::len).sum::<usize>();
+
+        let lexer = Lexer::new(
+            Syntax::Typescript(Default::default()),
+            Default::default(),
+            StringInput::from(&*fm),
+            None,
+        );
+        let tokens = lexer.map(|tok| tok.span).collect();
+
+        Some(Self {
+            module,
+            src: Src {
+                text: fm.src.to_string(),
+                start: fm.start_pos,
+            },
+            tokens,
+            num_comments,
+        })
+    }
+
+    /// The tokens of the code, without the ones of the type annotations.
+    fn untyped_tokens(&self) -> Vec<&str> {
+        let mut collector = TypeAnnCollector { spans: vec![] };
+        self.module.visit_with(&mut collector);
+        self.tokens
+            .iter()
+            .filter(|tok| {
+                !collector
+                    .spans
+                    .iter()
+                    .any(|ann| ann.lo <= tok.lo && tok.hi <= ann.hi)
+            })
+            .map(|tok| self.src.slice(*tok))
+            .collect()
+    }
+}
+
+/// Source text of the parsed code, used to slice nodes out by their spans.
+struct Src {
+    text: String,
+    start: BytePos,
+}
+
+impl Src {
+    fn slice(&self, span: Span) -> &str {
+        let lo = (span.lo.0 - self.start.0) as usize;
+        let hi = (span.hi.0 - self.start.0) as usize;
+        &self.text[lo..hi]
+    }
+}
+
+/// Collects the spans of the outermost type annotations, including their colon.
+struct TypeAnnCollector {
+    spans: Vec<Span>,
+}
+
+impl Visit for TypeAnnCollector {
+    fn visit_ts_type_ann(&mut self, ann: &TsTypeAnn) {
+        self.spans.push(ann.span);
+    }
+}
+
+/// Counts the type declarations (type aliases, interfaces and enums) of the module.
+fn type_decl_count(module: &Module) -> usize {
+    struct Counter(usize);
+    impl Visit for Counter {
+        fn visit_ts_type_alias_decl(&mut self, decl: &TsTypeAliasDecl) {
+            self.0 += 1;
+            decl.visit_children_with(self);
+        }
+
+        fn visit_ts_interface_decl(&mut self, decl: &TsInterfaceDecl) {
+            self.0 += 1;
+            decl.visit_children_with(self);
+        }
+
+        fn visit_ts_enum_decl(&mut self, decl: &TsEnumDecl) {
+            self.0 += 1;
+            decl.visit_children_with(self);
+        }
+    }
+
+    let mut counter = Counter(0);
+    module.visit_with(&mut counter);
+    counter.0
+}
+
+/// Returns the type annotation of the given pattern, if any.
+pub(crate) fn pat_type_ann(pat: &Pat) -> Option<&TsTypeAnn> {
+    match pat {
+        Pat::Ident(id) => id.type_ann.as_deref(),
+        Pat::Array(arr) => arr.type_ann.as_deref(),
+        Pat::Object(obj) => obj.type_ann.as_deref(),
+        Pat::Rest(rest) => rest.type_ann.as_deref(),
+        Pat::Assign(assign) => assign
+            .type_ann
+            .as_deref()
+            .or_else(|| pat_type_ann(&assign.left)),
+        _ => None,
+    }
+}
+
+/// Returns the type annotation of the given function type parameter, if any.
+pub(crate) fn fn_param_type_ann(param: &TsFnParam) -> Option<&TsTypeAnn> {
+    match param {
+        TsFnParam::Ident(id) => id.type_ann.as_deref(),
+        TsFnParam::Array(arr) => arr.type_ann.as_deref(),
+        TsFnParam::Rest(rest) => rest.type_ann.as_deref(),
+        TsFnParam::Object(obj) => obj.type_ann.as_deref(),
+    }
+}
+
+/// Removes the parentheses around the given type.
+fn unparen(ty: &TsType) -> &TsType {
+    match ty {
+        TsType::TsParenthesizedType(p) => unparen(&p.type_ann),
+        _ => ty,
+    }
+}
+
+/// Walks the type annotations of the completed code, like `typeTraversal` in the
+/// TypeScript compiler, and scores them.
+struct Scorer<'a> {
+    src: &'a Src,
+    is_completed: bool,
+    // the sum of the penalties of the types, normalized by the number of type nodes
+    raw_score: f64,
+    // the number of type nodes (only leaf nodes) encountered in the completed code
+    num_type_nodes: usize,
+    annot_problems: Vec<FoundProblem>,
+}
+
+impl Scorer<'_> {
+    /// Computes the final score. Can't be higher than 1000, and can't be lower than 0.
+    fn score(&self) -> u16 {
+        if self.num_type_nodes == 0 {
+            return 0;
+        }
+        let score = (self.raw_score / self.num_type_nodes as f64 * 1000.0).round();
+        score.clamp(0.0, 1000.0) as u16
+    }
+
+    fn report(&mut self, problem: CheckProblem, kind: AnnotateType) {
+        let found = FoundProblem {
+            problem,
+            annot: Some(kind),
+        };
+        if !self.annot_problems.contains(&found) {
+            self.annot_problems.push(found);
+        }
+    }
+
+    /// Scores the given annotation site. `bound_fn` is whether the site is a variable
+    /// bound to a function, which doesn't need a type.
+    fn annot(&mut self, ann: Option<&TsTypeAnn>, kind: AnnotateType, bound_fn: bool) {
+        match ann {
+            // means that the model removed the type
+            None => {
+                if !bound_fn {
+                    self.raw_score += 0.5;
+                    self.num_type_nodes += 1;
+                }
+            }
+            // we check holes by string, like the language server does
+            Some(ann) if self.src.slice(ann.span).contains("_hole_") => {
+                self.is_completed = false;
+            }
+            Some(ann) => self.check_type(&ann.type_ann, kind),
+        }
+    }
+
+    fn fn_annots(&mut self, func: &Function, kind: AnnotateType) {
+        self.annot(func.return_type.as_deref(), kind, false);
+        for param in &func.params {
+            self.annot(pat_type_ann(&param.pat), kind, false);
+        }
+    }
+
+    fn check_type(&mut self, ty: &TsType, kind: AnnotateType) {
+        match unparen(ty) {
+            TsType::TsKeywordType(kw) => match kw.kind {
+                TsKeywordTypeKind::TsAnyKeyword => {
+                    self.raw_score += 0.5;
+                    self.num_type_nodes += 1;
+                    self.report(CheckProblem::UsesAny, kind);
+                }
+                TsKeywordTypeKind::TsUnknownKeyword => {
+                    self.raw_score += 1.0;
+                    self.num_type_nodes += 1;
+                    self.report(CheckProblem::OverlyBroadType, kind);
+                }
+                TsKeywordTypeKind::TsObjectKeyword => {
+                    self.num_type_nodes += 1;
+                    self.report(CheckProblem::OverlyBroadType, kind);
+                }
+                TsKeywordTypeKind::TsUndefinedKeyword | TsKeywordTypeKind::TsNullKeyword => {
+                    self.raw_score += 0.2;
+                    self.num_type_nodes += 1;
+                }
+                _ => self.num_type_nodes += 1,
+            },
+            TsType::TsTypeRef(r) => {
+                match &r.type_name {
+                    // the catch all interfaces for objects and functions
+                    TsEntityName::Ident(id) if &*id.sym == "Function" => {
+                        self.raw_score += 0.5;
+                        self.report(CheckProblem::OverlyBroadType, kind);
+                    }
+                    TsEntityName::Ident(id) if &*id.sym == "Object" => {
+                        self.report(CheckProblem::OverlyBroadType, kind);
+                    }
+                    _ => {
+                        for param in r.type_params.iter().flat_map(|p| p.params.iter()) {
+                            self.check_type(param, kind);
+                        }
+                    }
+                }
+                self.num_type_nodes += 1;
+            }
+            // arrays and tuples are references to generic types
+            TsType::TsArrayType(arr) => {
+                self.check_type(&arr.elem_type, kind);
+                self.num_type_nodes += 1;
+            }
+            TsType::TsTupleType(tuple) => {
+                for elem in &tuple.elem_types {
+                    self.check_type(&elem.ty, kind);
+                }
+                self.num_type_nodes += 1;
+            }
+            TsType::TsUnionOrIntersectionType(TsUnionOrIntersectionType::TsUnionType(u)) => {
+                for member in &u.types {
+                    self.check_type(member, kind);
+                }
+            }
+            TsType::TsTypeLit(lit) => {
+                let call = lit.members.iter().find_map(|m| match m {
+                    TsTypeElement::TsCallSignatureDecl(call) => Some(call),
+                    _ => None,
+                });
+                if let Some(call) = call {
+                    if let Some(ret) = &call.type_ann {
+                        self.check_type(&ret.type_ann, kind);
+                    }
+                    for param in &call.params {
+                        self.check_param_type(fn_param_type_ann(param), kind);
+                    }
+                } else if lit.members.is_empty() {
+                    // the `{}` type, anything but null and undefined is assignable to it
+                    self.num_type_nodes += 1;
+                    self.report(CheckProblem::OverlyBroadType, kind);
+                }
+            }
+            TsType::TsFnOrConstructorType(TsFnOrConstructorType::TsFnType(func)) => {
+                self.check_type(&func.type_ann.type_ann, kind);
+                for param in &func.params {
+                    self.check_param_type(fn_param_type_ann(param), kind);
+                }
+            }
+            // constructor types have no call signatures, they are not scored
+            TsType::TsFnOrConstructorType(TsFnOrConstructorType::TsConstructorType(_)) => {}
+            // NOTE: in our abstract interpretation, this is considered a leaf node
+            _ => self.num_type_nodes += 1,
+        }
+    }
+
+    /// Scores the type of a parameter of a function type, where a missing type is `any`.
+    fn check_param_type(&mut self, ann: Option<&TsTypeAnn>, kind: AnnotateType) {
+        match ann {
+            Some(ann) => self.check_type(&ann.type_ann, kind),
+            None => {
+                self.raw_score += 0.5;
+                self.num_type_nodes += 1;
+                self.report(CheckProblem::UsesAny, kind);
+            }
+        }
+    }
+}
+
+impl Visit for Scorer<'_> {
+    fn visit_fn_decl(&mut self, decl: &FnDecl) {
+        self.fn_annots(&decl.function, AnnotateType::FuncDecl);
+        decl.visit_children_with(self);
+    }
+
+    fn visit_fn_expr(&mut self, expr: &FnExpr) {
+        self.fn_annots(&expr.function, AnnotateType::FuncExpr);
+        expr.visit_children_with(self);
+    }
+
+    fn visit_arrow_expr(&mut self, expr: &ArrowExpr) {
+        let kind = AnnotateType::FuncExpr;
+        self.annot(expr.return_type.as_deref(), kind, false);
+        for param in &expr.params {
+            self.annot(pat_type_ann(param), kind, false);
+        }
+        expr.visit_children_with(self);
+    }
+
+    fn visit_class_method(&mut self, method: &ClassMethod) {
+        self.fn_annots(&method.function, AnnotateType::ClassMethod);
+        method.visit_children_with(self);
+    }
+
+    fn visit_private_method(&mut self, method: &PrivateMethod) {
+        self.fn_annots(&method.function, AnnotateType::ClassMethod);
+        method.visit_children_with(self);
+    }
+
+    fn visit_constructor(&mut self, cons: &Constructor) {
+        // no need for return type for constructors
+        for param in &cons.params {
+            let ann = match param {
+                ParamOrTsParamProp::Param(param) => pat_type_ann(&param.pat),
+                ParamOrTsParamProp::TsParamProp(prop) => match &prop.param {
+                    TsParamPropParam::Ident(id) => id.type_ann.as_deref(),
+                    TsParamPropParam::Assign(assign) => assign
+                        .type_ann
+                        .as_deref()
+                        .or_else(|| pat_type_ann(&assign.left)),
+                },
+            };
+            self.annot(ann, AnnotateType::FuncDecl, false);
+        }
+        cons.visit_children_with(self);
+    }
+
+    fn visit_class_prop(&mut self, prop: &ClassProp) {
+        self.annot(prop.type_ann.as_deref(), AnnotateType::ClassProp, false);
+        prop.visit_children_with(self);
+    }
+
+    fn visit_private_prop(&mut self, prop: &PrivateProp) {
+        self.annot(prop.type_ann.as_deref(), AnnotateType::ClassProp, false);
+        prop.visit_children_with(self);
+    }
+
+    // only variable statements are annotated, not the variables of for loops
+    fn visit_decl(&mut self, decl: &Decl) {
+        if let Decl::Var(var) = decl {
+            for declarator in &var.decls {
+                let bound_fn = matches!(
+                    declarator.init.as_deref(),
+                    Some(Expr::Arrow(_) | Expr::Fn(_))
+                );
+                self.annot(
+                    pat_type_ann(&declarator.name),
+                    AnnotateType::VarDecl,
+                    bound_fn,
+                );
+            }
+        }
+        decl.visit_children_with(self);
+    }
+
+    fn visit_ts_property_signature(&mut self, prop: &TsPropertySignature) {
+        self.annot(prop.type_ann.as_deref(), AnnotateType::TypeDecl, false);
+        prop.visit_children_with(self);
+    }
+
+    fn visit_ts_fn_type(&mut self, func: &TsFnType) {
+        let kind = AnnotateType::FuncExpr;
+        for param in &func.params {
+            self.annot(fn_param_type_ann(param), kind, false);
+        }
+        self.annot(Some(&func.type_ann), kind, false);
+        func.visit_children_with(self);
+    }
+}
@@ -0,0 +1,351 @@
+//! The protocol between the client and the language servers.
+//!
+//! The client connects to the server's unix socket once per request, and sends a JSON
+//! object followed by `??END??`. Every request has the command in `cmd` and the code it
+//! is about, base64 encoded, in `text`. The server answers with a JSON object with a
+//! `type` field, which is `error` if the command failed, with the reason in `message`.
+//! Any code in the response is base64 encoded as well.
+//!
+//! Before sending any other command, the client sends `hello` with its protocol version.
+//! The server answers with its own version and the commands it supports, out of
+//! `COMMANDS`. The versions have to be equal, and the commands that the server doesn't
+//! support are never sent to it. Servers that are supervised with health checks also
+//! answer `{cmd: "ping", text: ""}` with `{type: "pong"}`, see `socket::Supervision`.
+//!
+//! Each command has a request struct here, which implements `LSRequest` and gives the
+//! fields of the request and the type of the response.
+
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    socket::{SendToSocket, SocketAbstraction, Supervision},
+    tree::CodeBlockTree,
+    typedef_gen::ObjectInfoMap,
+};
+
+use super::{AnnotateType, CheckProblem, FoundProblem, LangServerError};
+
+/// The version of the protocol, which changes when a command changes in a way that
+/// breaks servers or clients of the previous version.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The commands of the protocol, other than `hello` and `ping`.
+pub const COMMANDS: &[&str] = &[
+    "print",
+    "tree",
+    "stub",
+    "check",
+    "weave",
+    "usages",
+    "objectInfo",
+    "typedefGen",
+    "typecheck",
+    "checkDts",
+];
+
+/// A request to the language server, which is serialized into the fields of the request,
+/// without `cmd`.
+pub trait LSRequest: Serialize + Send + Sync {
+    /// The command of the request.
+    const CMD: &'static str;
+    /// The response to the request.
+    type Response: DeserializeOwned;
+}
+
+fn encode<S: Serializer>(text: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&base64::encode(text))
+}
+
+fn decode<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    let bytes = base64::decode(text).map_err(serde::de::Error::custom)?;
+    String::from_utf8(bytes).map_err(serde::de::Error::custom)
+}
+
+fn decode_json<'de, D: Deserializer<'de>, T: DeserializeOwned>(
+    deserializer: D,
+) -> Result<T, D::Error> {
+    serde_json::from_str(&decode(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+/// Request for the handshake, which has to be the first request to the server.
+/// in the format of {cmd: "hello", text: "", version: 1}
+#[derive(Debug, Clone, Serialize)]
+pub struct LSHelloReq {
+    #[serde(serialize_with = "encode")]
+    pub text: String,
+    pub version: u32,
+}
+
+impl LSRequest for LSHelloReq {
+    const CMD: &'static str = "hello";
+    type Response = LSHelloResp;
+}
+
+/// Response to the handshake, with the version of the server and the commands it supports.
+/// in the format of {type: "helloResponse", version: 1, commands: ["print", ...]}
+#[derive(Debug, Clone, Deserialize)]
+pub struct LSHelloResp {
+    pub version: u32,
+    pub commands: Vec<String>,
+}
+
+/// Response with code, for the commands that transform code.
+/// in the format of {type: "the-cmdResponse", text: "the-text"}
+#[derive(Debug, Clone, Deserialize)]
+pub struct LSTextResp {
+    #[serde(deserialize_with = "decode")]
+    pub text: String,
+}
+
+/// Response with a JSON value, encoded like code.
+/// in the format of {type: "the-cmdResponse", text: "the-json"}
+#[derive(Debug, Clone, Deserialize)]
+pub struct LSJsonResp<T: DeserializeOwned> {
+    #[serde(deserialize_with = "decode_json")]
+    pub text: T,
+}
+
+/// Response with the number of type errors in the code.
+/// in the format of {type: "the-cmdResponse", errors: 0}
+#[derive(Debug, Clone, Deserialize)]
+pub struct LSErrorsResp {
+    pub errors: usize,
+}
+
+/// Request to the language server for the printer command.
+/// in the format of {cmd: "print", text: "the-text", typeName: "the-type-name",
+///                   types: ["FuncDecl", ...]}
+#[derive(Debug, Clone, Serialize)]
+pub struct LSPrintReq {
+    #[serde(serialize_with = "encode")]
+    pub text: String,
+    #[serde(rename = "typeName")]
+    pub type_name: String,
+    pub types: Vec<AnnotateType>,
+}
+
+impl LSRequest for LSPrintReq {
+    const CMD: &'static str = "print";
+    type Response = LSTextResp;
+}
+
+/// Request to the language server for the tree command, which responds with the
+/// `CodeBlockTree` of the code.
+/// in the format of {cmd: "tree", text: "the-text"}
+#[derive(Debug, Clone, Serialize)]
+pub struct LSTreeReq {
+    #[serde(serialize_with = "encode")]
+    pub text: String,
+}
+
+impl LSRequest for LSTreeReq {
+    const CMD: &'static str = "tree";
+    type Response = LSJsonResp<CodeBlockTree>;
+}
+
+/// Request to the language server for the stub command.
+/// in the format of {cmd: "stub", text: "the-text"}
+#[derive(Debug, Clone, Serialize)]
+pub struct LSStubReq {
+    #[serde(serialize_with = "encode")]
+    pub text: String,
+}
+
+impl LSRequest for LSStubReq {
+    const CMD: &'static str = "stub";
+    type Response = LSTextResp;
+}
+
+/// Request to the language server for the check command.
+/// in the format of {cmd: "check", text: "the-completed-text", original: "the-original-text"}
+#[derive(Debug, Clone, Serialize)]
+pub struct LSCheckReq {
+    #[serde(serialize_with = "encode")]
+    pub text: String,
+    #[serde(serialize_with = "encode")]
+    pub original: String,
+}
+
+impl LSRequest for LSCheckReq {
+    const CMD: &'static str = "check";
+    type Response = LSCheckResp;
+}
+
+/// Response to the check command, with the problems about the whole completion, the
+/// problems found in annotations, along with the kind of statement, and the score.
+/// in the format of {type: "checkResponse", problems: ["NotComplete", ...],
+///                   annotProblems: [{problem: "UsesAny", annot: "VarDecl"}, ...], score: 0}
+#[derive(Debug, Clone, Deserialize)]
+pub struct LSCheckResp {
+    pub problems: Vec<CheckProblem>,
+    #[serde(rename = "annotProblems", default)]
+    pub annot_problems: Vec<FoundProblem>,
+    pub score: u16,
+}
+
+/// Request to the language server for the weave command.
+/// in the format of {cmd: "weave", text: "the-original-text",
+///                   nettle: "the-nettle-text", level: 0}
+#[derive(Debug, Clone, Serialize)]
+pub struct LSWeaveReq {
+    #[serde(serialize_with = "encode")]
+    pub text: String,
+    #[serde(serialize_with = "encode")]
+    pub nettle: String,
+    pub level: usize,
+}
+
+impl LSRequest for LSWeaveReq {
+    const CMD: &'static str = "weave";
+    type Response = LSTextResp;
+}
+
+/// Request to the language server, for the usages command.
+/// in the format of {cmd: "usages", text: "the-outer-block",
+///                   innerBlock: "the-inner-block"}
+#[derive(Debug, Clone, Serialize)]
+pub struct LSUsagesReq {
+    #[serde(serialize_with = "encode")]
+    pub text: String, // NOTE: this is outer_block
+    #[serde(rename = "innerBlock", serialize_with = "encode")]
+    pub inner_block: String,
+}
+
+impl LSRequest for LSUsagesReq {
+    const CMD: &'static str = "usages";
+    type Response = LSUsagesResp;
+}
+
+/// Response to the usages command, with the usages of the inner block and their number.
+/// in the format of {type: "usagesResponse", text: "the-usages", numUsages: 0}
+#[derive(Debug, Clone, Deserialize)]
+pub struct LSUsagesResp {
+    #[serde(deserialize_with = "decode")]
+    pub text: String,
+    #[serde(rename = "numUsages")]
+    pub num_usages: usize,
+}
+
+/// Request to the language server for the objectInfo command, which responds with the
+/// `ObjectInfoMap` of the code.
+/// in the format of {cmd: "objectInfo", text: "the-text"}
+#[derive(Debug, Clone, Serialize)]
+pub struct LSObjectInfoReq {
+    #[serde(serialize_with = "encode")]
+    pub text: String,
+}
+
+impl LSRequest for LSObjectInfoReq {
+    const CMD: &'static str = "objectInfo";
+    type Response = LSJsonResp<ObjectInfoMap>;
+}
+
+/// Request to the language server for the typedefGen command.
+/// in the format of {cmd: "typedefGen", text: "the-text"}
+#[derive(Debug, Clone, Serialize)]
+pub struct LSTypedefGenReq {
+    #[serde(serialize_with = "encode")]
+    pub text: String,
+}
+
+impl LSRequest for LSTypedefGenReq {
+    const CMD: &'static str = "typedefGen";
+    type Response = LSTextResp;
+}
+
+/// Request to the language server for the typecheck command.
+/// in the format of {cmd: "typecheck", text: "the-text", js: false}
+#[derive(Debug, Clone, Serialize)]
+pub struct LSTypeCheckReq {
+    #[serde(serialize_with = "encode")]
+    pub text: String,
+    pub js: bool,
+}
+
+impl LSRequest for LSTypeCheckReq {
+    const CMD: &'static str = "typecheck";
+    type Response = LSErrorsResp;
+}
+
+/// Request to the language server for the checkDts command.
+/// in the format of {cmd: "checkDts", text: "the-declarations", original: "the-javascript"}
+#[derive(Debug, Clone, Serialize)]
+pub struct LSCheckDtsReq {
+    #[serde(serialize_with = "encode")]
+    pub text: String,
+    #[serde(serialize_with = "encode")]
+    pub original: String,
+}
+
+impl LSRequest for LSCheckDtsReq {
+    const CMD: &'static str = "checkDts";
+    type Response = LSErrorsResp;
+}
+
+/// The socket of a language server that we did the handshake with.
+#[derive(Debug)]
+pub struct LSSocket {
+    socket: SocketAbstraction,
+    commands: Vec<String>,
+}
+
+impl LSSocket {
+    /// Does the handshake with the server behind the given socket. Fails if the server
+    /// speaks another version of the protocol.
+    pub async fn handshake(socket: SocketAbstraction) -> Result<Self, LangServerError> {
+        let req = LSHelloReq {
+            text: String::new(),
+            version: PROTOCOL_VERSION,
+        };
+        let resp = send(&socket, &req).await?;
+        if resp.version != PROTOCOL_VERSION {
+            return Err(LangServerError::ProtocolVersion {
+                expected: PROTOCOL_VERSION,
+                found: resp.version,
+            });
+        }
+        Ok(Self {
+            socket,
+            commands: resp.commands,
+        })
+    }
+
+    /// Sets how the server is supervised, see `SocketAbstraction::supervise`.
+    pub fn supervise(self, supervision: Supervision) -> Self {
+        Self {
+            socket: self.socket.supervise(supervision),
+            ..self
+        }
+    }
+
+    /// The commands of the protocol that the server doesn't support.
+    pub fn missing_commands(&self) -> Vec<&'static str> {
+        COMMANDS
+            .iter()
+            .filter(|cmd| !self.commands.iter().any(|c| c == *cmd))
+            .copied()
+            .collect()
+    }
+
+    /// Sends the given request to the server, if it supports the command.
+    pub async fn send<R: LSRequest>(&self, req: &R) -> Result<R::Response, LangServerError> {
+        if !self.commands.iter().any(|c| c == R::CMD) {
+            return Err(LangServerError::Unsupported(R::CMD));
+        }
+        send(&self.socket, req).await
+    }
+}
+
+async fn send<R: LSRequest>(
+    socket: &SocketAbstraction,
+    req: &R,
+) -> Result<R::Response, LangServerError> {
+    let mut value = serde_json::to_value(req).unwrap();
+    value["cmd"] = R::CMD.into();
+    let resp = socket.send_req(value).await?;
+    serde_json::from_value(resp).map_err(|e| LangServerError::MalformedResponse {
+        cmd: R::CMD,
+        reason: e.to_string(),
+    })
+}
@@ -1,60 +1,182 @@
+use std::{
+    path::Path,
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
 use async_trait::async_trait;
 
-use crate::{impl_langserver_commands, socket::SocketAbstraction};
+use crate::{
+    impl_langserver_commands,
+    socket::{SocketAbstraction, Supervision},
+    tree::CodeBlockTree,
+    typedef_gen::ObjectInfoMap,
+};
+
+use super::{
+    protocol::LSSocket, AnnotateType, FoundProblem, LangServer, LangServerCommands,
+    LangServerError, Normalizer,
+};
 
-use super::{LangServer, LangServerError};
+pub mod annotation;
 
+/// The Python language server. The `py-ast` server handles printing, stubbing and checking
+/// completions, and type checking runs a local type checker as a subprocess.
+///
+/// The server doesn't implement the `tree`, `weave`, `usages`, `objectInfo` and
+/// `typedefGen` commands, so only the `simple` strategy, without type definition
+/// generation, works with Python. The unsupported commands return
+/// `LangServerError::Unsupported`.
 #[derive(Debug)]
 pub struct PyServer {
-    socket: SocketAbstraction,
+    ast: PyAstServer,
+    type_checker: PyTypeChecker,
+}
+
+/// The connection to the `py-ast` server, which implements the commands that go over the
+/// socket.
+#[derive(Debug)]
+struct PyAstServer {
+    socket: LSSocket,
+}
+
+impl_langserver_commands!(PyAstServer);
+
+/// The type checkers that `PyServer` can run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PyTypeChecker {
+    Mypy,
+    Pyright,
+}
+
+impl FromStr for PyTypeChecker {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mypy" => Ok(PyTypeChecker::Mypy),
+            "pyright" => Ok(PyTypeChecker::Pyright),
+            _ => Err(format!("Unknown Python type checker: {s}")),
+        }
+    }
+}
+
+impl std::fmt::Display for PyTypeChecker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PyTypeChecker::Mypy => write!(f, "mypy"),
+            PyTypeChecker::Pyright => write!(f, "pyright"),
+        }
+    }
 }
 
-// NOTE: this is commented out because it's WIP. sorry for the mess!
+impl PyTypeChecker {
+    /// The command that type checks the given file.
+    fn command(&self, path: &Path) -> tokio::process::Command {
+        let mut cmd = match self {
+            PyTypeChecker::Mypy => {
+                let mut cmd = tokio::process::Command::new("mypy");
+                // many checks run at the same time, so they can't share a cache
+                cmd.args([
+                    "--ignore-missing-imports",
+                    "--no-error-summary",
+                    "--no-color-output",
+                    "--cache-dir=/dev/null",
+                ]);
+                cmd
+            }
+            PyTypeChecker::Pyright => {
+                let mut cmd = tokio::process::Command::new("pyright");
+                cmd.arg("--outputjson");
+                cmd
+            }
+        };
+        cmd.arg(path);
+        cmd
+    }
+
+    /// Counts the errors in the output of the type checker.
+    fn count_errors(&self, output: &std::process::Output) -> Result<usize, LangServerError> {
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        match self {
+            // exits with 1 if there are errors, and 2 if mypy itself failed
+            PyTypeChecker::Mypy => match output.status.code() {
+                Some(0 | 1) => Ok(stdout.lines().filter(|l| l.contains(": error:")).count()),
+                _ => Err(self.failed(output)),
+            },
+            // exits with 1 if there are errors, the errors are counted in the summary
+            PyTypeChecker::Pyright => match output.status.code() {
+                Some(0 | 1) => {
+                    let report: serde_json::Value =
+                        serde_json::from_str(&stdout).map_err(|_| self.failed(output))?;
+                    report["summary"]["errorCount"]
+                        .as_u64()
+                        .map(|n| n as usize)
+                        .ok_or_else(|| self.failed(output))
+                }
+                _ => Err(self.failed(output)),
+            },
+        }
+    }
+
+    fn failed(&self, output: &std::process::Output) -> LangServerError {
+        LangServerError::LC(format!(
+            "{self} failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+// makes the names of the files to type check unique within the process
+static TYPE_CHECK_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+impl PyServer {
+    /// Sets how the `py-ast` server is supervised. By default, the server is respawned when
+    /// a request fails to reach it, without health checks.
+    pub fn supervise(mut self, supervision: Supervision) -> Self {
+        self.ast.socket = self.ast.socket.supervise(supervision);
+        self
+    }
+
+    /// Sets the type checker to use, mypy by default.
+    pub fn type_checker(mut self, type_checker: PyTypeChecker) -> Self {
+        self.type_checker = type_checker;
+        self
+    }
+}
 
 #[async_trait]
 impl LangServer for PyServer {
-    async fn make(_path: &str) -> Result<Self, LangServerError> {
-        // let pid = std::process::id();
-        // let tmp_dir = std::env::temp_dir();
-        // let tmp_socket_file = tmp_dir.join(format!("codex-{}.sock", pid));
-        // println!("tmp_socket_file: {:?}", tmp_socket_file);
-
-        // todo!("python server call");
-        // let mut process = match tokio::process::Command::new("python")
-        // .args([])
-        // .stdout(Stdio::piped())
-        // // stderr is open by default, we want to see the output
-        // .spawn()
-        // {
-        // Ok(p) => p,
-        // Err(_) => return Err(LangServerError::ProcessSpawn),
-        // };
-
-        // // before allowing to connect, wait for the process to output "Listening"
-        // {
-        // let stdout = process.stdout.as_mut().unwrap();
-        // let reader = tokio::io::BufReader::new(stdout);
-        // let mut lines = reader.lines();
-        // println!("client output:");
-        // while let Some(line) = lines.next_line().await.unwrap() {
-        // println!("{}", line);
-        // if line.contains("Listening") {
-        // break;
-        // }
-        // }
-        // }
-        // println!("client ready to connect to socket!");
-        // let socket_path = tmp_socket_file.to_str().unwrap().to_string();
-        // let socket = SocketAbstraction {
-        // socket_path,
-        // process,
-        // };
-        // Ok(Self { socket })
-        todo!()
-    }
-
-    async fn type_check(&self, _code: &str) -> Result<usize, LangServerError> {
-        todo!()
+    async fn make(server_path: &str) -> Result<Self, LangServerError> {
+        let main = Path::new(server_path).join("main.py");
+        let args = ["python3", main.to_str().unwrap()];
+        let socket = SocketAbstraction::spawn_server("python", &args, true)
+            .await
+            .map_err(|_| LangServerError::ProcessSpawn)?;
+        let socket = LSSocket::handshake(socket).await?;
+        Ok(Self {
+            ast: PyAstServer { socket },
+            type_checker: PyTypeChecker::Mypy,
+        })
+    }
+
+    async fn type_check(&self, code: &str) -> Result<usize, LangServerError> {
+        let path = std::env::temp_dir().join(format!(
+            "opentau-typecheck-{}-{}.py",
+            std::process::id(),
+            TYPE_CHECK_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        tokio::fs::write(&path, code)
+            .await
+            .map_err(|e| LangServerError::LC(format!("Failed to write {path:?}: {e}")))?;
+        let output = self.type_checker.command(&path).output().await;
+        // the file is not needed anymore, even if the checker failed
+        let _ = tokio::fs::remove_file(&path).await;
+
+        let output = output.map_err(|e| {
+            LangServerError::LC(format!("Failed to run {}: {e}", self.type_checker))
+        })?;
+        self.type_checker.count_errors(&output)
     }
 
     fn any_type(&self) -> String {
@@ -62,8 +184,76 @@ impl LangServer for PyServer {
     }
 
     fn get_type_parser(&self) -> Option<Box<dyn Fn(&str) -> Option<String> + Sync + Send>> {
+        Some(Box::new(annotation::py_parse_type))
+    }
+
+    fn get_normalizer(&self) -> Option<Normalizer> {
         None
     }
+
+    fn unsupported_commands(&self) -> Vec<&'static str> {
+        // type checking runs locally, and never checks javascript or declarations
+        let mut commands: Vec<_> = self
+            .ast
+            .socket
+            .missing_commands()
+            .into_iter()
+            .filter(|cmd| !["typecheck", "checkDts"].contains(cmd))
+            .collect();
+        commands.extend(["typecheckJs", "checkDts"]);
+        commands
+    }
 }
 
-impl_langserver_commands!(PyServer);
+#[async_trait]
+impl LangServerCommands for PyServer {
+    async fn pretty_print(
+        &self,
+        code: &str,
+        type_name: &str,
+        types: &[AnnotateType],
+    ) -> Result<String, LangServerError> {
+        self.ast.pretty_print(code, type_name, types).await
+    }
+
+    async fn to_tree(&self, _code: &str) -> Result<CodeBlockTree, LangServerError> {
+        Err(LangServerError::Unsupported("tree"))
+    }
+
+    async fn stub(&self, code: &str) -> Result<String, LangServerError> {
+        self.ast.stub(code).await
+    }
+
+    async fn check_complete(
+        &self,
+        original: &str,
+        completed: &str,
+    ) -> Result<(Vec<FoundProblem>, u16), LangServerError> {
+        self.ast.check_complete(original, completed).await
+    }
+
+    async fn weave(
+        &self,
+        _original: &str,
+        _nettle: &str,
+        _level: usize,
+    ) -> Result<String, LangServerError> {
+        Err(LangServerError::Unsupported("weave"))
+    }
+
+    async fn usages(
+        &self,
+        _outer_block: &str,
+        _inner_block: &str,
+    ) -> Result<(String, usize), LangServerError> {
+        Err(LangServerError::Unsupported("usages"))
+    }
+
+    async fn object_info(&self, _code: &str) -> Result<ObjectInfoMap, LangServerError> {
+        Err(LangServerError::Unsupported("objectInfo"))
+    }
+
+    async fn typedef_gen(&self, _code: &str) -> Result<String, LangServerError> {
+        Err(LangServerError::Unsupported("typedefGen"))
+    }
+}
@@ -0,0 +1,336 @@
+/// Parses the given input and extracts the longest prefix that is a valid Python type
+/// annotation, or None if there is no such prefix. The annotations are the ones of the
+/// `typing` module and PEP 604, for example:
+/// - names and dotted names: `int`, `typing.List`, `None`
+/// - generics: `Dict[str, List[int]]`, `Tuple[int, ...]`, `Callable[[int], str]`,
+///   `Literal["a", -1]`
+/// - unions: `int | None`, also in parentheses
+/// - string annotations: `"Node"`, whose contents must be an annotation
+///
+/// The output of a model usually goes on after the annotation, for example
+/// `List[int]) -> str:`, so the rest of the input is ignored.
+pub fn py_parse_type(input: &str) -> Option<String> {
+    let input = input.trim();
+    let end = AnnotParser::new(input).annotation()?;
+    Some(input[..end].trim_end().to_string())
+}
+
+/// Whether the whole input is a valid annotation.
+fn is_annotation(input: &str) -> bool {
+    let input = input.trim();
+    AnnotParser::new(input).annotation() == Some(input.len())
+}
+
+/// The keywords that can't be names in an annotation. `None`, `True` and `False` are
+/// keywords too, but they can be annotations, or arguments of `Literal`.
+const KEYWORDS: &[&str] = &[
+    "and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del", "elif",
+    "else", "except", "finally", "for", "from", "global", "if", "import", "in", "is", "lambda",
+    "nonlocal", "not", "or", "pass", "raise", "return", "try", "while", "with", "yield",
+];
+
+/// A recursive descent parser of annotations. Every parsing method returns the end of what
+/// it parsed, and leaves the position there, or returns None and leaves the position
+/// where it started.
+struct AnnotParser<'a> {
+    src: &'a str,
+    pos: usize,
+    // how many brackets we are in, newlines are whitespace inside brackets
+    depth: usize,
+}
+
+impl<'a> AnnotParser<'a> {
+    fn new(src: &'a str) -> Self {
+        Self {
+            src,
+            pos: 0,
+            depth: 0,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn skip_ws(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == ' ' || c == '\t' || (self.depth > 0 && (c == '\n' || c == '\r')) {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Runs the given parsing function, restoring the position and depth if it fails.
+    fn attempt(&mut self, f: impl FnOnce(&mut Self) -> Option<usize>) -> Option<usize> {
+        let (pos, depth) = (self.pos, self.depth);
+        let res = f(self);
+        if res.is_none() {
+            self.pos = pos;
+            self.depth = depth;
+        }
+        res
+    }
+
+    /// annotation := primary ('|' primary)*
+    /// Stops at the last primary that parses.
+    fn annotation(&mut self) -> Option<usize> {
+        let mut end = self.primary()?;
+        while let Some(e) = self.attempt(|p| {
+            p.skip_ws();
+            if !p.eat('|') {
+                return None;
+            }
+            p.primary()
+        }) {
+            end = e;
+        }
+        Some(end)
+    }
+
+    /// primary := dotted_name ['[' subscripts ']'] | string | '(' annotation ')'
+    fn primary(&mut self) -> Option<usize> {
+        self.attempt(|p| {
+            p.skip_ws();
+            match p.peek()? {
+                '"' | '\'' => {
+                    let start = p.pos;
+                    let end = p.string()?;
+                    // inside brackets the string may be an argument of `Literal`,
+                    // otherwise it must be a forward reference
+                    if p.depth == 0 && !is_annotation(&p.src[start + 1..end - 1]) {
+                        return None;
+                    }
+                    Some(end)
+                }
+                '(' => {
+                    p.pos += 1;
+                    p.depth += 1;
+                    p.annotation()?;
+                    p.skip_ws();
+                    if !p.eat(')') {
+                        return None;
+                    }
+                    p.depth -= 1;
+                    Some(p.pos)
+                }
+                _ => {
+                    let (end, subscriptable) = p.dotted_name()?;
+                    if !subscriptable {
+                        return Some(end);
+                    }
+                    // a generic without its arguments is still an annotation
+                    Some(
+                        p.attempt(|p| {
+                            p.skip_ws();
+                            if !p.eat('[') {
+                                return None;
+                            }
+                            p.depth += 1;
+                            p.subscripts()
+                        })
+                        .unwrap_or(end),
+                    )
+                }
+            }
+        })
+    }
+
+    /// dotted_name := name ('.' name)*
+    /// Stops at the last name that parses. Also returns whether the name can be
+    /// subscripted, which `None`, `True` and `False` can't.
+    fn dotted_name(&mut self) -> Option<(usize, bool)> {
+        let first = self.name()?;
+        if matches!(first, "None" | "True" | "False") {
+            return Some((self.pos, false));
+        }
+        let mut end = self.pos;
+        while let Some(e) = self.attempt(|p| {
+            if !p.eat('.') {
+                return None;
+            }
+            match p.name()? {
+                "None" | "True" | "False" => None,
+                _ => Some(p.pos),
+            }
+        }) {
+            end = e;
+        }
+        Some((end, true))
+    }
+
+    /// A name that is not a keyword.
+    fn name(&mut self) -> Option<&'a str> {
+        let start = self.pos;
+        let rest = &self.src[start..];
+        let first = rest.chars().next()?;
+        if !(first == '_' || first.is_alphabetic()) {
+            return None;
+        }
+        let len = rest
+            .find(|c: char| !(c == '_' || c.is_alphanumeric()))
+            .unwrap_or(rest.len());
+        let name = &rest[..len];
+        if KEYWORDS.contains(&name) {
+            return None;
+        }
+        self.pos += len;
+        Some(name)
+    }
+
+    /// subscripts := element (',' element)* [','] ']'
+    /// Called after the '['. All of the subscripts must parse.
+    fn subscripts(&mut self) -> Option<usize> {
+        loop {
+            self.element()?;
+            self.skip_ws();
+            if self.eat(',') {
+                self.skip_ws();
+                if self.eat(']') {
+                    break;
+                }
+            } else if self.eat(']') {
+                break;
+            } else {
+                return None;
+            }
+        }
+        self.depth -= 1;
+        Some(self.pos)
+    }
+
+    /// element := '...' | '[' [element (',' element)* [',']] ']' | number | annotation
+    /// The lists are the parameters of `Callable`, the numbers are arguments of `Literal`.
+    fn element(&mut self) -> Option<usize> {
+        self.skip_ws();
+        if self.src[self.pos..].starts_with("...") {
+            self.pos += 3;
+            return Some(self.pos);
+        }
+        if self.eat('[') {
+            self.depth += 1;
+            self.skip_ws();
+            if self.eat(']') {
+                self.depth -= 1;
+                return Some(self.pos);
+            }
+            return self.subscripts();
+        }
+        if let Some(end) = self.attempt(Self::number) {
+            return Some(end);
+        }
+        self.annotation()
+    }
+
+    /// number := ['-'] digits ['.' digits]
+    fn number(&mut self) -> Option<usize> {
+        self.eat('-');
+        let digits = |p: &mut Self| {
+            let rest = &p.src[p.pos..];
+            let len = rest
+                .find(|c: char| !(c.is_ascii_digit() || c == '_'))
+                .unwrap_or(rest.len());
+            p.pos += len;
+            len > 0
+        };
+        if !digits(self) {
+            return None;
+        }
+        if self.eat('.') && !digits(self) {
+            return None;
+        }
+        // a number can't run into a name, like `1abc`
+        match self.peek() {
+            Some(c) if c == '_' || c.is_alphanumeric() => None,
+            _ => Some(self.pos),
+        }
+    }
+
+    /// A single or double quoted string, on a single line.
+    fn string(&mut self) -> Option<usize> {
+        let quote = self.peek()?;
+        self.pos += 1;
+        let mut escaped = false;
+        for (i, c) in self.src[self.pos..].char_indices() {
+            match c {
+                '\n' => return None,
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                _ if c == quote => {
+                    self.pos += i + 1;
+                    return Some(self.pos);
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> Option<String> {
+        py_parse_type(input)
+    }
+
+    #[test]
+    fn annotations() {
+        assert_eq!(parse("int").as_deref(), Some("int"));
+        assert_eq!(parse("typing.List").as_deref(), Some("typing.List"));
+        assert_eq!(
+            parse("Dict[str, List[int]]").as_deref(),
+            Some("Dict[str, List[int]]")
+        );
+        assert_eq!(
+            parse("Callable[[int, str], None]").as_deref(),
+            Some("Callable[[int, str], None]")
+        );
+        assert_eq!(parse("Tuple[int, ...]").as_deref(), Some("Tuple[int, ...]"));
+        assert_eq!(
+            parse("Literal[\"a\", -1]").as_deref(),
+            Some("Literal[\"a\", -1]")
+        );
+        assert_eq!(parse("(int | None)").as_deref(), Some("(int | None)"));
+        assert_eq!(parse("\"Node\"").as_deref(), Some("\"Node\""));
+    }
+
+    #[test]
+    fn trailing_input() {
+        assert_eq!(parse("List[int]) -> str:").as_deref(), Some("List[int]"));
+        assert_eq!(parse("int = 5").as_deref(), Some("int"));
+        assert_eq!(parse("Dict[str, int]]").as_deref(), Some("Dict[str, int]"));
+        assert_eq!(parse("int | None, y: str").as_deref(), Some("int | None"));
+        // a dangling union or dot is not part of the annotation
+        assert_eq!(parse("int | ").as_deref(), Some("int"));
+        assert_eq!(parse("os.").as_deref(), Some("os"));
+        // nor are unclosed arguments, but the generic without them is
+        assert_eq!(parse("Optional[int").as_deref(), Some("Optional"));
+        // the annotation ends at the end of the line, outside of brackets
+        assert_eq!(parse("int\n| str").as_deref(), Some("int"));
+        assert_eq!(
+            parse("Dict[str,\n int]\nx = 1").as_deref(),
+            Some("Dict[str,\n int]")
+        );
+    }
+
+    #[test]
+    fn not_annotations() {
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("def f():"), None);
+        assert_eq!(parse("1 + 2"), None);
+        assert_eq!(parse("\"not a type!\""), None);
+        assert_eq!(parse("None[int]").as_deref(), Some("None"));
+    }
+}
@@ -1,12 +1,45 @@
 use async_trait::async_trait;
 
-use crate::{impl_langserver_commands, socket::SendToSocket, socket::SocketAbstraction};
+use crate::{impl_langserver_commands, socket::SocketAbstraction, socket::Supervision};
 
-use super::{LSReq, LangServer, LangServerError};
+use super::{
+    protocol::{LSCheckDtsReq, LSSocket, LSTypeCheckReq},
+    LangServer, LangServerError, Normalizer, Splitter,
+};
+
+#[cfg(feature = "tsparser")]
+pub mod dts;
+#[cfg(feature = "tsparser")]
+pub mod jsdoc;
+#[cfg(feature = "tsnative")]
+pub mod native;
+#[cfg(feature = "tsparser")]
+pub mod normalize;
+#[cfg(feature = "tsparser")]
+pub mod split;
 
 #[derive(Debug)]
 pub struct TsServer {
-    socket: SocketAbstraction,
+    socket: LSSocket,
+}
+
+impl TsServer {
+    /// Sets how the server is supervised. By default, the server is respawned when a
+    /// request fails to reach it, without health checks.
+    pub fn supervise(self, supervision: Supervision) -> Self {
+        Self {
+            socket: self.socket.supervise(supervision),
+        }
+    }
+
+    async fn check(&self, code: &str, js: bool) -> Result<usize, LangServerError> {
+        // for typescript, we use the language server for typechecking
+        let req = LSTypeCheckReq {
+            text: code.to_string(),
+            js,
+        };
+        Ok(self.socket.send(&req).await?.errors)
+    }
 }
 
 #[async_trait]
@@ -16,21 +49,28 @@ impl LangServer for TsServer {
         let socket = SocketAbstraction::spawn_server("typescript", &args, true)
             .await
             .map_err(|_| LangServerError::ProcessSpawn)?;
+        let socket = LSSocket::handshake(socket).await?;
         Ok(Self { socket })
     }
 
     async fn type_check(&self, code: &str) -> Result<usize, LangServerError> {
-        // for typescript, we use the language server for typechecking
-        let req = LSReq {
-            cmd: "typecheck".to_string(),
-            text: base64::encode(code),
-        };
-        let resp = self
-            .socket
-            .send_req(serde_json::to_value(&req).unwrap())
-            .await?;
+        self.check(code, false).await
+    }
 
-        Ok(resp["errors"].as_u64().unwrap() as usize)
+    async fn type_check_js(&self, code: &str) -> Result<usize, LangServerError> {
+        self.check(code, true).await
+    }
+
+    async fn check_declarations(
+        &self,
+        declarations: &str,
+        original: &str,
+    ) -> Result<usize, LangServerError> {
+        let req = LSCheckDtsReq {
+            text: declarations.to_string(),
+            original: original.to_string(),
+        };
+        Ok(self.socket.send(&req).await?.errors)
     }
 
     fn any_type(&self) -> String {
@@ -47,6 +87,36 @@ impl LangServer for TsServer {
             None
         }
     }
+
+    fn unsupported_commands(&self) -> Vec<&'static str> {
+        let mut commands = self.socket.missing_commands();
+        if commands.contains(&"typecheck") {
+            commands.push("typecheckJs");
+        }
+        commands
+    }
+
+    fn get_normalizer(&self) -> Option<Normalizer> {
+        #[cfg(feature = "tsparser")]
+        {
+            Some(Box::new(normalize::ts_normalize))
+        }
+        #[cfg(not(feature = "tsparser"))]
+        {
+            None
+        }
+    }
+
+    fn get_splitter(&self) -> Option<Splitter> {
+        #[cfg(feature = "tsparser")]
+        {
+            Some(Box::new(split::ts_split))
+        }
+        #[cfg(not(feature = "tsparser"))]
+        {
+            None
+        }
+    }
 }
 
 // implement the LangServerCommands trait
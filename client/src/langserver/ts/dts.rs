@@ -0,0 +1,494 @@
+use std::collections::HashSet;
+
+use swc_common::{sync::Lrc, BytePos, FileName, SourceMap, Span, Spanned};
+use swc_ecma_ast::{
+    Accessibility, Class, ClassMember, Decl, DefaultDecl, ExportDecl, ExportDefaultDecl,
+    ExportSpecifier, Expr, Function, MethodKind, ModuleDecl, ModuleExportName, ModuleItem,
+    ParamOrTsParamProp, Pat, Stmt, TsParamPropParam, TsTypeAnn, TsTypeParamDecl, VarDecl,
+};
+use swc_ecma_parser::{Parser, StringInput, Syntax};
+
+use crate::langserver::native::pat_type_ann;
+
+/// Makes the declaration file (.d.ts) of the given typed TypeScript code, like the best
+/// completion of a JavaScript file. The declaration file has:
+/// - the imports and re-exports of the code,
+/// - the exported functions, variables and classes, with their types but without their
+///   bodies, and the local ones that are exported by name,
+/// - the interfaces and type aliases of the code, exported or not, which includes the ones
+///   made by `typedef_gen`.
+///
+/// Missing types are `any`, private members have no type, and the implementations of
+/// overloaded functions and methods are not declared, like in the declaration files of the
+/// TypeScript compiler. Only ES module exports are declared. Returns None if the code
+/// doesn't parse.
+pub fn ts_to_dts(code: &str) -> Option<String> {
+    let cm: Lrc<SourceMap> = Default::default();
+    let fm = cm.new_source_file(FileName::Anon, code.to_string());
+
+    let mut parser = Parser::new(
+        Syntax::Typescript(Default::default()),
+        StringInput::from(&*fm),
+        None,
+    );
+    let module = parser.parse_module().ok()?;
+    if !parser.take_errors().is_empty() {
+        return None;
+    }
+
+    let mut writer = DtsWriter {
+        text: &fm.src,
+        start: fm.start_pos,
+        exported: HashSet::new(),
+        decls: vec![],
+        overloaded: None,
+    };
+    // the local declarations that are exported by name need to be declared too
+    for item in &module.body {
+        writer.exported_names(item);
+    }
+    for item in &module.body {
+        writer.item(item);
+        writer.overloaded = overload_name(item).map(str::to_string);
+    }
+    Some(writer.decls.join("\n"))
+}
+
+/// Writes the declarations of the top-level items of the code.
+struct DtsWriter<'a> {
+    text: &'a str,
+    start: BytePos,
+    // the local names that are exported with `export { name }` or `export default name`
+    exported: HashSet<String>,
+    // the declarations, in the order of the code
+    decls: Vec<String>,
+    // the name of the function that the previous item is an overload of, if it is one
+    overloaded: Option<String>,
+}
+
+/// The name of the function that the given item is an overload signature of, which is a
+/// function declaration without a body.
+fn overload_name(item: &ModuleItem) -> Option<&str> {
+    let (name, func) = match item {
+        ModuleItem::Stmt(Stmt::Decl(Decl::Fn(func)))
+        | ModuleItem::ModuleDecl(ModuleDecl::ExportDecl(ExportDecl {
+            decl: Decl::Fn(func),
+            ..
+        })) => (&*func.ident.sym, &func.function),
+        ModuleItem::ModuleDecl(ModuleDecl::ExportDefaultDecl(ExportDefaultDecl {
+            decl: DefaultDecl::Fn(func),
+            ..
+        })) => (
+            func.ident.as_ref().map_or("", |id| &*id.sym),
+            &func.function,
+        ),
+        _ => return None,
+    };
+    func.body.is_none().then_some(name)
+}
+
+impl DtsWriter<'_> {
+    fn slice(&self, span: Span) -> &str {
+        let lo = (span.lo.0 - self.start.0) as usize;
+        let hi = (span.hi.0 - self.start.0) as usize;
+        &self.text[lo..hi]
+    }
+
+    fn type_text(&self, ann: Option<&TsTypeAnn>) -> &str {
+        ann.map_or("any", |ann| self.slice(ann.type_ann.span()))
+    }
+
+    fn type_params(&self, params: Option<&TsTypeParamDecl>) -> &str {
+        params.map_or("", |params| self.slice(params.span))
+    }
+
+    fn exported_names(&mut self, item: &ModuleItem) {
+        match item {
+            ModuleItem::ModuleDecl(ModuleDecl::ExportNamed(export)) if export.src.is_none() => {
+                for spec in &export.specifiers {
+                    if let ExportSpecifier::Named(spec) = spec {
+                        if let ModuleExportName::Ident(id) = &spec.orig {
+                            self.exported.insert(id.sym.to_string());
+                        }
+                    }
+                }
+            }
+            ModuleItem::ModuleDecl(ModuleDecl::ExportDefaultExpr(export)) => {
+                if let Expr::Ident(id) = &*export.expr {
+                    self.exported.insert(id.sym.to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn item(&mut self, item: &ModuleItem) {
+        match item {
+            ModuleItem::ModuleDecl(decl) => match decl {
+                ModuleDecl::Import(_)
+                | ModuleDecl::ExportNamed(_)
+                | ModuleDecl::ExportAll(_)
+                | ModuleDecl::TsImportEquals(_) => {
+                    let decl = self.slice(decl.span()).to_string();
+                    self.decls.push(decl);
+                }
+                ModuleDecl::ExportDecl(export) => self.decl(&export.decl, "export declare "),
+                ModuleDecl::ExportDefaultDecl(export) => match &export.decl {
+                    DefaultDecl::Fn(func) => {
+                        let name = func.ident.as_ref().map_or("", |id| &id.sym);
+                        if self.is_implementation(name, &func.function) {
+                            return;
+                        }
+                        let decl = self.function("export default function ", name, &func.function);
+                        self.decls.push(decl);
+                    }
+                    DefaultDecl::Class(class) => {
+                        let name = class.ident.as_ref().map_or("", |id| &id.sym);
+                        let decl = self.class("export default ", name, &class.class);
+                        self.decls.push(decl);
+                    }
+                    DefaultDecl::TsInterfaceDecl(_) => {
+                        let decl = self.slice(export.span).to_string();
+                        self.decls.push(decl);
+                    }
+                },
+                ModuleDecl::ExportDefaultExpr(export) => match &*export.expr {
+                    // the local is declared by itself
+                    Expr::Ident(_) => {
+                        let decl = self.slice(export.span).to_string();
+                        self.decls.push(decl);
+                    }
+                    _ => {
+                        self.decls.push("declare const _default: any;".to_string());
+                        self.decls.push("export default _default;".to_string());
+                    }
+                },
+                _ => {}
+            },
+            ModuleItem::Stmt(Stmt::Decl(decl)) => match decl {
+                Decl::TsInterface(_) | Decl::TsTypeAlias(_) => {
+                    let decl = self.slice(decl.span()).to_string();
+                    self.decls.push(decl);
+                }
+                _ => self.decl(decl, "declare "),
+            },
+            _ => {}
+        }
+    }
+
+    /// Declares the given declaration, which is not exported unless the prefix exports it.
+    fn decl(&mut self, decl: &Decl, prefix: &str) {
+        let exported = prefix.starts_with("export");
+        match decl {
+            Decl::Fn(func) if exported || self.exported.contains(&*func.ident.sym) => {
+                if self.is_implementation(&func.ident.sym, &func.function) {
+                    return;
+                }
+                let decl = self.function(
+                    &format!("{prefix}function "),
+                    &func.ident.sym,
+                    &func.function,
+                );
+                self.decls.push(decl);
+            }
+            Decl::Class(class) if exported || self.exported.contains(&*class.ident.sym) => {
+                let decl = self.class(prefix, &class.ident.sym, &class.class);
+                self.decls.push(decl);
+            }
+            Decl::Var(var) => self.var(var, prefix, exported),
+            // the exported interfaces, type aliases, enums and namespaces are already
+            // declarations
+            Decl::TsInterface(_) | Decl::TsTypeAlias(_) | Decl::TsEnum(_) | Decl::TsModule(_)
+                if exported =>
+            {
+                let decl = format!("export {}", self.slice(decl.span()));
+                self.decls.push(decl);
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether the given function is the implementation of the overloads before it, which
+    /// is not part of the declarations.
+    fn is_implementation(&self, name: &str, func: &Function) -> bool {
+        func.body.is_some() && self.overloaded.as_deref() == Some(name)
+    }
+
+    fn var(&mut self, var: &VarDecl, prefix: &str, exported: bool) {
+        let kind = var.kind.as_str();
+        for decl in &var.decls {
+            // destructured variables are not declared
+            let Pat::Ident(id) = &decl.name else {
+                continue;
+            };
+            if !exported && !self.exported.contains(&*id.id.sym) {
+                continue;
+            }
+            let ty = match (id.type_ann.as_deref(), decl.init.as_deref()) {
+                (Some(ann), _) => self.slice(ann.type_ann.span()).to_string(),
+                (None, Some(Expr::Fn(func))) => self.fn_type(&func.function),
+                (None, Some(Expr::Arrow(arrow))) => {
+                    let params = self.params(arrow.params.iter());
+                    format!(
+                        "{}({params}) => {}",
+                        self.type_params(arrow.type_params.as_deref()),
+                        self.type_text(arrow.return_type.as_deref())
+                    )
+                }
+                _ => "any".to_string(),
+            };
+            self.decls
+                .push(format!("{prefix}{kind} {}: {ty};", id.id.sym));
+        }
+    }
+
+    /// The parameters of a function, with their types but without their initializers.
+    fn params<'p>(&self, params: impl Iterator<Item = &'p Pat>) -> String {
+        params
+            .enumerate()
+            .map(|(i, pat)| self.param(i, pat))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    fn param(&self, i: usize, pat: &Pat) -> String {
+        let ty = self.type_text(pat_type_ann(pat));
+        let name = |pat: &Pat| match pat {
+            Pat::Ident(id) => id.id.sym.to_string(),
+            // destructured parameters get a name, like in the declaration files of the
+            // TypeScript compiler
+            _ => format!("param{i}"),
+        };
+        match pat {
+            Pat::Ident(id) if id.id.optional => format!("{}?: {ty}", id.id.sym),
+            Pat::Assign(assign) => format!("{}?: {ty}", name(&assign.left)),
+            Pat::Rest(rest) => format!("...{}: {ty}", name(&rest.arg)),
+            _ => format!("{}: {ty}", name(pat)),
+        }
+    }
+
+    /// The signature of a function, like `<T>(a: T): T`.
+    fn signature(&self, func: &Function) -> String {
+        format!(
+            "{}({}): {}",
+            self.type_params(func.type_params.as_deref()),
+            self.params(func.params.iter().map(|param| &param.pat)),
+            self.type_text(func.return_type.as_deref())
+        )
+    }
+
+    /// The type of a function, like `<T>(a: T) => T`.
+    fn fn_type(&self, func: &Function) -> String {
+        format!(
+            "{}({}) => {}",
+            self.type_params(func.type_params.as_deref()),
+            self.params(func.params.iter().map(|param| &param.pat)),
+            self.type_text(func.return_type.as_deref())
+        )
+    }
+
+    fn function(&self, prefix: &str, name: &str, func: &Function) -> String {
+        format!("{prefix}{name}{};", self.signature(func))
+    }
+
+    fn class(&self, prefix: &str, name: &str, class: &Class) -> String {
+        let mut decl = prefix.to_string();
+        if class.is_abstract {
+            decl.push_str("abstract ");
+        }
+        decl.push_str("class ");
+        if !name.is_empty() {
+            decl.push_str(name);
+        }
+        decl.push_str(self.type_params(class.type_params.as_deref()));
+        if let Some(super_class) = &class.super_class {
+            decl.push_str(" extends ");
+            decl.push_str(self.slice(super_class.span()));
+            if let Some(args) = &class.super_type_params {
+                decl.push_str(self.slice(args.span));
+            }
+        }
+        if !class.implements.is_empty() {
+            let implements: Vec<&str> = class
+                .implements
+                .iter()
+                .map(|implement| self.slice(implement.span))
+                .collect();
+            decl.push_str(" implements ");
+            decl.push_str(&implements.join(", "));
+        }
+        decl.push_str(" {\n");
+
+        let mut members = vec![];
+        let mut has_private_names = false;
+        // the key of the method that the previous member is an overload of, if it is one
+        let mut overloaded: Option<(&str, bool)> = None;
+        for member in &class.body {
+            let overload = match member {
+                ClassMember::Method(method) if method.function.body.is_none() => {
+                    Some((self.slice(method.key.span()), method.is_static))
+                }
+                _ => None,
+            };
+            let previous = std::mem::replace(&mut overloaded, overload);
+            match member {
+                // the implementation of overloads is not declared
+                ClassMember::Method(method)
+                    if method.function.body.is_some()
+                        && previous == Some((self.slice(method.key.span()), method.is_static)) => {}
+                ClassMember::Constructor(cons) => {
+                    let mut params = vec![];
+                    for (i, param) in cons.params.iter().enumerate() {
+                        match param {
+                            ParamOrTsParamProp::Param(param) => {
+                                params.push(self.param(i, &param.pat));
+                            }
+                            // the parameter properties are declared as properties
+                            ParamOrTsParamProp::TsParamProp(prop) => {
+                                let (pat, ty) = match &prop.param {
+                                    TsParamPropParam::Ident(id) => {
+                                        (id.id.sym.to_string(), id.type_ann.as_deref())
+                                    }
+                                    TsParamPropParam::Assign(assign) => {
+                                        let name = match &*assign.left {
+                                            Pat::Ident(id) => id.id.sym.to_string(),
+                                            _ => format!("param{i}"),
+                                        };
+                                        let ty = assign
+                                            .type_ann
+                                            .as_deref()
+                                            .or_else(|| pat_type_ann(&assign.left));
+                                        (name, ty)
+                                    }
+                                };
+                                let ty = self.type_text(ty);
+                                let optional = matches!(prop.param, TsParamPropParam::Assign(_));
+                                let modifiers = format!(
+                                    "{}{}",
+                                    accessibility(prop.accessibility),
+                                    if prop.readonly { "readonly " } else { "" }
+                                );
+                                members.push(match prop.accessibility {
+                                    Some(Accessibility::Private) => format!("{modifiers}{pat};"),
+                                    _ => format!("{modifiers}{pat}: {ty};"),
+                                });
+                                let q = if optional { "?" } else { "" };
+                                params.push(format!("{pat}{q}: {ty}"));
+                            }
+                        }
+                    }
+                    members.push(format!("constructor({});", params.join(", ")));
+                }
+                ClassMember::Method(method) => {
+                    let key = self.slice(method.key.span());
+                    let mut modifiers = accessibility(method.accessibility).to_string();
+                    if method.is_static {
+                        modifiers.push_str("static ");
+                    }
+                    if method.is_abstract {
+                        modifiers.push_str("abstract ");
+                    }
+                    let q = if method.is_optional { "?" } else { "" };
+                    let func = &method.function;
+                    members.push(match (method.accessibility, method.kind) {
+                        (Some(Accessibility::Private), _) => format!("{modifiers}{key};"),
+                        (_, MethodKind::Method) => {
+                            format!("{modifiers}{key}{q}{};", self.signature(func))
+                        }
+                        (_, MethodKind::Getter) => format!(
+                            "{modifiers}get {key}(): {};",
+                            self.type_text(func.return_type.as_deref())
+                        ),
+                        (_, MethodKind::Setter) => format!(
+                            "{modifiers}set {key}({});",
+                            self.params(func.params.iter().map(|param| &param.pat))
+                        ),
+                    });
+                }
+                ClassMember::ClassProp(prop) => {
+                    let key = self.slice(prop.key.span());
+                    let mut modifiers = accessibility(prop.accessibility).to_string();
+                    if prop.is_static {
+                        modifiers.push_str("static ");
+                    }
+                    if prop.is_abstract {
+                        modifiers.push_str("abstract ");
+                    }
+                    if prop.readonly {
+                        modifiers.push_str("readonly ");
+                    }
+                    let q = if prop.is_optional { "?" } else { "" };
+                    members.push(match prop.accessibility {
+                        Some(Accessibility::Private) => format!("{modifiers}{key};"),
+                        _ => format!(
+                            "{modifiers}{key}{q}: {};",
+                            self.type_text(prop.type_ann.as_deref())
+                        ),
+                    });
+                }
+                ClassMember::TsIndexSignature(sig) => {
+                    members.push(self.slice(sig.span).to_string());
+                }
+                // private names are declared once, without their names
+                ClassMember::PrivateMethod(_) | ClassMember::PrivateProp(_) => {
+                    has_private_names = true;
+                }
+                ClassMember::Empty(_) | ClassMember::StaticBlock(_) => {}
+            }
+        }
+        if has_private_names {
+            members.insert(0, "#private;".to_string());
+        }
+        for member in members {
+            decl.push_str(&format!("    {member}\n"));
+        }
+        decl.push('}');
+        decl
+    }
+}
+
+fn accessibility(accessibility: Option<Accessibility>) -> &'static str {
+    match accessibility {
+        None => "",
+        Some(Accessibility::Public) => "public ",
+        Some(Accessibility::Protected) => "protected ",
+        Some(Accessibility::Private) => "private ",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overloaded_function() {
+        let code = "export function f(a: string): string;\n\
+                    export function f(a: number): number;\n\
+                    export function f(a: any): any {\n  return a;\n}\n\
+                    export function g(b: boolean): void {}\n";
+        assert_eq!(
+            ts_to_dts(code).unwrap(),
+            "export declare function f(a: string): string;\n\
+             export declare function f(a: number): number;\n\
+             export declare function g(b: boolean): void;"
+        );
+    }
+
+    #[test]
+    fn overloaded_method() {
+        let code = "export class A {\n\
+                    \x20 m(a: string): string;\n\
+                    \x20 m(a: number): number;\n\
+                    \x20 m(a: any): any {\n    return a;\n  }\n\
+                    \x20 static m(a: any): any {}\n\
+                    }\n";
+        assert_eq!(
+            ts_to_dts(code).unwrap(),
+            "export declare class A {\n\
+             \x20   m(a: string): string;\n\
+             \x20   m(a: number): number;\n\
+             \x20   static m(a: any): any;\n\
+             }"
+        );
+    }
+}
@@ -0,0 +1,665 @@
+use swc_common::{sync::Lrc, BytePos, EqIgnoreSpan, FileName, SourceMap, Span, Spanned};
+use swc_ecma_ast::{
+    ArrowExpr, BindingIdent, ClassMethod, ClassProp, Constructor, Decl, DefaultDecl, ExportDecl,
+    ExportDefaultDecl, Expr, FnExpr, Function, MethodProp, Module, ParamOrTsParamProp, Pat,
+    PrivateMethod, PrivateProp, TsInterfaceDecl, TsTypeAliasDecl, TsTypeAnn, TsTypeParamDecl,
+    TsTypeParamInstantiation, VarDecl,
+};
+use swc_ecma_parser::{Parser, StringInput, Syntax};
+use swc_ecma_visit::{Visit, VisitWith};
+
+use crate::langserver::native::pat_type_ann;
+
+/// Turns the given typed TypeScript code, like a completion of a JavaScript file, back into
+/// JavaScript, where the types are JSDoc comments instead of annotations:
+/// - functions, methods and constructors get `@template`, `@param` and `@returns` tags,
+/// - variables and class properties get a `@type` tag,
+/// - function expressions that aren't bound to a variable get a `@type` tag with the type
+///   of the function, as they may be in the middle of an expression,
+/// - interfaces and type aliases become `@typedef` tags.
+///
+/// The rest of the code is unchanged. The result is meant to be type checked with
+/// `type_check_js`, which catches the TypeScript syntax that can't be turned into JSDoc.
+/// Returns None if the code doesn't parse.
+pub fn ts_to_jsdoc(code: &str) -> Option<String> {
+    Some(JsDoc::of(code)?.documented)
+}
+
+/// Writes the types of the given completion of a JavaScript file as JSDoc comments into the
+/// original file, like `ts_to_jsdoc`, so that the code, its formatting and its comments are
+/// the ones of the file and not the ones of the completion. The interfaces and type aliases
+/// of the completion become `@typedef` tags at the top of the file.
+///
+/// Returns None if either code doesn't parse, or if the completion is not the original code
+/// with types, e.g. because the model changed it.
+pub fn jsdoc_into(original: &str, completion: &str) -> Option<String> {
+    let typed = JsDoc::of(completion)?;
+    let untyped = JsDoc::of(original)?;
+    // the same code has the same nodes to document, in the same order. We compare the
+    // syntax trees, as the TypeScript printer adds semicolons and wraps the parameter of an
+    // arrow function in parentheses, where the file may not have them
+    let same = parse(&typed.stripped)?.eq_ignore_span(&parse(original)?);
+    if !same || typed.docs.len() != untyped.docs.len() {
+        return None;
+    }
+
+    let mut out = String::with_capacity(original.len());
+    for tags in &typed.typedefs {
+        out.push_str(&doc(original, 0, tags));
+    }
+    let mut docs: Vec<(usize, &[String])> = untyped
+        .docs
+        .iter()
+        .zip(&typed.docs)
+        .filter(|(_, (_, tags))| !tags.is_empty())
+        .map(|((off, _), (_, tags))| (*off, tags.as_slice()))
+        .collect();
+    // the nodes are in the order they are visited, which is not always the order of the code
+    docs.sort_by_key(|(off, _)| *off);
+    let mut last = 0;
+    for (off, tags) in docs {
+        out.push_str(&original[last..off]);
+        out.push_str(&doc(original, off, tags));
+        last = off;
+    }
+    out.push_str(&original[last..]);
+    Some(out)
+}
+
+/// Parses the given code, returns None if it doesn't parse.
+fn parse(code: &str) -> Option<Module> {
+    let cm: Lrc<SourceMap> = Default::default();
+    let fm = cm.new_source_file(FileName::Anon, code.to_string());
+    let mut parser = Parser::new(
+        Syntax::Typescript(Default::default()),
+        StringInput::from(&*fm),
+        None,
+    );
+    let module = parser.parse_module().ok()?;
+    if !parser.take_errors().is_empty() {
+        return None;
+    }
+    Some(module)
+}
+
+/// A JSDoc comment with the given tags, for the node that starts at the given offset of the
+/// text. When the node starts its line, the comment goes on its own lines, with the
+/// indentation of the node.
+fn doc(text: &str, off: usize, tags: &[String]) -> String {
+    let line_start = text[..off].rfind('\n').map_or(0, |i| i + 1);
+    let before = &text[line_start..off];
+    let (indent, after) = if before.trim().is_empty() {
+        (before, format!("\n{before}"))
+    } else {
+        ("", " ".to_string())
+    };
+    match tags {
+        [tag] => format!("/** {tag} */{after}"),
+        _ => {
+            let mut doc = "/**\n".to_string();
+            for tag in tags {
+                doc.push_str(&format!("{indent} * {tag}\n"));
+            }
+            format!("{doc}{indent} */{after}")
+        }
+    }
+}
+
+/// Typed code turned into JavaScript by `JsDocWriter`.
+struct JsDoc {
+    /// the code without its types
+    stripped: String,
+    /// the code with JSDoc comments instead of its types
+    documented: String,
+    /// every node that can be documented, as where its comment goes and its tags, which
+    /// are empty if it has no types
+    docs: Vec<(usize, Vec<String>)>,
+    /// the tags of the interfaces and type aliases
+    typedefs: Vec<Vec<String>>,
+}
+
+impl JsDoc {
+    /// Returns None if the code doesn't parse.
+    fn of(code: &str) -> Option<Self> {
+        let cm: Lrc<SourceMap> = Default::default();
+        let fm = cm.new_source_file(FileName::Anon, code.to_string());
+
+        let mut parser = Parser::new(
+            Syntax::Typescript(Default::default()),
+            StringInput::from(&*fm),
+            None,
+        );
+        let module = parser.parse_module().ok()?;
+        if !parser.take_errors().is_empty() {
+            return None;
+        }
+
+        let mut writer = JsDocWriter {
+            text: &fm.src,
+            start: fm.start_pos,
+            edits: vec![],
+            docs: vec![],
+            typedefs: vec![],
+            bound_fn: None,
+        };
+        module.visit_with(&mut writer);
+        Some(writer.finish())
+    }
+}
+
+/// A parameter of a function, as it is written in the JSDoc tags.
+struct DocParam {
+    name: String,
+    ty: Option<String>,
+    optional: bool,
+    rest: bool,
+}
+
+/// Removes the annotations of the code and writes JSDoc comments instead. Every type
+/// annotation, type parameter list and type argument list of the code is removed when it
+/// is visited, while the declarations collect the tags of their comments.
+struct JsDocWriter<'a> {
+    text: &'a str,
+    start: BytePos,
+    // the removals to make, as (lo, hi, "")
+    edits: Vec<(BytePos, BytePos, String)>,
+    // every node that can be documented, as where its comment goes and its tags
+    docs: Vec<(BytePos, Vec<String>)>,
+    // the interfaces and type aliases, which are replaced by comments with their tags
+    typedefs: Vec<(BytePos, Vec<String>)>,
+    // the start of the function bound to the variable we are documenting, which doesn't
+    // need a comment of its own
+    bound_fn: Option<BytePos>,
+}
+
+impl JsDocWriter<'_> {
+    fn offset(&self, pos: BytePos) -> usize {
+        (pos.0 - self.start.0) as usize
+    }
+
+    fn slice(&self, span: Span) -> &str {
+        &self.text[self.offset(span.lo)..self.offset(span.hi)]
+    }
+
+    fn type_text(&self, ann: Option<&TsTypeAnn>) -> Option<String> {
+        ann.map(|ann| self.slice(ann.type_ann.span()).to_string())
+    }
+
+    fn finish(self) -> JsDoc {
+        let stripped = self.apply(self.edits.clone());
+        // the comments go before the removals, which may start at the same position
+        let mut edits = vec![];
+        for (pos, tags) in self.docs.iter().filter(|(_, tags)| !tags.is_empty()) {
+            edits.push((*pos, *pos, self.doc(*pos, tags)));
+        }
+        for (pos, tags) in &self.typedefs {
+            let doc = self.doc(*pos, tags).trim_end().to_string();
+            edits.push((*pos, *pos, doc));
+        }
+        edits.extend(self.edits.iter().cloned());
+        let documented = self.apply(edits);
+
+        JsDoc {
+            stripped,
+            documented,
+            docs: self
+                .docs
+                .iter()
+                .map(|(pos, tags)| (self.offset(*pos), tags.clone()))
+                .collect(),
+            typedefs: self.typedefs.into_iter().map(|(_, tags)| tags).collect(),
+        }
+    }
+
+    fn apply(&self, mut edits: Vec<(BytePos, BytePos, String)>) -> String {
+        // a stable sort, so that insertions at the same position keep their order
+        edits.sort_by_key(|(lo, _, _)| *lo);
+        let mut out = String::with_capacity(self.text.len());
+        let mut last = 0;
+        for (lo, hi, text) in &edits {
+            let (lo, hi) = (self.offset(*lo), self.offset(*hi));
+            out.push_str(&self.text[last..lo]);
+            out.push_str(text);
+            last = hi;
+        }
+        out.push_str(&self.text[last..]);
+        out
+    }
+
+    fn remove(&mut self, span: Span) {
+        self.edits.push((span.lo, span.hi, String::new()));
+    }
+
+    /// Removes the given marker, like the `?` of an optional parameter, if it comes right
+    /// after the given position.
+    fn remove_marker(&mut self, pos: BytePos, markers: &[char]) {
+        let rest = &self.text[self.offset(pos)..];
+        let skipped = rest.len() - rest.trim_start().len();
+        if rest.trim_start().starts_with(markers) {
+            let lo = BytePos(pos.0 + skipped as u32);
+            self.edits.push((lo, BytePos(lo.0 + 1), String::new()));
+        }
+    }
+
+    fn doc(&self, pos: BytePos, tags: &[String]) -> String {
+        doc(self.text, self.offset(pos), tags)
+    }
+
+    /// Writes a JSDoc comment with the given tags before the node that starts at the given
+    /// position, if there are any tags. Every node that can be documented calls it, even
+    /// without tags, so that the nodes of two versions of the same code can be matched.
+    fn write_doc(&mut self, pos: BytePos, tags: &[String]) {
+        self.docs.push((pos, tags.to_vec()));
+    }
+
+    /// Replaces the node with the given span by a JSDoc comment with the given tags.
+    fn replace_with_doc(&mut self, span: Span, tags: &[String]) {
+        self.remove(span);
+        self.typedefs.push((span.lo, tags.to_vec()));
+    }
+
+    fn doc_param(&self, i: usize, pat: &Pat) -> DocParam {
+        let ty = self.type_text(pat_type_ann(pat));
+        // destructured parameters have no name, their tag is matched by position
+        let unnamed = || format!("param{i}");
+        match pat {
+            Pat::Ident(id) => DocParam {
+                name: id.id.sym.to_string(),
+                ty,
+                optional: id.id.optional,
+                rest: false,
+            },
+            Pat::Assign(assign) => DocParam {
+                name: match &*assign.left {
+                    Pat::Ident(id) => id.id.sym.to_string(),
+                    _ => unnamed(),
+                },
+                ty,
+                optional: true,
+                rest: false,
+            },
+            Pat::Rest(rest) => DocParam {
+                name: match &*rest.arg {
+                    Pat::Ident(id) => id.id.sym.to_string(),
+                    _ => unnamed(),
+                },
+                ty,
+                optional: false,
+                rest: true,
+            },
+            _ => DocParam {
+                name: unnamed(),
+                ty,
+                optional: false,
+                rest: false,
+            },
+        }
+    }
+
+    fn doc_params<'p>(&self, params: impl Iterator<Item = &'p Pat>) -> Vec<DocParam> {
+        params
+            .enumerate()
+            .map(|(i, pat)| self.doc_param(i, pat))
+            .collect()
+    }
+
+    /// The `@template`, `@param` and `@returns` tags of a function, or none if it has no
+    /// types. Every parameter gets a tag, as the tags of destructured parameters are
+    /// matched by position.
+    fn fn_tags(
+        &self,
+        type_params: Option<&TsTypeParamDecl>,
+        params: &[DocParam],
+        ret: Option<&TsTypeAnn>,
+    ) -> Vec<String> {
+        let mut tags = vec![];
+        for param in type_params.iter().flat_map(|decl| &decl.params) {
+            match &param.constraint {
+                Some(constraint) => tags.push(format!(
+                    "@template {{{}}} {}",
+                    self.slice(constraint.span()),
+                    param.name.sym
+                )),
+                None => tags.push(format!("@template {}", param.name.sym)),
+            }
+        }
+        if params.iter().any(|param| param.ty.is_some()) {
+            for param in params {
+                let ty = param.ty.as_deref().unwrap_or("*");
+                let tag = if param.rest {
+                    // the tag has the type of the elements
+                    match ty.strip_suffix("[]") {
+                        Some(elem) => format!("@param {{...{elem}}} {}", param.name),
+                        None => format!("@param {{...{ty}[number]}} {}", param.name),
+                    }
+                } else if param.optional {
+                    format!("@param {{{ty}}} [{}]", param.name)
+                } else {
+                    format!("@param {{{ty}}} {}", param.name)
+                };
+                tags.push(tag);
+            }
+        }
+        if let Some(ret) = self.type_text(ret) {
+            tags.push(format!("@returns {{{ret}}}"));
+        }
+        tags
+    }
+
+    /// The `@type` tag of a function expression, with the type of the function, or none if
+    /// it has no types.
+    fn fn_type_tag(
+        &self,
+        type_params: Option<&TsTypeParamDecl>,
+        params: &[DocParam],
+        ret: Option<&TsTypeAnn>,
+    ) -> Vec<String> {
+        if type_params.is_none() && ret.is_none() && params.iter().all(|p| p.ty.is_none()) {
+            return vec![];
+        }
+        let params = params
+            .iter()
+            .map(|param| {
+                let ty = param.ty.as_deref().unwrap_or("any");
+                match (param.rest, param.optional) {
+                    (true, _) => format!("...{}: {ty}", param.name),
+                    (false, true) => format!("{}?: {ty}", param.name),
+                    (false, false) => format!("{}: {ty}", param.name),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let type_params = type_params.map_or("", |decl| self.slice(decl.span));
+        let ret = self.type_text(ret).unwrap_or_else(|| "any".to_string());
+        vec![format!("@type {{{type_params}({params}) => {ret}}}")]
+    }
+
+    fn function_tags(&self, func: &Function) -> Vec<String> {
+        let params = self.doc_params(func.params.iter().map(|param| &param.pat));
+        self.fn_tags(
+            func.type_params.as_deref(),
+            &params,
+            func.return_type.as_deref(),
+        )
+    }
+
+    fn fn_decl(&mut self, func: &Function, pos: BytePos) {
+        let tags = self.function_tags(func);
+        self.write_doc(pos, &tags);
+        func.visit_children_with(self);
+    }
+
+    /// Documents the given variables. A single variable is documented before its
+    /// statement, and so is the function bound to it. When there are many, each one gets
+    /// its type right before its name.
+    fn var_decl(&mut self, var: &VarDecl, pos: BytePos) {
+        match var.decls.as_slice() {
+            [decl] => {
+                // the function is documented with the variable even if the variable has a
+                // type, so that it is documented the same way with and without types
+                match decl.init.as_deref() {
+                    Some(Expr::Fn(func)) => self.bound_fn = Some(func.function.span.lo),
+                    Some(Expr::Arrow(arrow)) => self.bound_fn = Some(arrow.span.lo),
+                    _ => {}
+                }
+                let tags = match (pat_type_ann(&decl.name), decl.init.as_deref()) {
+                    (Some(ann), _) => {
+                        vec![format!("@type {{{}}}", self.slice(ann.type_ann.span()))]
+                    }
+                    (None, Some(Expr::Fn(func))) => self.function_tags(&func.function),
+                    (None, Some(Expr::Arrow(arrow))) => {
+                        let params = self.doc_params(arrow.params.iter());
+                        self.fn_tags(
+                            arrow.type_params.as_deref(),
+                            &params,
+                            arrow.return_type.as_deref(),
+                        )
+                    }
+                    _ => vec![],
+                };
+                self.write_doc(pos, &tags);
+            }
+            decls => {
+                for decl in decls {
+                    let tags: Vec<String> = self
+                        .type_text(pat_type_ann(&decl.name))
+                        .map(|ty| format!("@type {{{ty}}}"))
+                        .into_iter()
+                        .collect();
+                    self.write_doc(decl.name.span().lo, &tags);
+                }
+            }
+        }
+        for decl in &var.decls {
+            if decl.definite {
+                self.remove_marker(decl.name.span().hi, &['!']);
+            }
+        }
+        var.visit_children_with(self);
+        self.bound_fn = None;
+    }
+
+    fn type_alias(&mut self, alias: &TsTypeAliasDecl, span: Span) {
+        let mut tags = self.fn_tags(alias.type_params.as_deref(), &[], None);
+        tags.push(format!(
+            "@typedef {{{}}} {}",
+            self.slice(alias.type_ann.span()),
+            alias.id.sym
+        ));
+        self.replace_with_doc(span, &tags);
+    }
+
+    fn interface(&mut self, interface: &TsInterfaceDecl, span: Span) {
+        let mut tags = self.fn_tags(interface.type_params.as_deref(), &[], None);
+        // the interfaces it extends are intersected with its body
+        let mut ty: Vec<&str> = interface
+            .extends
+            .iter()
+            .map(|base| self.slice(base.span))
+            .collect();
+        ty.push(self.slice(interface.body.span));
+        tags.push(format!(
+            "@typedef {{{}}} {}",
+            ty.join(" & "),
+            interface.id.sym
+        ));
+        self.replace_with_doc(span, &tags);
+    }
+
+    /// Documents a method, which has no type of its own.
+    fn method(&mut self, func: &Function, pos: BytePos) {
+        let tags = self.function_tags(func);
+        self.write_doc(pos, &tags);
+        func.visit_children_with(self);
+    }
+
+    fn prop(&mut self, ann: Option<&TsTypeAnn>, pos: BytePos, key_end: BytePos) {
+        let tags: Vec<String> = self
+            .type_text(ann)
+            .map(|ty| format!("@type {{{ty}}}"))
+            .into_iter()
+            .collect();
+        self.write_doc(pos, &tags);
+        self.remove_marker(key_end, &['?', '!']);
+    }
+}
+
+impl Visit for JsDocWriter<'_> {
+    fn visit_ts_type_ann(&mut self, ann: &TsTypeAnn) {
+        self.remove(ann.span);
+    }
+
+    fn visit_ts_type_param_decl(&mut self, decl: &TsTypeParamDecl) {
+        self.remove(decl.span);
+    }
+
+    fn visit_ts_type_param_instantiation(&mut self, inst: &TsTypeParamInstantiation) {
+        self.remove(inst.span);
+    }
+
+    fn visit_binding_ident(&mut self, id: &BindingIdent) {
+        if id.id.optional {
+            // the span of the name may or may not have the `?`
+            let name_end = BytePos(id.id.span.lo.0 + id.id.sym.len() as u32);
+            self.remove_marker(name_end, &['?']);
+        }
+        id.visit_children_with(self);
+    }
+
+    fn visit_export_decl(&mut self, export: &ExportDecl) {
+        match &export.decl {
+            Decl::Fn(func) => self.fn_decl(&func.function, export.span.lo),
+            Decl::Var(var) => self.var_decl(var, export.span.lo),
+            Decl::TsTypeAlias(alias) => self.type_alias(alias, export.span),
+            Decl::TsInterface(interface) => self.interface(interface, export.span),
+            _ => export.visit_children_with(self),
+        }
+    }
+
+    fn visit_export_default_decl(&mut self, export: &ExportDefaultDecl) {
+        match &export.decl {
+            DefaultDecl::Fn(func) => self.fn_decl(&func.function, export.span.lo),
+            DefaultDecl::TsInterfaceDecl(interface) => self.interface(interface, export.span),
+            _ => export.visit_children_with(self),
+        }
+    }
+
+    fn visit_decl(&mut self, decl: &Decl) {
+        match decl {
+            Decl::Fn(func) => self.fn_decl(&func.function, func.function.span.lo),
+            Decl::TsTypeAlias(alias) => self.type_alias(alias, alias.span),
+            Decl::TsInterface(interface) => self.interface(interface, interface.span),
+            _ => decl.visit_children_with(self),
+        }
+    }
+
+    fn visit_var_decl(&mut self, var: &VarDecl) {
+        self.var_decl(var, var.span.lo);
+    }
+
+    fn visit_fn_expr(&mut self, expr: &FnExpr) {
+        if self.bound_fn != Some(expr.function.span.lo) {
+            let params = self.doc_params(expr.function.params.iter().map(|param| &param.pat));
+            let tags = self.fn_type_tag(
+                expr.function.type_params.as_deref(),
+                &params,
+                expr.function.return_type.as_deref(),
+            );
+            self.write_doc(expr.function.span.lo, &tags);
+        }
+        expr.visit_children_with(self);
+    }
+
+    fn visit_arrow_expr(&mut self, arrow: &ArrowExpr) {
+        if self.bound_fn != Some(arrow.span.lo) {
+            let params = self.doc_params(arrow.params.iter());
+            let tags = self.fn_type_tag(
+                arrow.type_params.as_deref(),
+                &params,
+                arrow.return_type.as_deref(),
+            );
+            self.write_doc(arrow.span.lo, &tags);
+        }
+        arrow.visit_children_with(self);
+    }
+
+    fn visit_constructor(&mut self, cons: &Constructor) {
+        let pats = cons.params.iter().filter_map(|param| match param {
+            ParamOrTsParamProp::Param(param) => Some(&param.pat),
+            // parameter properties are TypeScript only
+            ParamOrTsParamProp::TsParamProp(_) => None,
+        });
+        let params = self.doc_params(pats);
+        let tags = self.fn_tags(None, &params, None);
+        self.write_doc(cons.span.lo, &tags);
+        cons.visit_children_with(self);
+    }
+
+    fn visit_class_method(&mut self, method: &ClassMethod) {
+        self.method(&method.function, method.span.lo);
+        method.key.visit_with(self);
+    }
+
+    fn visit_private_method(&mut self, method: &PrivateMethod) {
+        self.method(&method.function, method.span.lo);
+    }
+
+    fn visit_method_prop(&mut self, method: &MethodProp) {
+        let pos = method.key.span().lo.min(method.function.span.lo);
+        self.method(&method.function, pos);
+        method.key.visit_with(self);
+    }
+
+    fn visit_class_prop(&mut self, prop: &ClassProp) {
+        self.prop(prop.type_ann.as_deref(), prop.span.lo, prop.key.span().hi);
+        prop.visit_children_with(self);
+    }
+
+    fn visit_private_prop(&mut self, prop: &PrivateProp) {
+        self.prop(prop.type_ann.as_deref(), prop.span.lo, prop.key.span.hi);
+        prop.visit_children_with(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bare_arrow_param() {
+        let original = "const g = x => x;\n";
+        let completion = "const g = (x: number): number => x;\n";
+        assert_eq!(
+            jsdoc_into(original, completion).unwrap(),
+            "/**\n * @param {number} x\n * @returns {number}\n */\nconst g = x => x;\n"
+        );
+    }
+
+    #[test]
+    fn generics() {
+        let original = "function id(x) {\n  return x;\n}\nconst y = id(1);\n";
+        let completion =
+            "function id<T extends object>(x: T): T {\n  return x;\n}\nconst y: number = id<number>(1);\n";
+        assert_eq!(
+            jsdoc_into(original, completion).unwrap(),
+            "/**\n * @template {object} T\n * @param {T} x\n * @returns {T}\n */\n\
+             function id(x) {\n  return x;\n}\n/** @type {number} */\nconst y = id(1);\n"
+        );
+    }
+
+    #[test]
+    fn missing_semicolons() {
+        let original = "let a = 1\n// a comment\nlet b = a\n";
+        let completion = "let a: number = 1;\nlet b: number = a;\n";
+        assert_eq!(
+            jsdoc_into(original, completion).unwrap(),
+            "/** @type {number} */\nlet a = 1\n// a comment\n/** @type {number} */\nlet b = a\n"
+        );
+    }
+
+    #[test]
+    fn interface_to_typedef() {
+        let original = "let p = { x: 1 };\n";
+        let completion = "interface P {\n  x: number;\n}\nlet p: P = { x: 1 };\n";
+        assert_eq!(
+            jsdoc_into(original, completion).unwrap(),
+            "/** @typedef {{\n  x: number;\n}} P */\n/** @type {P} */\nlet p = { x: 1 };\n"
+        );
+    }
+
+    #[test]
+    fn changed_code() {
+        let original = "let a = 1;\n";
+        let completion = "let a: number = 2;\n";
+        assert_eq!(jsdoc_into(original, completion), None);
+    }
+
+    #[test]
+    fn ts_to_jsdoc_removes_types() {
+        let code = "class A {\n  x?: string;\n  m(y: number): void {}\n}\n";
+        assert_eq!(
+            ts_to_jsdoc(code).unwrap(),
+            "class A {\n  /** @type {string} */\n  x;\n  /**\n   * @param {number} y\n   * @returns {void}\n   */\n  m(y) {}\n}\n"
+        );
+    }
+}
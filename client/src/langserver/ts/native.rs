@@ -0,0 +1,381 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use swc_common::{
+    comments::{Comments, SingleThreadedComments},
+    sync::Lrc,
+    BytePos, FileName, SourceMap, Span, Spanned,
+};
+use swc_ecma_ast::{ArrowExpr, Function, Module, Pat};
+use swc_ecma_parser::{lexer::Lexer, Capturing, Parser, StringInput, Syntax};
+
+use crate::{debug, tree::CodeBlockTree, typedef_gen::ObjectInfoMap};
+
+use super::{
+    super::{
+        AnnotateType, ArcLangServer, FoundProblem, LangServer, LangServerCommands, LangServerError,
+        Normalizer, Splitter,
+    },
+    normalize::ts_normalize,
+    TsServer,
+};
+
+mod printer;
+mod stub;
+mod tree;
+mod weave;
+
+pub use printer::native_print;
+pub use stub::native_stub;
+pub use tree::native_tree;
+pub use weave::{native_weave, native_weave_written};
+
+/// A language server that runs the pure syntax transformations of the TypeScript
+/// language server (`pretty_print`, `stub`, `to_tree` and `weave`) natively on the swc AST
+/// of the code, instead of sending it to the TypeScript compiler. Every other command goes
+/// to the wrapped language server, and so do these when swc can't parse the code.
+///
+/// The TypeScript compiler prints the whole code again after transforming it, while the
+/// native commands edit the code in place, so the formatting and comments of the code are
+/// kept as they are.
+#[derive(Debug)]
+pub struct NativeTsServer {
+    inner: ArcLangServer,
+    /// defaults to false
+    validate_weave: bool,
+}
+
+impl NativeTsServer {
+    /// Wraps the given TypeScript language server.
+    pub fn new(inner: ArcLangServer) -> Self {
+        Self {
+            inner,
+            validate_weave: false,
+        }
+    }
+
+    /// Whether to check every native weave against the weave of the wrapped language
+    /// server, which is the one used when they differ. This validates the native weave on
+    /// a dataset, at the cost of the round trips that it saves.
+    pub fn validate_weave(mut self, validate_weave: bool) -> Self {
+        self.validate_weave = validate_weave;
+        self
+    }
+
+    /// Checks the native weave of the given nettle against the one of the wrapped language
+    /// server, and returns the latter if they differ.
+    async fn validated_weave(
+        &self,
+        original: &str,
+        nettle: &str,
+        level: usize,
+        native: String,
+    ) -> Result<String, LangServerError> {
+        let expected = self.inner.weave(original, nettle, level).await?;
+        // the language server prints the code again, so we compare the types it wove with
+        // ours by weaving its result into the original, which keeps the original's format
+        let rewoven = native_weave_written(original, &expected, 0);
+        let same = match (
+            ts_normalize(&native),
+            rewoven.as_deref().and_then(ts_normalize),
+        ) {
+            (Some(native), Some(rewoven)) => native == rewoven,
+            _ => false,
+        };
+        if same {
+            Ok(native)
+        } else {
+            eprintln!("The native weave differs from the weave of the language server");
+            debug!(
+                "original:\n{original}\nnettle:\n{nettle}\nnative:\n{native}\nexpected:\n{expected}"
+            );
+            Ok(expected)
+        }
+    }
+}
+
+#[async_trait]
+impl LangServer for NativeTsServer {
+    async fn make(server_path: &str) -> Result<Self, LangServerError> {
+        let inner = TsServer::make(server_path).await?;
+        Ok(Self::new(Arc::new(inner)))
+    }
+
+    async fn type_check(&self, code: &str) -> Result<usize, LangServerError> {
+        self.inner.type_check(code).await
+    }
+
+    async fn type_check_js(&self, code: &str) -> Result<usize, LangServerError> {
+        self.inner.type_check_js(code).await
+    }
+
+    async fn check_declarations(
+        &self,
+        declarations: &str,
+        original: &str,
+    ) -> Result<usize, LangServerError> {
+        self.inner.check_declarations(declarations, original).await
+    }
+
+    fn any_type(&self) -> String {
+        self.inner.any_type()
+    }
+
+    fn get_type_parser(&self) -> Option<Box<dyn Fn(&str) -> Option<String> + Sync + Send>> {
+        self.inner.get_type_parser()
+    }
+
+    fn get_normalizer(&self) -> Option<Normalizer> {
+        self.inner.get_normalizer()
+    }
+
+    fn get_splitter(&self) -> Option<Splitter> {
+        self.inner.get_splitter()
+    }
+
+    fn unsupported_commands(&self) -> Vec<&'static str> {
+        self.inner.unsupported_commands()
+    }
+}
+
+#[async_trait]
+impl LangServerCommands for NativeTsServer {
+    async fn pretty_print(
+        &self,
+        code: &str,
+        type_name: &str,
+        types: &[AnnotateType],
+    ) -> Result<String, LangServerError> {
+        match native_print(code, type_name, types) {
+            Some(res) => Ok(res),
+            None => self.inner.pretty_print(code, type_name, types).await,
+        }
+    }
+
+    async fn to_tree(&self, code: &str) -> Result<CodeBlockTree, LangServerError> {
+        match native_tree(code) {
+            Some(res) => Ok(res),
+            None => self.inner.to_tree(code).await,
+        }
+    }
+
+    async fn stub(&self, code: &str) -> Result<String, LangServerError> {
+        match native_stub(code) {
+            Some(res) => Ok(res),
+            None => self.inner.stub(code).await,
+        }
+    }
+
+    async fn check_complete(
+        &self,
+        original: &str,
+        completed: &str,
+    ) -> Result<(Vec<FoundProblem>, u16), LangServerError> {
+        self.inner.check_complete(original, completed).await
+    }
+
+    async fn weave(
+        &self,
+        original: &str,
+        nettle: &str,
+        level: usize,
+    ) -> Result<String, LangServerError> {
+        match native_weave(original, nettle, level) {
+            Some(res) if self.validate_weave => {
+                self.validated_weave(original, nettle, level, res).await
+            }
+            Some(res) => Ok(res),
+            None => self.inner.weave(original, nettle, level).await,
+        }
+    }
+
+    async fn usages(
+        &self,
+        outer_block: &str,
+        inner_block: &str,
+    ) -> Result<(String, usize), LangServerError> {
+        self.inner.usages(outer_block, inner_block).await
+    }
+
+    async fn object_info(&self, code: &str) -> Result<ObjectInfoMap, LangServerError> {
+        self.inner.object_info(code).await
+    }
+
+    async fn typedef_gen(&self, code: &str) -> Result<String, LangServerError> {
+        self.inner.typedef_gen(code).await
+    }
+}
+
+/// Code parsed by swc, along with the spans of its tokens and its comments, which the
+/// native commands use to find where to edit the code.
+struct Source {
+    module: Module,
+    text: String,
+    start: BytePos,
+    // the tokens as the parser saw them, ordered by position
+    tokens: Vec<Span>,
+    comments: SingleThreadedComments,
+}
+
+impl Source {
+    /// Parses the given code, returns None if it doesn't parse.
+    fn parse(code: &str) -> Option<Self> {
+        let cm: Lrc<SourceMap> = Default::default();
+        let fm = cm.new_source_file(FileName::Anon, code.to_string());
+
+        let comments = SingleThreadedComments::default();
+        let lexer = Lexer::new(
+            Syntax::Typescript(Default::default()),
+            Default::default(),
+            StringInput::from(&*fm),
+            Some(&comments),
+        );
+        // the lexer needs the context of the parser to lex some tokens right, like
+        // regular expressions, so we capture the tokens that the parser reads
+        let mut capturing = Capturing::new(lexer);
+        let mut parser = Parser::new_from(capturing.clone());
+        let module = parser.parse_module().ok()?;
+        if !parser.take_errors().is_empty() {
+            return None;
+        }
+        let tokens = Capturing::take(&mut capturing)
+            .into_iter()
+            .map(|tok| tok.span)
+            .collect();
+
+        Some(Self {
+            module,
+            text: fm.src.to_string(),
+            start: fm.start_pos,
+            tokens,
+            comments,
+        })
+    }
+
+    fn offset(&self, pos: BytePos) -> usize {
+        (pos.0 - self.start.0) as usize
+    }
+
+    fn slice(&self, span: Span) -> &str {
+        &self.text[self.offset(span.lo)..self.offset(span.hi)]
+    }
+
+    /// The first token that starts at or after the given position.
+    fn next_token(&self, pos: BytePos) -> Option<Span> {
+        let i = self.tokens.partition_point(|tok| tok.lo < pos);
+        self.tokens.get(i).copied()
+    }
+
+    /// The last token that ends at or before the given position.
+    fn prev_token(&self, pos: BytePos) -> Option<Span> {
+        let i = self.tokens.partition_point(|tok| tok.hi <= pos);
+        i.checked_sub(1).map(|i| self.tokens[i])
+    }
+
+    /// The last token with the given text that ends at or before the given position.
+    fn find_token_before(&self, pos: BytePos, text: &str) -> Option<Span> {
+        let i = self.tokens.partition_point(|tok| tok.hi <= pos);
+        self.tokens[..i]
+            .iter()
+            .rev()
+            .find(|tok| self.slice(**tok) == text)
+            .copied()
+    }
+
+    /// The first token with the given text that starts at or after the given position.
+    fn find_token_after(&self, pos: BytePos, text: &str) -> Option<Span> {
+        let i = self.tokens.partition_point(|tok| tok.lo < pos);
+        self.tokens[i..]
+            .iter()
+            .find(|tok| self.slice(**tok) == text)
+            .copied()
+    }
+
+    /// Skips the token right after the given position if it is one of the given tokens,
+    /// like the `?` of an optional parameter. Returns the position after it.
+    fn skip_token(&self, pos: BytePos, toks: &[&str]) -> BytePos {
+        match self.next_token(pos) {
+            Some(tok) if toks.contains(&self.slice(tok)) => tok.hi,
+            _ => pos,
+        }
+    }
+
+    /// The span of the given node, extended to include its leading comments.
+    fn with_comments(&self, span: Span) -> Span {
+        let lo = self
+            .comments
+            .get_leading(span.lo)
+            .and_then(|comments| comments.iter().map(|c| c.span.lo).min())
+            .unwrap_or(span.lo);
+        Span::new(lo.min(span.lo), span.hi, span.ctxt)
+    }
+
+    /// The position where the type of the given pattern goes, which is before its default
+    /// value and after its `?` if it is optional.
+    fn pat_end(&self, pat: &Pat) -> BytePos {
+        match pat {
+            Pat::Assign(assign) => self.pat_end(&assign.left),
+            _ => self.skip_token(pat.span().hi, &["?"]),
+        }
+    }
+
+    /// The position where the return type of the given function goes, after the
+    /// parentheses of its parameters.
+    fn return_type_pos(&self, func: &Function) -> Option<BytePos> {
+        let end = func.body.as_ref().map_or(func.span.hi, |body| body.span.lo);
+        self.find_token_before(end, ")").map(|paren| paren.hi)
+    }
+
+    /// The position where the return type of the given arrow function goes, right before
+    /// the arrow.
+    fn arrow_return_type_pos(&self, arrow: &ArrowExpr) -> Option<BytePos> {
+        let params_end = arrow.params.last().map_or(arrow.span.lo, |p| p.span().hi);
+        self.find_token_after(params_end, "=>")
+            .and_then(|tok| self.prev_token(tok.lo))
+            .map(|tok| tok.hi)
+    }
+
+    /// Whether the given arrow function has a single parameter without parentheses, which
+    /// needs them to have a type.
+    fn bare_arrow_param(&self, arrow: &ArrowExpr) -> bool {
+        match arrow.params.as_slice() {
+            [param] => self
+                .prev_token(param.span().lo)
+                .is_none_or(|tok| self.slice(tok) != "("),
+            _ => false,
+        }
+    }
+}
+
+/// Edits to make to a source, which are applied all at once so that the spans of the AST
+/// stay valid while collecting them. Edits must not overlap, and edits at the same
+/// position are applied in the order they were made.
+#[derive(Default)]
+struct Edits {
+    edits: Vec<(BytePos, BytePos, String)>,
+}
+
+impl Edits {
+    fn insert(&mut self, pos: BytePos, text: impl Into<String>) {
+        self.edits.push((pos, pos, text.into()));
+    }
+
+    fn replace(&mut self, lo: BytePos, hi: BytePos, text: impl Into<String>) {
+        self.edits.push((lo, hi, text.into()));
+    }
+
+    fn apply(mut self, src: &Source) -> String {
+        // a stable sort, so that insertions at the same position keep their order
+        self.edits.sort_by_key(|(lo, _, _)| *lo);
+        let mut out = String::with_capacity(src.text.len());
+        let mut last = 0;
+        for (lo, hi, text) in self.edits {
+            let (lo, hi) = (src.offset(lo), src.offset(hi));
+            out.push_str(&src.text[last..lo]);
+            out.push_str(&text);
+            last = hi;
+        }
+        out.push_str(&src.text[last..]);
+        out
+    }
+}
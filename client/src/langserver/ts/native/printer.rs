@@ -0,0 +1,355 @@
+use swc_common::{BytePos, Spanned};
+use swc_ecma_ast::{
+    ArrowExpr, ClassMethod, ClassProp, Constructor, Decl, DefaultDecl, ExportDefaultDecl, Expr,
+    FnDecl, FnExpr, Function, MethodKind, MethodProp, ParamOrTsParamProp, Pat, PrivateMethod,
+    PrivateProp, TsFnParam, TsFnType, TsParamPropParam, TsPropertySignature,
+};
+use swc_ecma_visit::{Visit, VisitWith};
+
+use crate::langserver::{
+    native::{fn_param_type_ann, pat_type_ann},
+    AnnotateType,
+};
+
+use super::{Edits, Source};
+
+/// Prints the given code like the `print` command of the TypeScript language server:
+/// every missing type in the given kinds of statements becomes the `type_name` type.
+/// Variables bound to functions are not annotated when function expressions are, their
+/// functions are annotated instead. Returns None if the code doesn't parse.
+pub fn native_print(code: &str, type_name: &str, types: &[AnnotateType]) -> Option<String> {
+    let src = Source::parse(code)?;
+    let mut printer = Printer {
+        src: &src,
+        types,
+        annot: format!(": {type_name}"),
+        edits: Edits::default(),
+    };
+    src.module.visit_with(&mut printer);
+    Some(printer.edits.apply(&src))
+}
+
+/// Inserts the fake type wherever a type is missing, like `typeTraversal` in the
+/// TypeScript language server.
+struct Printer<'a> {
+    src: &'a Source,
+    types: &'a [AnnotateType],
+    // the fake type, with its colon
+    annot: String,
+    edits: Edits,
+}
+
+impl Printer<'_> {
+    fn annotates(&self, kind: AnnotateType) -> bool {
+        self.types.contains(&kind)
+    }
+
+    fn insert_annot(&mut self, pos: BytePos) {
+        self.edits.insert(pos, self.annot.clone());
+    }
+
+    fn pat(&mut self, pat: &Pat) {
+        if pat_type_ann(pat).is_none() {
+            self.insert_annot(self.src.pat_end(pat));
+        }
+    }
+
+    /// Annotates the parameters and the return type of the given function.
+    fn function(&mut self, func: &Function) {
+        for param in &func.params {
+            self.pat(&param.pat);
+        }
+        if func.return_type.is_none() {
+            if let Some(pos) = self.src.return_type_pos(func) {
+                self.insert_annot(pos);
+            }
+        }
+    }
+
+    /// Annotates the given name or key that ends at the given position, after its `?` or
+    /// `!` if it has one.
+    fn name(&mut self, name_end: BytePos) {
+        let end = self.src.skip_token(name_end, &["?", "!"]);
+        self.insert_annot(end);
+    }
+}
+
+impl Visit for Printer<'_> {
+    fn visit_fn_decl(&mut self, decl: &FnDecl) {
+        if self.annotates(AnnotateType::FuncDecl) {
+            self.function(&decl.function);
+        }
+        decl.visit_children_with(self);
+    }
+
+    // for the TypeScript compiler, a named or anonymous default export of a function is a
+    // function declaration, not a function expression
+    fn visit_export_default_decl(&mut self, decl: &ExportDefaultDecl) {
+        match &decl.decl {
+            DefaultDecl::Fn(func) => {
+                if self.annotates(AnnotateType::FuncDecl) {
+                    self.function(&func.function);
+                }
+                func.function.visit_children_with(self);
+            }
+            _ => decl.visit_children_with(self),
+        }
+    }
+
+    fn visit_fn_expr(&mut self, expr: &FnExpr) {
+        if self.annotates(AnnotateType::FuncExpr) {
+            self.function(&expr.function);
+        }
+        expr.visit_children_with(self);
+    }
+
+    fn visit_arrow_expr(&mut self, expr: &ArrowExpr) {
+        if self.annotates(AnnotateType::FuncExpr) {
+            let wrap = self.src.bare_arrow_param(expr) && pat_type_ann(&expr.params[0]).is_none();
+            for param in &expr.params {
+                if wrap {
+                    self.edits.insert(param.span().lo, "(");
+                }
+                self.pat(param);
+                if wrap {
+                    self.edits.insert(param.span().hi, ")");
+                }
+            }
+            if expr.return_type.is_none() {
+                if let Some(pos) = self.src.arrow_return_type_pos(expr) {
+                    self.insert_annot(pos);
+                }
+            }
+        }
+        expr.visit_children_with(self);
+    }
+
+    fn visit_class_method(&mut self, method: &ClassMethod) {
+        if method.kind == MethodKind::Method && self.annotates(AnnotateType::ClassMethod) {
+            self.function(&method.function);
+        }
+        method.visit_children_with(self);
+    }
+
+    fn visit_private_method(&mut self, method: &PrivateMethod) {
+        if method.kind == MethodKind::Method && self.annotates(AnnotateType::ClassMethod) {
+            self.function(&method.function);
+        }
+        method.visit_children_with(self);
+    }
+
+    // methods of object literals are method declarations too
+    fn visit_method_prop(&mut self, method: &MethodProp) {
+        if self.annotates(AnnotateType::ClassMethod) {
+            self.function(&method.function);
+        }
+        method.visit_children_with(self);
+    }
+
+    fn visit_constructor(&mut self, cons: &Constructor) {
+        // no need for return type for constructors
+        if self.annotates(AnnotateType::FuncDecl) {
+            for param in &cons.params {
+                match param {
+                    ParamOrTsParamProp::Param(param) => self.pat(&param.pat),
+                    ParamOrTsParamProp::TsParamProp(prop) => match &prop.param {
+                        TsParamPropParam::Ident(id) if id.type_ann.is_none() => {
+                            self.name(id.id.span.hi);
+                        }
+                        TsParamPropParam::Assign(assign)
+                            if assign.type_ann.is_none()
+                                && pat_type_ann(&assign.left).is_none() =>
+                        {
+                            self.insert_annot(self.src.pat_end(&assign.left));
+                        }
+                        _ => {}
+                    },
+                }
+            }
+        }
+        cons.visit_children_with(self);
+    }
+
+    fn visit_class_prop(&mut self, prop: &ClassProp) {
+        if prop.type_ann.is_none() && self.annotates(AnnotateType::ClassProp) {
+            self.name(prop.key.span().hi);
+        }
+        prop.visit_children_with(self);
+    }
+
+    fn visit_private_prop(&mut self, prop: &PrivateProp) {
+        if prop.type_ann.is_none() && self.annotates(AnnotateType::ClassProp) {
+            self.name(prop.key.span.hi);
+        }
+        prop.visit_children_with(self);
+    }
+
+    // only variable statements are annotated, not the variables of for loops
+    fn visit_decl(&mut self, decl: &Decl) {
+        if let Decl::Var(var) = decl {
+            if self.annotates(AnnotateType::VarDecl) {
+                for declarator in &var.decls {
+                    let bound_fn = matches!(
+                        declarator.init.as_deref(),
+                        Some(Expr::Arrow(_) | Expr::Fn(_))
+                    );
+                    // the function gets annotated instead, the variable keeps no type
+                    if bound_fn && self.annotates(AnnotateType::FuncExpr) {
+                        continue;
+                    }
+                    self.pat(&declarator.name);
+                }
+            }
+        }
+        decl.visit_children_with(self);
+    }
+
+    fn visit_ts_property_signature(&mut self, prop: &TsPropertySignature) {
+        if prop.type_ann.is_none() && self.annotates(AnnotateType::TypeDecl) {
+            let mut key_end = prop.key.span().hi;
+            if prop.computed {
+                if let Some(bracket) = self.src.find_token_after(key_end, "]") {
+                    key_end = bracket.hi;
+                }
+            }
+            self.name(key_end);
+        }
+        prop.visit_children_with(self);
+    }
+
+    fn visit_ts_fn_type(&mut self, func: &TsFnType) {
+        if self.annotates(AnnotateType::FuncExpr) {
+            for param in &func.params {
+                if fn_param_type_ann(param).is_none() {
+                    let end = match param {
+                        TsFnParam::Ident(id) => id.id.span.hi,
+                        _ => param.span().hi,
+                    };
+                    self.name(end);
+                }
+            }
+        }
+        func.visit_children_with(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::langserver::ts::normalize::ts_normalize;
+
+    /// Checks the native printing against the output of the `print` command of the
+    /// TypeScript language server, which prints the code again, so we compare them after
+    /// `ts_normalize`.
+    fn assert_prints_like_ts(code: &str, types: &[AnnotateType], ts_output: &str) {
+        let native = native_print(code, "_hole_", types).unwrap();
+        assert_eq!(
+            ts_normalize(&native).unwrap(),
+            ts_normalize(ts_output).unwrap(),
+            "native print:\n{native}"
+        );
+    }
+
+    #[test]
+    fn bare_arrow_params() {
+        let code = "const f = x => x;\nconst g = async y => y;\n";
+        assert_prints_like_ts(
+            code,
+            &AnnotateType::all(),
+            "const f = (x: _hole_): _hole_ => x;\nconst g = async (y: _hole_): _hole_ => y;\n",
+        );
+        // without function expressions, the variable gets the type instead
+        assert_prints_like_ts(
+            code,
+            &[AnnotateType::VarDecl],
+            "const f: _hole_ = x => x;\nconst g: _hole_ = async y => y;\n",
+        );
+    }
+
+    #[test]
+    fn param_props() {
+        let code = "\
+class A {
+  constructor(public x, private y = 1, readonly z?, w) {}
+}
+";
+        let ts_output = "\
+class A {
+    constructor(public x: _hole_, private y: _hole_ = 1, readonly z?: _hole_, w: _hole_) { }
+}
+";
+        assert_prints_like_ts(code, &AnnotateType::all(), ts_output);
+        // constructors are annotated with the function declarations
+        assert_prints_like_ts(code, &[AnnotateType::ClassMethod], code);
+    }
+
+    #[test]
+    fn destructuring() {
+        let code = "\
+const { a, b: [c] } = obj;
+function f({ x, y }, [z] = [], ...rest) {
+  for (const [k, v] of entries) {}
+}
+";
+        // the variables of for loops can't have types
+        let ts_output = "\
+const { a, b: [c] }: _hole_ = obj;
+function f({ x, y }: _hole_, [z]: _hole_ = [], ...rest: _hole_): _hole_ {
+    for (const [k, v] of entries) { }
+}
+";
+        assert_prints_like_ts(code, &AnnotateType::all(), ts_output);
+    }
+
+    #[test]
+    fn class_members() {
+        let code = "\
+class C {
+  a;
+  b?;
+  c!;
+  static d = 1;
+  m(x) {
+    return x;
+  }
+  get g() {
+    return 1;
+  }
+  set s(v) {}
+}
+";
+        // accessors are not method declarations for the TypeScript compiler
+        let ts_output = "\
+class C {
+    a: _hole_;
+    b?: _hole_;
+    c!: _hole_;
+    static d: _hole_ = 1;
+    m(x: _hole_): _hole_ {
+        return x;
+    }
+    get g() {
+        return 1;
+    }
+    set s(v) { }
+}
+";
+        assert_prints_like_ts(code, &AnnotateType::all(), ts_output);
+        let ts_output = "\
+class C {
+    a;
+    b?;
+    c!;
+    static d = 1;
+    m(x: _hole_): _hole_ {
+        return x;
+    }
+    get g() {
+        return 1;
+    }
+    set s(v) { }
+}
+";
+        assert_prints_like_ts(code, &[AnnotateType::ClassMethod], ts_output);
+    }
+}
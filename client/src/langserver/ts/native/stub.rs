@@ -0,0 +1,316 @@
+use swc_common::{Span, Spanned};
+use swc_ecma_ast::{
+    BlockStmt, BlockStmtOrExpr, CatchClause, ClassMember, Decl, DefaultDecl, Expr, Function,
+    MethodKind, ModuleDecl, Param, Prop, PropOrSpread, Stmt, SwitchCase, TsModuleBlock, VarDecl,
+    VarDeclarator,
+};
+use swc_ecma_visit::{Visit, VisitWith};
+
+use super::{Edits, Source};
+
+/// Stubs the given code like the `stub` command of the TypeScript language server:
+/// - functions declared one level deep lose their body,
+/// - methods two or three levels deep lose their body,
+/// - functions bound to variables more than three levels deep get an empty body, if they
+///   have a return type.
+///
+/// The levels are the depths in the AST of the TypeScript compiler, where the statements
+/// of the code are at level 0. Returns None if the code doesn't parse.
+pub fn native_stub(code: &str) -> Option<String> {
+    let src = Source::parse(code)?;
+    let mut stubber = Stubber {
+        src: &src,
+        depth: 0,
+        edits: Edits::default(),
+    };
+    src.module.visit_with(&mut stubber);
+    Some(stubber.edits.apply(&src))
+}
+
+/// Walks the AST while keeping track of the depth that each node would have in the AST of
+/// the TypeScript compiler, which has some nodes that swc doesn't have (like variable
+/// declaration lists), and the other way around (like `Function`).
+struct Stubber<'a> {
+    src: &'a Source,
+    // the number of TypeScript nodes we are in
+    depth: usize,
+    edits: Edits,
+}
+
+impl Stubber<'_> {
+    /// Visits the children of a node that is a node for the TypeScript compiler too.
+    fn nested(&mut self, levels: usize, f: impl FnOnce(&mut Self)) {
+        self.depth += levels;
+        f(self);
+        self.depth -= levels;
+    }
+
+    /// The level of the node we are in.
+    fn level(&self) -> usize {
+        self.depth.saturating_sub(1)
+    }
+
+    /// Removes the given body, ending the declaration with a semicolon instead.
+    fn remove_body(&mut self, body: Span) {
+        // the whitespace before the body goes too
+        let lo = self.src.prev_token(body.lo).map_or(body.lo, |tok| tok.hi);
+        self.edits.replace(lo, body.hi, ";");
+    }
+
+    /// Visits a function, unless its body was removed.
+    fn function(&mut self, func: &Function, stub: bool) {
+        match &func.body {
+            Some(body) if stub => self.remove_body(body.span),
+            _ => func.visit_children_with(self),
+        }
+    }
+
+    /// Stubs a function declaration, which the TypeScript compiler only does one level
+    /// deep.
+    fn fn_decl(&mut self, func: &Function) {
+        let stub = self.level() == 1;
+        self.function(func, stub);
+    }
+
+    /// Stubs a method declaration, which the TypeScript compiler only does two or three
+    /// levels deep.
+    fn method(&mut self, func: &Function, kind: MethodKind) {
+        let stub = kind == MethodKind::Method && (2..=3).contains(&self.level());
+        self.function(func, stub);
+    }
+}
+
+impl Visit for Stubber<'_> {
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            // the block is counted by itself
+            Stmt::Block(block) => block.visit_with(self),
+            Stmt::Decl(Decl::Fn(decl)) => self.nested(1, |s| s.fn_decl(&decl.function)),
+            _ => self.nested(1, |s| stmt.visit_children_with(s)),
+        }
+    }
+
+    fn visit_module_decl(&mut self, decl: &ModuleDecl) {
+        // exporting is a modifier of the declaration for the TypeScript compiler
+        self.nested(1, |s| match decl {
+            ModuleDecl::ExportDecl(export) => match &export.decl {
+                Decl::Fn(func) => s.fn_decl(&func.function),
+                _ => export.decl.visit_children_with(s),
+            },
+            ModuleDecl::ExportDefaultDecl(export) => match &export.decl {
+                DefaultDecl::Fn(func) => s.fn_decl(&func.function),
+                _ => export.decl.visit_children_with(s),
+            },
+            _ => decl.visit_children_with(s),
+        });
+    }
+
+    fn visit_block_stmt(&mut self, block: &BlockStmt) {
+        self.nested(1, |s| block.visit_children_with(s));
+    }
+
+    // the statement of the variables, which we are already in when it is a statement, and
+    // the list of the variables
+    fn visit_var_decl(&mut self, var: &VarDecl) {
+        self.nested(1, |s| var.visit_children_with(s));
+    }
+
+    fn visit_var_declarator(&mut self, declarator: &VarDeclarator) {
+        self.nested(1, |s| {
+            declarator.name.visit_with(s);
+            let Some(init) = &declarator.init else {
+                return;
+            };
+            // the function is a level below the declaration
+            let stub = s.level() + 1 > 3;
+            let body = match &**init {
+                Expr::Arrow(arrow) if stub && arrow.return_type.is_some() => {
+                    Some(arrow.body.span())
+                }
+                Expr::Fn(func) if stub && func.function.return_type.is_some() => {
+                    func.function.body.as_ref().map(|body| body.span)
+                }
+                _ => None,
+            };
+            match body {
+                Some(body) => s.edits.replace(body.lo, body.hi, "{ }"),
+                None => init.visit_with(s),
+            }
+        });
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        self.nested(1, |s| expr.visit_children_with(s));
+    }
+
+    fn visit_block_stmt_or_expr(&mut self, body: &BlockStmtOrExpr) {
+        // both are counted by themselves
+        body.visit_children_with(self);
+    }
+
+    fn visit_param(&mut self, param: &Param) {
+        self.nested(1, |s| param.visit_children_with(s));
+    }
+
+    fn visit_class_member(&mut self, member: &ClassMember) {
+        self.nested(1, |s| match member {
+            ClassMember::Method(method) => s.method(&method.function, method.kind),
+            ClassMember::PrivateMethod(method) => s.method(&method.function, method.kind),
+            _ => member.visit_children_with(s),
+        });
+    }
+
+    fn visit_prop_or_spread(&mut self, prop: &PropOrSpread) {
+        self.nested(1, |s| match prop {
+            PropOrSpread::Prop(prop) => match &**prop {
+                Prop::Method(method) => s.method(&method.function, MethodKind::Method),
+                _ => prop.visit_children_with(s),
+            },
+            PropOrSpread::Spread(spread) => spread.visit_children_with(s),
+        });
+    }
+
+    fn visit_catch_clause(&mut self, clause: &CatchClause) {
+        self.nested(1, |s| clause.visit_children_with(s));
+    }
+
+    // the TypeScript compiler has a node for the block of the cases, and one for each case
+    fn visit_switch_case(&mut self, case: &SwitchCase) {
+        self.nested(2, |s| case.visit_children_with(s));
+    }
+
+    fn visit_ts_module_block(&mut self, block: &TsModuleBlock) {
+        self.nested(1, |s| block.visit_children_with(s));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::langserver::ts::normalize::ts_normalize;
+
+    /// Checks the native stubbing against the output of the `stub` command of the
+    /// TypeScript language server, which prints the code again, so we compare them after
+    /// `ts_normalize`.
+    fn assert_stubs_like_ts(code: &str, ts_output: &str) {
+        let native = native_stub(code).unwrap();
+        assert_eq!(
+            ts_normalize(&native).unwrap(),
+            ts_normalize(ts_output).unwrap(),
+            "native stub:\n{native}"
+        );
+    }
+
+    #[test]
+    fn fn_decls() {
+        // only the function in the block is one level deep, the function in the body of
+        // `outer` is two levels deep
+        let code = "\
+function outer() {
+  function inner() {
+    return 1;
+  }
+  return inner();
+}
+{
+  function f() {
+    return 2;
+  }
+}
+";
+        let ts_output = "\
+function outer() {
+    function inner() {
+        return 1;
+    }
+    return inner();
+}
+{
+    function f();
+}
+";
+        assert_stubs_like_ts(code, ts_output);
+    }
+
+    #[test]
+    fn methods() {
+        // the methods of a top-level class are one level deep, the ones of a class in a
+        // function three levels deep, and the ones of an object literal in a call three
+        // levels deep too
+        let code = "\
+class Top {
+  m() {
+    return 1;
+  }
+}
+function make() {
+  class A {
+    constructor() {
+      this.x = 1;
+    }
+    m() {
+      return 1;
+    }
+    get g() {
+      return 2;
+    }
+  }
+  return new A();
+}
+register({
+  run() {
+    return 3;
+  }
+});
+";
+        let ts_output = "\
+class Top {
+    m() {
+        return 1;
+    }
+}
+function make() {
+    class A {
+        constructor() {
+            this.x = 1;
+        }
+        m();
+        get g() {
+            return 2;
+        }
+    }
+    return new A();
+}
+register({
+    run();
+});
+";
+        assert_stubs_like_ts(code, ts_output);
+    }
+
+    #[test]
+    fn bound_fns() {
+        // only functions with a return type more than three levels deep get an empty body
+        let code = "\
+const top = (): number => 1;
+function outer() {
+  const typed = (x: number): number => x + 1;
+  const untyped = (x) => x + 1;
+  const expr = function (): string {
+    return \"\";
+  };
+  let later;
+}
+";
+        let ts_output = "\
+const top = (): number => 1;
+function outer() {
+    const typed = (x: number): number => { };
+    const untyped = (x) => x + 1;
+    const expr = function (): string { };
+    let later;
+}
+";
+        assert_stubs_like_ts(code, ts_output);
+    }
+}
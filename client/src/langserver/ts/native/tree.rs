@@ -0,0 +1,328 @@
+use std::collections::HashSet;
+
+use rand::Rng;
+use swc_common::Span;
+use swc_ecma_ast::{
+    BlockStmt, BlockStmtOrExpr, ClassDecl, Decl, DefaultDecl, ExportDefaultDecl, Expr, FnDecl,
+    ModuleDecl, ModuleItem, Pat, Stmt, VarDecl, VarDeclarator,
+};
+use swc_ecma_visit::{Visit, VisitWith};
+
+use crate::tree::CodeBlockTree;
+
+use super::Source;
+
+/// Makes the code block tree of the given code like the `tree` command of the TypeScript
+/// language server:
+/// - named functions and classes, and functions with a body bound to variables, are code
+///   blocks, whose children are the code blocks in their body,
+/// - the other typable top-level statements (variables, interfaces and anonymous
+///   functions) are code blocks named "topnode", and adjacent ones are joined into one.
+///
+/// The code of a code block includes its leading comments. Returns None if the code
+/// doesn't parse.
+pub fn native_tree(code: &str) -> Option<CodeBlockTree> {
+    let src = Source::parse(code)?;
+    let mut builder = TreeBuilder {
+        src: &src,
+        used: HashSet::new(),
+        parents: vec![CodeBlockTree {
+            // NOTE: the & is to make sure we don't have a name collision with some other function
+            name: "&root$".to_string(),
+            code: code.to_string(),
+            children: vec![],
+        }],
+    };
+    for item in &src.module.body {
+        builder.top_item(item);
+    }
+    builder.parents.pop()
+}
+
+struct TreeBuilder<'a> {
+    src: &'a Source,
+    // the numbers used by the generated names
+    used: HashSet<u32>,
+    // the code blocks we are in, the innermost last
+    parents: Vec<CodeBlockTree>,
+}
+
+impl TreeBuilder<'_> {
+    /// Generates a unique name with the given prefix, like `prefix$0`.
+    fn symgen(&mut self, prefix: &str) -> String {
+        let mut n = 0;
+        while self.used.contains(&n) {
+            n = rand::thread_rng().gen_range(0..1000000);
+        }
+        self.used.insert(n);
+        format!("{prefix}${n}")
+    }
+
+    /// The code of the node with the given span, with its leading comments.
+    fn code(&self, span: Span) -> String {
+        self.src.slice(self.src.with_comments(span)).to_string()
+    }
+
+    /// The code of the given variables, without the semicolon, like the TypeScript compiler
+    /// prints a variable declaration list.
+    fn var_code(&self, var: &VarDecl) -> String {
+        let code = self.code(var.span);
+        match code.strip_suffix(';') {
+            Some(code) => code.trim_end().to_string(),
+            None => code,
+        }
+    }
+
+    /// Makes a code block, whose children are the code blocks found by `f`.
+    fn block(&mut self, name: String, code: String, f: impl FnOnce(&mut Self)) {
+        self.parents.push(CodeBlockTree {
+            name,
+            code,
+            children: vec![],
+        });
+        f(self);
+        let block = self.parents.pop().unwrap();
+        self.parents.last_mut().unwrap().children.push(block);
+    }
+
+    /// Makes a top-level code block, or joins it with the previous one if they are
+    /// adjacent.
+    fn top_block(&mut self, code: String) {
+        let name = self.symgen("topnode");
+        let parent = self.parents.last_mut().unwrap();
+        match parent.children.last_mut() {
+            Some(last) if last.name.starts_with("topnode") => {
+                last.code.push('\n');
+                last.code.push_str(&code);
+            }
+            _ => parent.children.push(CodeBlockTree {
+                name,
+                code,
+                children: vec![],
+            }),
+        }
+    }
+
+    fn top_item(&mut self, item: &ModuleItem) {
+        match item {
+            ModuleItem::Stmt(Stmt::Decl(Decl::Var(var))) => self.var_decl(var, true),
+            ModuleItem::ModuleDecl(ModuleDecl::ExportDecl(export)) => match &export.decl {
+                Decl::Var(var) => self.var_decl(var, true),
+                Decl::TsInterface(_) => self.top_block(self.code(export.span)),
+                Decl::Fn(func) => self.fn_decl(func, export.span),
+                Decl::Class(class) => self.class_decl(class, export.span),
+                _ => item.visit_with(self),
+            },
+            ModuleItem::Stmt(Stmt::Decl(Decl::TsInterface(interface))) => {
+                self.top_block(self.code(interface.span));
+            }
+            // an anonymous function, which is typable
+            ModuleItem::ModuleDecl(ModuleDecl::ExportDefaultDecl(ExportDefaultDecl {
+                span,
+                decl: DefaultDecl::Fn(func),
+            })) if func.ident.is_none() => {
+                self.top_block(self.code(*span));
+                func.visit_with(self);
+            }
+            _ => item.visit_with(self),
+        }
+    }
+
+    fn fn_decl(&mut self, func: &FnDecl, span: Span) {
+        let name = self.symgen(&func.ident.sym);
+        self.block(name, self.code(span), |b| {
+            if let Some(body) = &func.function.body {
+                body.stmts.visit_with(b);
+            }
+        });
+    }
+
+    fn class_decl(&mut self, class: &ClassDecl, span: Span) {
+        let name = self.symgen(&class.ident.sym);
+        self.block(name, self.code(span), |b| class.class.body.visit_with(b));
+    }
+
+    /// Makes the code blocks of the given variables. Top-level variables are typable,
+    /// unless they are bound to a function with a body.
+    fn var_decl(&mut self, var: &VarDecl, at_top: bool) {
+        for declarator in &var.decls {
+            let body = match declarator.init.as_deref() {
+                Some(Expr::Arrow(arrow)) => match &arrow.body {
+                    BlockStmtOrExpr::BlockStmt(body) => Some(body),
+                    BlockStmtOrExpr::Expr(_) => None,
+                },
+                Some(Expr::Fn(func)) => func.function.body.as_ref(),
+                _ => None,
+            };
+            match body {
+                Some(body) => self.var_fn(var, declarator, body),
+                None => {
+                    if at_top {
+                        self.top_block(self.var_code(var));
+                    }
+                    declarator.visit_with(self);
+                }
+            }
+        }
+    }
+
+    /// Makes the code block of a function bound to the given variable.
+    fn var_fn(&mut self, var: &VarDecl, declarator: &VarDeclarator, body: &BlockStmt) {
+        let name = match &declarator.name {
+            Pat::Ident(id) => id.id.sym.to_string(),
+            // the TypeScript compiler doesn't have a name for patterns either
+            _ => "undefined".to_string(),
+        };
+        let name = self.symgen(&name);
+        self.block(name, self.var_code(var), |b| body.stmts.visit_with(b));
+    }
+}
+
+impl Visit for TreeBuilder<'_> {
+    fn visit_fn_decl(&mut self, func: &FnDecl) {
+        self.fn_decl(func, func.function.span);
+    }
+
+    fn visit_class_decl(&mut self, class: &ClassDecl) {
+        self.class_decl(class, class.class.span);
+    }
+
+    fn visit_export_default_decl(&mut self, export: &ExportDefaultDecl) {
+        match &export.decl {
+            DefaultDecl::Fn(func) if func.ident.is_some() => {
+                let name = self.symgen(&func.ident.as_ref().unwrap().sym);
+                self.block(name, self.code(export.span), |b| {
+                    if let Some(body) = &func.function.body {
+                        body.stmts.visit_with(b);
+                    }
+                });
+            }
+            DefaultDecl::Class(class) if class.ident.is_some() => {
+                let name = self.symgen(&class.ident.as_ref().unwrap().sym);
+                self.block(name, self.code(export.span), |b| {
+                    class.class.body.visit_with(b)
+                });
+            }
+            _ => export.visit_children_with(self),
+        }
+    }
+
+    fn visit_var_decl(&mut self, var: &VarDecl) {
+        self.var_decl(var, false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::langserver::ts::normalize::ts_normalize;
+
+    /// The code blocks of the tree in pre-order, as their depth, their name without the
+    /// generated number, and their code after `ts_normalize`. The TypeScript language
+    /// server prints the code of the blocks again, so we compare them normalized.
+    fn blocks(tree: &CodeBlockTree) -> Vec<(usize, String, String)> {
+        fn walk(tree: &CodeBlockTree, depth: usize, out: &mut Vec<(usize, String, String)>) {
+            let (name, _) = tree.name.rsplit_once('$').unwrap();
+            out.push((depth, name.to_string(), ts_normalize(&tree.code).unwrap()));
+            for child in &tree.children {
+                walk(child, depth + 1, out);
+            }
+        }
+        let mut out = vec![];
+        walk(tree, 0, &mut out);
+        out
+    }
+
+    /// Checks the native tree against the output of the `tree` command of the TypeScript
+    /// language server, given as the depth, name and code of its blocks in pre-order.
+    fn assert_tree_like_ts(code: &str, ts_output: &[(usize, &str, &str)]) {
+        let native = native_tree(code).unwrap();
+        let expected: Vec<_> = ts_output
+            .iter()
+            .map(|(depth, name, code)| (*depth, name.to_string(), ts_normalize(code).unwrap()))
+            .collect();
+        assert_eq!(blocks(&native), expected);
+    }
+
+    #[test]
+    fn top_nodes() {
+        let code = "\
+interface P {
+  x: number;
+}
+const a = 1, b = 2;
+function f(x) {
+  return x;
+}
+const { p, q } = obj;
+const k = () => 1;
+export default function () {
+  return 1;
+}
+";
+        // every declarator adds the whole list of variables, and adjacent top nodes are
+        // joined
+        assert_tree_like_ts(
+            code,
+            &[
+                (0, "&root", code),
+                (
+                    1,
+                    "topnode",
+                    "interface P {\n    x: number;\n}\nconst a = 1, b = 2\nconst a = 1, b = 2",
+                ),
+                (1, "f", "function f(x) {\n    return x;\n}"),
+                (
+                    1,
+                    "topnode",
+                    "const { p, q } = obj\nconst k = () => 1\n\
+                     export default function () {\n    return 1;\n}",
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn nested_blocks() {
+        let code = "\
+function f(x) {
+  const g = (y) => {
+    return y;
+  };
+  const h = (y) => y;
+  return g(h(x));
+}
+class C {
+  p = 1;
+  m() {
+    const k = function () {
+      return 1;
+    };
+    return k();
+  }
+}
+";
+        // the code of a bound function is the list of its variables, and the functions of
+        // methods are children of the class
+        assert_tree_like_ts(
+            code,
+            &[
+                (0, "&root", code),
+                (
+                    1,
+                    "f",
+                    "function f(x) {\n    const g = (y) => {\n        return y;\n    };\n    \
+                     const h = (y) => y;\n    return g(h(x));\n}",
+                ),
+                (2, "g", "const g = (y) => {\n    return y;\n}"),
+                (
+                    1,
+                    "C",
+                    "class C {\n    p = 1;\n    m() {\n        const k = function () {\n            \
+                     return 1;\n        };\n        return k();\n    }\n}",
+                ),
+                (2, "k", "const k = function () {\n    return 1;\n}"),
+            ],
+        );
+    }
+}
@@ -0,0 +1,747 @@
+use std::collections::HashMap;
+
+use swc_common::{BytePos, Spanned};
+use swc_ecma_ast::{
+    ArrowExpr, ClassDecl, ClassMethod, ClassProp, Constructor, DefaultDecl, ExportDefaultDecl,
+    Expr, FnDecl, ForInStmt, ForOfStmt, Function, MethodKind, MethodProp, ParamOrTsParamProp, Pat,
+    PrivateMethod, TsParamPropParam, TsTypeAnn, TsTypeParamDecl, VarDeclarator,
+};
+use swc_ecma_visit::{Visit, VisitWith};
+
+use crate::langserver::native::pat_type_ann;
+
+use super::{Edits, Source};
+
+/// Weaves the types of the `nettle` code into the `original` code like the `weave` command
+/// of the TypeScript language server: the types of the variables, class properties,
+/// functions, methods and constructors of the nettle are put on the declarations of the
+/// original with the same name and scope. The nettle is the original, or a part of it, with
+/// types; `level` is how deep in the original the nettle is, the scopes of the original
+/// only start at that level.
+///
+/// The TypeScript language server weaves the types that its type checker infers, while we
+/// can only weave the types written in the nettle. So that the result is the same, we only
+/// weave nettles where every declaration that it would weave has its types written.
+/// Returns None if either code doesn't parse, or if the nettle has a declaration without
+/// its types.
+pub fn native_weave(original: &str, nettle: &str, level: usize) -> Option<String> {
+    weave(original, nettle, level, true)
+}
+
+/// Weaves the types written in the nettle like `native_weave`, even if some declarations of
+/// the nettle have no types. Returns None if either code doesn't parse.
+pub fn native_weave_written(original: &str, nettle: &str, level: usize) -> Option<String> {
+    weave(original, nettle, level, false)
+}
+
+fn weave(original: &str, nettle: &str, level: usize, all_typed: bool) -> Option<String> {
+    let nettle = Source::parse(nettle)?;
+    let original = Source::parse(original)?;
+
+    let mut collector = TypeCollector {
+        src: &nettle,
+        scope: String::new(),
+        types: HashMap::new(),
+        untyped: false,
+    };
+    nettle.module.visit_with(&mut collector);
+    if all_typed && collector.untyped {
+        return None;
+    }
+
+    let mut weaver = Weaver {
+        src: &original,
+        types: collector.types,
+        scope: String::new(),
+        level: 0,
+        nettle_level: level,
+        edits: Edits::default(),
+    };
+    original.module.visit_with(&mut weaver);
+    Some(weaver.edits.apply(&original))
+}
+
+/// The types of a declaration in the nettle, as code.
+#[derive(Clone)]
+enum Woven {
+    /// The type of a variable or a class property.
+    Type(String),
+    /// The signature of a function.
+    Sig(Signature),
+}
+
+/// The types of a function, where the missing ones are None.
+#[derive(Clone)]
+struct Signature {
+    type_params: Option<String>,
+    params: Vec<Option<String>>,
+    ret: Option<String>,
+}
+
+/// The name of a variable, or its code if it is a pattern.
+fn var_name(src: &Source, declarator: &VarDeclarator) -> String {
+    match &declarator.name {
+        Pat::Ident(id) => id.id.sym.to_string(),
+        pat => src.slice(pat.span()).to_string(),
+    }
+}
+
+/// The function bound to the given variable, if any.
+enum BoundFn<'a> {
+    Fn(&'a Function),
+    Arrow(&'a ArrowExpr),
+}
+
+fn bound_fn(declarator: &VarDeclarator) -> Option<BoundFn<'_>> {
+    match declarator.init.as_deref()? {
+        Expr::Fn(func) => Some(BoundFn::Fn(&func.function)),
+        Expr::Arrow(arrow) => Some(BoundFn::Arrow(arrow)),
+        _ => None,
+    }
+}
+
+/// Collects the types of the nettle, like `buildTypeMap` in the TypeScript language server.
+/// The types are keyed by the scope and the name of their declaration, where the scope is
+/// the names of the functions and classes that the declaration is in, each followed by `$`.
+struct TypeCollector<'a> {
+    src: &'a Source,
+    scope: String,
+    types: HashMap<String, Woven>,
+    /// whether a declaration is missing a type, that the type checker would infer
+    untyped: bool,
+}
+
+impl TypeCollector<'_> {
+    fn type_code(&self, ann: Option<&TsTypeAnn>) -> Option<String> {
+        ann.map(|ann| self.src.slice(ann.type_ann.span()).to_string())
+    }
+
+    fn type_params_code(&self, params: Option<&TsTypeParamDecl>) -> Option<String> {
+        params.map(|params| self.src.slice(params.span).to_string())
+    }
+
+    fn sig(&self, func: &Function) -> Woven {
+        Woven::Sig(Signature {
+            type_params: self.type_params_code(func.type_params.as_deref()),
+            params: func
+                .params
+                .iter()
+                .map(|param| self.type_code(pat_type_ann(&param.pat)))
+                .collect(),
+            ret: self.type_code(func.return_type.as_deref()),
+        })
+    }
+
+    fn arrow_sig(&self, arrow: &ArrowExpr) -> Woven {
+        Woven::Sig(Signature {
+            type_params: self.type_params_code(arrow.type_params.as_deref()),
+            params: arrow
+                .params
+                .iter()
+                .map(|param| self.type_code(pat_type_ann(param)))
+                .collect(),
+            ret: self.type_code(arrow.return_type.as_deref()),
+        })
+    }
+
+    fn add(&mut self, name: &str, woven: Woven) {
+        if let Woven::Sig(sig) = &woven {
+            // constructors have no return type
+            let ret_typed = sig.ret.is_some() || name == "__constructor__";
+            if !ret_typed || sig.params.iter().any(Option::is_none) {
+                self.untyped = true;
+            }
+        }
+        self.types.insert(format!("{}{name}", self.scope), woven);
+    }
+
+    /// Visits the children of a declaration in the scope of the given name.
+    fn scoped(&mut self, name: &str, f: impl FnOnce(&mut Self)) {
+        let len = self.scope.len();
+        self.scope.push_str(name);
+        self.scope.push('$');
+        f(self);
+        self.scope.truncate(len);
+    }
+
+    fn fn_decl(&mut self, name: &str, func: &Function) {
+        let sig = self.sig(func);
+        self.add(name, sig);
+        self.scoped(name, |c| func.visit_children_with(c));
+    }
+}
+
+impl Visit for TypeCollector<'_> {
+    // the variables of for-of loops can't have types, the type checker skips them too
+    fn visit_for_of_stmt(&mut self, stmt: &ForOfStmt) {
+        stmt.right.visit_with(self);
+        stmt.body.visit_with(self);
+    }
+
+    fn visit_var_declarator(&mut self, declarator: &VarDeclarator) {
+        let name = var_name(self.src, declarator);
+        match self.type_code(pat_type_ann(&declarator.name)) {
+            Some(ty) => self.add(&name, Woven::Type(ty)),
+            // only the variables with a name get a type from the type checker
+            None if declarator.name.is_ident() && bound_fn(declarator).is_none() => {
+                self.untyped = true
+            }
+            None => {}
+        }
+        match bound_fn(declarator) {
+            // the signature of the function replaces the type of the variable
+            Some(BoundFn::Fn(func)) => {
+                let sig = self.sig(func);
+                self.add(&name, sig);
+                self.scoped(&name, |c| func.visit_children_with(c));
+            }
+            Some(BoundFn::Arrow(arrow)) => {
+                let sig = self.arrow_sig(arrow);
+                self.add(&name, sig);
+                self.scoped(&name, |c| arrow.visit_children_with(c));
+            }
+            None => declarator.visit_children_with(self),
+        }
+    }
+
+    fn visit_class_prop(&mut self, prop: &ClassProp) {
+        if prop.key.is_ident() {
+            match self.type_code(prop.type_ann.as_deref()) {
+                Some(ty) => {
+                    let name = self.src.slice(prop.key.span()).to_string();
+                    self.add(&name, Woven::Type(ty));
+                }
+                None => self.untyped = true,
+            }
+        }
+        prop.visit_children_with(self);
+    }
+
+    fn visit_constructor(&mut self, cons: &Constructor) {
+        let params = cons
+            .params
+            .iter()
+            .map(|param| match param {
+                ParamOrTsParamProp::Param(param) => self.type_code(pat_type_ann(&param.pat)),
+                ParamOrTsParamProp::TsParamProp(prop) => match &prop.param {
+                    TsParamPropParam::Ident(id) => self.type_code(id.type_ann.as_deref()),
+                    TsParamPropParam::Assign(assign) => self.type_code(
+                        assign
+                            .type_ann
+                            .as_deref()
+                            .or_else(|| pat_type_ann(&assign.left)),
+                    ),
+                },
+            })
+            .collect();
+        self.add(
+            "__constructor__",
+            Woven::Sig(Signature {
+                type_params: None,
+                params,
+                ret: None,
+            }),
+        );
+        self.scoped("__constructor__", |c| cons.visit_children_with(c));
+    }
+
+    fn visit_fn_decl(&mut self, decl: &FnDecl) {
+        self.fn_decl(&decl.ident.sym, &decl.function);
+    }
+
+    fn visit_export_default_decl(&mut self, decl: &ExportDefaultDecl) {
+        match &decl.decl {
+            DefaultDecl::Fn(func) if func.ident.is_some() => {
+                self.fn_decl(&func.ident.as_ref().unwrap().sym, &func.function);
+            }
+            DefaultDecl::Class(class) if class.ident.is_some() => {
+                let name = class.ident.as_ref().unwrap().sym.to_string();
+                self.scoped(&name, |c| class.class.visit_with(c));
+            }
+            _ => decl.visit_children_with(self),
+        }
+    }
+
+    // methods don't have a scope of their own
+    fn visit_class_method(&mut self, method: &ClassMethod) {
+        if method.kind == MethodKind::Method {
+            let sig = self.sig(&method.function);
+            self.add(self.src.slice(method.key.span()), sig);
+        }
+        method.visit_children_with(self);
+    }
+
+    fn visit_private_method(&mut self, method: &PrivateMethod) {
+        if method.kind == MethodKind::Method {
+            let sig = self.sig(&method.function);
+            self.add(self.src.slice(method.key.span), sig);
+        }
+        method.visit_children_with(self);
+    }
+
+    fn visit_method_prop(&mut self, method: &MethodProp) {
+        let sig = self.sig(&method.function);
+        self.add(self.src.slice(method.key.span()), sig);
+        method.visit_children_with(self);
+    }
+
+    fn visit_class_decl(&mut self, class: &ClassDecl) {
+        self.scoped(&class.ident.sym, |c| class.class.visit_with(c));
+    }
+}
+
+/// Puts the collected types on the original, like `weaveNode` in the TypeScript language
+/// server. Functions, functions bound to variables and classes are one level deeper, and
+/// the scope only starts at the level of the nettle.
+struct Weaver<'a> {
+    src: &'a Source,
+    types: HashMap<String, Woven>,
+    scope: String,
+    level: usize,
+    nettle_level: usize,
+    edits: Edits,
+}
+
+impl Weaver<'_> {
+    fn get(&self, name: &str) -> Option<Woven> {
+        self.types.get(&format!("{}{name}", self.scope)).cloned()
+    }
+
+    /// Visits the children of a declaration one level deeper, and in the scope of the
+    /// given name if we are as deep as the nettle.
+    fn nested(&mut self, name: &str, f: impl FnOnce(&mut Self)) {
+        let len = self.scope.len();
+        if self.level >= self.nettle_level {
+            self.scope.push_str(name);
+            self.scope.push('$');
+        }
+        self.level += 1;
+        f(self);
+        self.level -= 1;
+        self.scope.truncate(len);
+    }
+
+    /// Puts the given type on a node, replacing its type annotation if it has one, or
+    /// inserting it at the given position otherwise.
+    fn put_type(&mut self, ann: Option<&TsTypeAnn>, pos: BytePos, ty: &str) {
+        match ann {
+            Some(ann) => self
+                .edits
+                .replace(ann.span.lo, ann.span.hi, format!(": {ty}")),
+            None => self.edits.insert(pos, format!(": {ty}")),
+        }
+    }
+
+    fn put_pat_type(&mut self, pat: &Pat, ty: &str) {
+        let pos = self.src.pat_end(pat);
+        self.put_type(pat_type_ann(pat), pos, ty);
+    }
+
+    /// Puts the given type parameters on a function, replacing its own or inserting them
+    /// at the given position, which is the parenthesis of its parameters.
+    fn put_type_params(
+        &mut self,
+        params: Option<&TsTypeParamDecl>,
+        pos: Option<BytePos>,
+        woven: &Option<String>,
+    ) {
+        match (params, pos, woven) {
+            (Some(params), _, Some(ty)) => self.edits.replace(params.span.lo, params.span.hi, ty),
+            (None, Some(pos), Some(ty)) => self.edits.insert(pos, ty),
+            _ => {}
+        }
+    }
+
+    /// Puts the given signature on a function whose name or key ends at the given
+    /// position.
+    fn put_sig(&mut self, func: &Function, name_end: BytePos, sig: &Signature) {
+        let paren = self.src.find_token_after(name_end, "(").map(|tok| tok.lo);
+        self.put_type_params(func.type_params.as_deref(), paren, &sig.type_params);
+        for (param, ty) in func.params.iter().zip(&sig.params) {
+            if let Some(ty) = ty {
+                self.put_pat_type(&param.pat, ty);
+            }
+        }
+        if let (Some(ty), Some(pos)) = (&sig.ret, self.src.return_type_pos(func)) {
+            self.put_type(func.return_type.as_deref(), pos, ty);
+        }
+    }
+
+    fn put_arrow_sig(&mut self, arrow: &ArrowExpr, sig: &Signature) {
+        let bare = self.src.bare_arrow_param(arrow);
+        let paren = match arrow.params.first() {
+            Some(param) if bare => Some(param.span().lo),
+            Some(param) => self.src.prev_token(param.span().lo).map(|tok| tok.lo),
+            None => self
+                .src
+                .find_token_after(arrow.span.lo, "(")
+                .map(|tok| tok.lo),
+        };
+        self.put_type_params(arrow.type_params.as_deref(), paren, &sig.type_params);
+        for (param, ty) in arrow.params.iter().zip(&sig.params) {
+            if let Some(ty) = ty {
+                // a single parameter without parentheses needs them to have a type
+                if bare {
+                    self.edits.insert(param.span().lo, "(");
+                }
+                self.put_pat_type(param, ty);
+                if bare {
+                    self.edits.insert(param.span().hi, ")");
+                }
+            }
+        }
+        if let (Some(ty), Some(pos)) = (&sig.ret, self.src.arrow_return_type_pos(arrow)) {
+            self.put_type(arrow.return_type.as_deref(), pos, ty);
+        }
+    }
+
+    /// Puts the signature of a method, which has no scope of its own.
+    fn method(&mut self, name: &str, func: &Function, name_end: BytePos) {
+        if let Some(Woven::Sig(sig)) = self.get(name) {
+            self.put_sig(func, name_end, &sig);
+        }
+    }
+
+    fn fn_decl(&mut self, name: &str, func: &Function, name_end: BytePos) {
+        if let Some(Woven::Sig(sig)) = self.get(name) {
+            self.put_sig(func, name_end, &sig);
+        }
+        self.nested(name, |w| func.visit_children_with(w));
+    }
+}
+
+impl Visit for Weaver<'_> {
+    // the variables of for-in and for-of loops can't have types
+    fn visit_for_in_stmt(&mut self, stmt: &ForInStmt) {
+        stmt.right.visit_with(self);
+        stmt.body.visit_with(self);
+    }
+
+    fn visit_for_of_stmt(&mut self, stmt: &ForOfStmt) {
+        stmt.right.visit_with(self);
+        stmt.body.visit_with(self);
+    }
+
+    fn visit_var_declarator(&mut self, declarator: &VarDeclarator) {
+        let name = var_name(self.src, declarator);
+        let woven = self.get(&name);
+        match bound_fn(declarator) {
+            Some(func) => {
+                match (&func, &woven) {
+                    (BoundFn::Fn(func), Some(Woven::Sig(sig))) => {
+                        let name_end = func.span.lo;
+                        self.put_sig(func, name_end, sig);
+                    }
+                    (BoundFn::Arrow(arrow), Some(Woven::Sig(sig))) => {
+                        self.put_arrow_sig(arrow, sig);
+                    }
+                    // the nettle has the type of the function on the variable
+                    (_, Some(Woven::Type(ty))) => self.put_pat_type(&declarator.name, ty),
+                    _ => {}
+                }
+                self.nested(&name, |w| match func {
+                    BoundFn::Fn(func) => func.visit_children_with(w),
+                    BoundFn::Arrow(arrow) => arrow.visit_children_with(w),
+                });
+            }
+            None => {
+                if let Some(Woven::Type(ty)) = woven {
+                    self.put_pat_type(&declarator.name, &ty);
+                }
+                declarator.visit_children_with(self);
+            }
+        }
+    }
+
+    fn visit_class_prop(&mut self, prop: &ClassProp) {
+        if prop.key.is_ident() {
+            if let Some(Woven::Type(ty)) = self.get(self.src.slice(prop.key.span())) {
+                let pos = self.src.skip_token(prop.key.span().hi, &["?", "!"]);
+                self.put_type(prop.type_ann.as_deref(), pos, &ty);
+            }
+        }
+        prop.visit_children_with(self);
+    }
+
+    fn visit_constructor(&mut self, cons: &Constructor) {
+        if let Some(Woven::Sig(sig)) = self.get("__constructor__") {
+            for (param, ty) in cons.params.iter().zip(&sig.params) {
+                let Some(ty) = ty else {
+                    continue;
+                };
+                match param {
+                    ParamOrTsParamProp::Param(param) => self.put_pat_type(&param.pat, ty),
+                    ParamOrTsParamProp::TsParamProp(prop) => match &prop.param {
+                        TsParamPropParam::Ident(id) => {
+                            let pos = self.src.skip_token(id.id.span.hi, &["?"]);
+                            self.put_type(id.type_ann.as_deref(), pos, ty);
+                        }
+                        TsParamPropParam::Assign(assign) => {
+                            let ann = assign
+                                .type_ann
+                                .as_deref()
+                                .or_else(|| pat_type_ann(&assign.left));
+                            let pos = self.src.pat_end(&assign.left);
+                            self.put_type(ann, pos, ty);
+                        }
+                    },
+                }
+            }
+        }
+        // unlike the nettle, the constructor has no scope of its own here
+        cons.visit_children_with(self);
+    }
+
+    fn visit_fn_decl(&mut self, decl: &FnDecl) {
+        self.fn_decl(&decl.ident.sym, &decl.function, decl.ident.span.hi);
+    }
+
+    fn visit_export_default_decl(&mut self, decl: &ExportDefaultDecl) {
+        match &decl.decl {
+            DefaultDecl::Fn(func) if func.ident.is_some() => {
+                let ident = func.ident.as_ref().unwrap();
+                self.fn_decl(&ident.sym, &func.function, ident.span.hi);
+            }
+            DefaultDecl::Class(class) if class.ident.is_some() => {
+                let name = class.ident.as_ref().unwrap().sym.to_string();
+                self.nested(&name, |w| class.class.visit_with(w));
+            }
+            _ => decl.visit_children_with(self),
+        }
+    }
+
+    fn visit_class_method(&mut self, method: &ClassMethod) {
+        if method.kind == MethodKind::Method {
+            let key = method.key.span();
+            self.method(self.src.slice(key), &method.function, key.hi);
+        }
+        method.visit_children_with(self);
+    }
+
+    fn visit_private_method(&mut self, method: &PrivateMethod) {
+        if method.kind == MethodKind::Method {
+            let key = method.key.span;
+            self.method(self.src.slice(key), &method.function, key.hi);
+        }
+        method.visit_children_with(self);
+    }
+
+    fn visit_method_prop(&mut self, method: &MethodProp) {
+        let key = method.key.span();
+        self.method(self.src.slice(key), &method.function, key.hi);
+        method.visit_children_with(self);
+    }
+
+    fn visit_class_decl(&mut self, class: &ClassDecl) {
+        self.nested(&class.ident.sym, |w| class.class.visit_with(w));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::langserver::ts::normalize::ts_normalize;
+
+    /// Checks the native weave against the output of the `weave` command of the TypeScript
+    /// language server for the same input. The language server prints the code again, so
+    /// we compare them like `NativeTsServer` does when it validates the weave.
+    fn assert_weaves_like_ts(original: &str, nettle: &str, level: usize, ts_output: &str) {
+        let native = native_weave(original, nettle, level).unwrap();
+        assert_eq!(
+            ts_normalize(&native).unwrap(),
+            ts_normalize(ts_output).unwrap(),
+            "native weave:\n{native}"
+        );
+    }
+
+    #[test]
+    fn arrows() {
+        let original = "\
+const add = (a, b) => a + b;
+const inc = x => x + 1;
+const run = async () => {
+  const y = inc(1);
+  return y;
+};
+";
+        let nettle = "\
+const add = (a: number, b: number): number => a + b;
+const inc = (x: number): number => x + 1;
+const run = async (): Promise<number> => {
+  const y: number = inc(1);
+  return y;
+};
+";
+        let ts_output = "\
+const add = (a: number, b: number): number => a + b;
+const inc = (x: number): number => x + 1;
+const run = async (): Promise<number> => {
+    const y: number = inc(1);
+    return y;
+};
+";
+        assert_weaves_like_ts(original, nettle, 0, ts_output);
+    }
+
+    #[test]
+    fn constructor_param_props() {
+        let original = "\
+class Point {
+  sum;
+  constructor(public x, private y = 0, z) {
+    this.sum = x + y + z;
+  }
+}
+";
+        let nettle = "\
+class Point {
+  sum: number;
+  constructor(public x: number, private y: number = 0, z: number) {
+    this.sum = x + y + z;
+  }
+}
+";
+        let ts_output = "\
+class Point {
+    sum: number;
+    constructor(public x: number, private y: number = 0, z: number) {
+        this.sum = x + y + z;
+    }
+}
+";
+        assert_weaves_like_ts(original, nettle, 0, ts_output);
+    }
+
+    #[test]
+    fn export_default() {
+        let original = "\
+export default function parse(s) {
+  const parts = s.split(\",\");
+  return parts.length;
+}
+";
+        let nettle = "\
+export default function parse(s: string): number {
+  const parts: string[] = s.split(\",\");
+  return parts.length;
+}
+";
+        let ts_output = "\
+export default function parse(s: string): number {
+    const parts: string[] = s.split(\",\");
+    return parts.length;
+}
+";
+        assert_weaves_like_ts(original, nettle, 0, ts_output);
+    }
+
+    #[test]
+    fn nested_scopes() {
+        let original = "\
+function outer(a) {
+  function inner(b) {
+    const x = b * 2;
+    return x;
+  }
+  const x = String(inner(a));
+  return x;
+}
+class Counter {
+  count = 0;
+  add(n) {
+    const next = this.count + n;
+    this.count = next;
+  }
+  label() {
+    const next = `${this.count}`;
+    return next;
+  }
+}
+";
+        let nettle = "\
+function outer(a: number): string {
+  function inner(b: number): number {
+    const x: number = b * 2;
+    return x;
+  }
+  const x: string = String(inner(a));
+  return x;
+}
+class Counter {
+  count: number = 0;
+  add(n: number): void {
+    const next: number = this.count + n;
+    this.count = next;
+  }
+  label(): string {
+    const next: string = `${this.count}`;
+    return next;
+  }
+}
+";
+        // methods have no scope of their own, so the variables of both methods are the
+        // same, and the last one wins
+        let ts_output = "\
+function outer(a: number): string {
+    function inner(b: number): number {
+        const x: number = b * 2;
+        return x;
+    }
+    const x: string = String(inner(a));
+    return x;
+}
+class Counter {
+    count: number = 0;
+    add(n: number): void {
+        const next: string = this.count + n;
+        this.count = next;
+    }
+    label(): string {
+        const next: string = `${this.count}`;
+        return next;
+    }
+}
+";
+        assert_weaves_like_ts(original, nettle, 0, ts_output);
+    }
+
+    #[test]
+    fn nettle_below_the_root() {
+        let original = "\
+function outer() {
+  function inner(b) {
+    const y = b + 1;
+    return y;
+  }
+  return inner(1);
+}
+";
+        let nettle = "\
+function inner(b: number): number {
+  const y: number = b + 1;
+  return y;
+}
+";
+        let ts_output = "\
+function outer() {
+    function inner(b: number): number {
+        const y: number = b + 1;
+        return y;
+    }
+    return inner(1);
+}
+";
+        assert_weaves_like_ts(original, nettle, 1, ts_output);
+        // at the root, the declarations of the nettle are in the scope of `outer`
+        let ts_output = "\
+function outer() {
+    function inner(b) {
+        const y = b + 1;
+        return y;
+    }
+    return inner(1);
+}
+";
+        assert_weaves_like_ts(original, nettle, 0, ts_output);
+    }
+}
@@ -0,0 +1,357 @@
+use swc_common::{sync::Lrc, BytePos, FileName, SourceMap, Span, Spanned};
+use swc_ecma_ast::{
+    TsEntityName, TsType, TsTypeElement, TsTypeOperatorOp, TsUnionOrIntersectionType,
+};
+use swc_ecma_parser::{lexer::Lexer, Parser, StringInput, Syntax};
+use swc_ecma_visit::{Visit, VisitWith};
+
+/// Produces the canonical form of the given TypeScript code, such that two pieces of code
+/// that only differ in whitespace, comments, the order of union and intersection members,
+/// redundant parentheses or `Array<T>` vs `T[]` have the same canonical form.
+///
+/// The canonical form is the tokens of the code joined by a single space, where every type
+/// is replaced by its canonical printing. It is not meant to be valid code, only to be
+/// compared. Returns None if the code doesn't parse.
+pub fn ts_normalize(code: &str) -> Option<String> {
+    let cm: Lrc<SourceMap> = Default::default();
+    let fm = cm.new_source_file(FileName::Anon, code.to_string());
+    let src = Src {
+        text: &fm.src,
+        start: fm.start_pos,
+    };
+
+    let mut parser = Parser::new(
+        Syntax::Typescript(Default::default()),
+        StringInput::from(&*fm),
+        None,
+    );
+    let module = parser.parse_module().ok()?;
+    if !parser.take_errors().is_empty() {
+        return None;
+    }
+
+    let mut collector = TypeCollector {
+        src: &src,
+        types: vec![],
+    };
+    module.visit_with(&mut collector);
+    // the types don't overlap, as we don't visit the types inside of types
+    collector.types.sort_by_key(|(span, _)| span.lo);
+
+    let lexer = Lexer::new(
+        Syntax::Typescript(Default::default()),
+        Default::default(),
+        StringInput::from(&*fm),
+        None,
+    );
+    let mut types = collector.types.into_iter().peekable();
+    let mut emitted = false;
+    let mut tokens: Vec<String> = vec![];
+    for tok in lexer {
+        while let Some((span, _)) = types.peek() {
+            if tok.span.lo < span.hi {
+                break;
+            }
+            types.next();
+            emitted = false;
+        }
+        match types.peek() {
+            Some((span, canonical)) if tok.span.lo >= span.lo => {
+                if !emitted {
+                    tokens.push(canonical.clone());
+                    emitted = true;
+                }
+            }
+            _ => tokens.push(src.slice(tok.span).to_string()),
+        }
+    }
+
+    Some(tokens.join(" "))
+}
+
+/// Source text of the parsed code, used to slice nodes out by their spans.
+struct Src<'a> {
+    text: &'a str,
+    start: BytePos,
+}
+
+impl Src<'_> {
+    fn slice(&self, span: Span) -> &str {
+        let lo = (span.lo.0 - self.start.0) as usize;
+        let hi = (span.hi.0 - self.start.0) as usize;
+        &self.text[lo..hi]
+    }
+
+    /// The source of the given span, with the whitespace collapsed. Used for the nodes
+    /// that we don't canonicalize any further.
+    fn collapsed(&self, span: Span) -> String {
+        self.slice(span)
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Collects the outermost types of the code, along with their canonical printing.
+struct TypeCollector<'a> {
+    src: &'a Src<'a>,
+    types: Vec<(Span, String)>,
+}
+
+impl Visit for TypeCollector<'_> {
+    fn visit_ts_type(&mut self, ty: &TsType) {
+        // we don't visit the children, the canonical printing covers the whole type
+        self.types.push((ty.span(), canonical(self.src, ty)));
+    }
+}
+
+/// Removes the parentheses around the given type.
+fn unparen(ty: &TsType) -> &TsType {
+    match ty {
+        TsType::TsParenthesizedType(p) => unparen(&p.type_ann),
+        _ => ty,
+    }
+}
+
+/// Prints the given type in its canonical form.
+fn canonical(src: &Src, ty: &TsType) -> String {
+    match unparen(ty) {
+        TsType::TsArrayType(arr) => format!("{}[]", operand(src, &arr.elem_type)),
+        TsType::TsTypeRef(r) => {
+            let name = match &r.type_name {
+                TsEntityName::Ident(id) => id.sym.to_string(),
+                TsEntityName::TsQualifiedName(q) => src.collapsed(q.span()).replace(' ', ""),
+            };
+            let params: Vec<&TsType> = r
+                .type_params
+                .iter()
+                .flat_map(|p| p.params.iter().map(|t| t.as_ref()))
+                .collect();
+            match (name.as_str(), params.as_slice()) {
+                ("Array", [elem]) => format!("{}[]", operand(src, elem)),
+                ("ReadonlyArray", [elem]) => format!("readonly {}[]", operand(src, elem)),
+                (_, []) => name,
+                (_, params) => format!(
+                    "{name}<{}>",
+                    params
+                        .iter()
+                        .map(|t| canonical(src, t))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            }
+        }
+        TsType::TsUnionOrIntersectionType(TsUnionOrIntersectionType::TsUnionType(u)) => {
+            let mut members = vec![];
+            flatten_members(ty_kind_union, &u.types, &mut members);
+            join_members(src, members, " | ", false)
+        }
+        TsType::TsUnionOrIntersectionType(TsUnionOrIntersectionType::TsIntersectionType(i)) => {
+            let mut members = vec![];
+            flatten_members(ty_kind_intersection, &i.types, &mut members);
+            join_members(src, members, " & ", true)
+        }
+        TsType::TsTypeOperator(op) => {
+            let op_str = match op.op {
+                TsTypeOperatorOp::KeyOf => "keyof",
+                TsTypeOperatorOp::Unique => "unique",
+                TsTypeOperatorOp::ReadOnly => "readonly",
+            };
+            format!("{op_str} {}", operand(src, &op.type_ann))
+        }
+        TsType::TsTupleType(tuple) if tuple.elem_types.iter().all(|e| e.label.is_none()) => {
+            format!(
+                "[{}]",
+                tuple
+                    .elem_types
+                    .iter()
+                    .map(|e| canonical(src, &e.ty))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        }
+        TsType::TsOptionalType(opt) => format!("{}?", operand(src, &opt.type_ann)),
+        TsType::TsRestType(rest) => format!("...{}", operand(src, &rest.type_ann)),
+        TsType::TsIndexedAccessType(idx) if !idx.readonly => format!(
+            "{}[{}]",
+            operand(src, &idx.obj_type),
+            canonical(src, &idx.index_type)
+        ),
+        TsType::TsTypeLit(lit) => {
+            if lit.members.is_empty() {
+                return "{}".to_string();
+            }
+            let members: Vec<String> = lit
+                .members
+                .iter()
+                .map(|m| match m {
+                    TsTypeElement::TsPropertySignature(prop)
+                        if !prop.computed
+                            && prop.init.is_none()
+                            && prop.params.is_empty()
+                            && prop.type_params.is_none() =>
+                    {
+                        let mut member = String::new();
+                        if prop.readonly {
+                            member.push_str("readonly ");
+                        }
+                        member.push_str(&src.collapsed(prop.key.span()));
+                        if prop.optional {
+                            member.push('?');
+                        }
+                        if let Some(ann) = &prop.type_ann {
+                            member.push_str(": ");
+                            member.push_str(&canonical(src, &ann.type_ann));
+                        }
+                        member
+                    }
+                    _ => src
+                        .collapsed(m.span())
+                        .trim_end_matches([';', ','])
+                        .to_string(),
+                })
+                .collect();
+            format!("{{ {} }}", members.join("; "))
+        }
+        other => src.collapsed(other.span()),
+    }
+}
+
+fn ty_kind_union(ty: &TsType) -> Option<&[Box<TsType>]> {
+    match ty {
+        TsType::TsUnionOrIntersectionType(TsUnionOrIntersectionType::TsUnionType(u)) => {
+            Some(&u.types)
+        }
+        _ => None,
+    }
+}
+
+fn ty_kind_intersection(ty: &TsType) -> Option<&[Box<TsType>]> {
+    match ty {
+        TsType::TsUnionOrIntersectionType(TsUnionOrIntersectionType::TsIntersectionType(i)) => {
+            Some(&i.types)
+        }
+        _ => None,
+    }
+}
+
+/// Flattens nested unions (or intersections), such that `A | (B | C)` has the members
+/// `A`, `B` and `C`.
+fn flatten_members<'t>(
+    same_kind: fn(&TsType) -> Option<&[Box<TsType>]>,
+    types: &'t [Box<TsType>],
+    members: &mut Vec<&'t TsType>,
+) {
+    for ty in types {
+        let ty = unparen(ty);
+        match same_kind(ty) {
+            Some(inner) => flatten_members(same_kind, inner, members),
+            None => members.push(ty),
+        }
+    }
+}
+
+/// Joins the members of a union or intersection in sorted order, without duplicates.
+fn join_members(src: &Src, members: Vec<&TsType>, sep: &str, is_intersection: bool) -> String {
+    let mut printed: Vec<String> = members
+        .into_iter()
+        .map(|m| {
+            let needs_parens = matches!(
+                m,
+                TsType::TsFnOrConstructorType(_) | TsType::TsConditionalType(_)
+            ) || (is_intersection && ty_kind_union(m).is_some());
+            if needs_parens {
+                format!("({})", canonical(src, m))
+            } else {
+                canonical(src, m)
+            }
+        })
+        .collect();
+    printed.sort();
+    printed.dedup();
+    printed.join(sep)
+}
+
+/// Prints the given type as the operand of a postfix or prefix type operator, such as
+/// `T[]` or `keyof T`, adding parentheses where they are needed.
+fn operand(src: &Src, ty: &TsType) -> String {
+    let ty = unparen(ty);
+    let needs_parens = matches!(
+        ty,
+        TsType::TsUnionOrIntersectionType(_)
+            | TsType::TsConditionalType(_)
+            | TsType::TsTypeOperator(_)
+            | TsType::TsInferType(_)
+            | TsType::TsTypePredicate(_)
+            | TsType::TsFnOrConstructorType(_)
+    );
+    if needs_parens {
+        format!("({})", canonical(src, ty))
+    } else {
+        canonical(src, ty)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn same(a: &str, b: &str) {
+        assert_eq!(ts_normalize(a), ts_normalize(b), "{a} vs {b}");
+        assert!(ts_normalize(a).is_some());
+    }
+
+    #[test]
+    fn union_order() {
+        same("let x: string | number;", "let x: number | string;");
+        // nested unions are flattened and deduplicated
+        same("let x: A | (B | A);", "let x: B | A;");
+        same("let x: A & (B | C);", "let x: (C | B) & A;");
+        assert_eq!(
+            ts_normalize("let x: number | string;").unwrap(),
+            "let x : number | string ;"
+        );
+    }
+
+    #[test]
+    fn arrays() {
+        same("let x: Array<string>;", "let x: string[];");
+        same(
+            "let x: Array<string | number>;",
+            "let x: (number | string)[];",
+        );
+        same("let x: ReadonlyArray<T>;", "let x: readonly T[];");
+        same("let x: Array<Array<T>>;", "let x: T[][];");
+        assert_ne!(
+            ts_normalize("let x: Array<string | number>;"),
+            ts_normalize("let x: string | number[];")
+        );
+    }
+
+    #[test]
+    fn fn_types_in_unions() {
+        same(
+            "let f: (() => void) | string;",
+            "let f: string | (() => void);",
+        );
+        same("let f: Array<() => void>;", "let f: (() => void)[];");
+        assert_eq!(
+            ts_normalize("let f: string | (() => void);").unwrap(),
+            "let f : (() => void) | string ;"
+        );
+        // a function that returns a union is not a union
+        assert_ne!(
+            ts_normalize("let f: (() => void) | string;"),
+            ts_normalize("let f: () => void | string;")
+        );
+    }
+
+    #[test]
+    fn whitespace_and_comments() {
+        same(
+            "function f(a: number, b:string) { return a; }",
+            "function f(a: number, /* b */ b: string) {\n  return a;\n}",
+        );
+        assert_eq!(ts_normalize("let x: = ;"), None);
+    }
+}
@@ -0,0 +1,256 @@
+use swc_common::{sync::Lrc, BytePos, FileName, SourceMap, Span, Spanned};
+use swc_ecma_ast::{
+    BlockStmtOrExpr, Class, ClassMember, Decl, DefaultDecl, Expr, Function, ModuleDecl, ModuleItem,
+    Stmt,
+};
+use swc_ecma_parser::{Parser, StringInput, Syntax};
+
+/// Splits the given TypeScript code into parts of at most `max_tokens` tokens, as counted by
+/// `count_tokens`, that can be completed separately and woven back into the code.
+///
+/// We only split between top-level statements, class members and the statements of a
+/// body, never inside of one. A declaration that is too large on its own is split into
+/// its body: every part repeats the header of the declaration (e.g. the signature of the
+/// function) and its closing bracket, so that each part is valid code. A statement that
+/// is too large and has no body to split is left as a part of its own, even if it doesn't
+/// fit. Returns None if the code doesn't parse.
+pub fn ts_split(
+    code: &str,
+    max_tokens: usize,
+    count_tokens: &dyn Fn(&str) -> usize,
+) -> Option<Vec<String>> {
+    let cm: Lrc<SourceMap> = Default::default();
+    let fm = cm.new_source_file(FileName::Anon, code.to_string());
+    let src = Src {
+        text: &fm.src,
+        start: fm.start_pos,
+    };
+
+    let mut parser = Parser::new(
+        Syntax::Typescript(Default::default()),
+        StringInput::from(&*fm),
+        None,
+    );
+    let module = parser.parse_module().ok()?;
+    if !parser.take_errors().is_empty() {
+        return None;
+    }
+
+    let items: Vec<Item> = module.body.iter().map(Item::Module).collect();
+    let end = BytePos(fm.start_pos.0 + fm.src.len() as u32);
+    let mut builder = PartBuilder {
+        src: &src,
+        max_tokens,
+        count_tokens,
+        parts: vec![],
+    };
+    builder.split(&items, fm.start_pos, end, "", "");
+    Some(builder.parts)
+}
+
+struct Src<'a> {
+    text: &'a str,
+    start: BytePos,
+}
+
+impl Src<'_> {
+    fn slice(&self, lo: BytePos, hi: BytePos) -> &str {
+        let lo = (lo.0 - self.start.0) as usize;
+        let hi = (hi.0 - self.start.0) as usize;
+        &self.text[lo..hi]
+    }
+}
+
+/// A node that we can split between, or split into its children.
+#[derive(Clone, Copy)]
+enum Item<'a> {
+    Module(&'a ModuleItem),
+    Stmt(&'a Stmt),
+    Member(&'a ClassMember),
+}
+
+impl<'a> Item<'a> {
+    fn span(&self) -> Span {
+        match self {
+            Item::Module(item) => item.span(),
+            Item::Stmt(stmt) => stmt.span(),
+            Item::Member(member) => member.span(),
+        }
+    }
+
+    /// The statements or members in the body of the item, if it has a non-empty one.
+    fn children(&self) -> Option<Vec<Item<'a>>> {
+        let children = match self {
+            Item::Module(ModuleItem::Stmt(stmt)) => return Item::Stmt(stmt).children(),
+            Item::Module(ModuleItem::ModuleDecl(ModuleDecl::ExportDecl(export))) => {
+                decl_children(&export.decl)
+            }
+            Item::Module(ModuleItem::ModuleDecl(ModuleDecl::ExportDefaultDecl(export))) => {
+                match &export.decl {
+                    DefaultDecl::Class(class) => class_children(&class.class),
+                    DefaultDecl::Fn(func) => fn_children(&func.function),
+                    DefaultDecl::TsInterfaceDecl(_) => None,
+                }
+            }
+            Item::Stmt(Stmt::Decl(decl)) => decl_children(decl),
+            Item::Member(ClassMember::Constructor(constructor)) => constructor
+                .body
+                .as_ref()
+                .map(|body| body.stmts.iter().map(Item::Stmt).collect()),
+            Item::Member(ClassMember::Method(method)) => fn_children(&method.function),
+            Item::Member(ClassMember::PrivateMethod(method)) => fn_children(&method.function),
+            _ => None,
+        }?;
+        if children.is_empty() {
+            None
+        } else {
+            Some(children)
+        }
+    }
+}
+
+fn decl_children(decl: &Decl) -> Option<Vec<Item<'_>>> {
+    match decl {
+        Decl::Fn(func) => fn_children(&func.function),
+        Decl::Class(class) => class_children(&class.class),
+        // only `const f = function () {...}`, `const f = () => {...}` and
+        // `const C = class {...}`, splitting a body of a declarator among many is not
+        // valid code
+        Decl::Var(var) if var.decls.len() == 1 => match var.decls[0].init.as_deref()? {
+            Expr::Fn(func) => fn_children(&func.function),
+            Expr::Arrow(arrow) => match &arrow.body {
+                BlockStmtOrExpr::BlockStmt(body) => {
+                    Some(body.stmts.iter().map(Item::Stmt).collect())
+                }
+                BlockStmtOrExpr::Expr(_) => None,
+            },
+            Expr::Class(class) => class_children(&class.class),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn fn_children(func: &Function) -> Option<Vec<Item<'_>>> {
+    func.body
+        .as_ref()
+        .map(|body| body.stmts.iter().map(Item::Stmt).collect())
+}
+
+fn class_children(class: &Class) -> Option<Vec<Item<'_>>> {
+    Some(class.body.iter().map(Item::Member).collect())
+}
+
+struct PartBuilder<'a> {
+    src: &'a Src<'a>,
+    max_tokens: usize,
+    count_tokens: &'a dyn Fn(&str) -> usize,
+    parts: Vec<String>,
+}
+
+impl PartBuilder<'_> {
+    fn fits(&self, header: &str, text: &str, footer: &str) -> bool {
+        (self.count_tokens)(&format!("{header}{text}{footer}")) <= self.max_tokens
+    }
+
+    /// Groups the given items, which span from `lo` to `hi`, into parts that are wrapped in
+    /// the given header and footer. The text between two items goes with the first one.
+    fn split(&mut self, items: &[Item], lo: BytePos, hi: BytePos, header: &str, footer: &str) {
+        let mut group = String::new();
+        for (i, item) in items.iter().enumerate() {
+            let start = if i == 0 { lo } else { item.span().lo };
+            let end = items.get(i + 1).map_or(hi, |next| next.span().lo);
+            let text = self.src.slice(start, end);
+            if self.fits(header, &format!("{group}{text}"), footer) {
+                group.push_str(text);
+                continue;
+            }
+            if !group.is_empty() {
+                self.parts.push(format!("{header}{group}{footer}"));
+                group.clear();
+            }
+            if self.fits(header, text, footer) {
+                group.push_str(text);
+                continue;
+            }
+
+            // too large on its own, we split its body
+            match item.children() {
+                Some(children) => {
+                    let first = children[0].span().lo;
+                    let last = children[children.len() - 1].span().hi;
+                    let header = format!("{header}{}", self.src.slice(start, first));
+                    let footer = format!("{}{footer}", self.src.slice(last, end));
+                    self.split(&children, first, last, &header, &footer);
+                }
+                None => self.parts.push(format!("{header}{text}{footer}")),
+            }
+        }
+        if !group.is_empty() {
+            self.parts.push(format!("{header}{group}{footer}"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counts a token per byte, so that the sizes are easy to follow.
+    fn split(code: &str, max_tokens: usize) -> Option<Vec<String>> {
+        ts_split(code, max_tokens, &|t: &str| t.len())
+    }
+
+    #[test]
+    fn fits() {
+        let code = "let a = 1;\nlet b = 2;\n";
+        assert_eq!(split(code, 100).unwrap(), [code]);
+        assert_eq!(split("let x = ;", 100), None);
+    }
+
+    #[test]
+    fn top_level() {
+        let code = "let a = 1;\nlet b = 2;\nlet c = 3;\n";
+        assert_eq!(
+            split(code, 25).unwrap(),
+            ["let a = 1;\nlet b = 2;\n", "let c = 3;\n"]
+        );
+    }
+
+    #[test]
+    fn too_large_fn() {
+        let code = "function f() {\n  let a = 1;\n  let b = 2;\n  let c = 3;\n}\n";
+        // every part repeats the signature and the closing bracket
+        assert_eq!(
+            split(code, 40).unwrap(),
+            [
+                "function f() {\n  let a = 1;\n  \n}\n",
+                "function f() {\n  let b = 2;\n  \n}\n",
+                "function f() {\n  let c = 3;\n}\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn too_large_method() {
+        let code = "class A {\n  x = 1;\n  m() {\n    let a = 1;\n    let b = 2;\n  }\n}\n";
+        assert_eq!(
+            split(code, 40).unwrap(),
+            [
+                "class A {\n  x = 1;\n  \n}\n",
+                "class A {\n  m() {\n    let a = 1;\n    \n  }\n}\n",
+                "class A {\n  m() {\n    let b = 2;\n  }\n}\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn too_large_without_body() {
+        // the array has no body to split, so it is a part of its own even if it doesn't fit
+        let big = "const big = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13];\n";
+        let code = format!("let a = 1;\nlet b = 2;\n{big}let c = 3;\n");
+        let parts = split(&code, 25).unwrap();
+        assert_eq!(parts, ["let a = 1;\nlet b = 2;\n", big, "let c = 3;\n"]);
+        assert!(parts[1].len() > 25);
+    }
+}
@@ -1,10 +1,15 @@
 pub mod cache;
 pub mod completion;
+pub mod dedup;
+pub mod imports;
 pub mod langserver;
+pub mod lru;
 pub mod main_strategies;
+pub mod prompt;
 pub mod socket;
 pub mod tree;
 pub mod typedef_gen;
+pub mod usage;
 pub mod args;
 
 /// macro for debug printing, only prints if #cfg(debug_assertions) is true
@@ -0,0 +1,160 @@
+use std::{
+    borrow::Borrow,
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+};
+
+/// A map that holds at most `capacity` entries, and evicts the least recently used one
+/// when it is full. Looking up an entry with `get` or replacing it with `insert` makes it
+/// the most recently used one, `peek` doesn't.
+#[derive(Debug)]
+pub struct Lru<K, V> {
+    capacity: usize,
+    // the values, with the tick they were last used at
+    entries: HashMap<K, (V, u64)>,
+    // the keys, ordered by the tick they were last used at
+    recency: BTreeMap<u64, K>,
+    tick: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> Lru<K, V> {
+    /// Makes an empty map that holds at most `capacity` entries. With a capacity of 0,
+    /// nothing is ever inserted.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    /// The value of the given key, which becomes the most recently used one.
+    pub fn get<Q>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.tick += 1;
+        let (value, last_used) = self.entries.get_mut(key)?;
+        let last_used = std::mem::replace(last_used, self.tick);
+        let key = self.recency.remove(&last_used)?;
+        self.recency.insert(self.tick, key);
+        Some(value)
+    }
+
+    /// The value of the given key, without changing how recently it was used.
+    pub fn peek<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.entries.get(key).map(|(value, _)| value)
+    }
+
+    /// Inserts the given value as the most recently used one, replacing the value of the
+    /// key if it has one, or evicting the least recently used entry if the map is full.
+    pub fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some((_, last_used)) = self.entries.remove(&key) {
+            self.recency.remove(&last_used);
+        }
+        if self.entries.len() >= self.capacity {
+            if let Some((_, lru)) = self.recency.pop_first() {
+                self.entries.remove(&lru);
+            }
+        }
+        self.tick += 1;
+        self.recency.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, self.tick));
+    }
+
+    /// Removes the given key, returning its value if it had one.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (value, last_used) = self.entries.remove(key)?;
+        self.recency.remove(&last_used);
+        Some(value)
+    }
+
+    /// The entries of the map, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries.iter().map(|(key, (value, _))| (key, value))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut lru = Lru::new(2);
+        lru.insert("a", 1);
+        lru.insert("b", 2);
+        // "a" is used after "b", so "b" goes
+        assert_eq!(lru.get("a"), Some(&1));
+        lru.insert("c", 3);
+        assert_eq!(lru.peek("b"), None);
+        assert_eq!(lru.peek("a"), Some(&1));
+        assert_eq!(lru.peek("c"), Some(&3));
+        assert_eq!(lru.len(), 2);
+    }
+
+    #[test]
+    fn peek_keeps_recency() {
+        let mut lru = Lru::new(2);
+        lru.insert("a", 1);
+        lru.insert("b", 2);
+        assert_eq!(lru.peek("a"), Some(&1));
+        lru.insert("c", 3);
+        assert_eq!(lru.peek("a"), None);
+    }
+
+    #[test]
+    fn insert_replaces() {
+        let mut lru = Lru::new(2);
+        lru.insert("a", 1);
+        lru.insert("b", 2);
+        // replacing "a" makes it the most recently used, and doesn't evict anything
+        lru.insert("a", 10);
+        assert_eq!(lru.len(), 2);
+        lru.insert("c", 3);
+        assert_eq!(lru.peek("a"), Some(&10));
+        assert_eq!(lru.peek("b"), None);
+    }
+
+    #[test]
+    fn remove() {
+        let mut lru = Lru::new(2);
+        lru.insert("a", 1);
+        assert_eq!(lru.remove("a"), Some(1));
+        assert_eq!(lru.remove("a"), None);
+        assert!(lru.is_empty());
+        // the removed key doesn't count towards the capacity
+        lru.insert("b", 2);
+        lru.insert("c", 3);
+        assert_eq!(lru.iter().count(), 2);
+    }
+
+    #[test]
+    fn zero_capacity() {
+        let mut lru = Lru::new(0);
+        lru.insert("a", 1);
+        assert!(lru.is_empty());
+        assert_eq!(lru.get("a"), None);
+    }
+}
@@ -1,31 +1,62 @@
-use std::{str::FromStr, sync::Arc};
+use std::str::FromStr;
 
 use clap::Parser;
+#[cfg(feature = "tsparser")]
+use opentau::langserver::ts::{dts::ts_to_dts, jsdoc::jsdoc_into};
 use opentau::{
-    cache::Cache,
+    args::{Args, CacheArgs, CacheCommand, Command},
+    cache::manage::CacheManager,
     completion::{sort_completions, Completion, TypecheckedCompletion},
-    langserver::AnnotateType,
+    imports::ImportContext,
+    langserver::{AnnotateType, ArcLangServer},
     main_strategies::{MainCtx, MainStrategy},
+    usage::{PriceTable, UsageTracker},
 };
-use tokio::sync::Mutex;
 
 #[tokio::main]
 async fn main() {
-    let args = opentau::args::Args::parse();
+    let mut args = Args::parse();
+
+    // `main cache ...` manages the cache instead of completing a file
+    if let Some(Command::Cache(cache_args)) = args.command.take() {
+        cache_main(cache_args).await;
+        return;
+    }
+
+    if args.jsdoc && (args.lang != "ts" || cfg!(not(feature = "tsparser"))) {
+        eprintln!("JSDoc output is only for ts, and needs the tsparser feature");
+        std::process::exit(1);
+    }
+    if args.dts && (args.lang != "ts" || cfg!(not(feature = "tsparser"))) {
+        eprintln!("Declaration output is only for ts, and needs the tsparser feature");
+        std::process::exit(1);
+    }
+    if args.jsdoc && args.dts {
+        eprintln!("JSDoc and declaration outputs can't be used together");
+        std::process::exit(1);
+    }
 
     let lang_client = args.lang_client_factory().await;
     let strategy = args.stategy_factory();
 
-    let file_contents = tokio::fs::read_to_string(&args.file).await.unwrap();
+    let unsupported = lang_client.unsupported_commands();
+    let missing: Vec<&str> = strategy
+        .required_commands()
+        .iter()
+        .copied()
+        .filter(|cmd| unsupported.contains(cmd))
+        .collect();
+    if !missing.is_empty() {
+        eprintln!(
+            "The {} strategy needs the {} commands, which the {} language server does not support",
+            args.strategy,
+            missing.join(", "),
+            args.lang
+        );
+        std::process::exit(1);
+    }
 
-    let cache: Option<Arc<Mutex<Cache>>> = args.cache.as_ref().map(|u| {
-        Arc::new(Mutex::new(Cache::new(u, args.stop_at).unwrap_or_else(
-            |e| {
-                eprintln!("Failed to connect to redis: {e}");
-                std::process::exit(1);
-            },
-        )))
-    });
+    let file_contents = tokio::fs::read_to_string(args.file()).await.unwrap();
 
     let types_to_annot = match args.exclude {
         None => AnnotateType::all(),
@@ -41,9 +72,31 @@ async fn main() {
         }
     };
 
+    let cache = args.cache_factory(&types_to_annot).await;
+
+    let import_context = if args.disable_import_context {
+        None
+    } else {
+        Some(ImportContext::build(
+            std::path::Path::new(args.file()),
+            &file_contents,
+        ))
+    };
+
+    let prices = match &args.price_table {
+        Some(path) => PriceTable::from_file(path).unwrap_or_else(|e| {
+            eprintln!("Failed to read price table: {e}");
+            std::process::exit(1);
+        }),
+        None => PriceTable::default(),
+    };
+    let usage = UsageTracker::new();
+
     let ctx = MainCtx {
-        file_contents,
-        engine: args.completion_engine_factory(lang_client, cache).await,
+        file_contents: file_contents.clone(),
+        engine: args
+            .completion_engine_factory(lang_client.clone(), cache)
+            .await,
         num_comps: args.n,
         retries: args.retries,
         fallback: args.fallback,
@@ -55,11 +108,24 @@ async fn main() {
         enable_stubbing: !args.disable_stubbing,
         enable_parser: true,
         enable_checkproblems: true,
+        problem_whitelist: args.problem_whitelist(),
+        enable_streaming: args.enable_streaming,
         types: types_to_annot,
+        import_context,
+        usage: usage.clone(),
     };
 
     // the typechecked and completed code(s). here if we get errors we exit with 1
-    let mut good_ones: Vec<TypecheckedCompletion> = match strategy.run(ctx).await {
+    let res = strategy.run(ctx).await;
+
+    // report what the run cost, even if it failed
+    let usage = usage.get();
+    println!("Token usage: {usage}");
+    if let Some(cost) = prices.cost(&args.engine, &usage) {
+        println!("Estimated cost: ${cost:.4}");
+    }
+
+    let mut good_ones: Vec<TypecheckedCompletion> = match res {
         Ok(good_ones) => good_ones,
         Err(e) => {
             eprintln!("Fatal error while running strategy: {e}");
@@ -67,6 +133,12 @@ async fn main() {
         }
     };
 
+    // the types go in JSDoc comments, which get type checked again
+    #[cfg(feature = "tsparser")]
+    if args.jsdoc {
+        good_ones = jsdoc_completions(&lang_client, good_ones, &file_contents).await;
+    }
+
     // sort by error count, then score
     sort_completions(&mut good_ones);
 
@@ -78,18 +150,179 @@ async fn main() {
     println!("Number of good completions: {}", good_ones.len());
 
     // if the completed dir does not exist, create it
-    let output_dir = std::path::Path::new(&args.output);
+    let output_dir = std::path::Path::new(args.output());
     if !output_dir.exists() {
         tokio::fs::create_dir_all(output_dir).await.unwrap();
     }
 
+    // only the declarations of the best completion are written
+    #[cfg(feature = "tsparser")]
+    if args.dts {
+        write_dts(&args, &lang_client, &good_ones[0], &file_contents).await;
+        return;
+    }
+
     // write to the output dir
+    let ext = if args.jsdoc { "js" } else { &args.lang };
     for (i, comp) in good_ones.into_iter().enumerate() {
         let fallback = if comp.fallbacked { "_fallback" } else { "" };
         let output_path = format!(
             "{}/{}_errors_{}_score_{}{}.{}",
-            args.output, i, comp.num_type_errors, comp.score, fallback, args.lang
+            args.output(),
+            i,
+            comp.num_type_errors,
+            comp.score,
+            fallback,
+            ext
         );
         tokio::fs::write(&output_path, comp.code).await.unwrap();
     }
 }
+
+/// Writes the types of the completions as JSDoc comments into the original file, and counts
+/// their type errors as JavaScript. The completions that can't be turned into JSDoc, or
+/// that changed the code of the file, are dropped.
+#[cfg(feature = "tsparser")]
+async fn jsdoc_completions(
+    lang_client: &ArcLangServer,
+    comps: Vec<TypecheckedCompletion>,
+    original: &str,
+) -> Vec<TypecheckedCompletion> {
+    let mut js_comps = Vec::with_capacity(comps.len());
+    for comp in comps {
+        let Some(code) = jsdoc_into(original, &comp.code) else {
+            eprintln!("Could not write a completion into the file as JSDoc, skipping it");
+            continue;
+        };
+        let num_type_errors = lang_client.type_check_js(&code).await.unwrap_or_else(|e| {
+            eprintln!("Failed to type check the JSDoc completion: {e}");
+            std::process::exit(1);
+        });
+        js_comps.push(TypecheckedCompletion {
+            code,
+            num_type_errors,
+            ..comp
+        });
+    }
+    js_comps
+}
+
+/// Writes the declaration file of the given completion to the output dir, named after the
+/// input file, and reports its errors against the original code.
+#[cfg(feature = "tsparser")]
+async fn write_dts(
+    args: &Args,
+    lang_client: &ArcLangServer,
+    comp: &TypecheckedCompletion,
+    original: &str,
+) {
+    let Some(dts) = ts_to_dts(&comp.code) else {
+        eprintln!("Could not make the declarations of the completion");
+        std::process::exit(1);
+    };
+    let errors = lang_client
+        .check_declarations(&dts, original)
+        .await
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to check the declarations: {e}");
+            std::process::exit(1);
+        });
+    println!("Declaration errors: {errors}");
+
+    let stem = std::path::Path::new(args.file())
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("index");
+    let output_path = format!("{}/{stem}.d.ts", args.output());
+    tokio::fs::write(&output_path, dts).await.unwrap();
+}
+
+/// Runs the `cache` subcommand, exiting with 1 on errors.
+async fn cache_main(args: CacheArgs) {
+    let exit_on_err = |e: opentau::cache::CacheError| -> ! {
+        eprintln!("Cache error: {e}");
+        std::process::exit(1);
+    };
+    let manager = CacheManager::open(&args.cache)
+        .await
+        .unwrap_or_else(|e| exit_on_err(e));
+
+    match args.command {
+        CacheCommand::Stats(namespace) => {
+            let stats = manager
+                .stats(&namespace.filter())
+                .await
+                .unwrap_or_else(|e| exit_on_err(e));
+            println!("{stats}");
+        }
+        CacheCommand::Ls(namespace) => {
+            let entries = manager
+                .list(&namespace.filter())
+                .await
+                .unwrap_or_else(|e| exit_on_err(e));
+            for entry in entries {
+                let ttl = match entry.ttl {
+                    Some(ttl) => format!("{}s", ttl.as_secs()),
+                    None => "-".to_string(),
+                };
+                println!(
+                    "{}  {}  {}  {}  {} bytes  ttl {}",
+                    entry.id,
+                    entry.info.project,
+                    entry.info.model,
+                    entry.info.kind,
+                    entry.size,
+                    ttl
+                );
+            }
+        }
+        CacheCommand::Show { entry } => match manager.show(&entry).await {
+            Ok(Some(entry)) => {
+                println!("Key: {}", entry.key);
+                if let Some(expires_at) = entry.expires_at {
+                    println!("Expires at: {expires_at} (unix time)");
+                }
+                let comps: Vec<String> = serde_json::from_str(&entry.value).unwrap_or_default();
+                for (i, comp) in comps.iter().enumerate() {
+                    println!("--- Completion {i} ---\n{comp}");
+                }
+            }
+            Ok(None) => {
+                eprintln!("No entry {entry}");
+                std::process::exit(1);
+            }
+            Err(e) => exit_on_err(e),
+        },
+        CacheCommand::Purge { namespace, all } => {
+            if !all && namespace.project.is_none() && namespace.model.is_none() {
+                eprintln!("Refusing to purge every namespace without --all");
+                std::process::exit(1);
+            }
+            let deleted = manager
+                .purge(&namespace.filter())
+                .await
+                .unwrap_or_else(|e| exit_on_err(e));
+            println!("Deleted {deleted} entries");
+        }
+        CacheCommand::Export { file, namespace } => {
+            let mut writer = std::io::BufWriter::new(
+                std::fs::File::create(&file).unwrap_or_else(|e| exit_on_err(e.into())),
+            );
+            let exported = manager
+                .export(&mut writer, &namespace.filter())
+                .await
+                .unwrap_or_else(|e| exit_on_err(e));
+            println!("Exported {exported} entries to {file}");
+        }
+        CacheCommand::Import { file } => {
+            let reader = std::io::BufReader::new(
+                std::fs::File::open(&file).unwrap_or_else(|e| exit_on_err(e.into())),
+            );
+            let imported = manager
+                .import(reader)
+                .await
+                .unwrap_or_else(|e| exit_on_err(e));
+            println!("Imported {imported} entries from {file}");
+        }
+    }
+}
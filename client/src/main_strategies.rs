@@ -1,11 +1,22 @@
+use std::sync::Arc;
+
 use crate::{
     completion::ArcCompletionEngine,
-    completion::{Completion, CompletionError, CompletionQueryBuilder, TypecheckedCompletion},
+    completion::{
+        complete_keeping_partial, Completion, CompletionError, CompletionQueryBuilder,
+        CompletionStream, TypecheckedCompletion,
+    },
     debug,
-    langserver::{AnnotateType, CheckProblem, LangServerError},
+    imports::ImportContext,
+    langserver::{AnnotateType, CheckProblem, LangServerError, ProblemWhitelist},
+    prompt::PromptBuilder,
     tree::{stats::ArcTreeAlgoStats, CompletionLevels, HyperParams},
+    usage::UsageTracker,
+};
+use tokio::{
+    sync::{mpsc, Semaphore},
+    task::JoinHandle,
 };
-use tokio::{sync::Semaphore, task::JoinHandle};
 
 /// The context for the program.
 /// Splits into different strategies.
@@ -22,8 +33,18 @@ pub struct MainCtx {
     pub enable_stubbing: bool,
     pub enable_parser: bool,
     pub enable_checkproblems: bool,
+    /// The problems that are allowed in completions, when check problems are enabled.
+    pub problem_whitelist: ProblemWhitelist,
+    /// Whether to type check the completions as they come from the model, stopping the
+    /// model requests once `stop_at` completions are type checked.
+    pub enable_streaming: bool,
     pub depth_limit: Option<usize>,
     pub types: Vec<AnnotateType>,
+    /// The declarations imported by the file, which get prepended to the prompts.
+    /// None if import context is disabled.
+    pub import_context: Option<ImportContext>,
+    /// Where the token usage of the model requests for this file gets recorded.
+    pub usage: UsageTracker,
 }
 
 impl MainCtx {
@@ -57,12 +78,93 @@ impl MainCtx {
 
         comps
     }
+
+    /// Type checks the completions of the given stream as they come. Once `stop_at`
+    /// completions are type checked, the outstanding model requests are cancelled.
+    /// If `strip_imports` is the prompt, the imported declarations that were prepended to
+    /// it are stripped from the completions before type checking, and the completions that
+    /// changed them are dropped. Also returns whether the model requests were
+    /// cancelled, in which case the completions are only the ones that came first.
+    pub async fn type_check_stream(
+        &self,
+        mut stream: CompletionStream,
+        strip_imports: Option<&str>,
+    ) -> Result<(Vec<TypecheckedCompletion>, bool), CompletionError> {
+        println!(" --- Type Checking Candidates As They Come ---");
+        let mut comps: Vec<TypecheckedCompletion> = vec![];
+        let mut handles: Vec<JoinHandle<()>> = vec![];
+        // we drop our sender once the stream is done, so that the receiver ends once all
+        // the type checks are done
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut tx = Some(tx);
+        // don't overload it, max 5 at a time
+        let sem = Arc::new(Semaphore::new(5));
+        let mut num_candidates = 0;
+
+        loop {
+            tokio::select! {
+                next = stream.next(), if tx.is_some() => match next {
+                    Some(Ok(mut candidate)) => {
+                        if let Some(prompt) = strip_imports {
+                            match ImportContext::strip(prompt, &candidate.code) {
+                                Some(code) => candidate.code = code,
+                                None => {
+                                    debug!("candidate changed the imports, skipping it");
+                                    continue;
+                                }
+                            }
+                        }
+                        debug!("candidate {}:\n{}", num_candidates, candidate.code);
+                        num_candidates += 1;
+                        let lang_client = self.engine.get_ls();
+                        let sem = sem.clone();
+                        let tx = tx.clone().unwrap();
+                        handles.push(tokio::task::spawn(async move {
+                            let _permit = sem.acquire().await.unwrap();
+                            let res = lang_client.type_check(&candidate.code).await.map(
+                                |type_checks| TypecheckedCompletion::new(candidate, type_checks),
+                            );
+                            // the receiver is gone if we already have enough
+                            let _ = tx.send(res);
+                        }));
+                    }
+                    Some(Err(e)) => return Err(e),
+                    None => tx = None,
+                },
+                res = rx.recv() => match res {
+                    Some(res) => {
+                        comps.push(res?);
+                        if comps.len() >= self.stop_at {
+                            break;
+                        }
+                    }
+                    None => break,
+                },
+            }
+        }
+
+        // we have enough, or there is nothing left
+        let cancelled = tx.is_some();
+        stream.cancel();
+        for handle in handles {
+            handle.abort();
+        }
+
+        if num_candidates == 0 {
+            return Err(CompletionError::CouldNotComplete);
+        }
+
+        Ok((comps, cancelled))
+    }
 }
 
 #[async_trait::async_trait]
 pub trait MainStrategy {
     /// Run the strategy on the given context.
     async fn run(&self, context: MainCtx) -> Result<Vec<TypecheckedCompletion>, CompletionError>;
+
+    /// The language server commands that the strategy needs, by their name in the protocol.
+    fn required_commands(&self) -> &'static [&'static str];
 }
 
 pub struct TreeStrategy {
@@ -73,8 +175,7 @@ pub struct SimpleStrategy;
 #[async_trait::async_trait]
 impl MainStrategy for TreeStrategy {
     /// Runs the tree completion strategy. Documentation on the strategy is in the `tree.rs` file.
-    ///
-    /// TODO: somehow add caching to this strategy, maybe go up the tree?
+    /// If the engine has a cache, the completions of every node are cached by their prompt.
     ///
     /// TODO: implement enable_type_parser and enable_checkproblems options
     async fn run(&self, context: MainCtx) -> Result<Vec<TypecheckedCompletion>, CompletionError> {
@@ -96,9 +197,12 @@ impl MainStrategy for TreeStrategy {
             stub: context.enable_stubbing,
             stop_at: context.stop_at,
             types: context.types.clone(),
+            import_context: context.import_context.clone(),
+            problem_whitelist: context.problem_whitelist.clone(),
         };
 
-        let levels = CompletionLevels::new(hyper_params, self.stats.clone());
+        let levels = CompletionLevels::new(hyper_params, self.stats.clone())
+            .usage_tracker(context.usage.clone());
 
         let prepared = levels.prepare(tree, context.engine.get_ls()).await?;
         let completed = prepared.tree_complete(context.engine.clone()).await;
@@ -108,15 +212,20 @@ impl MainStrategy for TreeStrategy {
         let mut handles: Vec<JoinHandle<Completion>> = vec![];
         for code in disassembled {
             let ls = context.engine.get_ls();
+            let weights = context.engine.get_problem_weights();
             handles.push(tokio::task::spawn(async move {
-                let (_, score) = ls
+                let (problems, score) = ls
                     .check_complete(&code, &code)
                     .await
                     .unwrap_or((vec![], 1000));
+                let score = weights.score(&problems, score);
                 Completion {
                     code,
                     score,
                     fallbacked: false,
+                    // the code is assembled from the completions of many nodes, which we
+                    // don't keep the log-probabilities of
+                    logprob: None,
                 }
             }));
         }
@@ -138,6 +247,10 @@ impl MainStrategy for TreeStrategy {
                 .collect()
         })
     }
+
+    fn required_commands(&self) -> &'static [&'static str] {
+        &["tree", "usages", "stub", "print", "weave", "check"]
+    }
 }
 
 #[async_trait::async_trait]
@@ -155,22 +268,44 @@ impl MainStrategy for SimpleStrategy {
             context.file_contents.clone()
         };
 
-        let printed = context
-            .engine
-            .get_ls()
-            .pretty_print(&initial_input, "_hole_", &context.types)
+        // we can't stub or split the whole file, only drop the imported declarations
+        let max_tokens = context.engine.get_max_prompt_tokens();
+        let fitted = PromptBuilder::new(&initial_input, &context.types)
+            .import_context(context.import_context.as_ref())
+            .max_tokens(max_tokens)
+            .engine(&*context.engine)
+            .auto_stub(false)
+            .split(false)
+            .build(&context.engine.get_ls())
             .await?;
+        let printed = fitted.parts.into_iter().next().unwrap();
 
         debug!("pretty:\n{}", printed);
 
+        for warning in fitted.warnings {
+            eprintln!("Prompt too large: {warning}");
+        }
+        if let Some(max_tokens) = max_tokens {
+            let tokens = context.engine.count_tokens(&printed);
+            if tokens > max_tokens {
+                eprintln!(
+                    "Prompt has ~{tokens} tokens, more than the {max_tokens} the model allows. \
+                     Consider using the tree strategy."
+                );
+            }
+        }
+
         let mut query_builder = CompletionQueryBuilder::new(printed)
             .num_comps(context.num_comps)
             .retries(context.retries)
-            .fallback(context.fallback);
+            .fallback(context.fallback)
+            .usage(context.usage.clone());
 
-        if !context.enable_checkproblems {
-            query_builder = query_builder.problem_whitelist(CheckProblem::all());
-        }
+        query_builder = if context.enable_checkproblems {
+            query_builder.problem_whitelist(context.problem_whitelist.clone())
+        } else {
+            query_builder.problem_whitelist(CheckProblem::all())
+        };
 
         if !context.enable_parser {
             query_builder = query_builder.enable_type_parser(false);
@@ -182,46 +317,62 @@ impl MainStrategy for SimpleStrategy {
 
         let query = query_builder.build();
 
-        let candidates = match context.engine.complete(query.clone()).await {
-            Ok(r) => r,
-            Err(CompletionError::RateLimit(r)) if !r.is_empty() => {
-                eprintln!(
-                    "Rate limited, but got {} canditate completions before.",
-                    r.len()
-                );
-                r
-            }
-            Err(e) => {
-                return Err(e);
-            }
-        };
+        // the declarations from imports are only there for the model to see
+        let strip_imports = context
+            .import_context
+            .is_some()
+            .then_some(query.input.as_str());
 
-        let comps: Vec<TypecheckedCompletion> = if context.enable_type_check {
-            context.type_check_candidates(candidates).await
+        // the completions of a cancelled stream are not all the completions of the query,
+        // so they are not cached
+        let (comps, cacheable) = if context.enable_type_check && context.enable_streaming {
+            let stream = context.engine.complete_stream(query.clone()).await;
+            let (comps, cancelled) = context.type_check_stream(stream, strip_imports).await?;
+            (comps, !cancelled)
         } else {
-            candidates
-                .into_iter()
-                .map(|c| TypecheckedCompletion::new(c, 0))
-                .collect()
+            let mut candidates = complete_keeping_partial(&context.engine, query.clone()).await?;
+            if let Some(prompt) = strip_imports {
+                candidates = candidates
+                    .into_iter()
+                    .filter_map(|mut candidate| {
+                        candidate.code = ImportContext::strip(prompt, &candidate.code)?;
+                        Some(candidate)
+                    })
+                    .collect();
+            }
+            let comps = if context.enable_type_check {
+                context.type_check_candidates(candidates).await
+            } else {
+                candidates
+                    .into_iter()
+                    .map(|c| TypecheckedCompletion::new(c, 0))
+                    .collect()
+            };
+            (comps, true)
         };
 
         // cache the type-checked completions if we have a cache
-        if let Some(mut cache) = context.engine.get_cache().await {
+        if let Some(cache) = context.engine.get_cache().filter(|_| cacheable) {
             // we want to get all the completions that are typechecked
             // except the one that fallbacked (if there is any)
             let comps_no_fallback = comps
                 .iter()
                 .filter(|c| !c.fallbacked)
-                .map(|c| c.code.clone())
-                .collect::<Vec<String>>();
+                .map(|c| c.clone().into())
+                .collect::<Vec<Completion>>();
 
             if !comps_no_fallback.is_empty() {
-                cache
-                    .store(&query, &comps_no_fallback)
-                    .expect("failed to store in cache");
+                if let Err(e) = cache.store(&query, &comps_no_fallback).await {
+                    eprintln!("Failed to store completions in cache: {e}");
+                }
             }
         }
 
         Ok(comps)
     }
+
+    /// The `typedefGen` command is only needed with `enable_defgen`, so it is not listed.
+    fn required_commands(&self) -> &'static [&'static str] {
+        &["print", "check"]
+    }
 }
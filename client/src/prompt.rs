@@ -0,0 +1,262 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    completion::CompletionEngine,
+    debug,
+    imports::ImportContext,
+    langserver::{AnnotateType, ArcLangServer, LangServerError},
+    usage::estimate_tokens,
+};
+
+/// What had to be done to a prompt to make it fit in the context window of the model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ContextWarning {
+    /// The usage statements and the imported declarations were dropped.
+    DroppedContext,
+    /// The children of the code block were stubbed.
+    Stubbed,
+    /// The prompt was split into the given number of parts, that get completed separately.
+    Split(usize),
+}
+
+impl std::fmt::Display for ContextWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContextWarning::DroppedContext => write!(f, "dropped the usages and imports"),
+            ContextWarning::Stubbed => write!(f, "stubbed the children"),
+            ContextWarning::Split(n) => write!(f, "split the prompt into {n} parts"),
+        }
+    }
+}
+
+/// A prompt that fits in the context window, made by `PromptBuilder`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FittedPrompt {
+    /// The parts of the prompt. There is more than one part only if the prompt was split,
+    /// in which case the completions of the parts need to be woven back into the code.
+    pub parts: Vec<String>,
+    /// What had to be done to make the prompt fit, in order.
+    pub warnings: Vec<ContextWarning>,
+}
+
+/// Builds the prompt for a code block, making sure that it fits in the context window
+/// of the model. When the prompt is too large, we first drop the usage statements and
+/// imported declarations, then we stub the children of the code block, and then we
+/// split the code block into parts with the splitter of the language server, if it has one.
+#[derive(Debug, Clone)]
+pub struct PromptBuilder<'a> {
+    code: &'a str,
+    types: &'a [AnnotateType],
+    /// defaults to ""
+    usages: &'a str,
+    /// defaults to None
+    import_context: Option<&'a ImportContext>,
+    /// defaults to false
+    stub: bool,
+    /// defaults to None, meaning that there is no limit
+    max_tokens: Option<usize>,
+    /// defaults to None, meaning that the tokens are estimated
+    engine: Option<&'a (dyn CompletionEngine + Send + Sync)>,
+    /// defaults to true
+    auto_stub: bool,
+    /// defaults to true
+    split: bool,
+}
+
+impl<'a> PromptBuilder<'a> {
+    pub fn new(code: &'a str, types: &'a [AnnotateType]) -> Self {
+        Self {
+            code,
+            types,
+            usages: "",
+            import_context: None,
+            stub: false,
+            max_tokens: None,
+            engine: None,
+            auto_stub: true,
+            split: true,
+        }
+    }
+
+    /// The usage statements to prepend to the code.
+    pub fn usages(mut self, usages: &'a str) -> Self {
+        self.usages = usages;
+        self
+    }
+
+    /// The imported declarations to prepend to the code.
+    pub fn import_context(mut self, import_context: Option<&'a ImportContext>) -> Self {
+        self.import_context = import_context;
+        self
+    }
+
+    /// Whether to always stub the children of the code block.
+    pub fn stub(mut self, stub: bool) -> Self {
+        self.stub = stub;
+        self
+    }
+
+    /// The maximum number of tokens of the prompt.
+    pub fn max_tokens(mut self, max_tokens: Option<usize>) -> Self {
+        self.max_tokens = max_tokens;
+        self
+    }
+
+    /// The completion engine whose model counts the tokens of the prompt. Without one,
+    /// the tokens are estimated with `estimate_prompt_tokens`.
+    pub fn engine(mut self, engine: &'a (dyn CompletionEngine + Send + Sync)) -> Self {
+        self.engine = Some(engine);
+        self
+    }
+
+    /// Whether to stub the children of the code block if the prompt is too large.
+    pub fn auto_stub(mut self, auto_stub: bool) -> Self {
+        self.auto_stub = auto_stub;
+        self
+    }
+
+    /// Whether to split the prompt if it is still too large after everything else.
+    pub fn split(mut self, split: bool) -> Self {
+        self.split = split;
+        self
+    }
+
+    /// Pretty prints the code with holes, shrinking it until it fits.
+    pub async fn build(self, ls: &ArcLangServer) -> Result<FittedPrompt, LangServerError> {
+        let mut warnings = vec![];
+
+        let code = if self.stub {
+            ls.stub(self.code).await?
+        } else {
+            self.code.to_string()
+        };
+        let mut printed = ls.pretty_print(&code, "_hole_", self.types).await?;
+
+        let with_context = self.with_context(&printed);
+        if self.fits(&with_context) {
+            return Ok(FittedPrompt {
+                parts: vec![with_context],
+                warnings,
+            });
+        }
+
+        if with_context != printed {
+            warnings.push(ContextWarning::DroppedContext);
+            if self.fits(&printed) {
+                return Ok(FittedPrompt {
+                    parts: vec![printed],
+                    warnings,
+                });
+            }
+        }
+
+        if self.auto_stub && !self.stub {
+            let code = ls.stub(self.code).await?;
+            // leaves have nothing to stub
+            if code != self.code {
+                warnings.push(ContextWarning::Stubbed);
+                printed = ls.pretty_print(&code, "_hole_", self.types).await?;
+                if self.fits(&printed) {
+                    return Ok(FittedPrompt {
+                        parts: vec![printed],
+                        warnings,
+                    });
+                }
+            }
+        }
+        debug!(
+            "prompt still too large, {} tokens",
+            self.count_tokens(&printed)
+        );
+
+        let parts = match (self.max_tokens, ls.get_splitter()) {
+            (Some(max_tokens), Some(splitter)) if self.split => {
+                splitter(&printed, max_tokens, &|text| self.count_tokens(text))
+            }
+            _ => None,
+        };
+        match parts {
+            Some(parts) if parts.len() > 1 => {
+                warnings.push(ContextWarning::Split(parts.len()));
+                Ok(FittedPrompt { parts, warnings })
+            }
+            _ => Ok(FittedPrompt {
+                parts: vec![printed],
+                warnings,
+            }),
+        }
+    }
+
+    fn with_context(&self, printed: &str) -> String {
+        let mut prompt = if self.usages.is_empty() {
+            printed.to_string()
+        } else {
+            format!("{}\n{}", self.usages, printed)
+        };
+        if let Some(import_context) = self.import_context {
+            prompt = import_context.prepend_to(&prompt);
+        }
+        prompt
+    }
+
+    fn fits(&self, prompt: &str) -> bool {
+        self.max_tokens
+            .is_none_or(|max| self.count_tokens(prompt) <= max)
+    }
+
+    fn count_tokens(&self, text: &str) -> usize {
+        match self.engine {
+            Some(engine) => engine.count_tokens(text),
+            None => estimate_prompt_tokens(text),
+        }
+    }
+}
+
+/// How much `estimate_prompt_tokens` adds to the estimate of the usage, in percent. The
+/// estimate is off by some tokens either way, so without a margin a prompt that it puts
+/// right below the limit could overflow the context window of the model. The heuristic
+/// overestimates the codex tokenizer by about 10% to 30% on TypeScript, so this covers
+/// code that it underestimates by as much.
+pub const ESTIMATE_MARGIN_PERCENT: usize = 10;
+
+/// Estimates the tokens of the given prompt, for models whose tokenizer we don't have.
+/// This is the estimate used for the usage plus `ESTIMATE_MARGIN_PERCENT`, rounded up.
+pub fn estimate_prompt_tokens(text: &str) -> usize {
+    let estimate: usize = estimate_tokens(text).try_into().unwrap_or(usize::MAX);
+    estimate.saturating_add((estimate.saturating_mul(ESTIMATE_MARGIN_PERCENT)).div_ceil(100))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::completion::tokenizer::Tokenizer;
+
+    #[test]
+    fn estimate_margin() {
+        let text = "a b c d e f g h i j ".repeat(5);
+        assert_eq!(estimate_tokens(&text), 100);
+        assert_eq!(estimate_prompt_tokens(&text), 110);
+        assert_eq!(estimate_prompt_tokens("a"), 2);
+        assert_eq!(estimate_prompt_tokens(""), 0);
+    }
+
+    #[test]
+    fn estimate_covers_p50k() {
+        let p50k = Tokenizer::p50k();
+        let prompts = [
+            "function add(a: _hole_, b: _hole_): _hole_ {\n    return a + b;\n}\n",
+            "class Point {\n    constructor(public x: _hole_, public y: _hole_) {}\n\n    \
+             norm(): _hole_ {\n        return Math.sqrt(this.x * this.x + this.y * this.y);\n    \
+             }\n}\n",
+            "const xs: _hole_ = [1, 2, 3].map((x) => x * 2).filter((x) => x > 2);\n",
+            "export default function (s: _hole_) {\n\t\tif (s === \"\") {\n\t\t\treturn null;\
+             \n\t\t}\n\t\treturn s.split(\",\").map(Number);\n}\n",
+            "interface Config {\n  verbose: _hole_;\n  retries: _hole_;\n  onError: _hole_;\n}\n",
+        ];
+        for prompt in prompts {
+            let exact = p50k.count(prompt);
+            let estimate = estimate_prompt_tokens(prompt);
+            assert!(estimate >= exact, "{estimate} < {exact} for {prompt:?}");
+        }
+    }
+}
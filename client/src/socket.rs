@@ -1,4 +1,4 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, os::unix::process::CommandExt, sync::Arc, time::Duration};
 
 use lazy_static::lazy_static;
 use serde::Serialize;
@@ -14,10 +14,97 @@ use tokio::{
 
 use crate::debug;
 
+/// A connection to a server over a unix socket. When we spawned the server ourselves, the
+/// server is supervised: if it exits or stops answering, it is spawned again on a fresh
+/// socket, and the request that failed is retried. See `Supervision`.
 #[derive(Debug)]
 pub struct SocketAbstraction {
-    pub socket_path: String,
-    pub process: Option<tokio::process::Child>,
+    server: Arc<Mutex<Server>>,
+    // how to spawn the server again, None if we didn't spawn it
+    spawner: Option<Arc<Spawner>>,
+    supervision: Supervision,
+}
+
+/// The server behind a socket, which changes when the server is respawned.
+#[derive(Debug)]
+struct Server {
+    socket_path: String,
+    process: Option<tokio::process::Child>,
+    // the process group of the server, which has the id of the process we spawned. The
+    // command we spawn may spawn the actual server, e.g. `npm start` spawns node, so we
+    // kill the whole group.
+    process_group: Option<u32>,
+    // the number of times the server was respawned, so that many requests failing at
+    // the same time respawn it only once
+    generation: usize,
+}
+
+/// The command that spawns a server.
+#[derive(Debug)]
+struct Spawner {
+    name: String,
+    server_command_prefix: Vec<String>,
+    pid_coordination: bool,
+}
+
+/// How a spawned server is supervised. By default, a server is respawned when a request
+/// fails to reach it, and the request is retried once. Health checks, which also catch
+/// servers that hang or exit between requests, need the server to answer the `ping`
+/// command, so they are only enabled with `health_check`.
+#[derive(Debug, Clone)]
+pub struct Supervision {
+    health_check_interval: Option<Duration>,
+    ping_timeout: Duration,
+    max_retries: usize,
+    non_idempotent: Vec<String>,
+}
+
+impl Default for Supervision {
+    fn default() -> Self {
+        Self {
+            health_check_interval: None,
+            ping_timeout: Duration::from_secs(30),
+            max_retries: 1,
+            non_idempotent: vec![],
+        }
+    }
+}
+
+impl Supervision {
+    /// Pings the server at the given interval, and respawns it if it has exited or doesn't
+    /// answer.
+    pub fn health_check(mut self, interval: Duration) -> Self {
+        self.health_check_interval = Some(interval);
+        self
+    }
+
+    /// Sets how long a ping may take before the server is considered hung, 30 seconds by
+    /// default. The server answers the requests one at a time, so this has to be longer
+    /// than the requests that may be in front of the ping.
+    pub fn ping_timeout(mut self, timeout: Duration) -> Self {
+        self.ping_timeout = timeout;
+        self
+    }
+
+    /// Sets how many times a failed request is retried on a respawned server, once by
+    /// default.
+    pub fn max_retries(mut self, max_retries: usize) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Sets the commands that are not retried when the server fails, as it may have
+    /// handled them already. By default, every command is retried.
+    pub fn non_idempotent(mut self, commands: &[&str]) -> Self {
+        self.non_idempotent = commands.iter().map(|c| c.to_string()).collect();
+        self
+    }
+
+    fn retries(&self, req: &serde_json::Value) -> bool {
+        !req["cmd"]
+            .as_str()
+            .is_some_and(|cmd| self.non_idempotent.iter().any(|c| c == cmd))
+    }
 }
 
 #[derive(Debug)]
@@ -113,17 +200,24 @@ lazy_static!(
 impl SocketAbstraction {
     /// Creates a new socket abstraction, does not spawn a process for it.
     /// The socket path is the path to the socket file, so we assume a
-    /// server is already running.
+    /// server is already running. The server is not supervised.
     pub fn new(socket_path: String) -> Self {
         Self {
-            socket_path,
-            process: None,
+            server: Arc::new(Mutex::new(Server {
+                socket_path,
+                process: None,
+                process_group: None,
+                generation: 0,
+            })),
+            spawner: None,
+            supervision: Supervision::default(),
         }
     }
 
     /// Spawns a new server process and returns a socket abstraction for it.
     /// The server command prefix is the prefix of the command to spawn the server.
     /// Does not include the last two args, which are the socket path and pid (optional).
+    /// The server is supervised with the default `Supervision`.
     pub async fn spawn_server(
         name: &str,
         // This is the prefix of the command to spawn the server.
@@ -131,28 +225,74 @@ impl SocketAbstraction {
         server_command_prefix: &[&str],
         pid_coordination: bool,
     ) -> Result<SocketAbstraction, SocketError> {
+        let spawner = Spawner {
+            name: name.to_string(),
+            server_command_prefix: server_command_prefix
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            pid_coordination,
+        };
+        let (socket_path, process) = spawner.spawn().await?;
+        Ok(SocketAbstraction {
+            server: Arc::new(Mutex::new(Server {
+                socket_path,
+                process_group: process.id(),
+                process: Some(process),
+                generation: 0,
+            })),
+            spawner: Some(Arc::new(spawner)),
+            supervision: Supervision::default(),
+        })
+    }
+
+    /// Sets how the server is supervised, and starts its health checks if they are
+    /// enabled. Does nothing for servers that we didn't spawn.
+    pub fn supervise(mut self, supervision: Supervision) -> Self {
+        if let (Some(spawner), Some(interval)) = (&self.spawner, supervision.health_check_interval)
+        {
+            tokio::spawn(health_check(
+                Arc::downgrade(&self.server),
+                spawner.clone(),
+                interval,
+                supervision.ping_timeout,
+            ));
+        }
+        self.supervision = supervision;
+        self
+    }
+
+    /// The socket path and the generation of the current server.
+    async fn current(&self) -> (String, usize) {
+        let server = self.server.lock().await;
+        (server.socket_path.clone(), server.generation)
+    }
+}
+
+impl Spawner {
+    /// Spawns the server on a fresh socket, returns the socket path and the process.
+    async fn spawn(&self) -> Result<(String, tokio::process::Child), SocketError> {
+        let name = &self.name;
         let pid = std::process::id();
         let s_i = SOCKET_COUNTER.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
         let tmp_dir = std::env::temp_dir();
         let tmp_socket_file = tmp_dir.join(format!("{name}-{pid}-{s_i}.sock"));
         debug!("tmp_socket_file: {:?}", tmp_socket_file);
 
-        let argv0 = server_command_prefix[0];
-        let mut rest = server_command_prefix[1..]
-            .iter()
-            .map(|s| s.to_string())
-            .collect::<Vec<_>>();
+        let argv0 = &self.server_command_prefix[0];
+        let mut rest = self.server_command_prefix[1..].to_vec();
         rest.push(tmp_socket_file.to_str().unwrap().to_string());
-        if pid_coordination {
+        if self.pid_coordination {
             // add pid to the rest of the arguments
             rest.push(pid.to_string());
         }
 
-        let mut process = tokio::process::Command::new(argv0)
-            .args(rest)
-            .stdout(std::process::Stdio::piped())
-            // stderr is open by default, we want to see the output
-            .spawn()?;
+        let mut command = std::process::Command::new(argv0);
+        // stderr is open by default, we want to see the output
+        command.args(rest).stdout(std::process::Stdio::piped());
+        // in its own process group, so that respawning it kills everything it spawned
+        command.process_group(0);
+        let mut process = tokio::process::Command::from(command).spawn()?;
 
         // before allowing to connect, wait for the process to output "Listening"
         {
@@ -160,7 +300,13 @@ impl SocketAbstraction {
             let reader = tokio::io::BufReader::new(stdout);
             let mut lines = reader.lines();
             debug!("{name} client output:");
-            while let Some(line) = lines.next_line().await.unwrap() {
+            loop {
+                let Some(line) = lines.next_line().await? else {
+                    return Err(SocketError::Io(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        format!("the {name} server exited before listening"),
+                    )));
+                };
                 debug!("{}", line);
                 if line.contains("Listening") {
                     break;
@@ -170,36 +316,127 @@ impl SocketAbstraction {
 
         debug!("client ready to connect to {name} socket!");
         let socket_path = tmp_socket_file.to_str().unwrap().to_string();
-        let socket = SocketAbstraction {
-            socket_path,
-            process: Some(process),
+        Ok((socket_path, process))
+    }
+
+    /// Replaces the server with a new one, unless it was already replaced since the
+    /// given generation.
+    async fn respawn(&self, server: &Mutex<Server>, generation: usize) -> Result<(), SocketError> {
+        let mut server = server.lock().await;
+        if server.generation != generation {
+            return Ok(());
+        }
+        // it may be hanging, and it may have exited already
+        if let Some(pgid) = server.process_group.take() {
+            // SAFETY: kill has no memory safety requirements
+            unsafe {
+                libc::kill(-(pgid as libc::pid_t), libc::SIGKILL);
+            }
+        }
+        if let Some(mut process) = server.process.take() {
+            let _ = process.wait().await;
+        }
+        let _ = tokio::fs::remove_file(&server.socket_path).await;
+
+        let (socket_path, process) = self.spawn().await?;
+        eprintln!("Restarted the {} server", self.name);
+        server.socket_path = socket_path;
+        server.process_group = process.id();
+        server.process = Some(process);
+        server.generation += 1;
+        Ok(())
+    }
+}
+
+/// Whether the given error means that the server is gone, rather than that it failed to
+/// handle the request.
+fn is_server_failure(e: &SocketError) -> bool {
+    matches!(e, SocketError::Io(_))
+}
+
+/// Pings the server at the given interval, and respawns it if it has exited or doesn't
+/// answer in time. Stops when the socket is dropped.
+async fn health_check(
+    server: std::sync::Weak<Mutex<Server>>,
+    spawner: Arc<Spawner>,
+    interval: Duration,
+    ping_timeout: Duration,
+) {
+    let mut ticks = tokio::time::interval(interval);
+    // the first tick is immediate
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        let Some(server) = server.upgrade() else {
+            return;
+        };
+        let (socket_path, generation, exited) = {
+            let mut server = server.lock().await;
+            let exited = match server.process.as_mut() {
+                Some(process) => !matches!(process.try_wait(), Ok(None)),
+                None => false,
+            };
+            (server.socket_path.clone(), server.generation, exited)
+        };
+        let healthy = !exited && {
+            let ping = serde_json::json!({"cmd": "ping", "text": ""});
+            match tokio::time::timeout(ping_timeout, socket_transaction(&socket_path, &ping)).await
+            {
+                // an error response still means that the server is alive
+                Ok(res) => !res.as_ref().is_err_and(is_server_failure),
+                Err(_) => false,
+            }
         };
-        Ok(socket)
+        if !healthy {
+            if let Err(e) = spawner.respawn(&server, generation).await {
+                eprintln!("Failed to restart the {} server: {e}", spawner.name);
+            }
+        }
     }
+}
 
-    async fn socket_transaction<T>(&self, req: &T) -> Result<String, SocketError>
-    where
-        T: ?Sized + Serialize,
-    {
-        let mut stream = UnixStream::connect(self.socket_path.to_string()).await?;
-        let req = format!("{}{}", serde_json::to_string(req).unwrap(), END_TOKEN);
+async fn socket_transaction<T>(socket_path: &str, req: &T) -> Result<String, SocketError>
+where
+    T: ?Sized + Serialize,
+{
+    let mut stream = UnixStream::connect(socket_path).await?;
+    let req = format!("{}{}", serde_json::to_string(req).unwrap(), END_TOKEN);
 
-        stream.write_all(req.as_bytes()).await?;
-        stream.shutdown().await?;
+    stream.write_all(req.as_bytes()).await?;
+    stream.shutdown().await?;
 
-        let mut reader = tokio::io::BufReader::new(&mut stream);
-        let mut buf = String::new();
-        reader.read_line(&mut buf).await?;
-        Ok(buf)
+    let mut reader = tokio::io::BufReader::new(&mut stream);
+    let mut buf = String::new();
+    // the server closes the connection without answering when it dies
+    if reader.read_line(&mut buf).await? == 0 {
+        return Err(SocketError::Io(std::io::ErrorKind::UnexpectedEof.into()));
     }
+    Ok(buf)
 }
 
 #[async_trait::async_trait]
 impl SendToSocket for SocketAbstraction {
     /// Sends the given request to the server and returns the response as a JSON object.
     /// Expects the response to have a `type` field, and if it is `error`, returns an error.
+    /// If the server is supervised and fails, it is respawned and the request is retried.
     async fn send_req(&self, req: serde_json::Value) -> Result<serde_json::Value, SocketError> {
-        let buf = self.socket_transaction(&req).await?;
+        let mut retries = 0;
+        let buf = loop {
+            let (socket_path, generation) = self.current().await;
+            match socket_transaction(&socket_path, &req).await {
+                Err(e) if is_server_failure(&e) => {
+                    let Some(spawner) = &self.spawner else {
+                        return Err(e);
+                    };
+                    spawner.respawn(&self.server, generation).await?;
+                    if retries >= self.supervision.max_retries || !self.supervision.retries(&req) {
+                        return Err(e);
+                    }
+                    retries += 1;
+                }
+                res => break res?,
+            }
+        };
 
         // into json object
         let resp: serde_json::Value = serde_json::from_str(&buf)?;
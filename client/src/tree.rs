@@ -1,6 +1,6 @@
 use std::{
     cmp::Ordering,
-    collections::{HashMap, HashSet, VecDeque},
+    collections::{HashMap, VecDeque},
     sync::Arc,
 };
 
@@ -9,9 +9,14 @@ use tokio::{sync::Mutex, task::JoinHandle};
 
 use crate::{
     completion::{
-        ArcCompletionEngine, Completion, CompletionError, CompletionQuery, CompletionQueryBuilder,
+        complete_keeping_partial, ArcCompletionEngine, Completion, CompletionQuery,
+        CompletionQueryBuilder,
     },
-    langserver::{AnnotateType, CheckProblem},
+    dedup::CanonicalSet,
+    imports::ImportContext,
+    langserver::{AnnotateType, CheckProblem, ProblemWhitelist},
+    prompt::PromptBuilder,
+    usage::UsageTracker,
 };
 use crate::{
     debug,
@@ -26,12 +31,21 @@ pub mod stats {
     use serde::{Deserialize, Serialize};
     use tokio::sync::Mutex;
 
+    use crate::{prompt::ContextWarning, usage::TokenUsage};
+
     /// Keeps some statistics about the tree algorithm being run
     #[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
     pub struct TreeAlgoStats {
         pub num_nodes: usize,
         pub num_usages_per_node: HashMap<String, usize>,
         pub num_comps_per_node: HashMap<String, usize>,
+        #[serde(default)]
+        pub token_usage_per_node: HashMap<String, TokenUsage>,
+        #[serde(default)]
+        pub total_token_usage: TokenUsage,
+        /// What had to be done to the prompts of each node to fit in the context window
+        #[serde(default)]
+        pub context_warnings_per_node: HashMap<String, Vec<ContextWarning>>,
     }
 
     /// A mutexed and arced version of TreeAlgoStats
@@ -73,6 +87,39 @@ pub mod stats {
         })
         .await
     }
+
+    /// Adds the given context warnings to the given node's warnings, skipping duplicates
+    pub(super) async fn insert_context_warnings(
+        stats: &Option<ArcTreeAlgoStats>,
+        name: &str,
+        warnings: &[ContextWarning],
+    ) {
+        if_some_modify(stats, |stats| {
+            let node_warnings = stats
+                .context_warnings_per_node
+                .entry(name.to_string())
+                .or_default();
+            for warning in warnings {
+                if !node_warnings.contains(warning) {
+                    node_warnings.push(*warning);
+                }
+            }
+        })
+        .await
+    }
+
+    /// Sets the given node's token usage, and adds it to the total
+    pub(super) async fn insert_token_usage(
+        stats: &Option<ArcTreeAlgoStats>,
+        name: &str,
+        usage: TokenUsage,
+    ) {
+        if_some_modify(stats, |stats| {
+            stats.token_usage_per_node.insert(name.to_string(), usage);
+            stats.total_token_usage += usage;
+        })
+        .await
+    }
 }
 
 /// A codeblock tree, taken from the `tree` command of the language server
@@ -142,12 +189,16 @@ pub struct HyperParams {
     pub fallback: bool,
     // if we want to create usages block or not
     pub usages: bool,
-    // if we want to stub inner code blocks or not
+    // if we want to always stub inner code blocks, otherwise only when the prompt is too large
     pub stub: bool,
     // stop_at hyperparam
     pub stop_at: usize,
     // the kind of types that need to be annotated
     pub types: Vec<AnnotateType>,
+    // the declarations imported by the file, if we want to prepend them to the prompts
+    pub import_context: Option<ImportContext>,
+    // the problems that are allowed in completions
+    pub problem_whitelist: ProblemWhitelist,
 }
 
 #[derive(Debug, Clone)]
@@ -155,6 +206,8 @@ pub struct CompletionLevels<State = NewState> {
     levels: Vec<CompLevel>,
     params: HyperParams,
     stats: Option<ArcTreeAlgoStats>,
+    // the token usage of the whole tree, each node records into a child of this
+    usage: UsageTracker,
     // this is the state of the completion levels
     state: std::marker::PhantomData<State>,
 }
@@ -167,10 +220,17 @@ impl CompletionLevels<NewState> {
             levels: vec![],
             params: hyperparams,
             stats,
+            usage: UsageTracker::new(),
             state: std::marker::PhantomData,
         }
     }
 
+    /// Sets the tracker where the token usage of the completions gets recorded.
+    pub fn usage_tracker(mut self, usage: UsageTracker) -> Self {
+        self.usage = usage;
+        self
+    }
+
     /// Prepares the completion levels to be completed for the given codeblock tree
     pub async fn prepare(
         self,
@@ -246,6 +306,7 @@ impl CompletionLevels<NewState> {
             levels,
             params: self.params,
             stats: self.stats,
+            usage: self.usage,
             state: std::marker::PhantomData,
         })
     }
@@ -257,12 +318,12 @@ impl CompletionLevels<NewState> {
 async fn merge_below_all_combs(
     child: &CompNode,
     level: usize,
-    prompts_set: &mut HashSet<String>,
+    prompts_set: &mut CanonicalSet,
     ls: &ArcLangServer,
 ) {
     // make all possible combinations between prompt elements and
     // child.completed elements
-    let mut new_prompts = HashSet::new();
+    let mut new_prompts = CanonicalSet::for_ls(ls);
     for (p_i, parent_code) in prompts_set.iter().enumerate() {
         for (c_i, child_code) in child.completed.iter().enumerate() {
             debug!(
@@ -320,11 +381,7 @@ fn distribute_stop_at(stop_at: usize, num_children: usize) -> Vec<usize> {
 /// Generates all possible combinations between the prompts and the completions in pairs
 /// of (prompt, completion). The given upper bound is the maximum number of combinations
 /// that we want to generate, if any.
-fn all_combs(
-    prompts: &HashSet<String>,
-    comps: &[String],
-    upper: Option<usize>,
-) -> Vec<(String, String)> {
+fn all_combs(prompts: &[String], comps: &[String], upper: Option<usize>) -> Vec<(String, String)> {
     let mut upper = upper.unwrap_or(usize::MAX);
     let mut res = Vec::new();
     for prompt in prompts.iter() {
@@ -350,10 +407,10 @@ async fn merge_below_random_poisson(
     level: usize,
     // is our upper bound for the number of completions
     upper: usize,
-    prompts_set: &mut HashSet<String>,
+    prompts_set: &mut CanonicalSet,
     ls: &ArcLangServer,
 ) {
-    let mut new_prompts = HashSet::new();
+    let mut new_prompts = CanonicalSet::for_ls(ls);
 
     // 0.7 converges to this distribution:
     // 0: 50%
@@ -368,7 +425,7 @@ async fn merge_below_random_poisson(
     // that we will ever reach this upper bound, but it is a safety net for
     // state explosion.
     let combs_upper = upper * 5;
-    let mut all_combs = all_combs(prompts_set, &child.completed, Some(combs_upper));
+    let mut all_combs = all_combs(prompts_set.as_slice(), &child.completed, Some(combs_upper));
 
     let mut dbg_i = 0;
     while new_prompts.len() < upper && !all_combs.is_empty() {
@@ -402,28 +459,79 @@ async fn merge_below_random_poisson(
 }
 
 impl CompletionLevels<PreparedState> {
-    async fn retry_query_until_ok(
+    /// Queries the engine, whose model requests are retried according to its retry policy.
+    /// Returns None if the query failed anyway.
+    async fn query_or_give_up(
         engine: &ArcCompletionEngine,
         q: CompletionQuery,
     ) -> Option<Vec<Completion>> {
-        let mut res = engine.complete(q.clone()).await;
-        let mut retries = 0;
-        while res.is_err() {
-            // if it's a rate limit, print out to stderr
-            if let CompletionError::RateLimit(r) = res.unwrap_err() {
-                eprintln!(
-                    "Rate limited, but got {} canditate completions before.",
-                    r.len()
-                );
+        match complete_keeping_partial(engine, q).await {
+            Ok(comps) => Some(comps),
+            Err(e) => {
+                eprintln!("Giving up on query: {e}");
+                None
             }
-            if retries > 5 {
-                return None;
+        }
+    }
+
+    /// Completes the parts of a split prompt separately, and weaves their completions back
+    /// into the prompt: the i-th completion is made of the i-th completion of every part.
+    /// Parts that could not be completed get the any type in all of their holes, and the
+    /// completions they are in are marked as fallbacked.
+    async fn complete_parts(
+        engine: &ArcCompletionEngine,
+        prompt: &str,
+        parts: &[String],
+        make_query: &(impl Fn(String, bool) -> CompletionQuery + Sync),
+    ) -> Vec<Completion> {
+        let ls = engine.get_ls();
+        let any_type = ls.any_type();
+        let mut parts_comps: Vec<Vec<(String, Option<f64>)>> = vec![];
+        let mut fallbacked = false;
+        for part in parts {
+            let q = make_query(part.clone(), false);
+            debug!("part query: \n{}", q.input);
+            let comps = match Self::query_or_give_up(engine, q).await {
+                Some(comps) if !comps.is_empty() => {
+                    comps.into_iter().map(|c| (c.code, c.logprob)).collect()
+                }
+                _ => {
+                    fallbacked = true;
+                    vec![(part.replace("_hole_", &any_type), None)]
+                }
+            };
+            parts_comps.push(comps);
+        }
+
+        let num_comps = parts_comps.iter().map(|c| c.len()).max().unwrap_or(0);
+        let mut comps = vec![];
+        'comps: for i in 0..num_comps {
+            let picked: Vec<_> = parts_comps
+                .iter()
+                .map(|comps| &comps[i % comps.len()])
+                .collect();
+            // the parts of a split declaration all have its signature, we weave the first
+            // part last so that its types are the ones that stay
+            let mut code = prompt.to_string();
+            for (part, _) in picked.iter().rev() {
+                match ls.weave(&code, part, 0).await {
+                    Ok(woven) => code = woven,
+                    Err(e) => {
+                        debug!("failed to weave part: {e}");
+                        continue 'comps;
+                    }
+                }
             }
-            retries += 1;
-            let q = q.clone();
-            res = engine.complete(q).await;
+            comps.push(Completion {
+                code,
+                score: 0,
+                fallbacked,
+                logprob: picked
+                    .iter()
+                    .try_fold(0.0, |acc, (_, lp)| Some(acc + (*lp)?)),
+            });
         }
-        Some(res.unwrap())
+        comps
     }
 
     fn spawn_parallel_comp(
@@ -432,7 +540,10 @@ impl CompletionLevels<PreparedState> {
         level: usize,
         prev_level: Arc<Option<Vec<CompNode>>>,
         node: CompNode,
+        usage: UsageTracker,
+        stats: Option<ArcTreeAlgoStats>,
     ) -> JoinHandle<(String, Vec<String>)> {
+        let params = params.clone();
         let num_comps = params.num_comps;
         let retries = params.retries;
         let do_fallback = params.fallback;
@@ -440,9 +551,16 @@ impl CompletionLevels<PreparedState> {
         // we use stop_at as our upper bound for the number of completions
         let stop_at = params.stop_at;
         let types_to_annot = params.types.clone();
+        let import_context = params.import_context.clone();
+        // added comments are safe, we type-weave after
+        let problem_whitelist = params
+            .problem_whitelist
+            .clone()
+            .with(&[CheckProblem::ChangedComments]);
 
         tokio::task::spawn(async move {
-            let mut prompts_set: HashSet<String> = HashSet::from([node.code.clone()]);
+            let mut prompts_set = CanonicalSet::for_ls(&engine.get_ls());
+            prompts_set.insert(node.code.clone());
             // if we are not at a leaf, we need to patch the node with the children
             if !node.children_idxs.is_empty() {
                 let level_below: &Vec<CompNode> = prev_level.as_ref().as_ref().unwrap();
@@ -490,45 +608,106 @@ impl CompletionLevels<PreparedState> {
             match level.cmp(&0) {
                 Ordering::Greater => {
                     let ls = engine.get_ls();
-                    let mut new_comps = HashSet::new(); // we don't care about duplicates
+                    let max_tokens = engine.get_max_prompt_tokens();
+                    let make_query = |input: String, fallback: bool| {
+                        CompletionQueryBuilder::new(input)
+                            .num_comps(num_comps)
+                            .retries(retries)
+                            .fallback(fallback)
+                            .problem_whitelist(problem_whitelist.clone())
+                            .usage(usage.clone())
+                            .build()
+                    };
+                    // completions that only differ in how their types are written are duplicates
+                    let mut new_comps = CanonicalSet::for_ls(&ls);
                     for prompt in prompts.iter() {
-                        let stubbed = if do_stub {
-                            ls.stub(prompt).await.unwrap()
-                        } else {
-                            prompt.clone()
-                        };
+                        // nodes that were completed with the same prompt before come from the
+                        // cache, so nodes unaffected by an edit don't query the model again
+                        if let Some(cache) = engine.get_cache() {
+                            let cached = cache
+                                .retrieve_node(prompt, &node.usages, &params)
+                                .await
+                                .unwrap_or_else(|e| {
+                                    eprintln!("Failed to retrieve node from cache: {e}");
+                                    None
+                                });
+                            if let Some(cached) = cached {
+                                debug!("node prompt served from cache");
+                                for comp in cached {
+                                    new_comps.insert(comp);
+                                }
+                                continue;
+                            }
+                        }
 
-                        let mut printed = ls
-                            .pretty_print(&stubbed, "_hole_", &types_to_annot)
+                        // we add the usages and the declarations that the prompt imports,
+                        // shrinking the prompt if it doesn't fit
+                        let fitted = match PromptBuilder::new(prompt, &types_to_annot)
+                            .usages(&node.usages)
+                            .import_context(import_context.as_ref())
+                            .stub(do_stub)
+                            .max_tokens(max_tokens)
+                            .engine(&*engine)
+                            .build(&ls)
                             .await
-                            .unwrap();
+                        {
+                            Ok(fitted) => fitted,
+                            Err(e) => {
+                                eprintln!("Failed to build prompt for \"{}\": {e}", node.name);
+                                continue;
+                            }
+                        };
 
-                        // we add usages to the prompt
-                        if !node.usages.is_empty() {
-                            printed = format!("{}\n{}", node.usages, printed);
+                        if !fitted.warnings.is_empty() {
+                            for warning in fitted.warnings.iter() {
+                                eprintln!("Prompt for \"{}\" too large: {warning}", node.name);
+                            }
+                            stats::insert_context_warnings(&stats, &node.name, &fitted.warnings)
+                                .await;
                         }
 
-                        let q = CompletionQueryBuilder::new(printed)
-                            .num_comps(num_comps)
-                            .retries(retries)
-                            .fallback(do_fallback)
-                            // added comments are safe, we type-weave after
-                            .problem_whitelist(vec![CheckProblem::ChangedComments])
-                            .build();
-
-                        debug!("query: \n{}", q.input);
-                        let comps = Self::retry_query_until_ok(&engine, q).await;
+                        let comps = if let [part] = fitted.parts.as_slice() {
+                            let q = make_query(part.clone(), do_fallback);
+                            debug!("query: \n{}", q.input);
+                            Self::query_or_give_up(&engine, q).await
+                        } else {
+                            Some(
+                                Self::complete_parts(&engine, prompt, &fitted.parts, &make_query)
+                                    .await,
+                            )
+                        };
                         match comps {
                             Some(comps) => {
+                                // the completions of this prompt that we can cache, we don't
+                                // cache the ones that fallbacked
+                                let mut to_cache = vec![];
                                 for comp in comps {
                                     debug!("level comp: \n{}", comp.code);
-                                    let rewoven = ls
-                                        .weave(prompt, &comp.code, 0)
-                                        .await
-                                        .unwrap_or_else(|_| comp.code.clone());
+                                    // split prompts have no imported declarations
+                                    let Some(code) =
+                                        ImportContext::strip(&fitted.parts[0], &comp.code)
+                                    else {
+                                        debug!("completion changed the imports, skipping it");
+                                        continue;
+                                    };
+                                    let rewoven = ls.weave(prompt, &code, 0).await.unwrap_or(code);
                                     debug!("type-woven completion: \n{}", rewoven);
+                                    if !comp.fallbacked {
+                                        to_cache.push(rewoven.clone());
+                                    }
                                     new_comps.insert(rewoven);
                                 }
+
+                                if !to_cache.is_empty() {
+                                    if let Some(cache) = engine.get_cache() {
+                                        if let Err(e) = cache
+                                            .store_node(prompt, &node.usages, &params, &to_cache)
+                                            .await
+                                        {
+                                            eprintln!("Failed to store node in cache: {e}");
+                                        }
+                                    }
+                                }
                             }
                             None => {
                                 debug!("Failed to get completions for query, skipping prompt.",);
@@ -559,6 +738,7 @@ impl CompletionLevels<PreparedState> {
             let num_nodes = nodes.len();
             let mut handles: Vec<JoinHandle<(String, Vec<String>)>> = vec![]; // node's (name, code)
             let mut lookup: HashMap<String, usize> = HashMap::new(); // node's name -> idx
+            let mut node_usages: HashMap<String, UsageTracker> = HashMap::new();
 
             for (i, node) in nodes.iter().enumerate() {
                 // copy stuff for the async closure
@@ -569,6 +749,9 @@ impl CompletionLevels<PreparedState> {
                 // we store the idx of the node in the lookup table
                 lookup.insert(node.name.clone(), i);
 
+                let usage = self.usage.child();
+                node_usages.insert(node.name.clone(), usage.clone());
+
                 // we concurrently complete the code blocks at the level.
                 handles.push(Self::spawn_parallel_comp(
                     &self.params,
@@ -576,6 +759,8 @@ impl CompletionLevels<PreparedState> {
                     level,
                     prev_level,
                     node,
+                    usage,
+                    self.stats.clone(),
                 ));
             }
 
@@ -591,6 +776,8 @@ impl CompletionLevels<PreparedState> {
 
                 // insert stats into a possible stats object
                 stats::insert_num_comps(&self.stats, &name, num_final_comps).await;
+                let usage = node_usages[&name].get();
+                stats::insert_token_usage(&self.stats, &name, usage).await;
 
                 let idx = lookup.get(&name).unwrap();
                 nodes.get_mut(*idx).unwrap().completed = comps;
@@ -603,6 +790,7 @@ impl CompletionLevels<PreparedState> {
             levels: self.levels,
             params: self.params,
             stats: self.stats,
+            usage: self.usage,
             state: std::marker::PhantomData,
         }
     }
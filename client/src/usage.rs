@@ -0,0 +1,219 @@
+use std::{
+    collections::HashMap,
+    ops::{Add, AddAssign},
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// The number of tokens used by model requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub struct TokenUsage {
+    /// The number of requests that were made to the model.
+    pub requests: u64,
+    /// The number of tokens in the prompts.
+    pub prompt_tokens: u64,
+    /// The number of tokens generated by the model.
+    pub completion_tokens: u64,
+    /// The number of requests whose tokens were estimated with `estimate_tokens`, because
+    /// the model didn't report them.
+    #[serde(default)]
+    pub estimated_requests: u64,
+}
+
+impl TokenUsage {
+    /// The usage of a single request.
+    pub fn request(prompt_tokens: u64, completion_tokens: u64) -> Self {
+        Self {
+            requests: 1,
+            prompt_tokens,
+            completion_tokens,
+            estimated_requests: 0,
+        }
+    }
+
+    /// The usage of a single request that the model didn't report, estimated from the
+    /// prompt and the completions with `estimate_tokens`.
+    pub fn estimated(prompt: &str, completions: &[&str]) -> Self {
+        Self {
+            requests: 1,
+            prompt_tokens: estimate_tokens(prompt),
+            completion_tokens: completions.iter().map(|c| estimate_tokens(c)).sum(),
+            estimated_requests: 1,
+        }
+    }
+
+    /// The total number of tokens, prompt and completion.
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+impl Add for TokenUsage {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        Self {
+            requests: self.requests + rhs.requests,
+            prompt_tokens: self.prompt_tokens + rhs.prompt_tokens,
+            completion_tokens: self.completion_tokens + rhs.completion_tokens,
+            estimated_requests: self.estimated_requests + rhs.estimated_requests,
+        }
+    }
+}
+
+impl AddAssign for TokenUsage {
+    fn add_assign(&mut self, rhs: Self) {
+        *self = *self + rhs;
+    }
+}
+
+impl std::iter::Sum for TokenUsage {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::default(), Add::add)
+    }
+}
+
+impl std::fmt::Display for TokenUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} requests, {} prompt tokens, {} completion tokens",
+            self.requests, self.prompt_tokens, self.completion_tokens
+        )?;
+        if self.estimated_requests > 0 {
+            write!(
+                f,
+                " (estimated for {} of the requests, the model didn't report them)",
+                self.estimated_requests
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Estimates the number of tokens in the given text, as a fallback for models that don't
+/// report their usage. This approximates the BPE tokenizers of code models: identifiers take a token
+/// per ~4 characters, runs of whitespace take a single token, and every other character
+/// takes its own token.
+pub fn estimate_tokens(text: &str) -> u64 {
+    let mut tokens = 0;
+    let mut word_len: u64 = 0;
+    let mut in_space = false;
+    for c in text.chars() {
+        if c.is_alphanumeric() || c == '_' {
+            word_len += 1;
+            in_space = false;
+            continue;
+        }
+        tokens += word_len.div_ceil(4);
+        word_len = 0;
+        if c.is_whitespace() {
+            if !in_space {
+                tokens += 1;
+            }
+            in_space = true;
+        } else {
+            tokens += 1;
+            in_space = false;
+        }
+    }
+    tokens + word_len.div_ceil(4)
+}
+
+/// Records the token usage of model requests. A tracker may have a parent, in which case
+/// everything recorded is also recorded in the parent. This is how usage gets aggregated
+/// per tree node, per file and per run.
+///
+/// Cheap to clone, the clones share the same usage.
+#[derive(Debug, Clone, Default)]
+pub struct UsageTracker {
+    usage: Arc<Mutex<TokenUsage>>,
+    parent: Option<Box<UsageTracker>>,
+}
+
+impl UsageTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a new tracker that starts at zero, and records into this tracker as well.
+    pub fn child(&self) -> Self {
+        Self {
+            usage: Default::default(),
+            parent: Some(Box::new(self.clone())),
+        }
+    }
+
+    /// Records the given usage in this tracker and all of its parents.
+    pub fn record(&self, usage: TokenUsage) {
+        *self.usage.lock().unwrap() += usage;
+        if let Some(parent) = &self.parent {
+            parent.record(usage);
+        }
+    }
+
+    /// The usage recorded so far.
+    pub fn get(&self) -> TokenUsage {
+        *self.usage.lock().unwrap()
+    }
+}
+
+/// The price of a model, in dollars per 1000 tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct ModelPrice {
+    pub prompt_per_1k: f64,
+    pub completion_per_1k: f64,
+}
+
+/// Maps model names (the same names as the `--engine` argument, e.g. "codex") to their
+/// prices. Used to turn the token usage into a cost estimate.
+///
+/// The format of the JSON file is:
+/// ```json
+/// {
+///     "codex": { "prompt_per_1k": 0.02, "completion_per_1k": 0.02 },
+///     "santacoder": { "prompt_per_1k": 0.0, "completion_per_1k": 0.0 }
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct PriceTable {
+    pub models: HashMap<String, ModelPrice>,
+}
+
+impl Default for PriceTable {
+    fn default() -> Self {
+        let davinci = ModelPrice {
+            prompt_per_1k: 0.02,
+            completion_per_1k: 0.02,
+        };
+        let local = ModelPrice::default();
+        Self {
+            models: HashMap::from([
+                ("codex".to_string(), davinci),
+                ("incoder".to_string(), local),
+                ("santacoder".to_string(), local),
+                ("builtin".to_string(), local),
+            ]),
+        }
+    }
+}
+
+impl PriceTable {
+    /// Reads a price table from the given JSON file.
+    pub fn from_file(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let contents = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    /// The estimated cost of the given usage in dollars, if the model is in the table.
+    pub fn cost(&self, model: &str, usage: &TokenUsage) -> Option<f64> {
+        let price = self.models.get(model)?;
+        Some(
+            (usage.prompt_tokens as f64 * price.prompt_per_1k
+                + usage.completion_tokens as f64 * price.completion_per_1k)
+                / 1000.0,
+        )
+    }
+}
@@ -1,14 +1,22 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use opentau::{
     completion::{
-        builtin::BuiltinClient, local::LocalModelClientBuilder, ArcCompletionEngine,
-        ArcCompletionModel, CompletionClientBuilder, TypecheckedCompletion,
+        builtin::BuiltinClient, local::LocalModelClientBuilder, retry::RetryPolicy,
+        tokenizer::Tokenizer, ArcCompletionEngine, ArcCompletionModel, CompletionClientBuilder,
+        TypecheckedCompletion,
     },
     get_path_from_rootdir,
-    langserver::{ts::TsServer, AnnotateType, ArcLangServer, LangServer},
+    langserver::{
+        memo::MemoServer,
+        native::NativeCheckServer,
+        ts::{native::NativeTsServer, TsServer},
+        AnnotateType, ArcLangServer, LangServer, ProblemWeights, ProblemWhitelist,
+    },
     main_strategies::{MainCtx, MainStrategy, SimpleStrategy, TreeStrategy},
+    socket::Supervision,
     tree::stats::{ArcTreeAlgoStats, TreeAlgoStats},
+    usage::{PriceTable, TokenUsage, UsageTracker},
 };
 use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -56,9 +64,9 @@ pub struct EvalSpec {
     /// Should be enabled, only disable for ablation.
     #[serde(default = "eval_spec_defaults::default_enable_usages")]
     pub enable_usages: bool,
-    /// This is an option to enable stubbing of child nodes in the tree strategy.
-    /// We currently evaluate with this disabled, but can be enabled for
-    /// models with tight context windows.
+    /// This is an option to always stub child nodes in the tree strategy.
+    /// We currently evaluate with this disabled. Child nodes still get stubbed
+    /// when a prompt does not fit in the context window of the model.
     #[serde(default = "eval_spec_defaults::default_enable_stubbing")]
     pub enable_stubbing: bool,
     /// This enables the type parser at the completion generation stage. Types
@@ -70,6 +78,52 @@ pub struct EvalSpec {
     /// most of the times and disabled only for ablation.
     #[serde(default = "eval_spec_defaults::default_enable_checkproblems")]
     pub enable_checkproblems: bool,
+    /// This runs the heuristic natively instead of in the language server, which
+    /// saves a round trip for every candidate. The native heuristic only looks at
+    /// the syntax of the types, so it is disabled by default.
+    #[serde(default = "eval_spec_defaults::default_enable_native_check")]
+    pub enable_native_check: bool,
+    /// This runs the printing, stubbing, tree building and weaving natively instead of in
+    /// the language server, so that the language server mostly type checks.
+    /// Disabled by default, as the prompts then keep the formatting of the original
+    /// code instead of the one of the TypeScript printer.
+    #[serde(default = "eval_spec_defaults::default_enable_native_ls")]
+    pub enable_native_ls: bool,
+    /// This checks every native weave against the weave of the language server, using the
+    /// latter when they differ, to validate the native weave on the dataset.
+    /// Only with `enable_native_ls`, and disabled by default, as it costs the round trips
+    /// that the native weave saves.
+    #[serde(default = "eval_spec_defaults::default_enable_native_weave_validation")]
+    pub enable_native_weave_validation: bool,
+    /// This memoizes the results of the pure language server commands, which the
+    /// tree strategy calls many times with the same arguments.
+    #[serde(default = "eval_spec_defaults::default_enable_ls_memo")]
+    pub enable_ls_memo: bool,
+    /// This is the interval between the health checks of the language server, in
+    /// seconds. A server that exited or doesn't answer them is respawned. 0 disables
+    /// the health checks.
+    #[serde(default = "eval_spec_defaults::default_ls_health_check_interval")]
+    pub ls_health_check_interval: u64,
+    /// This is how long a health check may take before the language server is
+    /// considered hung, in seconds. Raise it if the server gets respawned while it
+    /// type checks large files.
+    #[serde(default = "eval_spec_defaults::default_ls_ping_timeout")]
+    pub ls_ping_timeout: u64,
+    /// These are the problems that are allowed in completions when the syntax checker
+    /// is enabled, either everywhere or per kind of statement. By default, the problems
+    /// that only say something about the quality of the types are allowed everywhere.
+    #[serde(default = "eval_spec_defaults::default_problem_whitelist")]
+    pub problem_whitelist: ProblemWhitelist,
+    /// These are the weights that problems add to the heuristic score of a completion.
+    /// By default, problems don't affect the score.
+    #[serde(default)]
+    pub problem_weights: ProblemWeights,
+    /// This enables type checking the completions of the simple strategy as they
+    /// come from the model, cancelling the outstanding model requests once `stop_at`
+    /// completions type check. This makes evaluation with slow models much faster,
+    /// but the completions are the first ones that type check, and they are not cached.
+    #[serde(default = "eval_spec_defaults::default_enable_streaming")]
+    pub enable_streaming: bool,
     /// This depth-limits the tree strategy. It may be useful for very deep
     /// trees, but is not recommended for best results. If this is set to 1,
     /// the tree strategy will behave like the simple strategy but will split
@@ -92,6 +146,25 @@ pub struct EvalSpec {
     /// we enabled all types except for VarDecls.
     #[serde(default = "eval_spec_defaults::default_types")]
     pub types: Vec<AnnotateType>,
+    /// This is the number of holes that local models fill per request. If None,
+    /// all the holes of a prompt are filled in one request. Servers that don't
+    /// support this get one request per hole.
+    #[serde(default)]
+    pub hole_batch_size: Option<usize>,
+    /// This is the path to the `tokenizer.json` of the local model, used to
+    /// count the tokens of prompts exactly. If None, the tokens are estimated
+    /// with a safety margin.
+    #[serde(default)]
+    pub tokenizer: Option<String>,
+    /// This is the retry policy for the model requests. Missing fields are set to
+    /// their defaults: 5 attempts, with exponential backoff starting at 500ms.
+    #[serde(default)]
+    pub retry_policy: RetryPolicy,
+    /// This is the price per 1000 tokens of each model, keyed by the model name,
+    /// used to estimate the cost of the evaluation. Replaces the default table,
+    /// which prices the local models at 0.
+    #[serde(default)]
+    pub prices: PriceTable,
 }
 
 /// Default values for the evaluation spec deserializer.
@@ -128,6 +201,38 @@ mod eval_spec_defaults {
         true
     }
 
+    pub(super) fn default_enable_native_check() -> bool {
+        false
+    }
+
+    pub(super) fn default_enable_native_ls() -> bool {
+        false
+    }
+
+    pub(super) fn default_enable_native_weave_validation() -> bool {
+        false
+    }
+
+    pub(super) fn default_enable_ls_memo() -> bool {
+        true
+    }
+
+    pub(super) fn default_ls_health_check_interval() -> u64 {
+        30
+    }
+
+    pub(super) fn default_ls_ping_timeout() -> u64 {
+        120
+    }
+
+    pub(super) fn default_problem_whitelist() -> ProblemWhitelist {
+        ProblemWhitelist::quality()
+    }
+
+    pub(super) fn default_enable_streaming() -> bool {
+        false
+    }
+
     pub(super) fn default_max_type_quality() -> u16 {
         1000
     }
@@ -144,7 +249,7 @@ mod eval_spec_defaults {
         false
     }
 
-    use opentau::langserver::AnnotateType;
+    use opentau::langserver::{AnnotateType, ProblemWhitelist};
     pub(super) fn default_types() -> Vec<AnnotateType> {
         AnnotateType::all_except(&[AnnotateType::VarDecl])
     }
@@ -152,18 +257,40 @@ mod eval_spec_defaults {
 
 impl EvalSpec {
     async fn get_langserver(&self) -> ArcLangServer {
-        match self.language.as_str() {
+        let ls: ArcLangServer = match self.language.as_str() {
             "ts" => {
                 let path = get_path_from_rootdir("ts-compiler".to_string());
-                Arc::new(
-                    TsServer::make(&path)
-                        .await
-                        .expect("failed to make ts server"),
-                )
+                let supervision =
+                    Supervision::default().ping_timeout(Duration::from_secs(self.ls_ping_timeout));
+                let supervision = match self.ls_health_check_interval {
+                    0 => supervision,
+                    secs => supervision.health_check(Duration::from_secs(secs)),
+                };
+                let ts = TsServer::make(&path)
+                    .await
+                    .expect("failed to make ts server")
+                    .supervise(supervision);
+                let ts: ArcLangServer = if self.enable_native_check {
+                    Arc::new(NativeCheckServer::new(Arc::new(ts)))
+                } else {
+                    Arc::new(ts)
+                };
+                if self.enable_native_ls {
+                    Arc::new(
+                        NativeTsServer::new(ts).validate_weave(self.enable_native_weave_validation),
+                    )
+                } else {
+                    ts
+                }
             }
             _ => {
                 pue!("Unknown language {}", self.language);
             }
+        };
+        if self.enable_ls_memo {
+            Arc::new(MemoServer::new(ls, MemoServer::DEFAULT_CAPACITY))
+        } else {
+            ls
         }
     }
 
@@ -173,6 +300,14 @@ impl EvalSpec {
             "santacoder" | "incoder" => {
                 let mut builder = LocalModelClientBuilder::new(self.model.clone());
                 builder = builder.socket_path(endpoint.clone());
+                if let Some(hole_batch_size) = self.hole_batch_size {
+                    builder = builder.hole_batch_size(hole_batch_size);
+                }
+                if let Some(path) = &self.tokenizer {
+                    let tokenizer = Tokenizer::from_file(path)
+                        .unwrap_or_else(|e| pue!("Failed to load the tokenizer: {e}"));
+                    builder = builder.tokenizer(tokenizer);
+                }
 
                 Arc::new(
                     builder
@@ -188,7 +323,9 @@ impl EvalSpec {
         };
         let engine = CompletionClientBuilder::new(langserver, model)
             .temperature(self.temperature)
-            .max_type_score(self.max_type_quality);
+            .max_type_score(self.max_type_quality)
+            .problem_weights(self.problem_weights.clone())
+            .retry_policy(self.retry_policy.clone());
         Arc::new(engine.build())
     }
 
@@ -218,7 +355,12 @@ impl EvalSpec {
         }
     }
 
-    pub fn make_main_ctx(&self, input_file: String, engine: ArcCompletionEngine) -> MainCtx {
+    pub fn make_main_ctx(
+        &self,
+        input_file: String,
+        engine: ArcCompletionEngine,
+        usage: UsageTracker,
+    ) -> MainCtx {
         MainCtx {
             engine,
             file_contents: input_file,
@@ -232,8 +374,13 @@ impl EvalSpec {
             enable_stubbing: self.enable_stubbing,
             enable_parser: self.enable_parser,
             enable_checkproblems: self.enable_checkproblems,
+            problem_whitelist: self.problem_whitelist.clone(),
+            enable_streaming: self.enable_streaming,
             depth_limit: self.depth_limit,
             types: self.types.clone(),
+            // dataset elements are not files on disk, so there is nothing to resolve
+            import_context: None,
+            usage,
         }
     }
 
@@ -267,6 +414,13 @@ pub struct ResultElement {
     pub stats: Option<TreeAlgoStats>,
     /// The completions that were generated, with typechecking information.
     pub completions: Vec<TypecheckedCompletion>,
+    /// The tokens used by the model requests for this element.
+    #[serde(default)]
+    pub usage: TokenUsage,
+    /// The estimated cost of the model requests for this element, in dollars.
+    /// None if the model is not in the price table.
+    #[serde(default)]
+    pub cost: Option<f64>,
 }
 
 fn resolve_path(path: &str) -> String {
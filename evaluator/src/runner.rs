@@ -4,6 +4,7 @@ use crate::{check_file_delete, get_content, get_name, write_results, EvalSpec, R
 use opentau::{
     completion::{sort_completions, ArcCompletionEngine, CompletionError, TypecheckedCompletion},
     tree::stats::ArcTreeAlgoStats,
+    usage::{TokenUsage, UsageTracker},
 };
 use tokio::{
     sync::{
@@ -55,6 +56,7 @@ struct TaskResult {
     comps: Vec<TypecheckedCompletion>,
     maybe_error: Option<String>,
     maybe_arc_stats: Option<ArcTreeAlgoStats>,
+    usage: TokenUsage,
     element: serde_json::Value,
     time_taken: u128,
 }
@@ -114,8 +116,12 @@ impl RunnerState {
             );
 
             let content = get_content(&element);
-            let context =
-                eval.make_main_ctx(content.to_string(), mutex_engine.lock().await.clone());
+            let usage = UsageTracker::new();
+            let context = eval.make_main_ctx(
+                content.to_string(),
+                mutex_engine.lock().await.clone(),
+                usage.clone(),
+            );
             let (strategy, maybe_arc_stats) = eval.get_strategy();
 
             // wrap in a task so that we can catch panics
@@ -179,6 +185,7 @@ impl RunnerState {
                 comps,
                 maybe_error,
                 maybe_arc_stats,
+                usage: usage.get(),
                 element,
                 time_taken: start.elapsed().as_millis(),
             }
@@ -211,6 +218,7 @@ impl RunnerState {
                 comps,
                 maybe_error,
                 maybe_arc_stats,
+                usage,
                 element,
                 time_taken,
             } = handle.await.unwrap();
@@ -231,6 +239,8 @@ impl RunnerState {
                 eval_spec: self.eval.clone(),
                 stats: maybe_stats,
                 completions: comps,
+                usage,
+                cost: self.eval.prices.cost(&self.eval.model, &usage),
             };
 
             self.results.push(elem);
@@ -250,5 +260,13 @@ impl RunnerState {
         println!("Median: {}", median);
         println!("Min: {}", times_taken[0]);
         println!("Max: {}", times_taken[times_taken.len() - 1]);
+
+        // total usage, including the results we resumed from
+        let total_usage: TokenUsage = self.results.iter().map(|r| r.usage).sum();
+        println!(" ### Token usage ###");
+        println!("{total_usage}");
+        if let Some(cost) = self.eval.prices.cost(&self.eval.model, &total_usage) {
+            println!("Estimated cost: ${cost:.4}");
+        }
     }
 }
@@ -1,12 +1,17 @@
 import ast
 from redbaron import RedBaron, redbaron
 
-from typing import Tuple
+from typing import List, Optional, Tuple
 
 _FAKE_TYPE = '_hole_'
 _PLACEHOLDER_TYPE = '_placeholder_'
 
 
+def _annotation_text(annotation) -> Optional[str]:
+    if annotation is None:
+        return None
+    return annotation.dumps().strip()
+
 def _get_comment_count(source_file: RedBaron) -> int:
     return len(source_file.find_all('CommentNode'))
 
@@ -47,31 +52,43 @@ def _count_nodes(source_file: RedBaron) -> int:
     return count
 
 # FIXME: recursion depth error
-def check_completed(original_ast: RedBaron, completed_ast: RedBaron) -> Tuple[bool, int]:
-    is_completed: bool = True
+# returns the problems found in the completion, by their name in the client
+# (e.g. "NotComplete"), and the heuristic score of the types
+def check_completed(original_ast: RedBaron, completed_ast: RedBaron) -> Tuple[List[str], int]:
+    problems: List[str] = []
     score: int = 0
 
+    annotations = []
     completed_funcs = completed_ast.find_all('DefNode')
     for func in completed_funcs:
+        annotations.append(_annotation_text(func.return_annotation))
         for arg in func.arguments:
-            if arg.annotation == _FAKE_TYPE:
-                is_completed = False
-            elif arg.annotation == 'Any':
-                score += 5
-
-    if not is_completed:
-        return False, score
+            annotations.append(_annotation_text(arg.annotation))
+    for a in completed_ast.find_all('AssignmentNode'):
+        annotations.append(_annotation_text(a.annotation))
+
+    for annotation in annotations:
+        if annotation is not None and _FAKE_TYPE in annotation:
+            if 'NotComplete' not in problems:
+                problems.append('NotComplete')
+        elif annotation == 'Any':
+            score += 5
+            if 'UsesAny' not in problems:
+                problems.append('UsesAny')
 
     original_comments = _get_comment_count(original_ast)
     completed_commments = _get_comment_count(completed_ast)
 
     if original_comments != completed_commments:
-        return False, score
+        problems.append('ChangedComments')
 
     original_count = _count_nodes(original_ast)
     completed_count = _count_nodes(completed_ast)
 
-    return original_count == completed_count, score
+    if original_count != completed_count:
+        problems.append('ChangedCode')
+
+    return problems, score
 
 
 """ Test
@@ -80,6 +97,6 @@ if __name__ == '__main__':
         original_ast = RedBaron(f_orig.read())
     with open('./__example_typed.py', 'r') as f_comp:
         completed_ast = RedBaron(f_comp.read())
-    status, score = check_completed(original_ast, completed_ast)
-    print(status, score)
+    problems, score = check_completed(original_ast, completed_ast)
+    print(problems, score)
 """
@@ -3,27 +3,27 @@ import sys
 import ast
 import time
 import json
-import sched
 import base64
 import socket
 import signal
 from threading import Thread
 from redbaron import RedBaron
-from functools import partial
 
 from printer import print_source
 from check import check_completed
 from stub_printer import stub_source
 
-from typing import Any, Union
+from typing import Any, Callable, Dict, Union
 
 
-if len(sys.argv) != 2:
-    print(f'usage: [path to socket]')
+if len(sys.argv) != 3:
+    print('usage: [path to socket] [pid of rust proc]')
     sys.exit(1)
 
-SERVER_ADDR = sys.argv[2]
+SERVER_ADDR = sys.argv[1]
 BUFF_SIZE = 4096
+# the client sends this after every request
+END_TOKEN = b'??END??'
 
 # checks if in use
 try:
@@ -33,9 +33,7 @@ except OSError:
         print(f'{SERVER_ADDR} already exists')
         sys.exit(1)
 
-rust_pid = int(sys.argv[3])
-
-s = sched.scheduler(time.time, time.sleep)
+rust_pid = int(sys.argv[2])
 
 # determines if rust proc is still running
 def is_pid_running(pid: int) -> bool:
@@ -46,13 +44,14 @@ def is_pid_running(pid: int) -> bool:
     else:
         return True
 
-# periodically check rust proc
-def run_func(sc):
-    is_pid_running(rust_pid)
-    sc.enter(3, 1, run_func, (sc,))
+# every 3 seconds, check if the rust proc is still alive, if not we quit
+def watch_rust_proc() -> None:
+    while True:
+        time.sleep(3)
+        if not is_pid_running(rust_pid):
+            os._exit(0)
 
-s.enter(3, 1, run_func, (s,))
-s.run()
+Thread(target=watch_rust_proc, daemon=True).start()
 
 # used to store and close all sockets before exit
 class SocketManager:
@@ -62,95 +61,123 @@ class SocketManager:
     def __call__(self, c: socket.socket) -> None:
         self._sockets.add(c)
 
+    def remove(self, c: socket.socket) -> None:
+        self._sockets.discard(c)
+
     def close_all(self) -> None:
         while len(self._sockets) > 0:
             s = self._sockets.pop()
             s.close()
 
-# an unbounded recv
-def recvall(s: socket.socket) -> bytes:
+# reads a whole request, the client stops writing after the end token
+def recv_req(s: socket.socket) -> bytes:
     data = b''
-    while True:
+    while not data.endswith(END_TOKEN):
         part = s.recv(BUFF_SIZE)
-        data += part
-        if len(part) < BUFF_SIZE:
+        if not part:
             break
+        data += part
+    if data.endswith(END_TOKEN):
+        data = data[:-len(END_TOKEN)]
     return data
 
+def decode(text: str) -> str:
+    return base64.b64decode(text).decode('utf-8')
+
+def encode(text: str) -> str:
+    return base64.b64encode(text.encode('utf-8')).decode('ascii')
+
 def gen_source_file(decoded_text: str, with_comments: bool = False) -> Union[ast.AST, RedBaron]:
     if with_comments:
         return RedBaron(decoded_text)
     return ast.parse(decoded_text)
 
-def handle_print(decoded_text: str) -> str:
+# req: {cmd: "print", text: "the-text", typeName: "the-type", types: ["FuncDecl", ...]}
+def handle_print(decoded_text: str, req: Dict[str, Any]) -> Dict[str, Any]:
     source_file = gen_source_file(decoded_text, with_comments=True)
     assert isinstance(source_file, RedBaron)
-    res = print_source(source_file)
-    # FIXME: figure out output format
-    return json.dumps({"type": "printResponse", "text": res})
-
-# TODO: implement
-def handle_tree(decoded_text: str) -> str:
-    source_file = gen_source_file(decoded_text)
-    assert isinstance(source_file, ast.AST)
-    res = ...
-    # FIXME: figure out output format
-    return json.dumps({"type": "treeResponse", "text": res})
+    type_name = req.get('typeName') or '_hole_'
+    types = req.get('types') or []
+    res = print_source(
+        source_file,
+        handle_assignments='VarDecl' in types,
+        type_name=type_name,
+    )
+    return {'type': 'printResponse', 'text': encode(res)}
 
-def handle_stub(decoded_text: str) -> str:
+def handle_stub(decoded_text: str, _req: Dict[str, Any]) -> Dict[str, Any]:
     source_file = gen_source_file(decoded_text)
     assert isinstance(source_file, ast.AST)
     res = stub_source(source_file)
-    # FIXME: figure out output format
-    return json.dumps({"type": "stubResponse", "text": res})
+    return {'type': 'stubResponse', 'text': encode(res)}
 
-def handle_check(decoded_text: str, req: Any) -> str:
-    decoded_original = str(base64.b64decode(req.original))
-    original_file = gen_source_file(decoded_original)
-    completed_file = gen_source_file(decoded_text)
+# req: {cmd: "check", text: "the-completed-text", original: "the-original-text"}
+def handle_check(decoded_text: str, req: Dict[str, Any]) -> Dict[str, Any]:
+    original_file = gen_source_file(decode(req['original']), with_comments=True)
+    completed_file = gen_source_file(decoded_text, with_comments=True)
     assert isinstance(original_file, RedBaron)
     assert isinstance(completed_file, RedBaron)
-    # FIXME: fix type return
-    text, score = check_completed(
+    problems, score = check_completed(
         original_ast=original_file,
         completed_ast=completed_file,
     )
-    return json.dumps({"type": "checkResponse", "text": text, "score": score})
-
-# TODO: implement
-def handle_weave() -> str:
-    NotImplemented()
-    return ''
-
-# handles a single client
-def on_client(c: socket.socket) -> None:
+    return {
+        'type': 'checkResponse',
+        'problems': problems,
+        'score': score,
+        'annotProblems': [],
+    }
+
+# the version of the protocol between the client and the server, see
+# client/src/langserver/protocol.rs
+PROTOCOL_VERSION = 1
+
+# req: {cmd: "hello", text: "", version: 1}, the handshake, which the client does before
+# any other command. answers with the version of the protocol and the supported commands.
+def handle_hello(_decoded_text: str, _req: Dict[str, Any]) -> Dict[str, Any]:
+    # the tree, weave, usages, objectInfo, typedefGen, typecheck and checkDts commands are
+    # not implemented
+    return {
+        'type': 'helloResponse',
+        'version': PROTOCOL_VERSION,
+        'commands': ['print', 'stub', 'check'],
+    }
+
+# req: {cmd: "ping", text: ""}, for the health checks of the client
+def handle_ping(_decoded_text: str, _req: Dict[str, Any]) -> Dict[str, Any]:
+    return {'type': 'pong'}
+
+HANDLERS: Dict[str, Callable[[str, Dict[str, Any]], Dict[str, Any]]] = {
+    'print': handle_print,
+    'stub': handle_stub,
+    'check': handle_check,
+    'ping': handle_ping,
+    'hello': handle_hello,
+}
+
+def handle_req(data: bytes) -> Dict[str, Any]:
     try:
-        while True:
-            data = recvall(c)
-            req = json.loads(data)
-            decoded_text = str(base64.b64decode(req.text))
-            if req.cmd == 'print':
-                res = handle_print(decoded_text)
-                c.send(bytes(res, 'utf-8'))
-            elif req.cmd == 'tree':
-                res = handle_tree(decoded_text)
-                c.send(bytes(res, 'utf-8'))
-            elif req.cmd == 'stub':
-                res = handle_stub(decoded_text)
-                c.send(bytes(res, 'utf-8'))
-            elif req.cmd == 'check':
-                res = handle_check(decoded_text, req)
-                c.send(bytes(res, 'utf-8'))
-            elif req.cmd == 'weave':
-                res = handle_weave()
-                c.send(bytes(res, 'utf-8'))
-            else:
-                c.send(json.dumps({
-                    'type': 'error',
-                    'message': f'unknown command {req.cmd}'
-                }).encode())
+        req = json.loads(data)
+        decoded_text = decode(req['text'])
+    except Exception as e:
+        return {'type': 'error', 'message': f'invalid request: {e}'}
+
+    handler = HANDLERS.get(req.get('cmd'))
+    if handler is None:
+        return {'type': 'error', 'message': f'unsupported command {req.get("cmd")}'}
+    # we want this to work no matter what, so errors go back to the client
+    try:
+        return handler(decoded_text, req)
+    except Exception as e:
+        return {'type': 'error', 'message': f'{type(e).__name__}: {e}'}
 
+# handles a single client, which makes a single request
+def on_client(c: socket.socket, sm: SocketManager) -> None:
+    try:
+        res = handle_req(recv_req(c))
+        c.sendall(json.dumps(res).encode('utf-8'))
     finally:
+        sm.remove(c)
         c.close()
 
 # listen for clients
@@ -158,24 +185,27 @@ def init_wait(s: socket.socket, sm: SocketManager) -> None:
     while True:
         c, _ = s.accept()
         sm(c)
-        Thread(target=on_client, args=(c,))
+        Thread(target=on_client, args=(c, sm), daemon=True).start()
 
 # called on exit signal
-def close(_, __, sm: SocketManager) -> None:
+def close(sm: SocketManager, _signum: int, _frame: Any) -> None:
     print(f'Closing {SERVER_ADDR}')
     sm.close_all()
+    try:
+        os.unlink(SERVER_ADDR)
+    except OSError:
+        pass
     sys.exit(0)
 
 # init socket manager
 sm = SocketManager()
 sock = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
 sock.bind(SERVER_ADDR)
-sock.listen(1)
+sock.listen()
 # store socket for future close
 sm(sock)
 
-# this should work but should be tested
-# other way is to use a lambdas
-signal.signal(signal.SIGINT, partial(close, sm)) # type: ignore
-print(f'Listening on {SERVER_ADDR}\n')
+signal.signal(signal.SIGINT, lambda signum, frame: close(sm, signum, frame))
+signal.signal(signal.SIGTERM, lambda signum, frame: close(sm, signum, frame))
+print(f'Listening on {SERVER_ADDR}\n', flush=True)
 init_wait(sock, sm)
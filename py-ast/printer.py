@@ -3,36 +3,40 @@ from redbaron import RedBaron, redbaron
 _FAKE_TYPE = '_hole_'
 
 
-def _handle_func(func) -> None:
-    _handle_arguments(func)
-    _handle_return(func)
+def _handle_func(func, type_name: str) -> None:
+    _handle_arguments(func, type_name)
+    _handle_return(func, type_name)
 
-def _handle_arguments(func) -> None:
+def _handle_arguments(func, type_name: str) -> None:
     for arg in func.arguments:
         if arg.annotation is None and arg.target.value != 'self':
-            arg.annotation = _FAKE_TYPE
+            arg.annotation = type_name
 
-def _handle_return(func) -> None:
+def _handle_return(func, type_name: str) -> None:
     if func.return_annotation is None:
-        func.return_annotation = _FAKE_TYPE
+        func.return_annotation = type_name
 
-def _handle_assignment(a) -> None:
+def _handle_assignment(a, type_name: str) -> None:
     if not isinstance(a.target, redbaron.nodes.TupleNode) \
         and a.operator == '' \
         and a.annotation is None:
-        a.annotation = _FAKE_TYPE
+        a.annotation = type_name
 
-def _handle_source(source: RedBaron, handle_assignments: bool) -> None:
+def _handle_source(source: RedBaron, handle_assignments: bool, type_name: str) -> None:
     funcs = source.find_all('DefNode')
     assignments = source.find_all('AssignmentNode')
     for func in funcs:
-        _handle_func(func)
+        _handle_func(func, type_name)
     if handle_assignments:
         for a in assignments:
-            _handle_assignment(a)
-
-def print_source(source_file: RedBaron, handle_assignments: bool = False) -> str:
-    _handle_source(source_file, handle_assignments)
+            _handle_assignment(a, type_name)
+
+def print_source(
+    source_file: RedBaron,
+    handle_assignments: bool = False,
+    type_name: str = _FAKE_TYPE,
+) -> str:
+    _handle_source(source_file, handle_assignments, type_name)
     out = source_file.dumps()
     return out
 
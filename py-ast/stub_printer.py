@@ -1,30 +1,26 @@
 import os
 import ast
+import tempfile
 import subprocess
 
-TMP_DIR = '/tmp'
-TMP_FILE = os.path.join(TMP_DIR, 'temp__.py')
-
 
 def stub_source(source_file: ast.AST) -> str:
-    with open(TMP_FILE, 'w') as f:
-        f.write(ast.unparse(source_file))
-    cmd = f'stubgen {TMP_FILE} --output {TMP_DIR} --no-import'
-    sp = subprocess.Popen(cmd.split(), stdout=subprocess.PIPE, stderr=subprocess.PIPE)
-    out, err = sp.communicate()
-    if out:
-        pyi = f'{TMP_FILE}i'
+    # requests are handled concurrently, so every stub gets its own directory
+    with tempfile.TemporaryDirectory() as tmp_dir:
+        tmp_file = os.path.join(tmp_dir, 'temp__.py')
+        with open(tmp_file, 'w') as f:
+            f.write(ast.unparse(source_file))
+        cmd = ['stubgen', tmp_file, '--output', tmp_dir, '--no-import']
+        sp = subprocess.Popen(cmd, stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+        out, err = sp.communicate()
+        if not out:
+            raise Exception(err.decode('utf-8'))
         s = ''
-        with open(pyi, 'r') as f:
-            lines = f.readlines()
-            for line in lines:
+        with open(f'{tmp_file}i', 'r') as f:
+            for line in f.readlines():
                 if 'import' not in line:
                     s += line
-            os.remove(pyi)
-            os.remove(TMP_FILE)
-            return handle_cutoff_err(s[1:-1])
-    else:
-        raise Exception(err.decode('utf-8'))
+        return handle_cutoff_err(s[1:-1])
 
 def handle_cutoff_err(s: str) -> str:
     if s.startswith('ef'):
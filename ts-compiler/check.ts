@@ -6,6 +6,7 @@ import {
   getDeepMutableClone,
 } from "./utils";
 import { codePrinter } from "./utils";
+import { AnnotateType } from "./printer";
 
 const count_nodes = (child: ts.Node): number => {
   let count = 1;
@@ -34,15 +35,50 @@ const get_comment_count = (s: ts.SourceFile): number => {
   return count;
 };
 
-type CheckProblem = "NotComplete" | "ChangedCode" | "ChangedComments";
+// gets the number of type declarations (type aliases, interfaces and enums) in a source file
+const get_type_decl_count = (s: ts.SourceFile): number => {
+  let count = 0;
+  const visit = (node: ts.Node) => {
+    if (
+      ts.isTypeAliasDeclaration(node) ||
+      ts.isInterfaceDeclaration(node) ||
+      ts.isEnumDeclaration(node)
+    ) {
+      count += 1;
+    }
+    node.forEachChild(visit);
+  };
+  s.forEachChild(visit);
+  return count;
+};
+
+// from langserver.rs in the Rust client
+type CheckProblem =
+  | "NotComplete"
+  | "ChangedCode"
+  | "ChangedComments"
+  | "UsesAny"
+  | "UsesUnknownIdentifier"
+  | "OverlyBroadType"
+  | "AddedTypeDeclaration";
+
+// a problem found in a type annotation, along with the kind of statement it annotates
+export type AnnotProblem = {
+  problem: CheckProblem;
+  annot: AnnotateType;
+};
+
+// names of the interfaces that every object or function is assignable to
+const broadInterfaces = ["Object", "Function"];
 
 export const checkCompleted = (
   original: ts.SourceFile,
   completed: ts.SourceFile,
   completedChecker: ts.TypeChecker
-): [CheckProblem[], number] => {
+): [CheckProblem[], number, AnnotProblem[]] => {
   let isCompleted = true;
   let problems: CheckProblem[] = [];
+  let annotProblems: AnnotProblem[] = [];
   let rawScore = 0;
 
   // this is the number of type nodes (only leaf nodes)
@@ -60,7 +96,15 @@ export const checkCompleted = (
 
   // checks completed types and scores them
   completed.forEachChild((toplevelChild) => {
-    typeTraversal(toplevelChild, (ty, child) => {
+    typeTraversal(toplevelChild, (ty, child, kind) => {
+      const report = (problem: CheckProblem) => {
+        if (
+          !annotProblems.some((p) => p.problem === problem && p.annot === kind)
+        ) {
+          annotProblems.push({ problem, annot: kind });
+        }
+      };
+
       // means that the model removed the type, or could be a vardecl-bound function
       if (!ty) {
         if (!isVarDeclBoundFunction(child)) {
@@ -109,14 +153,22 @@ export const checkCompleted = (
             return false;
           };
 
-          if (printed === "any" && !isUnresolved(tsType)) {
+          if (isUnresolved(tsType)) {
+            report("UsesUnknownIdentifier");
+          } else if (printed === "any") {
             rawScore += 0.5;
+            report("UsesAny");
           }
           numTypeNodes += 1;
         } else if (typeFlags & ts.TypeFlags.Unknown) {
           // console.log("got unknown");
           rawScore += 1;
           numTypeNodes += 1;
+          report("OverlyBroadType");
+        } else if (typeFlags & ts.TypeFlags.NonPrimitive) {
+          // the `object` type
+          numTypeNodes += 1;
+          report("OverlyBroadType");
         } else if (
           typeFlags & ts.TypeFlags.Undefined ||
           typeFlags & ts.TypeFlags.Null
@@ -146,6 +198,9 @@ export const checkCompleted = (
               if (name === "Function") {
                 rawScore += 0.5;
               }
+              if (broadInterfaces.includes(name)) {
+                report("OverlyBroadType");
+              }
             }
             numTypeNodes += 1;
           } else if (objType.objectFlags & ts.ObjectFlags.Anonymous) {
@@ -153,6 +208,17 @@ export const checkCompleted = (
             // anonymous objects are usually functions, so we need to check return and argument types
             const funcType = objType as ts.ObjectType;
             const callSignatures = funcType.getCallSignatures();
+            if (
+              callSignatures.length === 0 &&
+              funcType.getProperties().length === 0 &&
+              !funcType.getStringIndexType() &&
+              !funcType.getNumberIndexType()
+            ) {
+              // the `{}` type, anything but null and undefined is assignable to it
+              numTypeNodes += 1;
+              report("OverlyBroadType");
+              return;
+            }
             try {
               const returnType = callSignatures[0].getReturnType();
               const args = callSignatures[0]
@@ -202,6 +268,11 @@ export const checkCompleted = (
     problems.push("ChangedComments");
   }
 
+  // check if it declared new types, instead of using the ones that exist
+  if (get_type_decl_count(completed) > get_type_decl_count(original)) {
+    problems.push("AddedTypeDeclaration");
+  }
+
   // now strip types
   const fake = createFakeType("bleh");
   const stripTypes = (_: ts.TypeNode | undefined) => fake;
@@ -221,5 +292,12 @@ export const checkCompleted = (
     problems.push("ChangedCode");
   }
 
-  return [problems, computeScore(rawScore, numTypeNodes)];
+  // the problems found in annotations are problems of the whole completion too
+  annotProblems.forEach((p) => {
+    if (!problems.includes(p.problem)) {
+      problems.push(p.problem);
+    }
+  });
+
+  return [problems, computeScore(rawScore, numTypeNodes), annotProblems];
 };
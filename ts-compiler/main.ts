@@ -70,18 +70,24 @@ const makeCompilerHost = (
   readFile: () => "",
 });
 
-const createProgram = (code: string, setParentNodes = false): ts.Program => {
+const createProgram = (
+  code: string,
+  setParentNodes = false,
+  js = false
+): ts.Program => {
+  // javascript files get their types from JSDoc comments, and get checked with checkJs
+  const filename = js ? "comp.js" : "comp.ts";
   const prog = ts.createProgram({
-    rootNames: ["comp.ts"],
+    rootNames: [filename],
     options: compilerOptions,
     host: makeCompilerHost(
-      "comp.ts",
+      filename,
       ts.createSourceFile(
-        "comp.ts",
+        filename,
         code,
         ts.ScriptTarget.Latest,
         setParentNodes,
-        ts.ScriptKind.TS
+        js ? ts.ScriptKind.JS : ts.ScriptKind.TS
       )
     ),
   });
@@ -164,6 +170,7 @@ const handleCheck = (decodedText: string, req: any): string => {
     type: "checkResponse",
     problems: res[0],
     score: res[1],
+    annotProblems: res[2],
   });
 };
 
@@ -265,9 +272,12 @@ const handleTypedefGen = (decodedText: string): string => {
   });
 };
 
-const handleTypeCheck = (decodedText: string): string => {
-  const completedProgram = createProgram(decodedText, false);
-  const completedFile = completedProgram.getSourceFile("comp.ts")!;
+const handleTypeCheck = (decodedText: string, req: any): string => {
+  const js = req.js || false;
+  const completedProgram = createProgram(decodedText, false, js);
+  const completedFile = completedProgram.getSourceFile(
+    js ? "comp.js" : "comp.ts"
+  )!;
   const diag = ts.getPreEmitDiagnostics(completedProgram, completedFile);
   return JSON.stringify({
     type: "typeCheckResponse",
@@ -275,6 +285,78 @@ const handleTypeCheck = (decodedText: string): string => {
   });
 };
 
+// checks the given declaration file against the given javascript code. the declarations
+// have to be valid, and the exports of the code have to be assignable to them.
+const handleCheckDts = (decodedText: string, req: any): string => {
+  const original = Buffer.from(req.original, "base64").toString("utf8");
+  const files = new Map<string, ts.SourceFile>();
+  const addFile = (name: string, code: string, kind: ts.ScriptKind) => {
+    files.set(
+      name,
+      ts.createSourceFile(name, code, ts.ScriptTarget.Latest, false, kind)
+    );
+  };
+  addFile("decl.d.ts", decodedText, ts.ScriptKind.TS);
+  addFile("impl.js", original, ts.ScriptKind.JS);
+  addFile(
+    "check.ts",
+    [
+      'import * as decl from "./decl";',
+      'import * as impl from "./impl";',
+      "const check: typeof decl = impl;",
+    ].join("\n"),
+    ts.ScriptKind.TS
+  );
+
+  const host: ts.CompilerHost = {
+    ...makeCompilerHost("check.ts", files.get("check.ts")!),
+    getSourceFile: (name, languageVersion) =>
+      files.get(name) ||
+      defaultCompilerHost.getSourceFile(name, languageVersion),
+    // so that the imports resolve to our files
+    fileExists: (name) =>
+      files.has(name) || defaultCompilerHost.fileExists(name),
+  };
+  const prog = ts.createProgram({
+    rootNames: ["check.ts"],
+    options: compilerOptions,
+    host,
+  });
+  // the errors of the javascript code itself don't count
+  let errors = 0;
+  for (const name of ["decl.d.ts", "check.ts"]) {
+    errors += ts.getPreEmitDiagnostics(prog, prog.getSourceFile(name)).length;
+  }
+  return JSON.stringify({
+    type: "checkDtsResponse",
+    errors,
+  });
+};
+
+// the version of the protocol between the client and the server, see
+// client/src/langserver/protocol.rs
+const PROTOCOL_VERSION = 1;
+const COMMANDS = [
+  "print",
+  "tree",
+  "stub",
+  "check",
+  "weave",
+  "usages",
+  "objectInfo",
+  "typedefGen",
+  "typecheck",
+  "checkDts",
+];
+
+const handleHello = (): string => {
+  return JSON.stringify({
+    type: "helloResponse",
+    version: PROTOCOL_VERSION,
+    commands: COMMANDS,
+  });
+};
+
 var unixServer = net.createServer(function (client) {
   let completeData = "";
   const END_TOKEN = "??END??";
@@ -319,7 +401,8 @@ var unixServer = net.createServer(function (client) {
         }
         // check if the given text is complete
         // req: {cmd: "check", text: "the-completed-text", original: "the-original-text"}
-        // additionally, returns a score for the completion.
+        // additionally, returns a score for the completion, and the problems found in each
+        // kind of annotation.
         case "check": {
           client.write(handleCheck(decodedText, req));
           break;
@@ -347,8 +430,30 @@ var unixServer = net.createServer(function (client) {
           break;
         }
         // typecheck the given file contents, returns the number of errors
+        // req: {cmd: "typecheck", text: "the-text", js: false}
+        // with js, the text is checked as a javascript file, typed by JSDoc comments
         case "typecheck": {
-          client.write(handleTypeCheck(decodedText));
+          client.write(handleTypeCheck(decodedText, req));
+          break;
+        }
+        // the handshake, which the client does before any other command. answers with
+        // the version of the protocol and the supported commands.
+        // req: {cmd: "hello", text: "", version: 1}
+        case "hello": {
+          client.write(handleHello());
+          break;
+        }
+        // answers the health checks of the client
+        // req: {cmd: "ping", text: ""}
+        case "ping": {
+          client.write(JSON.stringify({ type: "pong" }));
+          break;
+        }
+        // checks a declaration file against the javascript code it declares, returns the
+        // number of errors
+        // req: {cmd: "checkDts", text: "the-declarations", original: "the-javascript"}
+        case "checkDts": {
+          client.write(handleCheckDts(decodedText, req));
           break;
         }
         default: {
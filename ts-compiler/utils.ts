@@ -67,7 +67,9 @@ export const typeTraversal = (
   node: ts.Node,
   visitor: (
     ty: ts.TypeNode | undefined,
-    inner_child: ts.Node
+    inner_child: ts.Node,
+    // the kind of statement that the type annotates
+    kind: AnnotateType
   ) => ts.TypeNode | undefined,
   // default to all types
   visit_list: AnnotateType[] = allTypes
@@ -76,46 +78,46 @@ export const typeTraversal = (
     (ts.isFunctionExpression(node) || ts.isArrowFunction(node)) &&
     visit_list.includes("FuncExpr")
   ) {
-    node.type = visitor(node.type, node); // NOTE: return type
+    node.type = visitor(node.type, node, "FuncExpr"); // NOTE: return type
     node.parameters.forEach((parameter) => {
-      parameter.type = visitor(parameter.type, parameter);
+      parameter.type = visitor(parameter.type, parameter, "FuncExpr");
     });
   } else if (
     ts.isFunctionDeclaration(node) &&
     visit_list.includes("FuncDecl")
   ) {
-    node.type = visitor(node.type, node); // NOTE: return type
+    node.type = visitor(node.type, node, "FuncDecl"); // NOTE: return type
     node.parameters.forEach((parameter) => {
-      parameter.type = visitor(parameter.type, parameter);
+      parameter.type = visitor(parameter.type, parameter, "FuncDecl");
     });
   } else if (
     ts.isMethodDeclaration(node) &&
     visit_list.includes("ClassMethod")
   ) {
-    node.type = visitor(node.type, node);
+    node.type = visitor(node.type, node, "ClassMethod");
     node.parameters.forEach((parameter) => {
-      parameter.type = visitor(parameter.type, parameter);
+      parameter.type = visitor(parameter.type, parameter, "ClassMethod");
     });
   } else if (
     ts.isPropertyDeclaration(node) &&
     visit_list.includes("ClassProp")
   ) {
-    node.type = visitor(node.type, node);
+    node.type = visitor(node.type, node, "ClassProp");
   } else if (ts.isVariableStatement(node) && visit_list.includes("VarDecl")) {
     node.declarationList.declarations.forEach((declaration) => {
-      declaration.type = visitor(declaration.type, declaration);
+      declaration.type = visitor(declaration.type, declaration, "VarDecl");
     });
   } else if (ts.isPropertySignature(node) && visit_list.includes("TypeDecl")) {
-    node.type = visitor(node.type, node);
+    node.type = visitor(node.type, node, "TypeDecl");
   } else if (
     ts.isFunctionTypeNode(node) &&
     // this falls under FuncExpr
     visit_list.includes("FuncExpr")
   ) {
     node.parameters.forEach((parameter) => {
-      parameter.type = visitor(parameter.type, parameter);
+      parameter.type = visitor(parameter.type, parameter, "FuncExpr");
     });
-    node.type = visitor(node.type, node) ?? node.type;
+    node.type = visitor(node.type, node, "FuncExpr") ?? node.type;
   } else if (
     ts.isConstructorDeclaration(node) &&
     // this falls under FuncDecl
@@ -123,7 +125,7 @@ export const typeTraversal = (
   ) {
     // no need for return type for constructors, why does typescript even declare it?
     node.parameters.forEach((parameter) => {
-      parameter.type = visitor(parameter.type, parameter);
+      parameter.type = visitor(parameter.type, parameter, "FuncDecl");
     });
   }
 